@@ -0,0 +1,11 @@
+//! Runs the bot loop, like `wdrc_rs bot <config>`.
+use anyhow::Result;
+use std::env;
+use wdrc_rs::{jobs::Job, WdRc};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config_file = env::args().nth(1).unwrap_or("config.json".to_string());
+    let mut wdrc = WdRc::new(&config_file)?;
+    Job::Bot.run(&mut wdrc).await
+}
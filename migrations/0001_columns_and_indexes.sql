@@ -0,0 +1,64 @@
+-- Columns and indexes added since the first deployments, for tables created before init-db.
+ALTER TABLE `statements` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `statements` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `qualifiers` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `qualifiers` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `references` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `references` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `labels` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `labels` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `badges` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `badges` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `statements` ADD COLUMN IF NOT EXISTS `user` varchar(255);
+ALTER TABLE `statements` ADD COLUMN IF NOT EXISTS `detail` text;
+ALTER TABLE `statements` ADD COLUMN IF NOT EXISTS `summary` int unsigned;
+ALTER TABLE `statements` ADD COLUMN IF NOT EXISTS `session` int unsigned;
+ALTER TABLE `labels` ADD COLUMN IF NOT EXISTS `user` varchar(255);
+ALTER TABLE `labels` ADD COLUMN IF NOT EXISTS `detail` text;
+ALTER TABLE `labels` ADD COLUMN IF NOT EXISTS `summary` int unsigned;
+ALTER TABLE `labels` ADD COLUMN IF NOT EXISTS `session` int unsigned;
+ALTER TABLE `change_values` ADD COLUMN IF NOT EXISTS `truncated` tinyint(1) NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS `timestamp` ON `creations` (`timestamp`);
+CREATE INDEX IF NOT EXISTS `timestamp` ON `deletions` (`timestamp`);
+ALTER TABLE `property_statements` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `property_statements` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `property_qualifiers` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `property_qualifiers` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `property_references` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `property_references` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `property_labels` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `property_labels` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `property_statements` ADD COLUMN IF NOT EXISTS `user` varchar(255);
+ALTER TABLE `property_statements` ADD COLUMN IF NOT EXISTS `detail` text;
+ALTER TABLE `property_statements` ADD COLUMN IF NOT EXISTS `summary` int unsigned;
+ALTER TABLE `property_statements` ADD COLUMN IF NOT EXISTS `session` int unsigned;
+ALTER TABLE `property_labels` ADD COLUMN IF NOT EXISTS `user` varchar(255);
+ALTER TABLE `property_labels` ADD COLUMN IF NOT EXISTS `detail` text;
+ALTER TABLE `property_labels` ADD COLUMN IF NOT EXISTS `summary` int unsigned;
+ALTER TABLE `property_labels` ADD COLUMN IF NOT EXISTS `session` int unsigned;
+ALTER TABLE `property_change_values` ADD COLUMN IF NOT EXISTS `truncated` tinyint(1) NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS `timestamp` ON `property_creations` (`timestamp`);
+CREATE INDEX IF NOT EXISTS `timestamp` ON `property_deletions` (`timestamp`);
+ALTER TABLE `lexeme_statements` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_statements` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_qualifiers` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_qualifiers` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_references` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_references` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_labels` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_labels` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_subentities` ADD COLUMN IF NOT EXISTS `is_bot` tinyint(1) NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_subentities` ADD COLUMN IF NOT EXISTS `engine` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_statements` ADD COLUMN IF NOT EXISTS `user` varchar(255);
+ALTER TABLE `lexeme_statements` ADD COLUMN IF NOT EXISTS `detail` text;
+ALTER TABLE `lexeme_statements` ADD COLUMN IF NOT EXISTS `summary` int unsigned;
+ALTER TABLE `lexeme_statements` ADD COLUMN IF NOT EXISTS `session` int unsigned;
+ALTER TABLE `lexeme_labels` ADD COLUMN IF NOT EXISTS `user` varchar(255);
+ALTER TABLE `lexeme_labels` ADD COLUMN IF NOT EXISTS `detail` text;
+ALTER TABLE `lexeme_labels` ADD COLUMN IF NOT EXISTS `summary` int unsigned;
+ALTER TABLE `lexeme_labels` ADD COLUMN IF NOT EXISTS `session` int unsigned;
+ALTER TABLE `lexeme_change_values` ADD COLUMN IF NOT EXISTS `truncated` tinyint(1) NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS `timestamp` ON `lexeme_creations` (`timestamp`);
+CREATE INDEX IF NOT EXISTS `timestamp` ON `lexeme_deletions` (`timestamp`);
+CREATE INDEX IF NOT EXISTS `timestamp` ON `badges` (`timestamp`);
+CREATE INDEX IF NOT EXISTS `target` ON `redirects` (`target`);
@@ -0,0 +1,29 @@
+-- Columns added after 0001: the entity's sitelink count, and the `reverted` change type.
+ALTER TABLE `statements` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `qualifiers` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `references` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `labels` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `badges` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `property_statements` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `property_qualifiers` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `property_references` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `property_labels` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_statements` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_qualifiers` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_references` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_labels` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `lexeme_subentities` ADD COLUMN IF NOT EXISTS `sitelinks` int unsigned NOT NULL DEFAULT 0;
+ALTER TABLE `statements` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `qualifiers` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `references` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `labels` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `badges` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `property_statements` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `property_qualifiers` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `property_references` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `property_labels` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `lexeme_statements` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `lexeme_qualifiers` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `lexeme_references` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `lexeme_labels` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
+ALTER TABLE `lexeme_subentities` MODIFY COLUMN `change_type` enum('added','changed','removed','reverted') NOT NULL;
@@ -0,0 +1,13 @@
+-- Indexes for listing the latest changes of a property or in a language.
+CREATE INDEX IF NOT EXISTS `property_timestamp` ON `statements` (`property`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `property_timestamp` ON `qualifiers` (`property`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `property_timestamp` ON `references` (`property`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `language_timestamp` ON `labels` (`language`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `property_timestamp` ON `property_statements` (`property`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `property_timestamp` ON `property_qualifiers` (`property`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `property_timestamp` ON `property_references` (`property`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `language_timestamp` ON `property_labels` (`language`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `property_timestamp` ON `lexeme_statements` (`property`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `property_timestamp` ON `lexeme_qualifiers` (`property`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `property_timestamp` ON `lexeme_references` (`property`,`timestamp`);
+CREATE INDEX IF NOT EXISTS `language_timestamp` ON `lexeme_labels` (`language`,`timestamp`);
@@ -0,0 +1,2 @@
+-- Published dataset months written again by a redaction.
+ALTER TABLE `redactions` ADD COLUMN IF NOT EXISTS `republished` varchar(255);
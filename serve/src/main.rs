@@ -0,0 +1,17 @@
+//! Serves the HTTP API, like `wdrc_rs serve <config> [address]`. Changes are streamed from
+//! polling the database, so the bot can run and restart on its own.
+use anyhow::Result;
+use std::env;
+use wdrc_rs::{
+    server::{Server, DEFAULT_ADDRESS},
+    WdRc,
+};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let config_file = args.get(1).map(|s| s.as_str()).unwrap_or("config.json");
+    let address = args.get(2).map(|s| s.as_str()).unwrap_or(DEFAULT_ADDRESS);
+    let wdrc = WdRc::new(config_file)?;
+    Server::serve(wdrc, address, None).await
+}
@@ -0,0 +1,164 @@
+use anyhow::{anyhow, Result};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use wikimisc::mysql_async::{from_row, prelude::Queryable};
+
+use crate::{recent_changes::ChangedItem, RevisionId, WdRc};
+
+/// Revisions read from the replica per batch.
+const BACKFILL_BATCH_SIZE: u64 = 500;
+
+/// A replica `revision` row: ID, parent ID, timestamp, entity ID, user name, edit summary, and
+/// `|`-separated change tags.
+type RevisionRow = (
+    RevisionId,
+    RevisionId,
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+/// The outcome of a backfill run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Backfilled {
+    pub revisions: u64,
+    pub batches: u64,
+    /// Timestamp and ID of the last revision processed.
+    pub position: String,
+}
+
+/// Logs the changes of past revisions from the replica `revision` table, for time ranges older than
+/// the 30 days `recentchanges` holds.
+///
+/// Every revision is compared to its parent on its own. Progress is kept in `meta` per time range,
+/// so an interrupted backfill continues where it stopped. The bot flag is not known from `revision`,
+/// so backfilled changes are not marked as bot edits.
+pub struct Backfill<'a> {
+    wdrc: &'a mut WdRc,
+    from: String,
+    to: String,
+}
+
+impl<'a> Backfill<'a> {
+    /// Backfills revisions with timestamps from `from` up to and including `to`, as `YYYYMMDDHHMMSS`.
+    pub fn new(wdrc: &'a mut WdRc, from: &str, to: &str) -> Result<Self> {
+        for timestamp in [from, to] {
+            NaiveDateTime::parse_from_str(timestamp, "%Y%m%d%H%M%S")
+                .map_err(|e| anyhow!("Bad timestamp {timestamp:?}: {e}"))?;
+        }
+        if from > to {
+            return Err(anyhow!("{from} is after {to}"));
+        }
+        Ok(Self {
+            wdrc,
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+
+    fn meta_key(&self) -> String {
+        format!("backfill_{}_{}", self.from, self.to)
+    }
+
+    /// `20240101000000:12345` as the timestamp and revision ID to continue after.
+    fn parse_position(position: &str) -> Option<(String, RevisionId)> {
+        let (timestamp, rev_id) = position.split_once(':')?;
+        Some((timestamp.to_string(), rev_id.parse().ok()?))
+    }
+
+    pub async fn run(&mut self) -> Result<Backfilled> {
+        let meta_key = self.meta_key();
+        let (mut timestamp, mut rev_id) = self
+            .wdrc
+            .get_key_value(&meta_key)
+            .await?
+            .and_then(|position| Self::parse_position(&position))
+            .unwrap_or_else(|| (self.from.to_owned(), 0));
+        let mut ret = Backfilled::default();
+        loop {
+            let rows = self.next_batch(&timestamp, rev_id).await?;
+            let last = match rows.last() {
+                Some(last) => (last.2.to_owned(), last.0),
+                None => break,
+            };
+            let items: Vec<ChangedItem> = rows.into_iter().map(Self::changed_item).collect();
+            self.wdrc.compare_and_log(&items).await?;
+            (timestamp, rev_id) = last;
+            self.wdrc
+                .set_key_value(&meta_key, &format!("{timestamp}:{rev_id}"))
+                .await?;
+            ret.revisions += items.len() as u64;
+            ret.batches += 1;
+        }
+        ret.position = format!("{timestamp}:{rev_id}");
+        Ok(ret)
+    }
+
+    async fn next_batch(&self, timestamp: &str, rev_id: RevisionId) -> Result<Vec<RevisionRow>> {
+        let namespaces: Vec<String> = self
+            .wdrc
+            .namespaces()
+            .iter()
+            .map(|ns| ns.to_string())
+            .collect();
+        let sql = format!("SELECT `rev_id`,`rev_parent_id`,`rev_timestamp`,`page_title`,`actor_name`,`comment_text`,(SELECT GROUP_CONCAT(`ctd_name` SEPARATOR '|') FROM `change_tag` JOIN `change_tag_def` ON `ctd_id`=`ct_tag_id` WHERE `ct_rev_id`=`rev_id`) AS `tags` FROM `revision` JOIN `page` ON `page_id`=`rev_page` LEFT JOIN `actor` ON `actor_id`=`rev_actor` LEFT JOIN `comment` ON `comment_id`=`rev_comment_id` WHERE `page_namespace` IN ({}) AND (`rev_timestamp`>? OR (`rev_timestamp`=? AND `rev_id`>?)) AND `rev_timestamp`<=? ORDER BY `rev_timestamp`,`rev_id` LIMIT ?", namespaces.join(","));
+        let rows = self
+            .wdrc
+            .db()
+            .get_connection("wikidata")
+            .await?
+            .exec_iter(
+                sql,
+                (timestamp, timestamp, rev_id, &self.to, BACKFILL_BATCH_SIZE),
+            )
+            .await?
+            .map_and_drop(from_row::<RevisionRow>)
+            .await?;
+        Ok(rows)
+    }
+
+    fn changed_item(row: RevisionRow) -> ChangedItem {
+        let (rev_id, parent_id, timestamp, q, user, comment, tags) = row;
+        let tags: Vec<String> = tags
+            .unwrap_or_default()
+            .split('|')
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| tag.to_string())
+            .collect();
+        ChangedItem::new(&q, parent_id, rev_id, &timestamp)
+            .with_user(user.as_deref())
+            .with_comment(comment.as_deref())
+            .with_tags(&tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_position() {
+        assert_eq!(
+            Backfill::parse_position("20240101000000:12345"),
+            Some(("20240101000000".to_string(), 12345))
+        );
+        assert_eq!(Backfill::parse_position("20240101000000"), None);
+        let item = Backfill::changed_item((
+            12345,
+            0,
+            "20240101000000".to_string(),
+            "Q42".to_string(),
+            Some("Example".to_string()),
+            None,
+            Some("mw-reverted|OAuth CID: 1".to_string()),
+        ));
+        assert_eq!(
+            item,
+            ChangedItem::new("Q42", 0, 12345, "20240101000000")
+                .with_user(Some("Example"))
+                .with_tags(&["mw-reverted".to_string(), "OAuth CID: 1".to_string()])
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    change::EntityType, publish::DATASET_SCHEMA_VERSION, query::API_VERSIONS,
+    revision_compare::ENGINE_VERSION, wiki::Wiki, WdRc,
+};
+
+/// What this deployment supports, so clients can check before relying on a feature and degrade
+/// gracefully on minimal deployments.
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub wiki: Wiki,
+    /// Versions accepted by the `version` query parameter.
+    pub api_versions: Vec<u32>,
+    pub engine_version: u32,
+    pub dataset_schema_version: u32,
+    pub entity_types: Vec<String>,
+    pub features: BTreeMap<String, bool>,
+}
+
+impl Capabilities {
+    pub fn new(wdrc: &WdRc) -> Self {
+        let tracked: Vec<EntityType> = EntityType::all()
+            .into_iter()
+            .filter(|et| wdrc.namespaces().contains(&et.namespace()))
+            .collect();
+        let mut features: BTreeMap<String, bool> = wdrc
+            .features()
+            .into_iter()
+            .map(|(name, on)| (name.to_string(), on))
+            .collect();
+        // Always logged, and monthly archives can always be published
+        for feature in ["qualifiers", "references", "archives"] {
+            features.insert(feature.to_string(), true);
+        }
+        features.insert("lexemes".to_string(), tracked.contains(&EntityType::Lexeme));
+        Self {
+            wiki: wdrc.wiki(),
+            api_versions: API_VERSIONS.to_vec(),
+            engine_version: ENGINE_VERSION,
+            dataset_schema_version: DATASET_SCHEMA_VERSION,
+            entity_types: tracked.iter().map(|et| et.as_str().to_string()).collect(),
+            features,
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use chrono::NaiveDateTime;
+use std::time::Duration;
+
+/// Consecutive batch windows covering part of a long backlog, for reading them concurrently
+/// after downtime. Windows overlap by their boundary second, like consecutive
+/// batches do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatchUpPlan {
+    windows: Vec<(String, String)>,
+}
+
+impl CatchUpPlan {
+    /// Plans `windows` windows of `window` each from `oldest`, if the backlog up to `now` is at
+    /// least that long; otherwise the regular single batch suffices.
+    pub fn new(oldest: &str, now: NaiveDateTime, window: Duration, windows: usize) -> Option<Self> {
+        let oldest = NaiveDateTime::parse_from_str(oldest, "%Y%m%d%H%M%S").ok()?;
+        let window = chrono::Duration::from_std(window).ok()?;
+        if windows < 2 || now - oldest < window * windows as i32 {
+            return None;
+        }
+        let format = |dt: NaiveDateTime| dt.format("%Y%m%d%H%M%S").to_string();
+        let windows = (0..windows as i32)
+            .map(|num| {
+                let from = oldest + window * num;
+                (format(from), format(from + window))
+            })
+            .collect();
+        Some(Self { windows })
+    }
+
+    /// The windows in order, as inclusive `(from, to)` timestamps.
+    pub fn windows(&self) -> &[(String, String)] {
+        &self.windows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catch_up_plan() {
+        let now = NaiveDateTime::parse_from_str("20240101120000", "%Y%m%d%H%M%S").unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        assert_eq!(CatchUpPlan::new("20240101110000", now, hour, 3), None);
+        assert_eq!(CatchUpPlan::new("20240101000000", now, hour, 1), None);
+        assert_eq!(CatchUpPlan::new("", now, hour, 3), None);
+        let plan = CatchUpPlan::new("20240101000000", now, hour, 3).unwrap();
+        assert_eq!(
+            plan.windows(),
+            [
+                ("20240101000000".to_string(), "20240101010000".to_string()),
+                ("20240101010000".to_string(), "20240101020000".to_string()),
+                ("20240101020000".to_string(), "20240101030000".to_string()),
+            ]
+        );
+    }
+}
@@ -1,7 +1,121 @@
-use crate::{revision_compare::RevisionId, ItemId, TextId, WdRc};
-use anyhow::Result;
+use crate::{
+    revision_compare::{RevisionId, ENGINE_VERSION},
+    ItemId, TextId, WdRc,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use wikimisc::mysql_async::Value;
 
-#[derive(Debug, Default, Clone, PartialEq)]
+/// The kind of entity a change belongs to, derived from its ID prefix.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntityType {
+    #[default]
+    Item,
+    Property,
+    Lexeme,
+}
+
+impl EntityType {
+    pub fn all() -> Vec<Self> {
+        vec![Self::Item, Self::Property, Self::Lexeme]
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Item => "item",
+            Self::Property => "property",
+            Self::Lexeme => "lexeme",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Self> {
+        match id.chars().next()? {
+            'Q' => Some(Self::Item),
+            'P' => Some(Self::Property),
+            'L' => Some(Self::Lexeme),
+            _ => None,
+        }
+    }
+
+    pub fn from_namespace(namespace: u64) -> Option<Self> {
+        Self::all()
+            .into_iter()
+            .find(|et| et.namespace() == namespace)
+    }
+
+    pub fn namespace(&self) -> u64 {
+        match self {
+            Self::Item => 0,
+            Self::Property => 120,
+            Self::Lexeme => 146,
+        }
+    }
+
+    /// The namespace prefix of page titles for this entity type.
+    pub fn title_prefix(&self) -> &'static str {
+        match self {
+            Self::Item => "",
+            Self::Property => "Property:",
+            Self::Lexeme => "Lexeme:",
+        }
+    }
+
+    /// The first letter of entity IDs of this type.
+    pub fn id_prefix(&self) -> &'static str {
+        match self {
+            Self::Item => "Q",
+            Self::Property => "P",
+            Self::Lexeme => "L",
+        }
+    }
+
+    /// The edit summary action on an entity of this type merged into another, which leaves it a
+    /// redirect; properties cannot be merged.
+    pub fn merge_action(&self) -> Option<&'static str> {
+        match self {
+            Self::Item => Some("wbmergeitems-to"),
+            Self::Property => None,
+            Self::Lexeme => Some("wblmergelexemes-to"),
+        }
+    }
+
+    /// Returns the name of a wdrc table for this entity type. Items use the plain table names.
+    pub fn table(&self, table: &str) -> String {
+        match self {
+            Self::Item => table.to_string(),
+            Self::Property => format!("property_{table}"),
+            Self::Lexeme => format!("lexeme_{table}"),
+        }
+    }
+
+    /// The parts of entities of this type that changes are logged for.
+    pub fn subjects(&self) -> Vec<ChangeSubject> {
+        let mut ret = match self {
+            Self::Lexeme => vec![ChangeSubject::Lemmas],
+            _ => vec![
+                ChangeSubject::Labels,
+                ChangeSubject::Descriptions,
+                ChangeSubject::Aliases,
+            ],
+        };
+        ret.extend([
+            ChangeSubject::Claims,
+            ChangeSubject::Qualifiers,
+            ChangeSubject::References,
+        ]);
+        match self {
+            Self::Item => ret.extend([ChangeSubject::Sitelinks, ChangeSubject::Badges]),
+            Self::Property => ret.push(ChangeSubject::Datatype),
+            Self::Lexeme => ret.extend([ChangeSubject::Forms, ChangeSubject::Senses]),
+        }
+        ret
+    }
+}
+
+/// The part of an entity a [`Change`] affects.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ChangeSubject {
     #[default]
     Labels,
@@ -9,9 +123,39 @@ pub enum ChangeSubject {
     Sitelinks,
     Aliases,
     Claims,
+    Qualifiers,
+    References,
+    Lemmas,
+    Forms,
+    Senses,
+    Datatype,
+    Badges,
 }
 
 impl ChangeSubject {
+    pub fn all() -> Vec<Self> {
+        vec![
+            Self::Labels,
+            Self::Descriptions,
+            Self::Sitelinks,
+            Self::Aliases,
+            Self::Claims,
+            Self::Qualifiers,
+            Self::References,
+            Self::Lemmas,
+            Self::Forms,
+            Self::Senses,
+            Self::Datatype,
+            Self::Badges,
+        ]
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::all()
+            .into_iter()
+            .find(|subject| subject.as_str() == name)
+    }
+
     pub fn as_str(&self) -> &str {
         match self {
             ChangeSubject::Labels => "labels",
@@ -19,29 +163,64 @@ impl ChangeSubject {
             ChangeSubject::Aliases => "aliases",
             ChangeSubject::Claims => "claims",
             ChangeSubject::Sitelinks => "sitelinks",
+            ChangeSubject::Qualifiers => "qualifiers",
+            ChangeSubject::References => "references",
+            ChangeSubject::Lemmas => "lemmas",
+            ChangeSubject::Forms => "forms",
+            ChangeSubject::Senses => "senses",
+            ChangeSubject::Datatype => "datatype",
+            ChangeSubject::Badges => "badges",
         }
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+/// Whether something was added, removed, or changed in place, or restored to its earlier value.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ChangeType {
     #[default]
     Changed,
     Removed,
     Added,
+    /// Exactly undoes an earlier change; see [`crate::reverts`].
+    Reverted,
 }
 
 impl ChangeType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Changed, Self::Removed, Self::Added, Self::Reverted]
+            .into_iter()
+            .find(|change_type| change_type.as_str() == name)
+    }
+
     pub fn as_str(&self) -> &str {
         match self {
             ChangeType::Changed => "changed",
             ChangeType::Removed => "removed",
             ChangeType::Added => "added",
+            ChangeType::Reverted => "reverted",
         }
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+/// A single difference between two revisions of an entity.
+///
+/// Only the fields relevant to `subject` are set: `language`/`text` for labels,
+/// descriptions, aliases and lemmas, `site`/`title` for sitelinks, `site`/`badge`
+/// for sitelink badges, `property`/`id` for claims, `id` for lexeme forms and senses,
+/// and `text` for property datatypes. Qualifier changes also set `qualifier` to the
+/// qualifier property, reference changes set `hash` to the reference hash.
+///
+/// `old_text`/`new_text` hold the value before and after the change, where there is one:
+/// the text for labels and the like, the page title for sitelinks, the datatype for
+/// properties, and the JSON of the main snak or qualifier snaks for claims and qualifiers.
+///
+/// As JSON, e.g. in the `ndjson` sink, a change is an object with the field names below;
+/// `subject`, `change_type` and `entity_type` are lowercase names, unset strings are empty,
+/// and unknown values are `null`. Fields are only ever added, so consumers should ignore
+/// unknown ones; missing fields take their defaults when reading.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Change {
     pub subject: ChangeSubject,
     pub change_type: ChangeType,
@@ -51,32 +230,358 @@ pub struct Change {
     pub title: String,
     pub property: String,
     pub id: String,
+    pub entity_type: EntityType,
+    pub qualifier: String,
+    pub hash: String,
+    pub badge: String,
+    pub old_text: String,
+    pub new_text: String,
+    /// Number of sitelinks of the entity in the new revision.
+    pub sitelinks: u64,
+    /// Number of statements of the entity in the new revision.
+    pub statements: u64,
+    /// User name of the editor; unknown if the compared edits were made by several users.
+    pub user: Option<String>,
+    /// Whether the edit was flagged as a bot edit; for several compared edits, whether all were.
+    pub is_bot: bool,
+    /// Change tags of the edit; for several compared edits, those of any of them.
+    pub tags: Vec<String>,
+    /// Edit summary; unknown if the compared edits had different summaries.
+    pub comment: Option<String>,
+    /// Further data for the `detail` column, for enrichments without a column of their own.
+    pub detail: serde_json::Map<String, serde_json::Value>,
+    /// The editing session of the edit in `sessions`, if sessions are tracked.
+    pub session: Option<u64>,
     pub item_id: ItemId,
     pub revision_id: RevisionId,
     pub timestamp: String,
 }
 
 impl Change {
-    pub fn get_statement_log(&self) -> Result<String> {
+    /// Sets the old and new values of this change.
+    pub fn with_values(mut self, old_text: &str, new_text: &str) -> Self {
+        self.old_text = old_text.to_string();
+        self.new_text = new_text.to_string();
+        self
+    }
+
+    /// The key of the changed value within the entity, for storing old and new values.
+    pub fn value_key(&self) -> String {
+        match self.subject {
+            ChangeSubject::Labels
+            | ChangeSubject::Descriptions
+            | ChangeSubject::Aliases
+            | ChangeSubject::Lemmas => self.language.to_owned(),
+            ChangeSubject::Sitelinks | ChangeSubject::Badges => self.site.to_owned(),
+            ChangeSubject::Claims | ChangeSubject::Forms | ChangeSubject::Senses => {
+                self.id.to_owned()
+            }
+            ChangeSubject::Qualifiers => format!("{}|{}", self.id, self.qualifier),
+            ChangeSubject::References => format!("{}|{}", self.id, self.hash),
+            ChangeSubject::Datatype => String::new(),
+        }
+    }
+
+    /// Returns the change in the JSON format of the predecessor PHP tool: `subject`, `change`
+    /// for the change type, and only the fields that are set, e.g. `language` and `text` for labels.
+    pub fn to_legacy_json(&self) -> serde_json::Value {
+        let mut ret = serde_json::Map::new();
+        ret.insert("subject".to_string(), self.subject.as_str().into());
+        ret.insert("change".to_string(), self.change_type.as_str().into());
+        for (key, value) in [
+            ("language", &self.language),
+            ("text", &self.text),
+            ("site", &self.site),
+            ("title", &self.title),
+            ("property", &self.property),
+            ("id", &self.id),
+            ("qualifier", &self.qualifier),
+            ("hash", &self.hash),
+            ("badge", &self.badge),
+        ] {
+            if !value.is_empty() {
+                ret.insert(key.to_string(), value.as_str().into());
+            }
+        }
+        serde_json::Value::Object(ret)
+    }
+
+    /// Cuts `text` down to at most `max_bytes` bytes, at a character boundary.
+    pub fn truncate(text: &str, max_bytes: usize) -> &str {
+        if text.len() <= max_bytes {
+            return text;
+        }
+        let mut end = max_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        &text[..end]
+    }
+
+    /// Whether the old or new value is longer than `max_bytes`.
+    pub fn is_truncated(&self, max_bytes: usize) -> bool {
+        self.old_text.len() > max_bytes || self.new_text.len() > max_bytes
+    }
+
+    /// Returns `detail` as JSON, together with the IDs and values that have no column in the change
+    /// tables, or `None` if there is nothing to add. Values are cut down to `max_bytes`.
+    pub fn detail_json(&self, max_bytes: usize) -> Option<String> {
+        let mut detail = self.detail.clone();
+        for (key, value) in [
+            ("id", &self.id),
+            ("qualifier", &self.qualifier),
+            ("hash", &self.hash),
+            ("title", &self.title),
+            ("old", &self.old_text),
+            ("new", &self.new_text),
+        ] {
+            if !value.is_empty() {
+                detail
+                    .entry(key)
+                    .or_insert_with(|| Self::truncate(value, max_bytes).into());
+            }
+        }
+        if self.is_truncated(max_bytes) {
+            detail.insert("truncated".to_string(), true.into());
+        }
+        if let Some(comment) = &self.comment {
+            detail
+                .entry("comment")
+                .or_insert_with(|| comment.as_str().into());
+        }
+        match detail.is_empty() {
+            true => None,
+            false => Some(serde_json::Value::Object(detail).to_string()),
+        }
+    }
+
+    /// Returns the bound parameters of a `statements` row.
+    pub fn get_statement_log(&self) -> Result<Vec<Value>> {
+        let property = WdRc::make_id_numeric(&self.property)?;
+        Ok(vec![
+            self.item_id.into(),
+            self.revision_id.into(),
+            property.into(),
+            self.timestamp.as_str().into(),
+            self.change_type.as_str().into(),
+            self.sitelinks.into(),
+            self.is_bot.into(),
+            self.user.as_deref().into(),
+            ENGINE_VERSION.into(),
+        ])
+    }
+
+    /// Returns the bound parameters of a `qualifiers` row.
+    pub fn get_qualifier_log(&self) -> Result<Vec<Value>> {
+        let property = WdRc::make_id_numeric(&self.property)?;
+        let qualifier = WdRc::make_id_numeric(&self.qualifier)?;
+        Ok(vec![
+            self.item_id.into(),
+            self.revision_id.into(),
+            property.into(),
+            qualifier.into(),
+            self.timestamp.as_str().into(),
+            self.change_type.as_str().into(),
+            self.sitelinks.into(),
+            self.is_bot.into(),
+            ENGINE_VERSION.into(),
+        ])
+    }
+
+    /// Returns the bound parameters of a `references` row.
+    pub fn get_reference_log(&self) -> Result<Vec<Value>> {
         let property = WdRc::make_id_numeric(&self.property)?;
-        Ok(format!(
-            "({},{},{property},'{}','{}')",
-            self.item_id,
-            self.revision_id,
-            self.timestamp,
-            self.change_type.as_str()
-        ))
-    }
-
-    pub fn get_label_log(&self, text_id: TextId) -> String {
-        format!(
-            "({},{},'{}','{}','{}',{})",
-            self.item_id,
-            self.revision_id,
-            self.subject.as_str(),
-            self.timestamp,
-            self.change_type.as_str(),
-            text_id
-        )
+        if !self.hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("Bad reference hash: {:?}", self.hash));
+        }
+        Ok(vec![
+            self.item_id.into(),
+            self.revision_id.into(),
+            property.into(),
+            self.hash.as_str().into(),
+            self.timestamp.as_str().into(),
+            self.change_type.as_str().into(),
+            self.sitelinks.into(),
+            self.is_bot.into(),
+            ENGINE_VERSION.into(),
+        ])
+    }
+
+    /// Returns the bound parameters of a lexeme form or sense change, using the numeric part of `L1-F2`.
+    pub fn get_subentity_log(&self) -> Result<Vec<Value>> {
+        let subentity = self
+            .id
+            .split_once('-')
+            .map(|(_, sub)| sub)
+            .ok_or_else(|| anyhow!("Bad sub-entity ID: {:?}", self.id))?;
+        let subentity = WdRc::make_id_numeric(subentity)?;
+        Ok(vec![
+            self.item_id.into(),
+            self.revision_id.into(),
+            self.subject.as_str().into(),
+            subentity.into(),
+            self.timestamp.as_str().into(),
+            self.change_type.as_str().into(),
+            self.sitelinks.into(),
+            self.is_bot.into(),
+            ENGINE_VERSION.into(),
+        ])
+    }
+
+    /// Returns the bound parameters of a `badges` row.
+    pub fn get_badge_log(&self, site_text_id: TextId) -> Result<Vec<Value>> {
+        let badge = WdRc::make_id_numeric(&self.badge)?;
+        Ok(vec![
+            self.item_id.into(),
+            self.revision_id.into(),
+            site_text_id.into(),
+            badge.into(),
+            self.timestamp.as_str().into(),
+            self.change_type.as_str().into(),
+            self.sitelinks.into(),
+            self.is_bot.into(),
+            ENGINE_VERSION.into(),
+        ])
+    }
+
+    /// Returns the bound parameters of a `labels` row.
+    pub fn get_label_log(&self, text_id: TextId) -> Vec<Value> {
+        vec![
+            self.item_id.into(),
+            self.revision_id.into(),
+            self.subject.as_str().into(),
+            self.timestamp.as_str().into(),
+            self.change_type.as_str().into(),
+            text_id.into(),
+            self.sitelinks.into(),
+            self.is_bot.into(),
+            self.user.as_deref().into(),
+            ENGINE_VERSION.into(),
+        ]
+    }
+
+    /// Returns the bound parameters of a `change_values` row, with values cut down to `max_bytes`.
+    pub fn get_value_log(&self, max_bytes: usize) -> Vec<Value> {
+        vec![
+            self.item_id.into(),
+            self.revision_id.into(),
+            self.subject.as_str().into(),
+            self.value_key().into(),
+            Self::truncate(&self.old_text, max_bytes).into(),
+            Self::truncate(&self.new_text, max_bytes).into(),
+            self.is_truncated(max_bytes).into(),
+            self.timestamp.as_str().into(),
+        ]
+    }
+
+    /// Returns the bound parameters of a `value_overflow` row, with the full values.
+    pub fn get_value_overflow_log(&self) -> Vec<Value> {
+        vec![
+            self.item_id.into(),
+            self.revision_id.into(),
+            self.subject.as_str().into(),
+            self.value_key().into(),
+            self.old_text.as_str().into(),
+            self.new_text.as_str().into(),
+            self.timestamp.as_str().into(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detail_json() {
+        assert_eq!(Change::default().detail_json(100), None);
+        let mut change = Change {
+            subject: ChangeSubject::Claims,
+            property: "P31".to_string(),
+            id: "Q1$abc".to_string(),
+            ..Default::default()
+        }
+        .with_values("", "{\"snaktype\":\"novalue\"}");
+        change.detail.insert("score".to_string(), 3.into());
+        assert_eq!(
+            change.detail_json(100).unwrap(),
+            r#"{"id":"Q1$abc","new":"{\"snaktype\":\"novalue\"}","score":3}"#
+        );
+        assert_eq!(
+            change.detail_json(12).unwrap(),
+            r#"{"id":"Q1$abc","new":"{\"snaktype\":","score":3,"truncated":true}"#
+        );
+    }
+
+    #[test]
+    fn test_to_legacy_json() {
+        let change = Change {
+            subject: ChangeSubject::Labels,
+            change_type: ChangeType::Changed,
+            language: "en".to_string(),
+            text: "new".to_string(),
+            item_id: 1,
+            revision_id: 2,
+            ..Default::default()
+        }
+        .with_values("old", "new");
+        assert_eq!(
+            change.to_legacy_json(),
+            serde_json::json!({"change":"changed","language":"en","text":"new","subject":"labels"})
+        );
+        let change = Change {
+            subject: ChangeSubject::Claims,
+            change_type: ChangeType::Removed,
+            property: "P1".to_string(),
+            id: "Q1$125".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            change.to_legacy_json(),
+            serde_json::json!({"subject": "claims","change": "removed","property": "P1","id": "Q1$125"})
+        );
+    }
+
+    #[test]
+    fn test_json_shape() {
+        let change = Change {
+            subject: ChangeSubject::Claims,
+            change_type: ChangeType::Added,
+            property: "P31".to_string(),
+            item_id: 42,
+            revision_id: 123,
+            ..Default::default()
+        };
+        let j = serde_json::to_value(&change).unwrap();
+        assert_eq!(j["subject"], "claims");
+        assert_eq!(j["change_type"], "added");
+        assert_eq!(j["entity_type"], "item");
+        assert_eq!(j["item_id"], 42);
+        assert_eq!(j["user"], serde_json::Value::Null);
+        assert_eq!(serde_json::from_value::<Change>(j).unwrap(), change);
+
+        let partial: Change =
+            serde_json::from_str(r#"{"subject":"labels","language":"de","future_field":1}"#)
+                .unwrap();
+        assert_eq!(partial.language, "de");
+        assert_eq!(partial.change_type, ChangeType::Changed);
+    }
+
+    #[test]
+    fn test_subjects() {
+        assert!(EntityType::Item
+            .subjects()
+            .contains(&ChangeSubject::Sitelinks));
+        assert!(!EntityType::Property
+            .subjects()
+            .contains(&ChangeSubject::Sitelinks));
+        assert_eq!(EntityType::Lexeme.subjects()[0], ChangeSubject::Lemmas);
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(Change::truncate("abc", 3), "abc");
+        assert_eq!(Change::truncate("Zürich", 2), "Z");
+        assert_eq!(Change::truncate("Zürich", 3), "Zü");
     }
 }
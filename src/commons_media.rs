@@ -0,0 +1,157 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::HashMap;
+use wikimisc::mysql_async::{from_row, prelude::Queryable};
+
+use crate::{
+    change::{Change, ChangeSubject, ChangeType},
+    WdRc,
+};
+
+/// Whether a Commons file exists, and how often it is used across wikis.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileUsage {
+    pub exists: bool,
+    /// Pages using the file, on all wikis.
+    pub usage: u64,
+    /// Wikis using the file.
+    pub wikis: u64,
+}
+
+/// Looks up files that changes point at on the Commons replica, so changes to missing files can be
+/// found by image maintenance patrollers.
+pub struct CommonsMedia;
+
+impl CommonsMedia {
+    /// Files referenced by an added or changed value: `commonsMedia` statements and qualifiers, and
+    /// Commons sitelinks to files. As Commons page titles, with underscores.
+    pub fn files(change: &Change) -> Vec<String> {
+        if !matches!(change.change_type, ChangeType::Added | ChangeType::Changed) {
+            return vec![];
+        }
+        match change.subject {
+            ChangeSubject::Claims | ChangeSubject::Qualifiers => {
+                let j: Value = serde_json::from_str(&change.new_text).unwrap_or_default();
+                let snaks = match j.as_array() {
+                    Some(snaks) => snaks.to_owned(),
+                    None => vec![j],
+                };
+                snaks
+                    .iter()
+                    .filter(|snak| snak["datatype"].as_str() == Some("commonsMedia"))
+                    .filter_map(|snak| snak["datavalue"]["value"].as_str())
+                    .map(Self::db_title)
+                    .collect()
+            }
+            ChangeSubject::Sitelinks if change.site == "commonswiki" => {
+                match change.title.strip_prefix("File:") {
+                    Some(file) => vec![Self::db_title(file)],
+                    None => vec![],
+                }
+            }
+            _ => vec![],
+        }
+    }
+
+    /// The property holding the file of a change from [`Self::files`]; empty for sitelinks.
+    pub fn property(change: &Change) -> &str {
+        match change.subject {
+            ChangeSubject::Qualifiers => &change.qualifier,
+            _ => &change.property,
+        }
+    }
+
+    /// `Douglas adams portrait.jpg` as `Douglas_adams_portrait.jpg`.
+    fn db_title(file: &str) -> String {
+        file.trim().replace(' ', "_")
+    }
+
+    /// Looks up existence and global usage of files on the Commons replica.
+    pub async fn usage(wdrc: &WdRc, files: &[String]) -> Result<HashMap<String, FileUsage>> {
+        let mut ret: HashMap<String, FileUsage> = files
+            .iter()
+            .map(|file| (file.to_owned(), FileUsage::default()))
+            .collect();
+        if files.is_empty() {
+            return Ok(ret);
+        }
+        let placeholders = vec!["?"; files.len()].join(",");
+        let mut conn = wdrc.db().get_connection("commons").await?;
+        let sql = format!("SELECT `page_title` FROM `page` WHERE `page_namespace`=6 AND `page_title` IN ({placeholders})");
+        let existing = conn
+            .exec_iter(sql, files.to_vec())
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        for file in existing {
+            if let Some(usage) = ret.get_mut(&file) {
+                usage.exists = true;
+            }
+        }
+        let sql = format!("SELECT `gil_to`,COUNT(*),COUNT(DISTINCT `gil_wiki`) FROM `globalimagelinks` WHERE `gil_to` IN ({placeholders}) GROUP BY `gil_to`");
+        let used = conn
+            .exec_iter(sql, files.to_vec())
+            .await?
+            .map_and_drop(from_row::<(String, u64, u64)>)
+            .await?;
+        for (file, pages, wikis) in used {
+            if let Some(usage) = ret.get_mut(&file) {
+                usage.usage = pages;
+                usage.wikis = wikis;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files() {
+        let claim = Change {
+            subject: ChangeSubject::Claims,
+            change_type: ChangeType::Added,
+            property: "P18".to_string(),
+            new_text: r#"{"snaktype":"value","property":"P18","datatype":"commonsMedia","datavalue":{"value":"Douglas adams portrait.jpg","type":"string"}}"#.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            CommonsMedia::files(&claim),
+            vec!["Douglas_adams_portrait.jpg"]
+        );
+        assert_eq!(CommonsMedia::property(&claim), "P18");
+
+        let removed = Change {
+            change_type: ChangeType::Removed,
+            ..claim.clone()
+        };
+        assert!(CommonsMedia::files(&removed).is_empty());
+
+        let qualifier = Change {
+            subject: ChangeSubject::Qualifiers,
+            change_type: ChangeType::Changed,
+            property: "P31".to_string(),
+            qualifier: "P18".to_string(),
+            new_text: r#"[{"snaktype":"value","property":"P18","datatype":"commonsMedia","datavalue":{"value":"A.png","type":"string"}},{"snaktype":"novalue","property":"P18","datatype":"commonsMedia"}]"#.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(CommonsMedia::files(&qualifier), vec!["A.png"]);
+        assert_eq!(CommonsMedia::property(&qualifier), "P18");
+
+        let sitelink = Change {
+            subject: ChangeSubject::Sitelinks,
+            change_type: ChangeType::Added,
+            site: "commonswiki".to_string(),
+            title: "File:Some map.svg".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(CommonsMedia::files(&sitelink), vec!["Some_map.svg"]);
+        let category = Change {
+            title: "Category:Maps".to_string(),
+            ..sitelink
+        };
+        assert!(CommonsMedia::files(&category).is_empty());
+    }
+}
@@ -0,0 +1,466 @@
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    time::{Duration as StdDuration, Instant},
+};
+use wikimisc::mysql_async::{from_row, prelude::Queryable};
+
+use crate::{
+    change::Change,
+    config::{NotifierConfig, WebhookConfig},
+    WdRc,
+};
+
+/// Failed attempts after which a delivery becomes a dead letter.
+pub(crate) const MAX_DELIVERY_ATTEMPTS: u32 = 10;
+/// Pending deliveries sent per subscription and run; the rest wait for the next run.
+const DELIVERIES_PER_RUN: u64 = 100;
+/// Delivered payloads are kept this long, for inspection.
+const DELIVERED_DAYS: i64 = 7;
+/// Dead letters listed by [`Deliveries::dead_letters`].
+const MAX_DEAD_LETTERS: u64 = 100;
+
+/// What a delivery waits for, or what became of it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    /// Failed `MAX_DELIVERY_ATTEMPTS` times; only sent again after `deliveries retry`.
+    Dead,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Pending => "pending",
+            Self::Delivered => "delivered",
+            Self::Dead => "dead",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Self::Pending, Self::Delivered, Self::Dead]
+            .into_iter()
+            .find(|status| status.as_str() == name)
+    }
+}
+
+/// A payload of a subscription in `deliveries`, without its body.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Delivery {
+    pub id: u64,
+    pub subscription: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub error: Option<String>,
+    pub created: String,
+    pub updated: String,
+}
+
+/// The deliveries of one subscription, by status.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SubscriptionStatus {
+    pub subscription: String,
+    /// Like `webhook 0`, if the subscription is still configured.
+    pub name: Option<String>,
+    pub pending: u64,
+    pub delivered: u64,
+    pub dead: u64,
+    /// The error of the last failed attempt.
+    pub last_error: Option<String>,
+}
+
+/// A change as posted to an endpoint, with the filters of the endpoint's subscriptions it
+/// matched, so a change matching several of them is posted once.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MatchedChange<'a> {
+    #[serde(flatten)]
+    pub change: &'a Change,
+    pub matched: Vec<String>,
+}
+
+/// At-least-once delivery for webhooks and notifiers, their subscriptions. Each payload is
+/// stored in `deliveries` before it is sent, and marked delivered once the receiver accepted it.
+/// Payloads that failed are sent again on later runs, in order, until they have failed
+/// `MAX_DELIVERY_ATTEMPTS` times; then they are dead letters, kept until `deliveries retry`.
+pub struct Deliveries<'a> {
+    wdrc: &'a WdRc,
+}
+
+impl<'a> Deliveries<'a> {
+    pub fn new(wdrc: &'a WdRc) -> Self {
+        Self { wdrc }
+    }
+
+    /// Merges `(endpoint, filter, change index)` matches into the changes per endpoint, in the
+    /// order of `changes`, each listing the filters it matched once.
+    pub(crate) fn merge_matches<'c>(
+        changes: &'c [Change],
+        matches: impl IntoIterator<Item = (String, String, usize)>,
+    ) -> BTreeMap<String, Vec<MatchedChange<'c>>> {
+        let mut endpoints: BTreeMap<String, BTreeMap<usize, Vec<String>>> = BTreeMap::new();
+        for (endpoint, filter, num) in matches {
+            let matched = endpoints
+                .entry(endpoint)
+                .or_default()
+                .entry(num)
+                .or_default();
+            if !matched.contains(&filter) {
+                matched.push(filter);
+            }
+        }
+        endpoints
+            .into_iter()
+            .map(|(endpoint, by_change)| {
+                let matching = by_change
+                    .into_iter()
+                    .map(|(num, matched)| MatchedChange {
+                        change: &changes[num],
+                        matched,
+                    })
+                    .collect();
+                (endpoint, matching)
+            })
+            .collect()
+    }
+
+    /// A stable key for the subscription of `kind` sending to `target`; the target may hold a
+    /// token, so it is hashed.
+    pub fn key(kind: &str, target: &str) -> String {
+        let hash: String = Sha256::digest(target.as_bytes())
+            .iter()
+            .take(8)
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!("{kind}:{hash}")
+    }
+
+    /// The subscription of a webhook, by its URL.
+    pub fn webhook_key(webhook: &WebhookConfig) -> String {
+        Self::key("webhook", &webhook.url)
+    }
+
+    /// The subscription of a notifier, by its channel.
+    pub fn notifier_key(notifier: &NotifierConfig) -> String {
+        Self::key("notifier", &format!("{:?}", notifier.channel))
+    }
+
+    /// The configured subscriptions, by key, with their names in errors.
+    fn names(&self) -> BTreeMap<String, String> {
+        let webhooks = self
+            .wdrc
+            .webhooks()
+            .iter()
+            .enumerate()
+            .map(|(num, webhook)| (Self::webhook_key(webhook), format!("webhook {num}")));
+        let notifiers = self
+            .wdrc
+            .notifiers()
+            .iter()
+            .enumerate()
+            .map(|(num, notifier)| (Self::notifier_key(notifier), format!("notifier {num}")));
+        webhooks.chain(notifiers).collect()
+    }
+
+    /// Stores `body`, if any, for `subscription`, then sends its pending payloads with `send`,
+    /// oldest first. Stops at the first failure, so payloads arrive in order.
+    pub async fn deliver<F, Fut>(
+        &self,
+        subscription: &str,
+        body: Option<String>,
+        send: F,
+    ) -> Result<()>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let now = Self::now();
+        if let Some(body) = body {
+            conn.exec_drop(
+                "INSERT INTO `deliveries` (`subscription`,`body`,`created`,`updated`) VALUES (?,?,?,?)",
+                (subscription, body, &now, &now),
+            )
+            .await?;
+        }
+        let pending: Vec<(u64, String, u32)> = conn
+            .exec_iter(
+                "SELECT `id`,`body`,`attempts` FROM `deliveries` WHERE `subscription`=? AND `status`='pending' ORDER BY `id` LIMIT ?",
+                (subscription, DELIVERIES_PER_RUN),
+            )
+            .await?
+            .map_and_drop(from_row::<(u64, String, u32)>)
+            .await?;
+        for (id, body, attempts) in pending {
+            let result = send(body).await;
+            let (status, error) = match &result {
+                Ok(()) => (DeliveryStatus::Delivered, None),
+                Err(e) => (Self::status_after_failure(attempts), Some(e.to_string())),
+            };
+            conn.exec_drop(
+                "UPDATE `deliveries` SET `status`=?,`attempts`=`attempts`+1,`error`=?,`updated`=? WHERE `id`=?",
+                (status.as_str(), error, Self::now(), id),
+            )
+            .await?;
+            result?;
+        }
+        Ok(())
+    }
+
+    /// The status of a payload that failed after `attempts` earlier attempts.
+    fn status_after_failure(attempts: u32) -> DeliveryStatus {
+        match attempts + 1 >= MAX_DELIVERY_ATTEMPTS {
+            true => DeliveryStatus::Dead,
+            false => DeliveryStatus::Pending,
+        }
+    }
+
+    /// Delivery counts of all subscriptions with deliveries.
+    pub async fn status(&self) -> Result<Vec<SubscriptionStatus>> {
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let counts: Vec<(String, String, u64)> = conn
+            .exec_iter(
+                "SELECT `subscription`,`status`,count(*) FROM `deliveries` GROUP BY `subscription`,`status`",
+                (),
+            )
+            .await?
+            .map_and_drop(from_row::<(String, String, u64)>)
+            .await?;
+        let errors: Vec<(String, String)> = conn
+            .exec_iter(
+                "SELECT `subscription`,`error` FROM `deliveries` WHERE `id` IN (SELECT max(`id`) FROM `deliveries` WHERE `error` IS NOT NULL GROUP BY `subscription`)",
+                (),
+            )
+            .await?
+            .map_and_drop(from_row::<(String, String)>)
+            .await?;
+        let names = self.names();
+        let mut ret: BTreeMap<String, SubscriptionStatus> = BTreeMap::new();
+        for (subscription, status, count) in counts {
+            let entry = ret
+                .entry(subscription.to_owned())
+                .or_insert_with(|| SubscriptionStatus {
+                    name: names.get(&subscription).cloned(),
+                    subscription,
+                    ..Default::default()
+                });
+            match DeliveryStatus::from_name(&status) {
+                Some(DeliveryStatus::Pending) => entry.pending = count,
+                Some(DeliveryStatus::Delivered) => entry.delivered = count,
+                Some(DeliveryStatus::Dead) => entry.dead = count,
+                None => {}
+            }
+        }
+        for (subscription, error) in errors {
+            if let Some(entry) = ret.get_mut(&subscription) {
+                entry.last_error = Some(error);
+            }
+        }
+        Ok(ret.into_values().collect())
+    }
+
+    /// The latest dead letters.
+    pub async fn dead_letters(&self) -> Result<Vec<Delivery>> {
+        let rows: Vec<(u64, String, u32, Option<String>, String, String)> = self
+            .wdrc
+            .db()
+            .get_connection("wdrc")
+            .await?
+            .exec_iter(
+                "SELECT `id`,`subscription`,`attempts`,`error`,`created`,`updated` FROM `deliveries` WHERE `status`='dead' ORDER BY `id` DESC LIMIT ?",
+                (MAX_DEAD_LETTERS,),
+            )
+            .await?
+            .map_and_drop(from_row::<(u64, String, u32, Option<String>, String, String)>)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(id, subscription, attempts, error, created, updated)| Delivery {
+                    id,
+                    subscription,
+                    status: DeliveryStatus::Dead,
+                    attempts,
+                    error,
+                    created,
+                    updated,
+                },
+            )
+            .collect())
+    }
+
+    /// Makes a dead letter, or all of them, pending again with no attempts counted. Returns the
+    /// number of deliveries changed.
+    pub async fn retry(&self, id: Option<u64>) -> Result<u64> {
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let sql = "UPDATE `deliveries` SET `status`='pending',`attempts`=0,`updated`=? WHERE `status`='dead'";
+        match id {
+            Some(id) => {
+                conn.exec_drop(format!("{sql} AND `id`=?"), (Self::now(), id))
+                    .await?
+            }
+            None => conn.exec_drop(sql, (Self::now(),)).await?,
+        }
+        match (id, conn.affected_rows()) {
+            (Some(id), 0) => Err(anyhow!("No dead letter with ID {id}")),
+            (_, rows) => Ok(rows),
+        }
+    }
+
+    /// Removes delivered payloads older than `DELIVERED_DAYS`; returns the number removed.
+    pub async fn purge_delivered(&self) -> Result<u64> {
+        let cutoff = (Utc::now() - Duration::days(DELIVERED_DAYS))
+            .format("%Y%m%d%H%M%S")
+            .to_string();
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        conn.exec_drop(
+            "DELETE FROM `deliveries` WHERE `status`='delivered' AND `updated`<?",
+            (cutoff,),
+        )
+        .await?;
+        Ok(conn.affected_rows())
+    }
+
+    fn now() -> String {
+        Utc::now().format("%Y%m%d%H%M%S").to_string()
+    }
+}
+
+/// Holds the changes of each entity until `notify_window_secs` have passed since its first one,
+/// so they are sent together. Held changes are lost if the bot stops.
+#[derive(Debug, Default)]
+pub(crate) struct NotifyWindow {
+    window: StdDuration,
+    held: BTreeMap<String, (Instant, Vec<Change>)>,
+}
+
+impl NotifyWindow {
+    pub(crate) fn new(window: StdDuration) -> Self {
+        Self {
+            window,
+            held: BTreeMap::new(),
+        }
+    }
+
+    /// Holds `changes`, and returns the changes of entities whose window has passed at `now`,
+    /// by entity, oldest entity first.
+    pub(crate) fn release(&mut self, changes: Vec<Change>, now: Instant) -> Vec<Change> {
+        for change in changes {
+            let entity = format!("{}{}", change.entity_type.id_prefix(), change.item_id);
+            self.held
+                .entry(entity)
+                .or_insert_with(|| (now, vec![]))
+                .1
+                .push(change);
+        }
+        let due: Vec<String> = self
+            .held
+            .iter()
+            .filter(|(_, (first, _))| now.duration_since(*first) >= self.window)
+            .map(|(entity, _)| entity.to_owned())
+            .collect();
+        let mut groups: Vec<(Instant, Vec<Change>)> = due
+            .iter()
+            .filter_map(|entity| self.held.remove(entity))
+            .collect();
+        groups.sort_by_key(|(first, _)| *first);
+        groups
+            .into_iter()
+            .flat_map(|(_, changes)| changes)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deliveries() {
+        let key = Deliveries::key("webhook", "https://example.org/hook?token=secret");
+        assert!(key.starts_with("webhook:"));
+        assert_eq!(key.len(), "webhook:".len() + 16);
+        assert!(!key.contains("secret"));
+        assert_eq!(
+            key,
+            Deliveries::key("webhook", "https://example.org/hook?token=secret")
+        );
+
+        assert_eq!(Deliveries::status_after_failure(0), DeliveryStatus::Pending);
+        assert_eq!(
+            Deliveries::status_after_failure(MAX_DELIVERY_ATTEMPTS - 1),
+            DeliveryStatus::Dead
+        );
+        assert_eq!(
+            DeliveryStatus::from_name("dead"),
+            Some(DeliveryStatus::Dead)
+        );
+    }
+
+    #[test]
+    fn test_merge_matches() {
+        let change = |item_id, property: &str| Change {
+            item_id,
+            property: property.to_string(),
+            ..Default::default()
+        };
+        let changes = [change(42, "P31"), change(42, "P18"), change(64, "P31")];
+        let matches = [
+            ("a", "items=Q42", 0),
+            ("a", "items=Q42", 1),
+            ("a", "props=P31", 2),
+            ("a", "props=P31", 0),
+            ("b", "props=P31", 0),
+            ("a", "props=P31", 0),
+        ]
+        .map(|(endpoint, filter, num)| (endpoint.to_string(), filter.to_string(), num));
+        let merged = Deliveries::merge_matches(&changes, matches);
+        assert_eq!(merged.len(), 2);
+        let a = &merged["a"];
+        assert_eq!(a.len(), 3);
+        assert_eq!(a[0].change, &changes[0]);
+        assert_eq!(a[0].matched, ["items=Q42", "props=P31"]);
+        assert_eq!(a[1].matched, ["items=Q42"]);
+        assert_eq!(a[2].change, &changes[2]);
+        assert_eq!(merged["b"][0].matched, ["props=P31"]);
+        let json = serde_json::to_value(&a[0]).unwrap();
+        assert_eq!(json["property"], "P31");
+        assert_eq!(
+            json["matched"],
+            serde_json::json!(["items=Q42", "props=P31"])
+        );
+    }
+
+    #[test]
+    fn test_notify_window() {
+        let change = |item_id, revision_id| Change {
+            item_id,
+            revision_id,
+            ..Default::default()
+        };
+        let revisions =
+            |changes: Vec<Change>| changes.iter().map(|c| c.revision_id).collect::<Vec<_>>();
+        let start = Instant::now();
+        let mut window = NotifyWindow::new(StdDuration::from_secs(60));
+        let released = window.release(vec![change(42, 1), change(64, 2)], start);
+        assert!(released.is_empty());
+        let released = window.release(
+            vec![change(64, 3), change(42, 4)],
+            start + StdDuration::from_secs(30),
+        );
+        assert!(released.is_empty());
+        let released = window.release(vec![change(7, 5)], start + StdDuration::from_secs(60));
+        assert_eq!(revisions(released), vec![1, 4, 2, 3]);
+        let released = window.release(vec![], start + StdDuration::from_secs(120));
+        assert_eq!(revisions(released), vec![5]);
+
+        let mut window = NotifyWindow::new(StdDuration::ZERO);
+        let released = window.release(vec![change(42, 1), change(64, 2), change(42, 3)], start);
+        assert_eq!(revisions(released), vec![1, 3, 2]);
+    }
+}
@@ -0,0 +1,383 @@
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+#[cfg(feature = "notifiers")]
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+#[cfg(feature = "notifiers")]
+use crate::config::{SmtpConfig, SmtpSecurity};
+use crate::{
+    change::EntityType,
+    config::DigestConfig,
+    live::LiveFilter,
+    query::{ChangeFilter, ChangeRow, MAX_LIMIT},
+    schedule::Schedule,
+    WdRc,
+};
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S";
+
+/// The changes since the last digest matching the filter of a digest, as an email with a plain text
+/// and an HTML part. Changes are grouped by entity, most recently changed first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Digest {
+    name: String,
+    since: String,
+    until: String,
+    rows: Vec<ChangeRow>,
+    /// Whether some changes were left out, as a query hit `MAX_LIMIT`.
+    truncated: bool,
+    /// The time zone times are shown in.
+    timezone: Tz,
+}
+
+impl Digest {
+    /// Sends each configured digest whose schedule is due and that has changes, or prints it if
+    /// there is no mail server. When each was due last is kept in `meta` as
+    /// `digest_sent:<name>`; the next one covers the changes since.
+    pub async fn send_all(wdrc: &WdRc) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        for config in wdrc.digests() {
+            let key = format!("digest_sent:{}", config.name);
+            let last_sent = wdrc
+                .get_key_value(&key)
+                .await?
+                .and_then(|sent| NaiveDateTime::parse_from_str(&sent, TIMESTAMP_FORMAT).ok());
+            let schedule = Schedule::parse(&config.schedule)?;
+            if !schedule.is_due(Self::timezone(wdrc, config)?, last_sent, now) {
+                continue;
+            }
+            let since =
+                last_sent.unwrap_or_else(|| now - chrono::Duration::hours(config.hours as i64));
+            let digest = Self::collect(wdrc, config, since, now).await?;
+            if !digest.rows.is_empty() {
+                match wdrc.smtp() {
+                    #[cfg(feature = "notifiers")]
+                    Some(smtp) => digest.send(smtp, config, wdrc.wiki().server()).await?,
+                    #[cfg(not(feature = "notifiers"))]
+                    Some(_) => return Err(anyhow!("Sending digests needs the notifiers feature")),
+                    None => println!("{}", digest.to_text(wdrc.wiki().server())),
+                }
+            }
+            wdrc.set_key_value(&key, &now.format(TIMESTAMP_FORMAT).to_string())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The time zone of a digest: its own, or the configured one.
+    fn timezone(wdrc: &WdRc, config: &DigestConfig) -> Result<Tz> {
+        match &config.timezone {
+            Some(timezone) => timezone.parse().map_err(|e| anyhow!("{e}")),
+            None => Ok(wdrc.timezone()),
+        }
+    }
+
+    pub async fn collect(
+        wdrc: &WdRc,
+        config: &DigestConfig,
+        since: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> Result<Self> {
+        let format = |dt: NaiveDateTime| dt.format(TIMESTAMP_FORMAT).to_string();
+        let since = format(since);
+        let entity_types: Vec<EntityType> = EntityType::all()
+            .into_iter()
+            .filter(|et| wdrc.namespaces().contains(&et.namespace()))
+            .collect();
+        let mut rows = vec![];
+        let mut truncated = false;
+        for query in Self::queries(&config.filter, &since, &entity_types) {
+            let found = ChangeFilter::from_query(&query)?.run(wdrc).await?;
+            truncated |= found.len() as u64 >= MAX_LIMIT;
+            rows.extend(found.into_iter().filter(|row| config.filter.matches(row)));
+        }
+        rows.sort_by(|a, b| {
+            (&b.timestamp, b.revision, &b.entity).cmp(&(&a.timestamp, a.revision, &a.entity))
+        });
+        rows.dedup();
+        let timezone = Self::timezone(wdrc, config)?;
+        Ok(Self {
+            name: config.name.to_owned(),
+            since,
+            until: format(now),
+            rows,
+            truncated,
+            timezone,
+        })
+    }
+
+    /// The change listing queries covering a filter: per item, else per property, else per
+    /// language, else everything. Other conditions of the filter are checked on the results.
+    fn queries(filter: &LiveFilter, since: &str, entity_types: &[EntityType]) -> Vec<String> {
+        let mut base = format!("since={since}&limit={MAX_LIMIT}");
+        if !filter.subjects.is_empty() {
+            let names: Vec<&str> = filter.subjects.iter().map(|s| s.as_str()).collect();
+            base += &format!("&subjects={}", names.join(","));
+        }
+        if !filter.change_types.is_empty() {
+            let names: Vec<&str> = filter.change_types.iter().map(|t| t.as_str()).collect();
+            base += &format!("&types={}", names.join(","));
+        }
+        let per_type = |condition: &str| -> Vec<String> {
+            entity_types
+                .iter()
+                .map(|et| format!("entity={}{condition}&{base}", et.as_str()))
+                .collect()
+        };
+        if !filter.items.is_empty() {
+            filter
+                .items
+                .iter()
+                .filter_map(|id| {
+                    let entity_type = EntityType::from_id(id)?;
+                    Some(format!("entity={}&item={id}&{base}", entity_type.as_str()))
+                })
+                .collect()
+        } else if !filter.properties.is_empty() {
+            filter
+                .properties
+                .iter()
+                .flat_map(|p| per_type(&format!("&prop={p}")))
+                .collect()
+        } else if !filter.languages.is_empty() {
+            filter
+                .languages
+                .iter()
+                .flat_map(|l| per_type(&format!("&lang={l}")))
+                .collect()
+        } else {
+            per_type("")
+        }
+    }
+
+    #[cfg(feature = "notifiers")]
+    async fn send(&self, smtp: &SmtpConfig, config: &DigestConfig, server: &str) -> Result<()> {
+        let mut message = Message::builder()
+            .from(smtp.from.parse::<Mailbox>()?)
+            .subject(self.subject());
+        for to in &config.to {
+            message = message.to(to
+                .parse::<Mailbox>()
+                .map_err(|e| anyhow!("Bad address {to:?}: {e}"))?);
+        }
+        let email = message.multipart(MultiPart::alternative_plain_html(
+            self.to_text(server),
+            self.to_html(server),
+        ))?;
+        let transport = match smtp.security {
+            SmtpSecurity::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?
+            }
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+            SmtpSecurity::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host)
+            }
+        }
+        .port(smtp.port);
+        let transport = match (&smtp.username, &smtp.password) {
+            (Some(username), Some(password)) => {
+                transport.credentials(Credentials::new(username.to_owned(), password.to_owned()))
+            }
+            _ => transport,
+        };
+        transport.build().send(email).await?;
+        Ok(())
+    }
+
+    pub fn subject(&self) -> String {
+        format!(
+            "wdrc digest \"{}\": {} changes since {}",
+            self.name,
+            self.rows.len(),
+            self.format_timestamp(&self.since)
+        )
+    }
+
+    pub fn to_text(&self, server: &str) -> String {
+        let mut ret = format!("{}\n", self.header());
+        for (entity, rows) in self.by_entity() {
+            ret += &format!("\n{entity}\n");
+            for row in rows {
+                ret += &format!("  {} {}\n", self.describe(row), Self::diff_url(row, server));
+            }
+        }
+        ret
+    }
+
+    pub fn to_html(&self, server: &str) -> String {
+        let mut ret = format!("<p>{}</p>\n", Self::escape(&self.header()));
+        for (entity, rows) in self.by_entity() {
+            ret += &format!(
+                "<h3><a href=\"{server}/entity/{entity}\">{entity}</a></h3>\n<ul>\n",
+                entity = Self::escape(entity)
+            );
+            for row in rows {
+                ret += &format!(
+                    "<li>{} <a href=\"{}\">diff</a></li>\n",
+                    Self::escape(&self.describe(row)),
+                    Self::diff_url(row, server)
+                );
+            }
+            ret += "</ul>\n";
+        }
+        ret
+    }
+
+    fn header(&self) -> String {
+        let mut ret = format!(
+            "{} changes from {} to {} ({}).",
+            self.rows.len(),
+            self.format_timestamp(&self.since),
+            self.format_timestamp(&self.until),
+            self.timezone
+        );
+        if self.truncated {
+            ret += " Only the most recent changes are listed.";
+        }
+        ret
+    }
+
+    /// Rows grouped by entity, in the order of their most recent change.
+    fn by_entity(&self) -> Vec<(&str, Vec<&ChangeRow>)> {
+        let mut ret: Vec<(&str, Vec<&ChangeRow>)> = vec![];
+        for row in &self.rows {
+            match ret.iter_mut().find(|(entity, _)| *entity == row.entity) {
+                Some((_, rows)) => rows.push(row),
+                None => ret.push((&row.entity, vec![row])),
+            }
+        }
+        ret
+    }
+
+    fn describe(&self, row: &ChangeRow) -> String {
+        let key = match (&row.property, &row.language) {
+            (Some(key), _) | (None, Some(key)) => format!(" [{key}]"),
+            (None, None) => String::new(),
+        };
+        format!(
+            "{} {}{key} {}",
+            self.format_timestamp(&row.timestamp),
+            row.subject,
+            row.change_type
+        )
+    }
+
+    fn diff_url(row: &ChangeRow, server: &str) -> String {
+        format!("{server}/w/index.php?diff={}", row.revision)
+    }
+
+    /// `YYYYMMDDHHMMSS` in UTC as `YYYY-MM-DD HH:MM` in the time zone of the digest.
+    fn format_timestamp(&self, timestamp: &str) -> String {
+        match NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT) {
+            Ok(dt) => Utc
+                .from_utc_datetime(&dt)
+                .with_timezone(&self.timezone)
+                .format("%Y-%m-%d %H:%M")
+                .to_string(),
+            Err(_) => timestamp.to_string(),
+        }
+    }
+
+    pub(crate) fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(entity: &str, property: Option<&str>, timestamp: &str, revision: u64) -> ChangeRow {
+        ChangeRow {
+            entity: entity.to_string(),
+            revision,
+            subject: "claims".to_string(),
+            timestamp: timestamp.to_string(),
+            change_type: "added".to_string(),
+            language: None,
+            property: property.map(|p| p.to_string()),
+            in_wdqs: None,
+            redirected_from: None,
+            cursor: None,
+        }
+    }
+
+    #[test]
+    fn test_queries() {
+        let types = [EntityType::Item, EntityType::Property];
+        let filter = LiveFilter::from_query("items=Q42,P31&props=P569&types=added").unwrap();
+        assert_eq!(
+            Digest::queries(&filter, "20240101", &types),
+            vec![
+                "entity=item&item=Q42&since=20240101&limit=5000&types=added",
+                "entity=property&item=P31&since=20240101&limit=5000&types=added",
+            ]
+        );
+        let filter = LiveFilter::from_query("props=P569&subjects=claims").unwrap();
+        assert_eq!(
+            Digest::queries(&filter, "20240101", &types),
+            vec![
+                "entity=item&prop=P569&since=20240101&limit=5000&subjects=claims",
+                "entity=property&prop=P569&since=20240101&limit=5000&subjects=claims",
+            ]
+        );
+        assert_eq!(
+            Digest::queries(&LiveFilter::default(), "20240101", &types[..1]),
+            vec!["entity=item&since=20240101&limit=5000"]
+        );
+        for query in Digest::queries(&filter, "20240101", &types) {
+            assert!(ChangeFilter::from_query(&query).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let digest = Digest {
+            name: "humans".to_string(),
+            since: "20240101000000".to_string(),
+            until: "20240102000000".to_string(),
+            rows: vec![
+                row("Q42", Some("P569"), "20240101120000", 3),
+                row("Q1", None, "20240101110000", 2),
+                row("Q42", Some("P570"), "20240101100000", 1),
+            ],
+            truncated: false,
+            timezone: Tz::UTC,
+        };
+        assert_eq!(
+            digest.subject(),
+            "wdrc digest \"humans\": 3 changes since 2024-01-01 00:00"
+        );
+        let server = "https://www.wikidata.org";
+        assert_eq!(
+            digest.to_text(server),
+            "3 changes from 2024-01-01 00:00 to 2024-01-02 00:00 (UTC).\n\n\
+             Q42\n  2024-01-01 12:00 claims [P569] added https://www.wikidata.org/w/index.php?diff=3\n  \
+             2024-01-01 10:00 claims [P570] added https://www.wikidata.org/w/index.php?diff=1\n\n\
+             Q1\n  2024-01-01 11:00 claims added https://www.wikidata.org/w/index.php?diff=2\n"
+        );
+        let html = digest.to_html(server);
+        assert!(html.contains("<h3><a href=\"https://www.wikidata.org/entity/Q42\">Q42</a></h3>"));
+        assert_eq!(html.matches("<li>").count(), 3);
+        assert_eq!(
+            Digest::escape("<a & \"b\">"),
+            "&lt;a &amp; &quot;b&quot;&gt;"
+        );
+
+        let digest = Digest {
+            timezone: Tz::Europe__Berlin,
+            ..digest
+        };
+        assert!(digest
+            .to_text(server)
+            .starts_with("3 changes from 2024-01-01 01:00 to 2024-01-02 01:00 (Europe/Berlin).\n\nQ42\n  2024-01-01 13:00 claims [P569] added"));
+    }
+}
@@ -0,0 +1,507 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Serialize;
+use std::{collections::HashMap, path::Path, process::Command};
+use wikimisc::mysql_async::{from_row, prelude::Queryable, Row};
+
+use crate::{change::EntityType, query::ChangeFilter, ChangeSource, WdRc};
+
+/// Full table scans of tables with more rows than this are reported.
+const MAX_SCAN_ROWS: u64 = 10_000;
+/// Privileges the pipeline needs on the wdrc database.
+const REQUIRED_PRIVILEGES: &[&str] = &[
+    "SELECT", "INSERT", "UPDATE", "DELETE", "CREATE", "ALTER", "INDEX",
+];
+/// Less free space than this, in MiB, fails the disk check.
+const MIN_FREE_DISK_MB: u64 = 1024;
+/// Clocks further apart than this, in seconds, fail the time check.
+const MAX_CLOCK_SKEW_SECS: i64 = 30;
+
+/// Indexes the hot queries need, as the columns an index must start with, per table. Tables of
+/// entity types other than items get the type prefix.
+const EXPECTED_INDEXES: &[(&str, &[&str])] = &[
+    ("statements", &["timestamp"]),
+    ("statements", &["item", "revision"]),
+    ("statements", &["property", "timestamp"]),
+    ("qualifiers", &["timestamp"]),
+    ("qualifiers", &["item", "revision"]),
+    ("qualifiers", &["property", "timestamp"]),
+    ("references", &["timestamp"]),
+    ("references", &["item", "revision"]),
+    ("references", &["property", "timestamp"]),
+    ("labels", &["timestamp"]),
+    ("labels", &["item", "revision"]),
+    ("labels", &["language", "timestamp"]),
+    ("badges", &["timestamp"]),
+    ("badges", &["item", "revision"]),
+    ("subentities", &["timestamp"]),
+    ("subentities", &["item", "revision"]),
+    ("creations", &["q"]),
+    ("creations", &["timestamp"]),
+    ("deletions", &["q"]),
+    ("deletions", &["timestamp"]),
+];
+
+/// Indexes of tables without entity type prefix.
+const EXPECTED_SHARED_INDEXES: &[(&str, &[&str])] = &[
+    ("meta", &["key"]),
+    ("texts", &["value"]),
+    ("redirects", &["source"]),
+    ("redirects", &["target"]),
+    ("failed_items", &["q", "rev_new"]),
+    ("sessions", &["entity_type", "item", "user"]),
+];
+
+/// Problems with the indexes of the wdrc tables, which hand-made schemas often lack.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexAdvice {
+    /// Table and columns of missing indexes.
+    missing: Vec<(String, Vec<String>)>,
+    /// Full table scans found by EXPLAINing hot queries: query, table, estimated rows.
+    scans: Vec<(String, String, u64)>,
+}
+
+impl IndexAdvice {
+    /// Checks the indexes of all existing wdrc tables of the tracked entity types, and EXPLAINs
+    /// the change listings.
+    pub async fn check(wdrc: &WdRc) -> Result<Self> {
+        let sql = "SELECT `TABLE_NAME`,`INDEX_NAME`,`COLUMN_NAME` FROM `information_schema`.`STATISTICS` WHERE `TABLE_SCHEMA`=DATABASE() ORDER BY `TABLE_NAME`,`INDEX_NAME`,`SEQ_IN_INDEX`";
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let rows: Vec<(String, String, String)> = conn
+            .query_iter(sql)
+            .await?
+            .map_and_drop(from_row::<(String, String, String)>)
+            .await?;
+        let tables: Vec<String> = conn
+            .query_iter("SELECT `TABLE_NAME` FROM `information_schema`.`TABLES` WHERE `TABLE_SCHEMA`=DATABASE()")
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        let indexes = Self::group_indexes(rows);
+
+        let mut expected: Vec<(String, &[&str])> = EXPECTED_SHARED_INDEXES
+            .iter()
+            .map(|(table, columns)| (table.to_string(), *columns))
+            .collect();
+        for entity_type in EntityType::all() {
+            if wdrc.namespaces().contains(&entity_type.namespace()) {
+                expected.extend(
+                    EXPECTED_INDEXES
+                        .iter()
+                        .map(|(table, columns)| (entity_type.table(table), *columns)),
+                );
+            }
+        }
+        let missing = expected
+            .into_iter()
+            .filter(|(table, _)| tables.contains(table))
+            .filter(|(table, columns)| !Self::has_index(&indexes, table, columns))
+            .map(|(table, columns)| (table, columns.iter().map(|c| c.to_string()).collect()))
+            .collect();
+
+        let mut scans = vec![];
+        for query in ["", "item=Q42"] {
+            let filter = ChangeFilter::from_query(query)?;
+            let (sql, params) = match filter.to_sql() {
+                Some(query) => query,
+                None => continue,
+            };
+            let explain: Vec<Row> = conn.exec(format!("EXPLAIN {sql}"), params).await?;
+            for row in explain {
+                let access: Option<String> = row.get("type").flatten();
+                let table: Option<String> = row.get("table").flatten();
+                let estimate: u64 = row.get::<Option<u64>, _>("rows").flatten().unwrap_or(0);
+                if let (Some("ALL"), Some(table)) = (access.as_deref(), table) {
+                    if estimate > MAX_SCAN_ROWS {
+                        scans.push((format!("changes?{query}"), table, estimate));
+                    }
+                }
+            }
+        }
+        Ok(Self { missing, scans })
+    }
+
+    /// Column lists of all indexes, per table.
+    fn group_indexes(rows: Vec<(String, String, String)>) -> HashMap<String, Vec<Vec<String>>> {
+        let mut by_name: Vec<((String, String), Vec<String>)> = vec![];
+        for (table, index, column) in rows {
+            let key = (table, index);
+            match by_name.last_mut() {
+                Some((last, columns)) if *last == key => columns.push(column),
+                _ => by_name.push((key, vec![column])),
+            }
+        }
+        let mut ret: HashMap<String, Vec<Vec<String>>> = HashMap::new();
+        for ((table, _), columns) in by_name {
+            ret.entry(table).or_default().push(columns);
+        }
+        ret
+    }
+
+    /// Whether an index of `table` starts with `columns`.
+    fn has_index(
+        indexes: &HashMap<String, Vec<Vec<String>>>,
+        table: &str,
+        columns: &[&str],
+    ) -> bool {
+        indexes.get(table).into_iter().flatten().any(|index| {
+            index.len() >= columns.len() && index.iter().zip(columns).all(|(a, b)| a == b)
+        })
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.scans.is_empty()
+    }
+
+    /// Describes the problems, one per line, with `CREATE INDEX` statements for missing indexes.
+    pub fn diagnostic(&self) -> String {
+        let missing = self.missing.iter().map(|(table, columns)| {
+            let columns_sql = columns
+                .iter()
+                .map(|c| format!("`{c}`"))
+                .collect::<Vec<String>>()
+                .join(",");
+            format!(
+                "Table `{table}` lacks an index on ({}): CREATE INDEX `{}` ON `{table}` ({columns_sql});",
+                columns.join(", "),
+                columns.join("_")
+            )
+        });
+        let scans = self.scans.iter().map(|(query, table, rows)| {
+            format!("Query {query} scans all of `{table}` (about {rows} rows)")
+        });
+        missing.chain(scans).collect::<Vec<String>>().join("\n")
+    }
+}
+
+/// The outcome of one check of [`Doctor`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DoctorCheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Diagnostics of a whole deployment: config, databases, API, checkpoints, disk space, clocks,
+/// and indexes, each passing or failing on its own.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Doctor {
+    pub checks: Vec<DoctorCheck>,
+}
+
+impl Doctor {
+    /// Runs all checks. Only an invalid config stops the others.
+    pub async fn run(config_file: &str) -> Self {
+        let mut ret = Self::default();
+        let wdrc = match WdRc::new(config_file) {
+            Ok(wdrc) => wdrc,
+            Err(e) => {
+                ret.add("config", Err(e));
+                return ret;
+            }
+        };
+        ret.add("config", Ok(format!("{config_file} is valid")));
+        ret.add("wdrc database", Self::check_wdrc_db(&wdrc).await);
+        ret.add("replica", Self::check_replica(&wdrc).await);
+        ret.add("api", Self::check_api(&wdrc).await);
+        ret.add("checkpoints", Self::check_checkpoints(&wdrc).await);
+        ret.add("disk space", Self::check_disk_space(&wdrc));
+        ret.add("time skew", Self::check_time_skew(&wdrc).await);
+        let indexes = IndexAdvice::check(&wdrc)
+            .await
+            .and_then(|advice| match advice.is_ok() {
+                true => Ok("all expected indexes present".to_string()),
+                false => Err(anyhow!("{}", advice.diagnostic())),
+            });
+        ret.add("indexes", indexes);
+        ret
+    }
+
+    fn add(&mut self, name: &str, result: Result<String>) {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, e.to_string()),
+        };
+        self.checks.push(DoctorCheck {
+            name: name.to_string(),
+            passed,
+            detail,
+        });
+    }
+
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+
+    /// One `PASS` or `FAIL` line per check.
+    pub fn report(&self) -> String {
+        self.checks
+            .iter()
+            .map(|check| {
+                let status = if check.passed { "PASS" } else { "FAIL" };
+                format!("{status} {}: {}", check.name, check.detail)
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    async fn check_wdrc_db(wdrc: &WdRc) -> Result<String> {
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let grants: Vec<String> = conn
+            .query_iter("SHOW GRANTS FOR CURRENT_USER()")
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        match Self::missing_privileges(&grants).as_slice() {
+            [] => Ok("connected, with all required privileges".to_string()),
+            missing => Err(anyhow!("missing privileges: {}", missing.join(", "))),
+        }
+    }
+
+    /// The required privileges that none of the `GRANT` statements give.
+    fn missing_privileges(grants: &[String]) -> Vec<&'static str> {
+        let grants = grants.join("\n").to_uppercase();
+        if grants.contains("ALL PRIVILEGES") {
+            return vec![];
+        }
+        REQUIRED_PRIVILEGES
+            .iter()
+            .filter(|privilege| !grants.contains(*privilege))
+            .copied()
+            .collect()
+    }
+
+    async fn check_replica(wdrc: &WdRc) -> Result<String> {
+        let latest = async {
+            let mut conn = wdrc.db().get_connection("wikidata").await?;
+            let latest: Option<String> = conn
+                .query_first("SELECT MAX(`rc_timestamp`) FROM `recentchanges`")
+                .await?;
+            Ok::<_, anyhow::Error>(latest.unwrap_or_default())
+        };
+        match (latest.await, wdrc.change_source()) {
+            (Ok(latest), _) => Ok(format!("reachable, latest change {latest}")),
+            (Err(e), ChangeSource::EventStreams) => Ok(format!(
+                "unreachable ({e}), only needed for deletions and redirects with EventStreams"
+            )),
+            (Err(e), ChangeSource::Replica) => Err(e),
+        }
+    }
+
+    async fn check_api(wdrc: &WdRc) -> Result<String> {
+        let url = wdrc.wiki().api_url();
+        let response = wdrc
+            .wd()
+            .reqwest_client()?
+            .get(&url)
+            .query(&[
+                ("action", "query"),
+                ("meta", "siteinfo"),
+                ("format", "json"),
+            ])
+            .timeout(wdrc.api_timeout())
+            .send()
+            .await?;
+        let status = response.status();
+        if status.as_u16() == 403 {
+            return Err(anyhow!("{url} answers 403, the user agent may be rejected"));
+        }
+        if !status.is_success() {
+            return Err(anyhow!("{url} answers {status}"));
+        }
+        let j: serde_json::Value = response.json().await?;
+        let sitename = j["query"]["general"]["sitename"]
+            .as_str()
+            .ok_or_else(|| anyhow!("{url} returned no site info"))?;
+        Ok(format!("{url} reachable ({sitename})"))
+    }
+
+    async fn check_checkpoints(wdrc: &WdRc) -> Result<String> {
+        let timestamp = match wdrc.get_key_value("timestamp").await? {
+            Some(timestamp) => timestamp,
+            None => return Ok("no checkpoint yet, the first run starts a new one".to_string()),
+        };
+        let checkpoint = NaiveDateTime::parse_from_str(&timestamp, "%Y%m%d%H%M%S")
+            .map_err(|_| anyhow!("timestamp checkpoint {timestamp:?} is not YYYYMMDDHHMMSS"))?;
+        let lag = Utc::now().naive_utc() - checkpoint;
+        if lag.num_seconds() < -MAX_CLOCK_SKEW_SECS {
+            return Err(anyhow!("timestamp checkpoint {timestamp} is in the future"));
+        }
+        let rc_id = wdrc.get_key_value("rc_id").await?;
+        if let Some(rc_id) = &rc_id {
+            rc_id
+                .parse::<u64>()
+                .map_err(|_| anyhow!("rc_id checkpoint {rc_id:?} is not a number"))?;
+        }
+        Ok(format!(
+            "timestamp {timestamp}, {} minutes behind, rc_id {}",
+            lag.num_minutes(),
+            rc_id.as_deref().unwrap_or("not set")
+        ))
+    }
+
+    /// Free space where files are written: the working directory, and the public stats file.
+    fn check_disk_space(wdrc: &WdRc) -> Result<String> {
+        let mut dirs = vec![Path::new(".")];
+        if let Some(parent) = wdrc
+            .public_stats()
+            .and_then(|path| Path::new(path).parent())
+        {
+            if !parent.as_os_str().is_empty() {
+                dirs.push(parent);
+            }
+        }
+        let mut details = vec![];
+        for dir in dirs {
+            let output = Command::new("df").arg("-Pk").arg(dir).output()?;
+            let free_mb = Self::df_available_kb(&String::from_utf8_lossy(&output.stdout))
+                .ok_or_else(|| anyhow!("could not read free space of {}", dir.display()))?
+                / 1024;
+            if free_mb < MIN_FREE_DISK_MB {
+                return Err(anyhow!("only {free_mb} MiB free in {}", dir.display()));
+            }
+            details.push(format!("{free_mb} MiB free in {}", dir.display()));
+        }
+        Ok(details.join(", "))
+    }
+
+    /// The available KiB from the output of `df -Pk`.
+    fn df_available_kb(output: &str) -> Option<u64> {
+        output
+            .lines()
+            .nth(1)?
+            .split_whitespace()
+            .nth(3)?
+            .parse()
+            .ok()
+    }
+
+    /// Compares the local clock with the wdrc database and the API server.
+    async fn check_time_skew(wdrc: &WdRc) -> Result<String> {
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let db_now: Option<String> = conn
+            .query_first("SELECT DATE_FORMAT(UTC_TIMESTAMP(),'%Y%m%d%H%i%S')")
+            .await?;
+        let db_now = db_now
+            .and_then(|ts| NaiveDateTime::parse_from_str(&ts, "%Y%m%d%H%M%S").ok())
+            .ok_or_else(|| anyhow!("could not read the database clock"))?;
+        let now = Utc::now().naive_utc();
+        let mut skews = vec![("database", (db_now - now).num_seconds())];
+
+        let response = wdrc
+            .wd()
+            .reqwest_client()?
+            .head(wdrc.wiki().api_url())
+            .timeout(wdrc.api_timeout())
+            .send()
+            .await;
+        let api_now = response.ok().and_then(|response| {
+            let date = response.headers().get("date")?.to_str().ok()?.to_string();
+            DateTime::parse_from_rfc2822(&date).ok()
+        });
+        if let Some(api_now) = api_now {
+            skews.push(("API server", (api_now.naive_utc() - now).num_seconds()));
+        }
+
+        let details: Vec<String> = skews
+            .iter()
+            .map(|(name, secs)| format!("{name} {secs:+}s"))
+            .collect();
+        match skews
+            .iter()
+            .any(|(_, secs)| secs.abs() > MAX_CLOCK_SKEW_SECS)
+        {
+            true => Err(anyhow!("clocks differ: {}", details.join(", "))),
+            false => Ok(details.join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_indexes() {
+        let rows = [
+            ("statements", "PRIMARY", "id"),
+            ("statements", "change", "item"),
+            ("statements", "change", "revision"),
+            ("statements", "change", "property"),
+            ("texts", "value", "value"),
+        ];
+        let indexes = IndexAdvice::group_indexes(
+            rows.iter()
+                .map(|(t, i, c)| (t.to_string(), i.to_string(), c.to_string()))
+                .collect(),
+        );
+        assert!(IndexAdvice::has_index(
+            &indexes,
+            "statements",
+            &["item", "revision"]
+        ));
+        assert!(!IndexAdvice::has_index(
+            &indexes,
+            "statements",
+            &["timestamp"]
+        ));
+        assert!(!IndexAdvice::has_index(
+            &indexes,
+            "statements",
+            &["revision"]
+        ));
+        assert!(!IndexAdvice::has_index(&indexes, "labels", &["timestamp"]));
+
+        let advice = IndexAdvice {
+            missing: vec![("statements".to_string(), vec!["timestamp".to_string()])],
+            scans: vec![],
+        };
+        assert_eq!(
+            advice.diagnostic(),
+            "Table `statements` lacks an index on (timestamp): CREATE INDEX `timestamp` ON `statements` (`timestamp`);"
+        );
+    }
+
+    #[test]
+    fn test_expected_indexes_in_schema() {
+        let statements = crate::schema::Schema::create_statements();
+        for (table, columns) in EXPECTED_INDEXES {
+            // Tables of only some entity types are created with their prefix
+            let (_, sql) = statements
+                .iter()
+                .find(|(name, _)| EntityType::all().iter().any(|et| et.table(table) == *name))
+                .unwrap();
+            let quoted = columns
+                .iter()
+                .map(|c| format!("`{c}`"))
+                .collect::<Vec<String>>()
+                .join(",");
+            let primary = sql.lines().any(|line| {
+                line.trim().starts_with(&format!("{quoted} ")) && line.contains("PRIMARY KEY")
+            });
+            assert!(
+                sql.contains(&format!("({quoted}")) || primary,
+                "{table} lacks an index on ({quoted})"
+            );
+        }
+    }
+
+    #[test]
+    fn test_checks() {
+        let grants = |g: &str| vec![g.to_string()];
+        assert!(Doctor::missing_privileges(&grants(
+            "GRANT ALL PRIVILEGES ON `wdrc`.* TO `wdrc`@`%`"
+        ))
+        .is_empty());
+        assert_eq!(
+            Doctor::missing_privileges(&grants(
+                "GRANT SELECT, INSERT, UPDATE ON `wdrc`.* TO `wdrc`@`%`"
+            )),
+            vec!["DELETE", "CREATE", "ALTER", "INDEX"]
+        );
+
+        let df = "Filesystem 1024-blocks Used Available Capacity Mounted on\n/dev/sda1 1000000 400000 600000 40% /\n";
+        assert_eq!(Doctor::df_available_kb(df), Some(600000));
+        assert_eq!(Doctor::df_available_kb(""), None);
+    }
+}
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// Why the pipeline dropped a record instead of logging it, or ignored it as nothing to log.
+/// As JSON, the [`as_str`](Self::as_str) name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    /// A `recentchanges` row that could not be read, e.g. due to an unparsable title.
+    BadRecentChange,
+    /// An EventStreams event that is not valid JSON.
+    MalformedEvent,
+    /// A redirect, deletion, or log event with an unparsable entity ID.
+    BadEntityId,
+    /// A revision comparison that failed; the item is queued for a retry.
+    FailedCompare,
+    /// A change with a property, qualifier, hash, badge, or sub-entity ID that could not be stored.
+    BadChange,
+    /// A change whose language or site could not be stored in `texts`.
+    TextIdError,
+    /// A `recentchanges` log row for a deletion, move, or protection, read from `logging` instead.
+    LoggedElsewhere,
+    /// A `recentchanges` log row of a type that does not change entities, e.g. a patrol.
+    UnhandledLogEntry,
+    /// A `recentchanges` row for a change on another wiki, e.g. via Wikibase client usage.
+    ExternalChange,
+    /// A `recentchanges` row for a page added to or removed from a category.
+    Categorization,
+    /// A `recentchanges` row of an unknown `rc_type`.
+    UnknownRcType,
+    /// A change left out by the `log_rules`.
+    FilteredOut,
+    /// A changed entity outside the `scope`.
+    OutOfScope,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::BadRecentChange => "bad_recent_change",
+            Self::MalformedEvent => "malformed_event",
+            Self::BadEntityId => "bad_entity_id",
+            Self::FailedCompare => "failed_compare",
+            Self::BadChange => "bad_change",
+            Self::TextIdError => "text_id_error",
+            Self::LoggedElsewhere => "logged_elsewhere",
+            Self::UnhandledLogEntry => "unhandled_log_entry",
+            Self::ExternalChange => "external_change",
+            Self::Categorization => "categorization",
+            Self::UnknownRcType => "unknown_rc_type",
+            Self::FilteredOut => "filtered_out",
+            Self::OutOfScope => "out_of_scope",
+        }
+    }
+
+    /// Whether the record was ignored as expected, rather than lost.
+    pub fn is_ignored(&self) -> bool {
+        matches!(
+            self,
+            Self::LoggedElsewhere
+                | Self::UnhandledLogEntry
+                | Self::ExternalChange
+                | Self::Categorization
+                | Self::UnknownRcType
+                | Self::FilteredOut
+                | Self::OutOfScope
+        )
+    }
+}
+
+/// Counts dropped records per reason, over the current run.
+#[derive(Debug, Default)]
+pub struct DropCounts {
+    counts: Mutex<BTreeMap<DropReason, u64>>,
+}
+
+impl DropCounts {
+    pub fn add(&self, reason: DropReason, count: u64) {
+        if count == 0 {
+            return;
+        }
+        if let Ok(mut counts) = self.counts.lock() {
+            *counts.entry(reason).or_default() += count;
+        }
+    }
+
+    /// Returns the counts so far, and starts counting from zero.
+    pub fn take(&self) -> BTreeMap<DropReason, u64> {
+        self.counts
+            .lock()
+            .map(|mut counts| std::mem::take(&mut *counts))
+            .unwrap_or_default()
+    }
+
+    /// Returns the counts as a JSON object, keyed by reason.
+    pub fn to_json(counts: &BTreeMap<DropReason, u64>) -> Value {
+        let counts: serde_json::Map<String, Value> = counts
+            .iter()
+            .map(|(reason, count)| (reason.as_str().to_string(), json!(count)))
+            .collect();
+        Value::Object(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drop_counts() {
+        let drops = DropCounts::default();
+        drops.add(DropReason::BadChange, 2);
+        drops.add(DropReason::BadRecentChange, 1);
+        drops.add(DropReason::BadChange, 1);
+        drops.add(DropReason::TextIdError, 0);
+        let counts = drops.take();
+        assert_eq!(
+            DropCounts::to_json(&counts),
+            json!({"bad_recent_change": 1, "bad_change": 3})
+        );
+        assert!(DropReason::LoggedElsewhere.is_ignored());
+        assert!(!DropReason::BadRecentChange.is_ignored());
+        assert!(drops.take().is_empty());
+    }
+}
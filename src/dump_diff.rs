@@ -0,0 +1,223 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, BufReader, Lines},
+};
+
+use crate::{
+    change::{Change, EntityType},
+    recent_changes::ChangedItem,
+    RevisionId, WdRc,
+};
+
+/// Entities per `wbgetentities` request when comparing against the live wiki.
+const IDS_PER_REQUEST: usize = 50;
+/// Changes collected before they are logged or printed.
+const CHANGES_PER_FLUSH: usize = 10_000;
+
+/// The outcome of a dump comparison.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DumpDiffed {
+    pub entities: u64,
+    pub created: u64,
+    pub changed: u64,
+    pub unchanged: u64,
+    /// Entities only in the older dump, or missing on the live wiki.
+    pub deleted: u64,
+    pub changes: u64,
+}
+
+/// Compares entities of a JSON dump with a newer dump or the live wiki, using the revision
+/// comparison, so long periods can be audited or a new database bootstrapped without replaying
+/// every revision.
+///
+/// Dumps are read uncompressed, one entity per line, as in the Wikidata JSON dumps; compressed
+/// dumps can be passed via process substitution, e.g. `<(bzcat latest-all.json.bz2)`. The older
+/// dump is held in memory, so comparing two dumps is meant for subsets. Changes get the revision
+/// ID and modification time of the newer version.
+pub struct DumpDiff<'a> {
+    wdrc: &'a mut WdRc,
+    /// Whether changes are written to the wdrc tables, rather than printed as JSON lines.
+    log: bool,
+    pending: Vec<Change>,
+    ret: DumpDiffed,
+}
+
+impl<'a> DumpDiff<'a> {
+    pub fn new(wdrc: &'a mut WdRc, log: bool) -> Self {
+        Self {
+            wdrc,
+            log,
+            pending: vec![],
+            ret: DumpDiffed::default(),
+        }
+    }
+
+    async fn open(path: &str) -> Result<Lines<BufReader<File>>> {
+        let file = File::open(path)
+            .await
+            .map_err(|e| anyhow!("Could not open {path}: {e}"))?;
+        Ok(BufReader::new(file).lines())
+    }
+
+    /// An entity from a dump line; the array brackets and trailing commas are skipped.
+    fn parse_line(line: &str) -> Option<Value> {
+        let line = line.trim().trim_end_matches(',');
+        match line.starts_with('{') {
+            true => serde_json::from_str(line).ok(),
+            false => None,
+        }
+    }
+
+    /// `2024-01-01T12:34:56Z` as `20240101123456`.
+    fn timestamp(entity: &Value) -> String {
+        entity["modified"]
+            .as_str()
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .collect()
+    }
+
+    fn revision(entity: &Value) -> RevisionId {
+        entity["lastrevid"].as_u64().unwrap_or(0)
+    }
+
+    pub async fn compare_dumps(mut self, old: &str, new: &str) -> Result<DumpDiffed> {
+        let mut old_entities: HashMap<String, String> = HashMap::new();
+        let mut lines = Self::open(old).await?;
+        while let Some(line) = lines.next_line().await? {
+            if let Some(id) =
+                Self::parse_line(&line).and_then(|e| e["id"].as_str().map(String::from))
+            {
+                old_entities.insert(id, line);
+            }
+        }
+        let mut lines = Self::open(new).await?;
+        while let Some(line) = lines.next_line().await? {
+            let new_entity = match Self::parse_line(&line) {
+                Some(entity) => entity,
+                None => continue,
+            };
+            let old_entity = new_entity["id"]
+                .as_str()
+                .and_then(|id| old_entities.remove(id))
+                .and_then(|line| Self::parse_line(&line));
+            self.diff(old_entity.as_ref(), &new_entity).await?;
+        }
+        self.ret.deleted += old_entities.len() as u64;
+        self.flush().await?;
+        Ok(self.ret)
+    }
+
+    pub async fn compare_live(mut self, dump: &str) -> Result<DumpDiffed> {
+        let mut lines = Self::open(dump).await?;
+        let mut batch = vec![];
+        while let Some(line) = lines.next_line().await? {
+            batch.extend(Self::parse_line(&line));
+            if batch.len() >= IDS_PER_REQUEST {
+                self.diff_live(&batch).await?;
+                batch.clear();
+            }
+        }
+        self.diff_live(&batch).await?;
+        self.flush().await?;
+        Ok(self.ret)
+    }
+
+    async fn diff_live(&mut self, entities: &[Value]) -> Result<()> {
+        let ids: Vec<&str> = entities.iter().filter_map(|e| e["id"].as_str()).collect();
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let j: Value = self
+            .wdrc
+            .wd()
+            .reqwest_client()?
+            .get(self.wdrc.wiki().api_url())
+            .query(&[
+                ("action", "wbgetentities"),
+                ("ids", &ids.join("|")),
+                ("format", "json"),
+            ])
+            .timeout(self.wdrc.api_timeout())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        for old_entity in entities {
+            let id = old_entity["id"].as_str().unwrap_or_default();
+            let live = &j["entities"][id];
+            match live.is_object() && live.get("missing").is_none() {
+                true => self.diff(Some(old_entity), live).await?,
+                false => self.ret.deleted += 1,
+            }
+        }
+        Ok(())
+    }
+
+    async fn diff(&mut self, old: Option<&Value>, new: &Value) -> Result<()> {
+        let q = match new["id"].as_str() {
+            Some(q) if EntityType::from_id(q).is_some() => q,
+            _ => return Ok(()),
+        };
+        self.ret.entities += 1;
+        let rev_new = Self::revision(new);
+        let rev_old = old.map(Self::revision).unwrap_or(0);
+        match old {
+            Some(_) if rev_old == rev_new => {
+                self.ret.unchanged += 1;
+                return Ok(());
+            }
+            Some(_) => self.ret.changed += 1,
+            None => self.ret.created += 1,
+        }
+        let ci = ChangedItem::new(q, rev_old, rev_new, &Self::timestamp(new));
+        let empty = json!({});
+        let mut changes =
+            self.wdrc
+                .revision_compare()
+                .compare_entity(&ci, old.unwrap_or(&empty), new)?;
+        self.pending.append(&mut changes);
+        if self.pending.len() >= CHANGES_PER_FLUSH {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        let changes = std::mem::take(&mut self.pending);
+        match self.log {
+            true => self.wdrc.log_changes(&changes).await?,
+            false => {
+                for change in &changes {
+                    println!("{}", serde_json::to_string(change)?);
+                }
+            }
+        }
+        self.ret.changes += changes.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        assert!(DumpDiff::parse_line("[").is_none());
+        assert!(DumpDiff::parse_line("]").is_none());
+        let entity = DumpDiff::parse_line(
+            r#"{"type":"item","id":"Q42","lastrevid":123,"modified":"2024-01-01T12:34:56Z"},"#,
+        )
+        .unwrap();
+        assert_eq!(entity["id"], "Q42");
+        assert_eq!(DumpDiff::revision(&entity), 123);
+        assert_eq!(DumpDiff::timestamp(&entity), "20240101123456");
+    }
+}
@@ -0,0 +1,60 @@
+/// An edit summary, split into the Wikibase "magic" autocomment and the free text.
+///
+/// `/* wbsetlabel-add:1|de */ typo` has the action `wbsetlabel-add`, the arguments `1` and `de`,
+/// and the text `typo`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditSummary {
+    pub action: Option<String>,
+    pub args: Vec<String>,
+    pub text: String,
+}
+
+impl EditSummary {
+    pub fn parse(comment: &str) -> Self {
+        let comment = comment.trim();
+        let (autocomment, text) = match comment
+            .strip_prefix("/*")
+            .and_then(|rest| rest.split_once("*/"))
+        {
+            Some((autocomment, text)) => (autocomment.trim(), text.trim()),
+            None => {
+                return Self {
+                    text: comment.to_string(),
+                    ..Default::default()
+                }
+            }
+        };
+        let (action, args) = autocomment.split_once(':').unwrap_or((autocomment, ""));
+        let args = match args.is_empty() {
+            true => vec![],
+            false => args.split('|').map(|arg| arg.to_string()).collect(),
+        };
+        Self {
+            action: Some(action.to_string()).filter(|action| !action.is_empty()),
+            args,
+            text: text.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let summary = EditSummary::parse("/* wbsetlabel-add:1|de */ Douglas Adams");
+        assert_eq!(summary.action, Some("wbsetlabel-add".to_string()));
+        assert_eq!(summary.args, vec!["1", "de"]);
+        assert_eq!(summary.text, "Douglas Adams");
+
+        let summary = EditSummary::parse("/* wbcreateclaim */");
+        assert_eq!(summary.action, Some("wbcreateclaim".to_string()));
+        assert!(summary.args.is_empty());
+        assert_eq!(summary.text, "");
+
+        let summary = EditSummary::parse("Reverted edits");
+        assert_eq!(summary.action, None);
+        assert_eq!(summary.text, "Reverted edits");
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use wikimisc::wikidata::Wikidata;
+
+use crate::{
+    drops::{DropCounts, DropReason},
+    recent_changes::RecentChanges,
+    wiki::Wiki,
+};
+
+const EVENTSTREAM_URL: &str = "https://stream.wikimedia.org/v2/stream/recentchange";
+const EVENTSTREAM_IDLE_SECS: u64 = 30;
+
+/// Reads Wikidata recent changes from the Wikimedia EventStreams `recentchange` stream.
+pub struct EventStream {
+    wd: Arc<Wikidata>,
+    wiki: Wiki,
+}
+
+impl EventStream {
+    pub fn new(wd: Arc<Wikidata>, wiki: Wiki) -> Self {
+        Self { wd, wiki }
+    }
+
+    /// Converts a `YYYYMMDDHHMMSS` timestamp into the ISO 8601 form EventStreams expects for `since`.
+    fn since_param(oldest: &str) -> String {
+        NaiveDateTime::parse_from_str(oldest, "%Y%m%d%H%M%S")
+            .map(|dt| dt.and_utc().format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_else(|_| Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string())
+    }
+
+    /// Converts a UNIX timestamp from an event into `YYYYMMDDHHMMSS`.
+    pub fn event_timestamp(seconds: i64) -> Option<String> {
+        DateTime::from_timestamp(seconds, 0).map(|dt| dt.format("%Y%m%d%H%M%S").to_string())
+    }
+
+    /// Reads events from `oldest` on, until `max` changes in `namespaces` were collected,
+    /// or the stream has caught up with the time of the request.
+    pub async fn get_recent_changes(
+        &self,
+        oldest: &str,
+        max: u64,
+        namespaces: &[u64],
+        drops: &DropCounts,
+    ) -> Result<Vec<RecentChanges>> {
+        let started = Utc::now().timestamp();
+        let url = format!("{EVENTSTREAM_URL}?since={}", Self::since_param(oldest));
+        let client = self.wd.reqwest_client()?;
+        let mut response = client
+            .get(url)
+            .header("Accept", "text/event-stream")
+            .send()
+            .await?;
+
+        let mut ret = vec![];
+        let mut buffer: Vec<u8> = vec![];
+        loop {
+            let idle = Duration::from_secs(EVENTSTREAM_IDLE_SECS);
+            let chunk = match tokio::time::timeout(idle, response.chunk()).await {
+                Ok(Ok(Some(chunk))) => chunk,
+                Ok(Ok(None)) | Err(_) => break, // Stream closed, or idle
+                Ok(Err(e)) if ret.is_empty() => return Err(e.into()),
+                Ok(Err(_)) => break, // Keep what we have, and resume from there on the next run
+            };
+            buffer.extend_from_slice(&chunk);
+            while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line);
+                let data = match line.trim_end().strip_prefix("data:") {
+                    Some(data) => data.trim(),
+                    None => continue,
+                };
+                let event: Value = match serde_json::from_str(data) {
+                    Ok(event) => event,
+                    Err(_) => {
+                        drops.add(DropReason::MalformedEvent, 1);
+                        continue;
+                    }
+                };
+                if event["timestamp"].as_i64().unwrap_or(0) >= started {
+                    return Ok(ret); // Caught up
+                }
+                let namespace = event["namespace"].as_u64().unwrap_or_default();
+                if !namespaces.contains(&namespace) {
+                    continue;
+                }
+                if let Some(rc) = RecentChanges::from_event(&event, self.wiki) {
+                    ret.push(rc);
+                    if ret.len() as u64 >= max {
+                        return Ok(ret);
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
@@ -0,0 +1,377 @@
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDateTime, SecondsFormat};
+use std::{fs, path::Path};
+
+use crate::{
+    change::EntityType,
+    digest::Digest,
+    query::{ChangeFilter, ChangeRow},
+    WdRc,
+};
+
+/// Revisions listed per feed, newest first.
+const FEED_ENTRIES: u64 = 100;
+
+/// The slice of the change log a feed follows: `item/Q42` for the changes to an entity of any
+/// type, `property/P31` for those using a property, or `language/de` for terms in a language.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeedSlice {
+    Item(String),
+    Property(String),
+    Language(String),
+}
+
+impl FeedSlice {
+    pub fn new(kind: &str, key: &str) -> Result<Self> {
+        let ret = match kind {
+            "item" => Self::Item(key.to_string()),
+            "property" => Self::Property(key.to_string()),
+            "language" => Self::Language(key.to_string()),
+            _ => return Err(anyhow!("Unknown feed kind: {kind:?}")),
+        };
+        match &ret {
+            Self::Item(id) if EntityType::from_id(id).is_none() => {
+                return Err(anyhow!("Not an entity ID: {id:?}"))
+            }
+            Self::Property(id) if EntityType::from_id(id) != Some(EntityType::Property) => {
+                return Err(anyhow!("Not a property ID: {id:?}"))
+            }
+            Self::Language(language)
+                if language.is_empty()
+                    || !language
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-') =>
+            {
+                return Err(anyhow!("Not a language code: {language:?}"))
+            }
+            _ => {}
+        }
+        Ok(ret)
+    }
+
+    /// Parses `kind/key`, as in the config and the server path.
+    pub fn from_path(path: &str) -> Result<Self> {
+        let (kind, key) = path
+            .split_once('/')
+            .ok_or_else(|| anyhow!("Feed must be kind/key, like item/Q42: {path:?}"))?;
+        Self::new(kind, key)
+    }
+
+    pub fn kind(&self) -> &str {
+        match self {
+            Self::Item(_) => "item",
+            Self::Property(_) => "property",
+            Self::Language(_) => "language",
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Item(key) | Self::Property(key) | Self::Language(key) => key,
+        }
+    }
+
+    /// The file name of the feed, without extension.
+    pub fn file_stem(&self) -> String {
+        format!("{}-{}", self.kind(), self.key())
+    }
+
+    fn title(&self) -> String {
+        match self {
+            Self::Item(id) => format!("Changes to {id}"),
+            Self::Property(id) => format!("Changes using {id}"),
+            Self::Language(language) => format!("Changes to terms in {language}"),
+        }
+    }
+
+    /// The page the feed is about.
+    fn link(&self, server: &str) -> String {
+        match self {
+            Self::Item(id) | Self::Property(id) => format!("{server}/entity/{id}"),
+            Self::Language(_) => format!("{server}/wiki/Special:RecentChanges"),
+        }
+    }
+
+    /// The change listing queries for the slice, one per tracked entity type unless the slice
+    /// is a single entity.
+    fn queries(&self, entity_types: &[EntityType]) -> Vec<String> {
+        let per_type = |condition: String| -> Vec<String> {
+            entity_types
+                .iter()
+                .map(|et| format!("entity={}&{condition}&limit={FEED_ENTRIES}", et.as_str()))
+                .collect()
+        };
+        match self {
+            Self::Item(id) => match EntityType::from_id(id) {
+                Some(entity_type) => vec![format!(
+                    "entity={}&item={id}&limit={FEED_ENTRIES}",
+                    entity_type.as_str()
+                )],
+                None => vec![],
+            },
+            Self::Property(id) => per_type(format!("prop={id}")),
+            Self::Language(language) => per_type(format!("lang={language}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+impl FeedFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "atom" => Some(Self::Atom),
+            "rss" => Some(Self::Rss),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &str {
+        match self {
+            Self::Atom => "atom",
+            Self::Rss => "rss",
+        }
+    }
+
+    pub fn content_type(&self) -> &str {
+        match self {
+            Self::Atom => "application/atom+xml; charset=utf-8",
+            Self::Rss => "application/rss+xml; charset=utf-8",
+        }
+    }
+}
+
+/// The latest logged changes of a slice as an Atom or RSS feed, one entry per revision.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feed {
+    slice: FeedSlice,
+    /// Changes newest first, with those of a revision next to each other.
+    rows: Vec<ChangeRow>,
+}
+
+impl Feed {
+    pub async fn collect(wdrc: &WdRc, slice: FeedSlice) -> Result<Self> {
+        let entity_types: Vec<EntityType> = EntityType::all()
+            .into_iter()
+            .filter(|et| wdrc.namespaces().contains(&et.namespace()))
+            .collect();
+        let mut rows = vec![];
+        for query in slice.queries(&entity_types) {
+            rows.extend(ChangeFilter::from_query(&query)?.run(wdrc).await?);
+        }
+        rows.sort_by(|a, b| (&b.timestamp, b.revision).cmp(&(&a.timestamp, a.revision)));
+        Ok(Self { slice, rows })
+    }
+
+    /// Writes an Atom and an RSS file for each configured feed.
+    pub async fn write_all(wdrc: &WdRc) -> Result<()> {
+        let directory = wdrc
+            .feeds_dir()
+            .ok_or_else(|| anyhow!("No feeds_dir in config"))?;
+        let server = wdrc.wiki().server();
+        for path in wdrc.feeds() {
+            let feed = Self::collect(wdrc, FeedSlice::from_path(path)?).await?;
+            for format in [FeedFormat::Atom, FeedFormat::Rss] {
+                let file = Path::new(directory).join(format!(
+                    "{}.{}",
+                    feed.slice.file_stem(),
+                    format.extension()
+                ));
+                fs::write(file, feed.render(format, server, wdrc.wiki().dbname()))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, format: FeedFormat, server: &str, wiki: &str) -> String {
+        match format {
+            FeedFormat::Atom => self.to_atom(server, wiki),
+            FeedFormat::Rss => self.to_rss(server),
+        }
+    }
+
+    pub fn to_atom(&self, server: &str, wiki: &str) -> String {
+        let updated = self
+            .rows
+            .first()
+            .map(|row| Self::rfc3339(&row.timestamp))
+            .unwrap_or_else(|| "1970-01-01T00:00:00Z".to_string());
+        let mut ret = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+             <id>urn:wdrc:{wiki}:{}:{}</id>\n\
+             <title>{}</title>\n\
+             <link href=\"{}\"/>\n\
+             <updated>{updated}</updated>\n\
+             <author><name>wdrc</name></author>\n",
+            self.slice.kind(),
+            Digest::escape(self.slice.key()),
+            Digest::escape(&self.slice.title()),
+            Digest::escape(&self.slice.link(server)),
+        );
+        for rows in self.revisions() {
+            let link = Self::diff_url(rows[0], server);
+            ret += &format!(
+                "<entry>\n<id>{link}</id>\n<title>{}</title>\n<link href=\"{link}\"/>\n\
+                 <updated>{}</updated>\n<summary>{}</summary>\n</entry>\n",
+                Digest::escape(&Self::title(&rows)),
+                Self::rfc3339(&rows[0].timestamp),
+                Digest::escape(&Self::summary(&rows)),
+            );
+        }
+        ret + "</feed>\n"
+    }
+
+    pub fn to_rss(&self, server: &str) -> String {
+        let mut ret = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <rss version=\"2.0\">\n<channel>\n\
+             <title>{}</title>\n<link>{}</link>\n<description>{}</description>\n",
+            Digest::escape(&self.slice.title()),
+            Digest::escape(&self.slice.link(server)),
+            Digest::escape(&self.slice.title()),
+        );
+        for rows in self.revisions() {
+            let link = Self::diff_url(rows[0], server);
+            ret += &format!(
+                "<item>\n<guid>{link}</guid>\n<title>{}</title>\n<link>{link}</link>\n\
+                 <pubDate>{}</pubDate>\n<description>{}</description>\n</item>\n",
+                Digest::escape(&Self::title(&rows)),
+                Self::rfc2822(&rows[0].timestamp),
+                Digest::escape(&Self::summary(&rows)),
+            );
+        }
+        ret + "</channel>\n</rss>\n"
+    }
+
+    /// Rows grouped by revision, up to `FEED_ENTRIES` revisions.
+    fn revisions(&self) -> Vec<Vec<&ChangeRow>> {
+        let mut ret: Vec<Vec<&ChangeRow>> = vec![];
+        for row in &self.rows {
+            match ret.last_mut() {
+                Some(rows) if rows[0].revision == row.revision => rows.push(row),
+                _ => ret.push(vec![row]),
+            }
+        }
+        ret.truncate(FEED_ENTRIES as usize);
+        ret
+    }
+
+    /// `Q42: claims [P569] added (+2 more)`.
+    fn title(rows: &[&ChangeRow]) -> String {
+        let more = match rows.len() {
+            1 => String::new(),
+            n => format!(" (+{} more)", n - 1),
+        };
+        format!("{}: {}{more}", rows[0].entity, Self::change_text(rows[0]))
+    }
+
+    fn summary(rows: &[&ChangeRow]) -> String {
+        rows.iter()
+            .map(|row| Self::change_text(row))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn change_text(row: &ChangeRow) -> String {
+        let key = match (&row.property, &row.language) {
+            (Some(key), _) | (None, Some(key)) => format!(" [{key}]"),
+            (None, None) => String::new(),
+        };
+        format!("{}{key} {}", row.subject, row.change_type)
+    }
+
+    fn diff_url(row: &ChangeRow, server: &str) -> String {
+        format!("{server}/w/index.php?diff={}", row.revision)
+    }
+
+    fn parse_timestamp(timestamp: &str) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(timestamp, "%Y%m%d%H%M%S").ok()
+    }
+
+    fn rfc3339(timestamp: &str) -> String {
+        Self::parse_timestamp(timestamp)
+            .map(|dt| dt.and_utc().to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_default()
+    }
+
+    fn rfc2822(timestamp: &str) -> String {
+        Self::parse_timestamp(timestamp)
+            .map(|dt| dt.and_utc().to_rfc2822())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(entity: &str, property: &str, timestamp: &str, revision: u64) -> ChangeRow {
+        ChangeRow {
+            entity: entity.to_string(),
+            revision,
+            subject: "claims".to_string(),
+            timestamp: timestamp.to_string(),
+            change_type: "added".to_string(),
+            language: None,
+            property: Some(property.to_string()),
+            in_wdqs: None,
+            redirected_from: None,
+            cursor: None,
+        }
+    }
+
+    #[test]
+    fn test_feed_slice() {
+        let slice = FeedSlice::from_path("property/P31").unwrap();
+        assert_eq!(slice, FeedSlice::Property("P31".to_string()));
+        assert_eq!(slice.file_stem(), "property-P31");
+        assert_eq!(
+            slice.queries(&[EntityType::Item, EntityType::Lexeme]),
+            vec![
+                "entity=item&prop=P31&limit=100",
+                "entity=lexeme&prop=P31&limit=100"
+            ]
+        );
+        assert_eq!(
+            FeedSlice::from_path("item/L7").unwrap().queries(&[]),
+            vec!["entity=lexeme&item=L7&limit=100"]
+        );
+        assert!(FeedSlice::from_path("property/Q42").is_err());
+        assert!(FeedSlice::from_path("language/de<").is_err());
+        assert!(FeedSlice::from_path("site/dewiki").is_err());
+        assert!(FeedSlice::from_path("Q42").is_err());
+        for query in FeedSlice::from_path("language/de")
+            .unwrap()
+            .queries(&EntityType::all())
+        {
+            assert!(ChangeFilter::from_query(&query).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let feed = Feed {
+            slice: FeedSlice::Property("P569".to_string()),
+            rows: vec![
+                row("Q42", "P569", "20240101120000", 3),
+                row("Q42", "P569", "20240101120000", 3),
+                row("Q1", "P569", "20240101110000", 2),
+            ],
+        };
+        let server = "https://www.wikidata.org";
+        let atom = feed.to_atom(server, "wikidatawiki");
+        assert!(atom.contains("<id>urn:wdrc:wikidatawiki:property:P569</id>"));
+        assert!(atom.contains("<updated>2024-01-01T12:00:00Z</updated>"));
+        assert!(atom.contains("<title>Q42: claims [P569] added (+1 more)</title>"));
+        assert_eq!(atom.matches("<entry>").count(), 2);
+        let rss = feed.to_rss(server);
+        assert!(rss.contains("<guid>https://www.wikidata.org/w/index.php?diff=2</guid>"));
+        assert!(rss.contains("<pubDate>Mon, 1 Jan 2024 11:00:00 +0000</pubDate>"));
+        assert_eq!(rss.matches("<item>").count(), 2);
+    }
+}
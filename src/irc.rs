@@ -0,0 +1,188 @@
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use crate::config::IrcConfig;
+
+/// Batches of lines waiting while the feed is (re)connecting.
+const QUEUE_CAPACITY: usize = 100;
+/// Pause between messages, to stay below server flood limits.
+const LINE_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Message text is cut to this many bytes, leaving room for the command within 512 bytes per line.
+const MAX_MESSAGE_BYTES: usize = 400;
+
+/// A connection to an IRC channel that stays open in the background, posting the lines sent to
+/// it and reconnecting after errors.
+#[derive(Debug, Clone)]
+pub struct IrcFeed {
+    channel: String,
+    sender: mpsc::Sender<Vec<String>>,
+}
+
+impl IrcFeed {
+    /// Connects in a new task, which ends once all clones of the feed are dropped.
+    pub fn spawn(config: IrcConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let channel = config.channel.to_owned();
+        tokio::spawn(Self::run(config, receiver));
+        Self { channel, sender }
+    }
+
+    /// Queues lines for the channel; fails if the queue is full, e.g. while the server is down.
+    pub fn send(&self, lines: Vec<String>) -> Result<()> {
+        let count = lines.len();
+        self.sender.try_send(lines).map_err(|_| {
+            anyhow!(
+                "IRC queue of {} is full, dropped {count} lines",
+                self.channel
+            )
+        })
+    }
+
+    async fn run(config: IrcConfig, mut receiver: mpsc::Receiver<Vec<String>>) {
+        loop {
+            match Self::session(&config, &mut receiver).await {
+                Ok(()) => return,
+                Err(e) => eprintln!("IRC {}: {e}", config.channel),
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Connects and posts until the feed is dropped, or the connection fails.
+    async fn session(config: &IrcConfig, receiver: &mut mpsc::Receiver<Vec<String>>) -> Result<()> {
+        let tcp = TcpStream::connect((config.server.as_str(), config.port)).await?;
+        match config.tls {
+            true => {
+                let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
+                let stream = connector.connect(&config.server, tcp).await?;
+                Self::chat(config, stream, receiver).await
+            }
+            false => Self::chat(config, tcp, receiver).await,
+        }
+    }
+
+    async fn chat<S: AsyncRead + AsyncWrite + Unpin>(
+        config: &IrcConfig,
+        stream: S,
+        receiver: &mut mpsc::Receiver<Vec<String>>,
+    ) -> Result<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = BufReader::new(reader).lines();
+        let mut register = vec![];
+        if let Some(password) = &config.password {
+            register.push(format!("PASS {password}"));
+        }
+        register.push(format!("NICK {}", config.nick));
+        register.push(format!("USER {} 0 * :wdrc", config.nick));
+        for line in register {
+            writer.write_all(format!("{line}\r\n").as_bytes()).await?;
+        }
+        // Channels can only be joined after the welcome
+        loop {
+            let line = lines
+                .next_line()
+                .await?
+                .ok_or_else(|| anyhow!("Connection closed"))?;
+            if let Some(pong) = Self::pong(&line) {
+                writer.write_all(format!("{pong}\r\n").as_bytes()).await?;
+            }
+            match Self::command(&line) {
+                "001" => break,
+                "432" | "433" => return Err(anyhow!("Nick {} not available", config.nick)),
+                "ERROR" => return Err(anyhow!("Server error: {line}")),
+                _ => {}
+            }
+        }
+        writer
+            .write_all(format!("JOIN {}\r\n", config.channel).as_bytes())
+            .await?;
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let line = line?.ok_or_else(|| anyhow!("Connection closed"))?;
+                    if let Some(pong) = Self::pong(&line) {
+                        writer.write_all(format!("{pong}\r\n").as_bytes()).await?;
+                    }
+                }
+                batch = receiver.recv() => {
+                    let batch = match batch {
+                        Some(batch) => batch,
+                        None => {
+                            writer.write_all(b"QUIT\r\n").await?;
+                            return Ok(());
+                        }
+                    };
+                    for message in Self::privmsgs(&config.channel, &batch) {
+                        writer.write_all(format!("{message}\r\n").as_bytes()).await?;
+                        tokio::time::sleep(LINE_DELAY).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The reply to a server `PING`.
+    fn pong(line: &str) -> Option<String> {
+        line.strip_prefix("PING ")
+            .map(|token| format!("PONG {token}"))
+    }
+
+    /// The command or numeric reply of a server line, after the optional `:prefix`.
+    fn command(line: &str) -> &str {
+        let line = match line.starts_with(':') {
+            true => line
+                .split_once(' ')
+                .map(|(_, rest)| rest)
+                .unwrap_or_default(),
+            false => line,
+        };
+        line.split(' ').next().unwrap_or_default()
+    }
+
+    /// A `PRIVMSG` per line, cut to `MAX_MESSAGE_BYTES`.
+    fn privmsgs(channel: &str, lines: &[String]) -> Vec<String> {
+        lines
+            .iter()
+            .flat_map(|line| line.lines())
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let mut end = line.len().min(MAX_MESSAGE_BYTES);
+                while !line.is_char_boundary(end) {
+                    end -= 1;
+                }
+                format!("PRIVMSG {channel} :{}", &line[..end])
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irc_lines() {
+        assert_eq!(
+            IrcFeed::pong("PING :tantalum.libera.chat"),
+            Some("PONG :tantalum.libera.chat".to_string())
+        );
+        assert_eq!(IrcFeed::pong(":a PRIVMSG #b :PING"), None);
+        assert_eq!(
+            IrcFeed::command(":tantalum.libera.chat 001 wdrc :Welcome"),
+            "001"
+        );
+        assert_eq!(IrcFeed::command("ERROR :Closing link"), "ERROR");
+        let long = "é".repeat(MAX_MESSAGE_BYTES);
+        let messages = IrcFeed::privmsgs("#wd", &["a\nb".to_string(), long]);
+        assert_eq!(messages[0], "PRIVMSG #wd :a");
+        assert_eq!(messages[1], "PRIVMSG #wd :b");
+        assert_eq!(messages[2].len(), "PRIVMSG #wd :".len() + MAX_MESSAGE_BYTES);
+    }
+}
@@ -0,0 +1,313 @@
+use anyhow::{anyhow, Result};
+use std::{
+    future::Future,
+    path::Path,
+    time::{Duration, Instant},
+};
+use wikimisc::mysql_async::{from_row, prelude::Queryable, Conn};
+
+use crate::{
+    deliveries::Deliveries, digest::Digest, doctor::IndexAdvice, feeds::Feed,
+    migrations::Migrations, public_stats::PublicStats, report::StatsReport, sink::SinkType,
+    tombstones::Tombstone, watch_pages::WatchPages, WdRc,
+};
+
+/// How often a one-off job checks that it still holds its lock, which also keeps the lock's
+/// connection from being closed as idle.
+const LOCK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The Toolforge jobs this tool runs, one subcommand each.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Job {
+    /// Continuous job, polling for recent changes.
+    Bot,
+    /// Run by hand: the bot loop with changes written to stdout as NDJSON, detached from the
+    /// bot's checkpoints.
+    Stream,
+    /// Scheduled daily: catches up on deletions, redirects, log events, and merges, and purges old entries.
+    DailyMaintenance,
+    /// Scheduled weekly: stores the weekly change statistics.
+    WeeklyAggregate,
+    /// Scheduled hourly: updates the on-wiki watch pages of properties.
+    WatchPages,
+    /// Scheduled weekly: removes tombstones older than `tombstone_days`.
+    CompactTombstones,
+    /// Scheduled hourly: writes the anonymous usage statistics to the `public_stats` file.
+    PublicStats,
+    /// Scheduled every 5 minutes: emails the configured digests whose schedules are due.
+    Digest,
+    /// Scheduled every 15 minutes: writes the configured feeds to `feeds_dir`.
+    Feeds,
+}
+
+impl Job {
+    pub fn from_command(command: &str) -> Option<Self> {
+        match command {
+            "bot" => Some(Self::Bot),
+            "stream" => Some(Self::Stream),
+            "daily-maintenance" => Some(Self::DailyMaintenance),
+            "weekly-aggregate" => Some(Self::WeeklyAggregate),
+            "watch-pages" => Some(Self::WatchPages),
+            "compact-tombstones" => Some(Self::CompactTombstones),
+            "public-stats" => Some(Self::PublicStats),
+            "digest" => Some(Self::Digest),
+            "feeds" => Some(Self::Feeds),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Bot => "bot",
+            Self::Stream => "stream",
+            Self::DailyMaintenance => "daily-maintenance",
+            Self::WeeklyAggregate => "weekly-aggregate",
+            Self::WatchPages => "watch-pages",
+            Self::CompactTombstones => "compact-tombstones",
+            Self::PublicStats => "public-stats",
+            Self::Digest => "digest",
+            Self::Feeds => "feeds",
+        }
+    }
+
+    /// Maximum runtime of the job; for the bot, of a single run, checked between its steps.
+    pub fn max_runtime(&self) -> Duration {
+        match self {
+            Self::Bot | Self::Stream => Duration::from_secs(15 * 60),
+            Self::DailyMaintenance => Duration::from_secs(2 * 60 * 60),
+            Self::WeeklyAggregate => Duration::from_secs(60 * 60),
+            Self::WatchPages => Duration::from_secs(30 * 60),
+            Self::CompactTombstones => Duration::from_secs(60 * 60),
+            Self::PublicStats => Duration::from_secs(10 * 60),
+            Self::Digest => Duration::from_secs(30 * 60),
+            Self::Feeds => Duration::from_secs(10 * 60),
+        }
+    }
+
+    fn lock_name(&self) -> String {
+        format!("wdrc_job_{}", self.as_str())
+    }
+
+    /// Runs the job while holding a database lock, so only one instance runs at a time.
+    pub async fn run(&self, wdrc: &mut WdRc) -> Result<()> {
+        if matches!(self, Self::Bot | Self::Stream | Self::DailyMaintenance) {
+            Migrations::run(wdrc).await?;
+            wdrc.check_replica_schema().await?;
+        }
+        if *self == Self::Bot {
+            // Missing indexes degrade silently, so they are reported but not fatal
+            match IndexAdvice::check(wdrc).await {
+                Ok(advice) if !advice.is_ok() => eprintln!("{}", advice.diagnostic()),
+                Ok(_) => {}
+                Err(e) => eprintln!("Could not check indexes: {e}"),
+            }
+        }
+        let mut lock = wdrc.db().get_connection("wdrc").await?;
+        self.lock(&mut lock).await?;
+        let result = match self {
+            Self::Bot => {
+                wdrc.collect_notifications();
+                self.run_bot(wdrc, &mut lock).await
+            }
+            Self::Stream => {
+                wdrc.set_sink(SinkType::Ndjson);
+                wdrc.detach();
+                self.run_bot(wdrc, &mut lock).await
+            }
+            Self::DailyMaintenance => {
+                self.run_locked(wdrc, &mut lock, Self::daily_maintenance(wdrc))
+                    .await
+            }
+            Self::WeeklyAggregate => {
+                self.run_locked(wdrc, &mut lock, Self::weekly_aggregate(wdrc))
+                    .await
+            }
+            Self::WatchPages => {
+                self.run_locked(wdrc, &mut lock, Self::watch_pages(wdrc))
+                    .await
+            }
+            Self::CompactTombstones => {
+                self.run_locked(wdrc, &mut lock, Self::compact_tombstones(wdrc))
+                    .await
+            }
+            Self::PublicStats => {
+                self.run_locked(wdrc, &mut lock, Self::public_stats(wdrc))
+                    .await
+            }
+            Self::Digest => {
+                self.run_locked(wdrc, &mut lock, Digest::send_all(wdrc))
+                    .await
+            }
+            Self::Feeds => {
+                self.run_locked(wdrc, &mut lock, Feed::write_all(wdrc))
+                    .await
+            }
+        };
+        let _ = lock
+            .exec_drop("SELECT RELEASE_LOCK(?)", (self.lock_name(),))
+            .await;
+        result
+    }
+
+    async fn lock(&self, conn: &mut Conn) -> Result<()> {
+        let acquired: Vec<Option<i64>> = conn
+            .exec_iter("SELECT GET_LOCK(?,0)", (self.lock_name(),))
+            .await?
+            .map_and_drop(from_row::<Option<i64>>)
+            .await?;
+        match acquired.first() {
+            Some(Some(1)) => Ok(()),
+            _ => Err(anyhow!("{} is already running", self.as_str())),
+        }
+    }
+
+    /// Checks that `conn` still holds the lock, which also keeps it from idling out. If the
+    /// connection was lost, and the lock with it, takes the lock again on a new connection.
+    async fn keep_lock(&self, wdrc: &WdRc, conn: &mut Conn) -> Result<()> {
+        let held = conn
+            .exec_iter(
+                "SELECT IS_USED_LOCK(?)=CONNECTION_ID()",
+                (self.lock_name(),),
+            )
+            .await;
+        let held = match held {
+            Ok(result) => result.map_and_drop(from_row::<Option<i64>>).await.ok(),
+            Err(_) => None,
+        };
+        if matches!(held.as_deref(), Some([Some(1)])) {
+            return Ok(());
+        }
+        let mut new_conn = wdrc.db().get_connection("wdrc").await?;
+        self.lock(&mut new_conn).await?;
+        *conn = new_conn;
+        Ok(())
+    }
+
+    /// Runs a one-off job within its maximum runtime, stopping it if the lock is lost.
+    async fn run_locked(
+        &self,
+        wdrc: &WdRc,
+        lock: &mut Conn,
+        job: impl Future<Output = Result<()>>,
+    ) -> Result<()> {
+        let keep = async {
+            loop {
+                tokio::time::sleep(LOCK_CHECK_INTERVAL).await;
+                if let Err(e) = self.keep_lock(wdrc, lock).await {
+                    return e;
+                }
+            }
+        };
+        tokio::select! {
+            result = self.bounded(job) => result,
+            e = keep => Err(e),
+        }
+    }
+
+    async fn bounded(&self, future: impl Future<Output = Result<()>>) -> Result<()> {
+        tokio::time::timeout(self.max_runtime(), future)
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "{} did not finish within {:?}",
+                    self.as_str(),
+                    self.max_runtime()
+                )
+            })?
+    }
+
+    async fn run_bot(&self, wdrc: &mut WdRc, lock: &mut Conn) -> Result<()> {
+        let mut errors = 0;
+        loop {
+            // Never run without the lock, e.g. once another instance has taken it over
+            self.keep_lock(wdrc, lock).await?;
+            // Checked between steps, since cancelling a run partway would lose its progress
+            wdrc.set_deadline(Some(Instant::now() + self.max_runtime()));
+            match wdrc.run_once().await {
+                Ok(_) => {
+                    errors = 0;
+                    // After the checkpoint, and outside the time limit of the run
+                    wdrc.notify().await;
+                }
+                Err(e) => {
+                    errors += 1;
+                    wdrc.discard_notifications();
+                    eprintln!("Error: {}", e)
+                }
+            }
+            tokio::time::sleep(wdrc.bot_delay(errors)).await;
+        }
+    }
+
+    async fn daily_maintenance(wdrc: &WdRc) -> Result<()> {
+        wdrc.update_recent_deletions().await?;
+        wdrc.update_recent_redirects().await?;
+        wdrc.update_recent_log_events().await?;
+        wdrc.update_recent_merges().await?;
+        wdrc.purge_old_entries().await?;
+        Deliveries::new(wdrc).purge_delivered().await?;
+        Ok(())
+    }
+
+    async fn weekly_aggregate(wdrc: &WdRc) -> Result<()> {
+        let report = StatsReport::weekly(wdrc).await?;
+        let rows: Vec<_> = report
+            .deltas
+            .iter()
+            .map(|d| {
+                vec![
+                    report.end.as_str().into(),
+                    d.group.as_str().into(),
+                    d.key.as_str().into(),
+                    d.current.into(),
+                    d.previous.into(),
+                ]
+            })
+            .collect();
+        let sql = "REPLACE INTO `weekly_stats` (`end`,`group`,`key`,`current`,`previous`) VALUES";
+        wdrc.insert_rows(sql, &rows).await
+    }
+
+    async fn compact_tombstones(wdrc: &WdRc) -> Result<()> {
+        Tombstone::compact(wdrc, wdrc.tombstone_days()).await?;
+        Ok(())
+    }
+
+    async fn watch_pages(wdrc: &WdRc) -> Result<()> {
+        let config = wdrc
+            .watch_pages()
+            .ok_or_else(|| anyhow!("No watch_pages in config"))?;
+        WatchPages::new(wdrc, config).update().await?;
+        Ok(())
+    }
+
+    async fn public_stats(wdrc: &WdRc) -> Result<()> {
+        let path = wdrc
+            .public_stats()
+            .ok_or_else(|| anyhow!("No public_stats in config"))?;
+        PublicStats::collect(wdrc).await?.write(Path::new(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_command() {
+        for job in [
+            Job::Bot,
+            Job::Stream,
+            Job::DailyMaintenance,
+            Job::WeeklyAggregate,
+            Job::WatchPages,
+            Job::CompactTombstones,
+            Job::PublicStats,
+            Job::Digest,
+            Job::Feeds,
+        ] {
+            assert_eq!(Job::from_command(job.as_str()), Some(job));
+        }
+        assert_eq!(Job::from_command("run"), None);
+    }
+}
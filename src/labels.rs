@@ -0,0 +1,157 @@
+use anyhow::Result;
+use futures::StreamExt;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use wikimisc::wikidata::Wikidata;
+
+use crate::wiki::Wiki;
+
+/// Maximum number of entities the API returns per `wbgetentities` request.
+const IDS_PER_REQUEST: usize = 50;
+/// `wbgetentities` requests run at a time.
+const MAX_CONCURRENT: usize = 4;
+/// How long labels are used before being fetched again.
+pub const LABEL_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Labels of entities per language, fetched in batches and kept for a while, so rendering many
+/// notifications does not need an API call each.
+pub struct LabelCache {
+    wd: Arc<Wikidata>,
+    wiki: Wiki,
+    ttl: Duration,
+    /// (language, entity) => (label, fetched), with `None` for entities without a label in that language.
+    labels: HashMap<(String, String), (Option<String>, Instant)>,
+}
+
+impl LabelCache {
+    pub fn new(wd: Arc<Wikidata>, wiki: Wiki, ttl: Duration) -> Self {
+        Self {
+            wd,
+            wiki,
+            ttl,
+            labels: HashMap::new(),
+        }
+    }
+
+    /// Fetches labels of the entities not cached in `language`, or cached longer than the TTL.
+    /// Labels of batches that could not be loaded are left out.
+    pub async fn fetch(&mut self, ids: &[String], language: &str) -> Result<()> {
+        let mut missing: Vec<&String> = ids
+            .iter()
+            .filter(|id| !self.is_fresh(id, language))
+            .collect();
+        missing.sort();
+        missing.dedup();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let client = self.wd.reqwest_client()?;
+        let api_url = self.wiki.api_url();
+        let futures = missing.chunks(IDS_PER_REQUEST).map(|chunk| {
+            let ids: Vec<&str> = chunk.iter().map(|id| id.as_str()).collect();
+            let request = client
+                .get(&api_url)
+                .query(&[
+                    ("action", "wbgetentities"),
+                    ("ids", &ids.join("|")),
+                    ("props", "labels"),
+                    ("languages", language),
+                    ("languagefallback", "1"),
+                    ("format", "json"),
+                ])
+                .send();
+            async move {
+                let j: Value = request.await?.json().await?;
+                Ok::<_, anyhow::Error>(Self::parse_labels(&j, language))
+            }
+        });
+        let results = futures::stream::iter(futures)
+            .buffer_unordered(MAX_CONCURRENT)
+            .collect::<Vec<_>>()
+            .await;
+        let now = Instant::now();
+        for labels in results.into_iter().flatten() {
+            for (id, label) in labels {
+                self.labels.insert((language.to_string(), id), (label, now));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_fresh(&self, id: &str, language: &str) -> bool {
+        self.labels
+            .get(&(language.to_string(), id.to_string()))
+            .is_some_and(|(_, fetched)| fetched.elapsed() < self.ttl)
+    }
+
+    /// The cached label of an entity, if it has one in `language` (or a fallback language).
+    pub fn label(&self, id: &str, language: &str) -> Option<&str> {
+        self.labels
+            .get(&(language.to_string(), id.to_string()))
+            .and_then(|(label, _)| label.as_deref())
+    }
+
+    /// `date of birth (P569)`, or just `P569` if no label is cached.
+    pub fn describe(&self, id: &str, language: &str) -> String {
+        match self.label(id, language) {
+            Some(label) => format!("{label} ({id})"),
+            None => id.to_string(),
+        }
+    }
+
+    /// `date of birth (P569) on Douglas Adams (Q42)`, for a change of a property on an entity.
+    pub fn describe_change(&self, property: &str, entity: &str, language: &str) -> String {
+        format!(
+            "{} on {}",
+            self.describe(property, language),
+            self.describe(entity, language)
+        )
+    }
+
+    fn parse_labels(j: &Value, language: &str) -> Vec<(String, Option<String>)> {
+        let entities = match j["entities"].as_object() {
+            Some(entities) => entities,
+            None => return vec![],
+        };
+        entities
+            .iter()
+            .map(|(id, entity)| {
+                let label = entity["labels"][language]["value"]
+                    .as_str()
+                    .map(|label| label.to_string());
+                (id.to_string(), label)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_describe_change() {
+        let j = json!({"entities": {
+            "P569": {"id": "P569", "labels": {"en": {"language": "en", "value": "date of birth"}}},
+            "Q42": {"id": "Q42", "labels": {"en": {"language": "en", "value": "Douglas Adams"}}},
+            "Q1": {"id": "Q1", "labels": {}},
+        }});
+        let mut cache = LabelCache::new(Arc::new(Wikidata::new()), Wiki::Wikidata, LABEL_TTL);
+        let now = Instant::now();
+        for (id, label) in LabelCache::parse_labels(&j, "en") {
+            cache.labels.insert(("en".to_string(), id), (label, now));
+        }
+        assert_eq!(
+            cache.describe_change("P569", "Q42", "en"),
+            "date of birth (P569) on Douglas Adams (Q42)"
+        );
+        assert_eq!(cache.describe("Q1", "en"), "Q1");
+        assert!(cache.is_fresh("Q1", "en"));
+        assert!(!cache.is_fresh("Q42", "de"));
+    }
+}
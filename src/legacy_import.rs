@@ -0,0 +1,239 @@
+use anyhow::{anyhow, Result};
+use chrono::{Duration, NaiveDateTime};
+use std::collections::{BTreeMap, HashMap};
+use wikimisc::mysql_async::{from_row, from_value_opt, prelude::Queryable, Row, Value as SqlValue};
+
+use crate::WdRc;
+
+/// Legacy rows are copied in windows of this many hours.
+const WINDOW_HOURS: i64 = 1;
+
+/// A table of the predecessor tool, copied into the wdrc table of the same name.
+struct LegacyTable {
+    name: &'static str,
+    columns: &'static [&'static str],
+    /// Columns referencing `texts`, whose IDs differ between the databases.
+    text_columns: &'static [&'static str],
+}
+
+const LEGACY_TABLES: &[LegacyTable] = &[
+    LegacyTable {
+        name: "statements",
+        columns: &["item", "revision", "property", "timestamp", "change_type"],
+        text_columns: &[],
+    },
+    LegacyTable {
+        name: "labels",
+        columns: &[
+            "item",
+            "revision",
+            "type",
+            "timestamp",
+            "change_type",
+            "language",
+        ],
+        text_columns: &["language"],
+    },
+    LegacyTable {
+        name: "creations",
+        columns: &["q", "timestamp"],
+        text_columns: &[],
+    },
+    LegacyTable {
+        name: "deletions",
+        columns: &["q", "timestamp"],
+        text_columns: &[],
+    },
+    LegacyTable {
+        name: "redirects",
+        columns: &["source", "target", "timestamp"],
+        text_columns: &[],
+    },
+];
+
+impl LegacyTable {
+    fn select_sql(&self) -> String {
+        format!(
+            "SELECT `{}` FROM `{}` WHERE `timestamp`>=? AND `timestamp`<?",
+            self.columns.join("`,`"),
+            self.name
+        )
+    }
+
+    /// Rows already in wdrc are kept.
+    fn insert_sql(&self) -> String {
+        format!(
+            "INSERT IGNORE INTO `{}` (`{}`) VALUES",
+            self.name,
+            self.columns.join("`,`")
+        )
+    }
+
+    fn text_column_positions(&self) -> Vec<usize> {
+        self.columns
+            .iter()
+            .enumerate()
+            .filter(|(_, column)| self.text_columns.contains(column))
+            .map(|(pos, _)| pos)
+            .collect()
+    }
+}
+
+/// Copies the history of the predecessor tool from the `legacy` database into wdrc.
+///
+/// Legacy rows are only copied up to the oldest row wdrc logged itself, so overlapping time ranges
+/// are taken from wdrc. Progress is kept in `meta`, so an interrupted import continues where it stopped.
+pub struct LegacyImporter<'a> {
+    wdrc: &'a mut WdRc,
+    /// Legacy text ID to value.
+    legacy_texts: HashMap<u64, String>,
+}
+
+impl<'a> LegacyImporter<'a> {
+    pub fn new(wdrc: &'a mut WdRc) -> Self {
+        Self {
+            wdrc,
+            legacy_texts: HashMap::new(),
+        }
+    }
+
+    /// Imports all tables; returns the number of legacy rows read per table.
+    pub async fn import(&mut self) -> Result<BTreeMap<String, u64>> {
+        let texts: Vec<(u64, String)> = self
+            .wdrc
+            .db()
+            .get_connection("legacy")
+            .await?
+            .exec_iter("SELECT `id`,`value` FROM `texts`", ())
+            .await?
+            .map_and_drop(from_row::<(u64, String)>)
+            .await?;
+        self.legacy_texts = texts.into_iter().collect();
+
+        let mut ret = BTreeMap::new();
+        for table in LEGACY_TABLES {
+            let rows = self.import_table(table).await?;
+            ret.insert(table.name.to_string(), rows);
+        }
+        Ok(ret)
+    }
+
+    async fn import_table(&mut self, table: &LegacyTable) -> Result<u64> {
+        let sql = format!("SELECT MIN(`timestamp`) FROM `{}`", table.name);
+        let cutoff: Option<String> = self
+            .wdrc
+            .db()
+            .get_connection("wdrc")
+            .await?
+            .exec_first::<Option<String>, _, _>(sql, ())
+            .await?
+            .flatten();
+        let cutoff = cutoff.unwrap_or_else(|| "99991231235959".to_string());
+        let meta_key = format!("legacy_import_{}", table.name);
+        let mut start = self
+            .wdrc
+            .get_key_value(&meta_key)
+            .await?
+            .unwrap_or_default();
+
+        let mut total = 0;
+        while let Some(next) = self.next_timestamp(table, &start, &cutoff).await? {
+            let end = Self::window_end(&next)?.min(cutoff.to_owned());
+            total += self.import_window(table, &next, &end).await?;
+            self.wdrc.set_key_value(&meta_key, &end).await?;
+            start = end;
+        }
+        Ok(total)
+    }
+
+    /// The first legacy timestamp from `start` on, skipping empty time ranges.
+    async fn next_timestamp(
+        &self,
+        table: &LegacyTable,
+        start: &str,
+        cutoff: &str,
+    ) -> Result<Option<String>> {
+        let sql = format!(
+            "SELECT MIN(`timestamp`) FROM `{}` WHERE `timestamp`>=? AND `timestamp`<?",
+            table.name
+        );
+        let next = self
+            .wdrc
+            .db()
+            .get_connection("legacy")
+            .await?
+            .exec_first::<Option<String>, _, _>(sql, (start, cutoff))
+            .await?
+            .flatten();
+        Ok(next)
+    }
+
+    fn window_end(start: &str) -> Result<String> {
+        let start = NaiveDateTime::parse_from_str(start, "%Y%m%d%H%M%S")
+            .map_err(|e| anyhow!("Bad legacy timestamp {start:?}: {e}"))?;
+        Ok((start + Duration::hours(WINDOW_HOURS))
+            .format("%Y%m%d%H%M%S")
+            .to_string())
+    }
+
+    async fn import_window(&mut self, table: &LegacyTable, start: &str, end: &str) -> Result<u64> {
+        let mut rows: Vec<Vec<SqlValue>> = self
+            .wdrc
+            .db()
+            .get_connection("legacy")
+            .await?
+            .exec_iter(table.select_sql(), (start, end))
+            .await?
+            .map_and_drop(|row: Row| row.unwrap())
+            .await?;
+        let read = rows.len() as u64;
+        let positions = table.text_column_positions();
+        if !positions.is_empty() {
+            rows = self.map_texts(rows, &positions).await?;
+        }
+        self.wdrc.insert_rows(&table.insert_sql(), &rows).await?;
+        Ok(read)
+    }
+
+    /// Replaces legacy text IDs with wdrc text IDs; rows with unknown text IDs are dropped.
+    async fn map_texts(
+        &mut self,
+        rows: Vec<Vec<SqlValue>>,
+        positions: &[usize],
+    ) -> Result<Vec<Vec<SqlValue>>> {
+        let mut ret = Vec::with_capacity(rows.len());
+        'rows: for mut row in rows {
+            for pos in positions {
+                let legacy_id = row
+                    .get(*pos)
+                    .and_then(|value| from_value_opt::<u64>(value.to_owned()).ok());
+                let text = match legacy_id.and_then(|id| self.legacy_texts.get(&id)) {
+                    Some(text) => text.to_owned(),
+                    None => continue 'rows,
+                };
+                row[*pos] = self.wdrc.get_or_create_text_id(&text).await?.into();
+            }
+            ret.push(row);
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_table_sql() {
+        let labels = LEGACY_TABLES.iter().find(|t| t.name == "labels").unwrap();
+        assert_eq!(
+            labels.insert_sql(),
+            "INSERT IGNORE INTO `labels` (`item`,`revision`,`type`,`timestamp`,`change_type`,`language`) VALUES"
+        );
+        assert_eq!(labels.text_column_positions(), vec![5]);
+        assert_eq!(
+            LegacyImporter::window_end("20191231233000").unwrap(),
+            "20200101003000"
+        );
+    }
+}
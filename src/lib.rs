@@ -0,0 +1,67 @@
+//! Wikidata recent changes tracking.
+//!
+//! [`WdRc`] polls the Wikidata `recentchanges` replica (or the EventStreams feed), diffs changed items with
+//! [`RevisionCompare`] and logs the resulting [`Change`]s to the wdrc database.
+
+pub mod backfill;
+pub mod capabilities;
+pub mod catch_up;
+pub mod change;
+pub mod commons_media;
+pub mod config;
+pub mod deliveries;
+pub mod digest;
+pub mod doctor;
+pub mod drops;
+pub mod dump_diff;
+pub mod edit_summary;
+pub mod event_stream;
+pub mod feeds;
+#[cfg(feature = "irc")]
+pub mod irc;
+pub mod jobs;
+pub mod labels;
+pub mod legacy_import;
+pub mod liftwing;
+pub mod live;
+pub mod migrations;
+#[cfg(feature = "notifiers")]
+pub mod notifiers;
+pub mod object_storage;
+pub mod prelude;
+pub mod public_stats;
+pub mod publish;
+pub mod query;
+pub mod recent_changes;
+pub mod redact;
+pub mod replica_schema;
+pub mod report;
+pub mod reprocess;
+pub mod reverts;
+pub mod revision_compare;
+pub mod schedule;
+pub mod schema;
+pub mod scope;
+#[cfg(feature = "serve")]
+pub mod server;
+pub mod sessions;
+pub mod shadow;
+pub mod sink;
+pub mod sitelink_conflicts;
+pub mod time_travel;
+pub mod tombstones;
+pub mod value_format;
+pub mod watch_pages;
+pub mod watchlist;
+pub mod wdqs;
+pub mod wdrc;
+pub mod webhooks;
+pub mod wiki;
+#[cfg(feature = "rest")]
+pub mod wikibase_rest;
+
+pub use change::{Change, ChangeSubject, ChangeType, EntityType};
+pub use config::Config;
+pub use recent_changes::{ChangedItem, NewItem, RecentChangesResults};
+pub use revision_compare::{RevisionBackend, RevisionCompare, RevisionId};
+pub use wdrc::{ChangeSource, Checkpoint, ItemId, TextId, WdRc};
@@ -0,0 +1,86 @@
+use anyhow::Result;
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use wikimisc::wikidata::Wikidata;
+
+use crate::{config::LiftWingConfig, revision_compare::RevisionId};
+
+const LIFTWING_URL: &str = "https://api.wikimedia.org/service/lw/inference/v1/models";
+const LIFTWING_WIKI: &str = "wikidatawiki";
+
+/// Probabilities that a revision is damaging, and that it was made in good faith.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RevisionScore {
+    pub revision: RevisionId,
+    pub damaging: Option<f64>,
+    pub goodfaith: Option<f64>,
+}
+
+/// Scores revisions with the `damaging` and `goodfaith` models of the LiftWing API.
+pub struct LiftWing {
+    wd: Arc<Wikidata>,
+    config: LiftWingConfig,
+}
+
+impl LiftWing {
+    pub fn new(wd: Arc<Wikidata>, config: LiftWingConfig) -> Self {
+        Self { wd, config }
+    }
+
+    /// Scores revisions in batches of `batch_size`, with up to `max_concurrent` requests at a time.
+    /// A model that could not be queried leaves its probability unset.
+    pub async fn score(&self, revisions: &[RevisionId]) -> Vec<RevisionScore> {
+        let mut ret = vec![];
+        for batch in revisions.chunks(self.config.batch_size.max(1)) {
+            let futures = batch.iter().map(|revision| self.score_revision(*revision));
+            let mut scores = futures::stream::iter(futures)
+                .buffer_unordered(self.config.max_concurrent.max(1))
+                .collect::<Vec<_>>()
+                .await;
+            ret.append(&mut scores);
+        }
+        ret
+    }
+
+    async fn score_revision(&self, revision: RevisionId) -> RevisionScore {
+        RevisionScore {
+            revision,
+            damaging: self.predict("damaging", revision).await.ok().flatten(),
+            goodfaith: self.predict("goodfaith", revision).await.ok().flatten(),
+        }
+    }
+
+    async fn predict(&self, model: &str, revision: RevisionId) -> Result<Option<f64>> {
+        let url = format!("{LIFTWING_URL}/{LIFTWING_WIKI}-{model}:predict");
+        let client = self.wd.reqwest_client()?;
+        let j: Value = client
+            .post(url)
+            .json(&json!({"rev_id": revision}))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(Self::probability(&j, model, revision))
+    }
+
+    /// The probability of `true` from a LiftWing response.
+    fn probability(j: &Value, model: &str, revision: RevisionId) -> Option<f64> {
+        j[LIFTWING_WIKI]["scores"][revision.to_string()][model]["score"]["probability"]["true"]
+            .as_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probability() {
+        let j = json!({"wikidatawiki": {"models": {"damaging": {"version": "0.5.0"}}, "scores": {"12345": {"damaging": {"score": {"prediction": false, "probability": {"false": 0.93, "true": 0.07}}}}}}});
+        assert_eq!(LiftWing::probability(&j, "damaging", 12345), Some(0.07));
+        assert_eq!(LiftWing::probability(&j, "goodfaith", 12345), None);
+        assert_eq!(LiftWing::probability(&j, "damaging", 1), None);
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use crate::{
+    change::{ChangeSubject, ChangeType, EntityType},
+    query::{query_pairs, ChangeFilter, ChangeRow, MAX_LIMIT},
+    WdRc,
+};
+
+/// Changes buffered per subscriber; slower subscribers miss the oldest ones.
+const CHANNEL_CAPACITY: usize = 10000;
+
+/// Which live changes a subscriber receives. Empty lists match everything. As a query:
+/// `items=Q42,Q64&props=P31&langs=de&subjects=claims&types=added`; as JSON:
+/// `{"items": ["Q42"], "properties": ["P31"], "languages": ["de"], "subjects": ["claims"],
+/// "change_types": ["added"]}`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LiveFilter {
+    /// Entity IDs, of any entity type.
+    pub items: Vec<String>,
+    pub properties: Vec<String>,
+    /// Languages of terms, or sites of sitelinks.
+    pub languages: Vec<String>,
+    pub subjects: Vec<ChangeSubject>,
+    pub change_types: Vec<ChangeType>,
+}
+
+impl LiveFilter {
+    /// Parses `key=value` pairs separated by `&`. Unknown keys are an error.
+    pub fn from_query(query: &str) -> Result<Self> {
+        Self::from_pairs(&query_pairs(query))
+    }
+
+    /// Parses decoded query parameters.
+    pub fn from_pairs(pairs: &[(String, String)]) -> Result<Self> {
+        let mut ret = Self::default();
+        for (key, value) in pairs {
+            let list = value.split(',').filter(|v| !v.is_empty()).map(String::from);
+            match key.as_str() {
+                "items" => ret.items.extend(list),
+                "props" => ret.properties.extend(list),
+                "langs" => ret.languages.extend(list),
+                "subjects" => {
+                    for name in list {
+                        let subject = ChangeSubject::from_name(&name)
+                            .ok_or_else(|| anyhow!("Unknown subject: {name:?}"))?;
+                        ret.subjects.push(subject);
+                    }
+                }
+                "types" => {
+                    for name in list {
+                        let change_type = ChangeType::from_name(&name)
+                            .ok_or_else(|| anyhow!("Unknown change type: {name:?}"))?;
+                        ret.change_types.push(change_type);
+                    }
+                }
+                other => return Err(anyhow!("Unknown parameter: {other:?}")),
+            }
+        }
+        ret.validate()?;
+        Ok(ret)
+    }
+
+    /// The filter as a query, like `items=Q42&props=P31`, or `all` if it matches everything.
+    pub fn name(&self) -> String {
+        let mut pairs = vec![];
+        let mut push = |key: &str, values: Vec<&str>| {
+            if !values.is_empty() {
+                pairs.push(format!("{key}={}", values.join(",")));
+            }
+        };
+        push("items", self.items.iter().map(String::as_str).collect());
+        push(
+            "props",
+            self.properties.iter().map(String::as_str).collect(),
+        );
+        push("langs", self.languages.iter().map(String::as_str).collect());
+        push(
+            "subjects",
+            self.subjects.iter().map(|s| s.as_str()).collect(),
+        );
+        push(
+            "types",
+            self.change_types.iter().map(|t| t.as_str()).collect(),
+        );
+        match pairs.is_empty() {
+            true => "all".to_string(),
+            false => pairs.join("&"),
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let ret: Self = serde_json::from_str(json)?;
+        ret.validate()?;
+        Ok(ret)
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        for id in &self.items {
+            EntityType::from_id(id)
+                .and_then(|_| WdRc::make_id_numeric(id).ok())
+                .ok_or_else(|| anyhow!("Not an entity ID: {id:?}"))?;
+        }
+        for id in &self.properties {
+            if EntityType::from_id(id) != Some(EntityType::Property) {
+                return Err(anyhow!("Not a property ID: {id:?}"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, row: &ChangeRow) -> bool {
+        let listed = |list: &[String], value: &Option<String>| {
+            list.is_empty() || value.as_ref().is_some_and(|value| list.contains(value))
+        };
+        (self.items.is_empty() || self.items.contains(&row.entity))
+            && listed(&self.properties, &row.property)
+            && listed(&self.languages, &row.language)
+            && (self.subjects.is_empty() || self.subjects.iter().any(|s| s.as_str() == row.subject))
+            && (self.change_types.is_empty()
+                || self
+                    .change_types
+                    .iter()
+                    .any(|t| t.as_str() == row.change_type))
+    }
+}
+
+/// Broadcasts changes to the subscribers of a [`WdRc`] as they appear in the wdrc database, for
+/// when the bot loop runs in another process. Polls the database once per poll interval; changes
+/// logged faster than `MAX_LIMIT` per entity type and poll are partly skipped.
+pub struct LiveFeed;
+
+impl LiveFeed {
+    pub fn channel() -> broadcast::Sender<ChangeRow> {
+        broadcast::channel(CHANNEL_CAPACITY).0
+    }
+
+    /// Polls until the process ends; polling errors are reported and retried.
+    pub async fn run(wdrc: Arc<WdRc>) {
+        let sender = wdrc.live();
+        let mut since = Self::now();
+        // Rows with the `since` timestamp that were already sent
+        let mut sent: Vec<ChangeRow> = vec![];
+        loop {
+            tokio::time::sleep(wdrc.poll_interval()).await;
+            if sender.receiver_count() == 0 {
+                since = Self::now();
+                sent.clear();
+                continue;
+            }
+            let rows = match Self::poll(&wdrc, &since).await {
+                Ok(rows) => rows,
+                Err(e) => {
+                    eprintln!("Live feed: {e}");
+                    continue;
+                }
+            };
+            for row in Self::new_rows(rows, &mut since, &mut sent) {
+                let _ = sender.send(row); // Fails only without subscribers
+            }
+        }
+    }
+
+    /// Changes at or after `since`, for all tracked entity types, oldest first.
+    async fn poll(wdrc: &WdRc, since: &str) -> Result<Vec<ChangeRow>> {
+        let mut rows = vec![];
+        for entity_type in EntityType::all() {
+            if !wdrc.namespaces().contains(&entity_type.namespace()) {
+                continue;
+            }
+            let query = format!(
+                "entity={}&since={since}&limit={MAX_LIMIT}",
+                entity_type.as_str()
+            );
+            rows.extend(ChangeFilter::from_query(&query)?.run(wdrc).await?);
+        }
+        rows.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(rows)
+    }
+
+    /// Leaves out the rows sent before, and moves `since` to the newest timestamp.
+    fn new_rows(
+        rows: Vec<ChangeRow>,
+        since: &mut String,
+        sent: &mut Vec<ChangeRow>,
+    ) -> Vec<ChangeRow> {
+        let rows: Vec<ChangeRow> = rows
+            .into_iter()
+            .filter(|row| !sent.iter().any(|s| Self::same_change(s, row)))
+            .collect();
+        if let Some(newest) = rows.last().map(|row| row.timestamp.to_owned()) {
+            if newest != *since {
+                sent.clear();
+                *since = newest;
+            }
+            sent.extend(rows.iter().filter(|row| row.timestamp == *since).cloned());
+        }
+        rows
+    }
+
+    /// Whether two rows are the same change; `in_wdqs` may differ between polls.
+    fn same_change(a: &ChangeRow, b: &ChangeRow) -> bool {
+        ChangeRow {
+            in_wdqs: b.in_wdqs,
+            ..a.clone()
+        } == *b
+    }
+
+    fn now() -> String {
+        Utc::now().format("%Y%m%d%H%M%S").to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(entity: &str, subject: &str, property: Option<&str>, timestamp: &str) -> ChangeRow {
+        ChangeRow {
+            entity: entity.to_string(),
+            revision: 1,
+            subject: subject.to_string(),
+            timestamp: timestamp.to_string(),
+            change_type: "added".to_string(),
+            language: None,
+            property: property.map(|p| p.to_string()),
+            in_wdqs: None,
+            redirected_from: None,
+            cursor: None,
+        }
+    }
+
+    #[test]
+    fn test_live_filter() {
+        let filter = LiveFilter::from_query("items=Q42,Q64&props=P31&subjects=claims").unwrap();
+        assert_eq!(
+            LiveFilter::from_json(
+                r#"{"items": ["Q42", "Q64"], "properties": ["P31"], "subjects": ["claims"]}"#
+            )
+            .unwrap(),
+            filter
+        );
+        assert!(LiveFilter::from_json(r#"{"props": ["P31"]}"#).is_err());
+        assert!(filter.matches(&row("Q42", "claims", Some("P31"), "20240101000000")));
+        assert!(!filter.matches(&row("Q1", "claims", Some("P31"), "20240101000000")));
+        assert!(!filter.matches(&row("Q42", "labels", None, "20240101000000")));
+        assert!(LiveFilter::default().matches(&row("Q1", "labels", None, "20240101000000")));
+        assert!(LiveFilter::from_query("props=Q5").is_err());
+        assert!(LiveFilter::from_query("items=X").is_err());
+        let mut label = row("Q1", "labels", None, "20240101000000");
+        label.language = Some("de".to_string());
+        let german = LiveFilter::from_query("langs=de").unwrap();
+        assert!(german.matches(&label));
+        assert!(!german.matches(&row("Q1", "claims", Some("P31"), "20240101000000")));
+        let removed = LiveFilter::from_query("types=removed").unwrap();
+        assert!(!removed.matches(&row("Q1", "labels", None, "20240101000000")));
+        assert!(LiveFilter::from_query("types=moved").is_err());
+        assert_eq!(filter.name(), "items=Q42,Q64&props=P31&subjects=claims");
+        assert_eq!(LiveFilter::from_query(&filter.name()).unwrap(), filter);
+        assert_eq!(LiveFilter::default().name(), "all");
+    }
+
+    #[test]
+    fn test_new_rows() {
+        let mut since = "20240101000000".to_string();
+        let mut sent = vec![];
+        let first = vec![
+            row("Q1", "labels", None, "20240101000000"),
+            row("Q2", "labels", None, "20240101000001"),
+        ];
+        assert_eq!(
+            LiveFeed::new_rows(first.clone(), &mut since, &mut sent),
+            first
+        );
+        assert_eq!(since, "20240101000001");
+        // Rows with the last timestamp are polled again, but not sent twice
+        let second = vec![
+            row("Q2", "labels", None, "20240101000001"),
+            row("Q3", "labels", None, "20240101000001"),
+        ];
+        assert_eq!(
+            LiveFeed::new_rows(second, &mut since, &mut sent),
+            vec![row("Q3", "labels", None, "20240101000001")]
+        );
+        assert_eq!(sent.len(), 2);
+        let mut in_wdqs = row("Q3", "labels", None, "20240101000001");
+        in_wdqs.in_wdqs = Some(true);
+        assert!(LiveFeed::new_rows(vec![in_wdqs], &mut since, &mut sent).is_empty());
+        assert!(LiveFeed::new_rows(vec![], &mut since, &mut sent).is_empty());
+    }
+}
@@ -1,36 +1,409 @@
-mod change;
-mod recent_changes;
-mod revision_compare;
-mod wdrc;
+use anyhow::{anyhow, Result};
+use std::{env, path::Path, sync::Arc};
+#[cfg(feature = "serve")]
+use wdrc_rs::server::{Server, DEFAULT_ADDRESS};
+use wdrc_rs::{
+    backfill::Backfill,
+    capabilities::Capabilities,
+    deliveries::Deliveries,
+    doctor::Doctor,
+    dump_diff::DumpDiff,
+    jobs::Job,
+    legacy_import::LegacyImporter,
+    migrations::Migrations,
+    publish::Publisher,
+    query::{ChangeFilter, EventFilter},
+    redact::Redactor,
+    report::{Heatmap, StatsReport},
+    reprocess::Reprocessor,
+    shadow::ShadowReport,
+    time_travel::EntityState,
+    value_format::ValueFormat,
+    watchlist::Watchlist,
+    ChangedItem, RevisionCompare, RevisionId, WdRc,
+};
 
-use std::env;
-use wdrc::*;
+async fn compare(args: &[String]) -> Result<()> {
+    let usage = "Usage: compare <Q-id> <old-rev> <new-rev> [legacy|text]";
+    let q = args.get(2).ok_or_else(|| anyhow!(usage))?;
+    let rev_old: RevisionId = args.get(3).ok_or_else(|| anyhow!(usage))?.parse()?;
+    let rev_new: RevisionId = args.get(4).ok_or_else(|| anyhow!(usage))?.parse()?;
+    let ci = ChangedItem::new(q, rev_old, rev_new, "");
+    let mut revision_compare = RevisionCompare::new(Arc::new(WdRc::prepare_wd()));
+    let changes = revision_compare.run(&ci).await?;
+    if args.get(5).map(|s| s.as_str()) == Some("text") {
+        for change in &changes {
+            let (old, new) = ValueFormat::change_values(change);
+            println!(
+                "{} {} {}: {old:?} => {new:?}",
+                change.change_type.as_str(),
+                change.subject.as_str(),
+                change.value_key()
+            );
+        }
+        return Ok(());
+    }
+    let json = match args.get(5).map(|s| s.as_str()) {
+        Some("legacy") => changes.iter().map(|c| c.to_legacy_json()).collect(),
+        _ => serde_json::to_value(&changes)?,
+    };
+    println!("{}", serde_json::to_string_pretty(&json)?);
+    Ok(())
+}
+
+#[cfg(feature = "serve")]
+fn api_key(args: &[String]) -> Result<()> {
+    let usage = "Usage: api-key <name> reader|writer|admin";
+    let name = args.get(2).ok_or_else(|| anyhow!(usage))?;
+    let role = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    let (key, entry) = Server::new_api_key(name, role)?;
+    println!("Key (shown only once): {key}");
+    println!("Add to \"api_keys\": {entry}");
+    Ok(())
+}
+
+async fn report(wdrc: &WdRc, format: Option<&String>) -> Result<()> {
+    let report = StatsReport::weekly(wdrc).await?;
+    match format.map(|s| s.as_str()) {
+        Some("wiki") => print!("{}", report.to_wikitext()),
+        _ => println!("{}", serde_json::to_string_pretty(&report.to_json()?)?),
+    }
+    Ok(())
+}
+
+async fn heatmap(wdrc: &WdRc, id: Option<&String>) -> Result<()> {
+    let id = id.ok_or_else(|| anyhow!("Usage: heatmap <config> <Q-id|P-id|L-id>"))?;
+    let heatmap = Heatmap::for_entity(wdrc, id).await?;
+    println!("{}", serde_json::to_string_pretty(&heatmap)?);
+    Ok(())
+}
+
+async fn publish(wdrc: &WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: publish <config> <YYYYMM> <directory>";
+    let month = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    let dir = args.get(4).ok_or_else(|| anyhow!(usage))?;
+    let manifest = Publisher::new(wdrc).publish(month, Path::new(dir)).await?;
+    println!("{}", serde_json::to_string_pretty(&manifest)?);
+    Ok(())
+}
+
+async fn upload(wdrc: &WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: upload <config> <YYYYMM> <directory>";
+    let month = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    let dir = args.get(4).ok_or_else(|| anyhow!(usage))?;
+    let manifest = Publisher::new(wdrc)
+        .upload(&Path::new(dir).join(month))
+        .await?;
+    println!("Uploaded {} files of {month}", manifest.files.len() + 1);
+    Ok(())
+}
+
+async fn verify_archives(wdrc: &WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: verify-archives <config> <directory>";
+    let dir = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    let problems = Publisher::new(wdrc).verify(Path::new(dir)).await?;
+    if !problems.is_empty() {
+        return Err(anyhow!(problems.join("\n")));
+    }
+    println!("All files and manifests match the published records");
+    Ok(())
+}
+
+async fn redact(wdrc: &WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: redact <config> <revision-ids|file> <reason>\n       redact <config> --user <name> <reason>";
+    let revisions = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    if revisions == "--user" {
+        let user = args.get(4).ok_or_else(|| anyhow!(usage))?;
+        let reason = args.get(5).ok_or_else(|| anyhow!(usage))?;
+        let redaction = Redactor::new(wdrc).redact_user(user, reason).await?;
+        println!("{}", serde_json::to_string_pretty(&redaction)?);
+        return Ok(());
+    }
+    let reason = args.get(4).ok_or_else(|| anyhow!(usage))?;
+    // A list of revision IDs, or a file containing them
+    let revisions = match Path::new(revisions).is_file() {
+        true => std::fs::read_to_string(revisions)?,
+        false => revisions.to_string(),
+    };
+    let revisions = Redactor::parse_revisions(&revisions)?;
+    let redaction = Redactor::new(wdrc).redact(&revisions, reason).await?;
+    println!("{}", serde_json::to_string_pretty(&redaction)?);
+    Ok(())
+}
+
+async fn reprocess(wdrc: &mut WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: reprocess <config> <Q-id> <old-rev> <new-rev> [timestamp]\n       reprocess <config> --engine-older-than <vX>";
+    let q = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    if q == "--engine-older-than" {
+        let version = args.get(4).ok_or_else(|| anyhow!(usage))?;
+        let version = Reprocessor::parse_engine_version(version)?;
+        let result = Reprocessor::new(wdrc)
+            .reprocess_engine_older_than(version)
+            .await?;
+        println!("{}", serde_json::to_string_pretty(&result)?);
+        return Ok(());
+    }
+    let rev_old: RevisionId = args.get(4).ok_or_else(|| anyhow!(usage))?.parse()?;
+    let rev_new: RevisionId = args.get(5).ok_or_else(|| anyhow!(usage))?.parse()?;
+    let timestamp = args.get(6).map(|s| s.as_str());
+    let result = Reprocessor::new(wdrc)
+        .reprocess(q, rev_old, rev_new, timestamp)
+        .await?;
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
+async fn changes(wdrc: &WdRc, query: Option<&String>) -> Result<()> {
+    let filter = ChangeFilter::from_query(query.map(|s| s.as_str()).unwrap_or_default())?;
+    let rows = filter.run(wdrc).await?;
+    println!("{}", serde_json::to_string_pretty(&filter.to_json(&rows)?)?);
+    Ok(())
+}
+
+async fn events(wdrc: &WdRc, query: Option<&String>) -> Result<()> {
+    let filter = EventFilter::from_query(query.map(|s| s.as_str()).unwrap_or_default())?;
+    let rows = filter.run(wdrc).await?;
+    println!("{}", serde_json::to_string_pretty(&rows)?);
+    Ok(())
+}
+
+#[cfg(feature = "serve")]
+async fn serve(wdrc: WdRc, config_file: &str, args: &[String]) -> Result<()> {
+    // Usage: serve <config> [address] [--bot]
+    let options: Vec<&str> = args.iter().skip(3).map(|s| s.as_str()).collect();
+    let address = options
+        .iter()
+        .find(|option| !option.starts_with("--"))
+        .unwrap_or(&DEFAULT_ADDRESS);
+    // With the bot loop in the same process, changes are streamed as they are logged
+    let bot = match options.contains(&"--bot") {
+        true => Some(WdRc::new(config_file)?),
+        false => None,
+    };
+    Server::serve(wdrc, address, bot).await
+}
+
+async fn watchlist(wdrc: &WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: watchlist <config> <watcher> add|remove <entity>...\n       watchlist <config> <watcher> url [url] [secret]\n       watchlist <config> <watcher> notifications [after-id]\n       watchlist <config> <watcher> token";
+    let watcher = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    let action = args.get(4).ok_or_else(|| anyhow!(usage))?;
+    let rest = &args[5.min(args.len())..];
+    let watchlist = Watchlist::new(wdrc);
+    match action.as_str() {
+        "add" if !rest.is_empty() => watchlist.watch(watcher, rest).await?,
+        "remove" if !rest.is_empty() => {
+            let removed = watchlist.unwatch(watcher, rest).await?;
+            println!("Removed {removed} entities");
+        }
+        "url" => {
+            let url = rest.first().map(|s| s.as_str());
+            let secret = rest.get(1).map(|s| s.as_str());
+            watchlist.set_url(watcher, url, secret).await?;
+        }
+        "token" => {
+            let token = watchlist.new_token(watcher).await?;
+            println!("{token}");
+        }
+        "notifications" => {
+            let after: u64 = rest.first().map(|s| s.parse()).transpose()?.unwrap_or(0);
+            let notifications = watchlist.notifications(watcher, after).await?;
+            println!("{}", serde_json::to_string_pretty(&notifications)?);
+        }
+        _ => return Err(anyhow!(usage)),
+    }
+    Ok(())
+}
+
+async fn deliveries(wdrc: &WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: deliveries <config> [dead|retry [id]]";
+    let deliveries = Deliveries::new(wdrc);
+    match args.get(3).map(|s| s.as_str()) {
+        None => {
+            let status = deliveries.status().await?;
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+        Some("dead") => {
+            let dead = deliveries.dead_letters().await?;
+            println!("{}", serde_json::to_string_pretty(&dead)?);
+        }
+        Some("retry") => {
+            let id = args.get(4).map(|s| s.parse()).transpose()?;
+            let retried = deliveries.retry(id).await?;
+            println!("Retrying {retried} deliveries");
+        }
+        _ => return Err(anyhow!(usage)),
+    }
+    Ok(())
+}
+
+async fn state(wdrc: &WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: state <config> <entity> <YYYYMMDDHHMMSS> [P31,P569]";
+    let entity = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    let at = args.get(4).ok_or_else(|| anyhow!(usage))?;
+    let properties: Vec<String> = args
+        .get(5)
+        .map(|p| p.split(',').map(|p| p.to_string()).collect())
+        .unwrap_or_default();
+    let state = EntityState::at(wdrc, entity, at, &properties).await?;
+    println!("{}", serde_json::to_string_pretty(&state)?);
+    Ok(())
+}
+
+async fn import_legacy(wdrc: &mut WdRc) -> Result<()> {
+    let rows = LegacyImporter::new(wdrc).import().await?;
+    println!("{}", serde_json::to_string_pretty(&rows)?);
+    Ok(())
+}
+
+async fn backfill(wdrc: &mut WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: backfill <config> <from-timestamp> <to-timestamp>";
+    let from = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    let to = args.get(4).ok_or_else(|| anyhow!(usage))?;
+    let result = Backfill::new(wdrc, from, to)?.run().await?;
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
+async fn dump_diff(wdrc: &mut WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: dump-diff <config> <old-dump|--live> <new-dump|old-dump> [log]";
+    let old = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    let new = args.get(4).ok_or_else(|| anyhow!(usage))?;
+    let log = args.get(5).map(|s| s.as_str()) == Some("log");
+    let dump_diff = DumpDiff::new(wdrc, log);
+    let result = match old.as_str() {
+        "--live" => dump_diff.compare_live(new).await?,
+        _ => dump_diff.compare_dumps(old, new).await?,
+    };
+    // Changes go to stdout unless logged
+    eprintln!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
+async fn shadow_report(wdrc: &WdRc, args: &[String]) -> Result<()> {
+    let usage = "Usage: shadow-report <config> <start> [end]";
+    let start = args.get(3).ok_or_else(|| anyhow!(usage))?;
+    let end = args.get(4).map(|s| s.as_str()).unwrap_or("99991231235959");
+    let report = ShadowReport::run(wdrc, start, end).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.is_empty() {
+        std::process::exit(2);
+    }
+    Ok(())
+}
+
+/// Commands that need a config, other than jobs.
+const COMMANDS: &[&str] = &[
+    "init-db",
+    "run",
+    "report",
+    "heatmap",
+    "publish",
+    "upload",
+    "verify-archives",
+    "capabilities",
+    "changes",
+    "events",
+    "serve",
+    "state",
+    "deliveries",
+    "watchlist",
+    "import-legacy",
+    "shadow-report",
+    "reprocess",
+    "redact",
+    "backfill",
+    "dump-diff",
+];
+
+const USAGE: &str = "Usage: wdrc_rs <command> [config] [arguments]
+Jobs: bot, stream, daily-maintenance, weekly-aggregate, watch-pages, compact-tombstones,
+      public-stats, digest, feeds
+Commands: doctor, compare, api-key, init-db, run, report, heatmap, publish, upload,
+          verify-archives, capabilities, changes, events, serve, state, deliveries, watchlist,
+          import-legacy, shadow-report, reprocess, redact, backfill, dump-diff";
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
 
-    let command = args.get(1).expect("command required");
+    let command = args.get(1).ok_or_else(|| anyhow!(USAGE))?;
+
+    if command == "doctor" {
+        // Runs without a valid config, to report on it
+        let config_file = args.get(2).map(|s| s.as_str()).unwrap_or("config.json");
+        let doctor = Doctor::run(config_file).await;
+        println!("{}", doctor.report());
+        if !doctor.passed() {
+            std::process::exit(2);
+        }
+        return Ok(());
+    }
+
+    if command == "compare" {
+        return compare(&args).await;
+    }
+
+    if !cfg!(feature = "serve") && ["serve", "api-key"].contains(&command.as_str()) {
+        return Err(anyhow!("{command} needs the serve feature"));
+    }
+
+    #[cfg(feature = "serve")]
+    if command == "api-key" {
+        return api_key(&args);
+    }
+
+    let job = Job::from_command(command);
+    if job.is_none() && !COMMANDS.contains(&command.as_str()) {
+        return Err(anyhow!("Unknown command {command:?}\n{USAGE}"));
+    }
 
     let config_file = args
         .get(2)
         .map(|s| s.to_string())
         .unwrap_or("config.json".to_string());
-    let mut wdrc = WdRc::new(&config_file);
+    let mut wdrc = WdRc::new(&config_file)?;
 
-    if command == "bot" {
-        loop {
-            match wdrc.run_once().await {
-                Ok(_) => (),
-                Err(e) => eprintln!("Error: {}", e),
-            }
+    if let Some(job) = job {
+        return job.run(&mut wdrc).await;
+    }
+    match command.as_str() {
+        "init-db" => {
+            let applied = Migrations::run(&wdrc).await?;
+            println!(
+                "Schema at version {}, applied migrations: {applied:?}",
+                Migrations::latest()
+            );
         }
-    } else if command == "run" {
-        match wdrc.run_once().await {
-            Ok(_) => (),
-            Err(e) => eprintln!("Error: {}", e),
+        "run" => {
+            Migrations::run(&wdrc).await?;
+            wdrc.check_replica_schema().await?;
+            wdrc.run_once().await?;
         }
+        "report" => report(&wdrc, args.get(3)).await?,
+        "heatmap" => heatmap(&wdrc, args.get(3)).await?,
+        "publish" => publish(&wdrc, &args).await?,
+        "upload" => upload(&wdrc, &args).await?,
+        "verify-archives" => verify_archives(&wdrc, &args).await?,
+        "capabilities" => println!(
+            "{}",
+            serde_json::to_string_pretty(&Capabilities::new(&wdrc))?
+        ),
+        "changes" => changes(&wdrc, args.get(3)).await?,
+        "events" => events(&wdrc, args.get(3)).await?,
+        #[cfg(feature = "serve")]
+        "serve" => serve(wdrc, &config_file, &args).await?,
+        "state" => state(&wdrc, &args).await?,
+        "deliveries" => deliveries(&wdrc, &args).await?,
+        "watchlist" => watchlist(&wdrc, &args).await?,
+        "import-legacy" => import_legacy(&mut wdrc).await?,
+        "shadow-report" => shadow_report(&wdrc, &args).await?,
+        "reprocess" => reprocess(&mut wdrc, &args).await?,
+        "redact" => redact(&wdrc, &args).await?,
+        "backfill" => backfill(&mut wdrc, &args).await?,
+        "dump-diff" => dump_diff(&mut wdrc, &args).await?,
+        _ => unreachable!("checked against COMMANDS"),
     }
+    Ok(())
 }
 
 /* TESTING
@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Result};
+use wikimisc::mysql_async::prelude::Queryable;
+
+use crate::{schema::Schema, WdRc};
+
+/// Schema changes in `migrations/`, in order. Add new files at the end with the next version;
+/// never change released ones, since installed instances have already applied them.
+const MIGRATIONS: &[(u32, &str)] = &[
+    (
+        1,
+        include_str!("../migrations/0001_columns_and_indexes.sql"),
+    ),
+    (
+        2,
+        include_str!("../migrations/0002_sitelinks_and_reverted.sql"),
+    ),
+    (
+        3,
+        include_str!("../migrations/0003_property_and_language_indexes.sql"),
+    ),
+    (
+        4,
+        include_str!("../migrations/0004_redaction_republished.sql"),
+    ),
+];
+
+/// The `meta` key holding the version of the last applied migration.
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Brings the wdrc tables up to date: creates missing tables, then applies the migrations newer
+/// than `schema_version` in `meta`. Migrations must be idempotent, since tables created by
+/// `init-db` already have their changes.
+pub struct Migrations;
+
+impl Migrations {
+    /// Returns the versions of the migrations applied.
+    pub async fn run(wdrc: &WdRc) -> Result<Vec<u32>> {
+        Schema::init(wdrc).await?;
+        let current: u32 = match wdrc.get_key_value(SCHEMA_VERSION_KEY).await? {
+            Some(version) => version
+                .parse()
+                .map_err(|_| anyhow!("Bad {SCHEMA_VERSION_KEY} in meta: {version:?}"))?,
+            None => 0,
+        };
+        let latest = Self::latest();
+        if current > latest {
+            return Err(anyhow!(
+                "Schema version {current} is newer than this build supports ({latest})"
+            ));
+        }
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let mut applied = vec![];
+        for (version, sql) in MIGRATIONS.iter().filter(|(v, _)| *v > current) {
+            for statement in Self::statements(sql) {
+                conn.query_drop(statement)
+                    .await
+                    .map_err(|e| anyhow!("Migration {version} failed: {e}"))?;
+            }
+            wdrc.set_key_value(SCHEMA_VERSION_KEY, &version.to_string())
+                .await?;
+            applied.push(*version);
+        }
+        Ok(applied)
+    }
+
+    pub fn latest() -> u32 {
+        MIGRATIONS.iter().map(|(v, _)| *v).max().unwrap_or(0)
+    }
+
+    /// The statements of a migration file, without comments.
+    fn statements(sql: &str) -> Vec<String> {
+        let sql: Vec<&str> = sql
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("--"))
+            .collect();
+        sql.join("\n")
+            .split(';')
+            .map(|statement| statement.trim().to_string())
+            .filter(|statement| !statement.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::{Schema, CHANGE_TYPE};
+
+    /// Columns the tables created before `init-db` had from the start.
+    const ORIGINAL_COLUMNS: &[&str] = &[
+        "id",
+        "q",
+        "item",
+        "revision",
+        "property",
+        "qualifier",
+        "hash",
+        "site",
+        "badge",
+        "subentity",
+        "type",
+        "language",
+        "key",
+        "old_value",
+        "new_value",
+        "source",
+        "target",
+        "timestamp",
+        "change_type",
+    ];
+
+    /// Columns of tables first created by `init-db`, which later migrations extend.
+    const CREATED_COLUMNS: &[(&str, &[&str])] = &[(
+        "redactions",
+        &["id", "revision", "reason", "timestamp", "rows"],
+    )];
+
+    #[test]
+    fn test_migrations() {
+        // Versions are consecutive, starting at 1
+        for (num, (version, _)) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(*version as usize, num + 1);
+        }
+        assert_eq!(
+            Migrations::statements(
+                "-- comment\nALTER TABLE `a` ADD `b` int;\n\nCREATE INDEX `c` ON `a` (`b`);\n"
+            ),
+            vec![
+                "ALTER TABLE `a` ADD `b` int".to_string(),
+                "CREATE INDEX `c` ON `a` (`b`)".to_string()
+            ]
+        );
+        assert!(Migrations::statements(MIGRATIONS[0].1)
+            .iter()
+            .all(|s| s.contains("IF NOT EXISTS")));
+    }
+
+    /// Tables created before `init-db` end up with the columns of `schema.rs` once migrated.
+    #[test]
+    fn test_migrated_schema() {
+        let statements: Vec<String> = MIGRATIONS
+            .iter()
+            .flat_map(|(_, sql)| Migrations::statements(sql))
+            .collect();
+        for (table, sql) in Schema::create_statements() {
+            let alter = format!("ALTER TABLE `{table}` ");
+            let migrated: Vec<&String> = statements
+                .iter()
+                .filter(|s| s.starts_with(&alter) || s.contains(&format!(" ON `{table}` (")))
+                .collect();
+            if migrated.is_empty() {
+                continue; // Only created by init-db
+            }
+            let original = CREATED_COLUMNS
+                .iter()
+                .find(|(name, _)| *name == table)
+                .map_or(ORIGINAL_COLUMNS, |(_, columns)| *columns);
+            for line in sql.lines().skip(1).map(str::trim) {
+                let Some((name, definition)) = line
+                    .strip_prefix('`')
+                    .and_then(|line| line.split_once("` "))
+                else {
+                    continue; // Keys
+                };
+                let definition = definition.trim_end_matches(',');
+                if name == "change_type" {
+                    let modify = format!("{alter}MODIFY COLUMN `change_type` {definition}");
+                    assert_eq!(definition, CHANGE_TYPE);
+                    assert!(statements.contains(&modify), "{table}.{name} not widened");
+                } else if !original.contains(&name) {
+                    let add = format!("{alter}ADD COLUMN IF NOT EXISTS `{name}` ");
+                    assert!(
+                        statements.iter().any(|s| s.starts_with(&add)),
+                        "{table}.{name} not added"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,486 @@
+#[cfg(not(feature = "irc"))]
+use anyhow::anyhow;
+use anyhow::Result;
+use futures::future::join_all;
+use http::Method;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    change::{Change, ChangeSubject, ChangeType},
+    config::{NotifierChannel, NotifierConfig, NotifyRule},
+    deliveries::{Deliveries, MatchedChange},
+    liftwing::LiftWing,
+    query::ChangeRow,
+    revision_compare::RevisionId,
+    value_format::ValueFormat,
+    webhooks::{JsonRequest, Webhooks},
+    WdRc,
+};
+
+const TELEGRAM_API: &str = "https://api.telegram.org";
+/// The longest Telegram message, in characters.
+const TELEGRAM_MAX_CHARS: usize = 4096;
+/// Matrix events may have up to 64 KiB, including the envelope.
+const MATRIX_MAX_CHARS: usize = 16000;
+/// Lines posted to IRC per batch; the rest are counted in a last line.
+#[cfg(feature = "irc")]
+const MAX_IRC_LINES: usize = 20;
+/// Longer old or new values are cut short in summaries.
+const MAX_VALUE_CHARS: usize = 200;
+
+/// Posts one-line summaries of logged changes to Telegram chats, Matrix rooms and IRC channels, e.g.
+/// `Q42 descriptions [en] changed: "writer" → "vandal" by 1.2.3.4 https://www.wikidata.org/w/index.php?diff=123`.
+pub struct Notifiers<'a> {
+    wdrc: &'a WdRc,
+    notifiers: &'a [NotifierConfig],
+}
+
+impl<'a> Notifiers<'a> {
+    pub fn new(wdrc: &'a WdRc, notifiers: &'a [NotifierConfig]) -> Self {
+        Self { wdrc, notifiers }
+    }
+
+    /// Posts the changes matching any rule of each notifier to its chat, all chats at once,
+    /// through [`Deliveries`], so lines that failed before are posted first. Notifiers posting
+    /// to the same chat get each change once, sent by the first of them. Returns the errors of
+    /// chats that still failed after retries.
+    pub async fn dispatch(&self, changes: &[Change]) -> Vec<String> {
+        let rows: Vec<ChangeRow> = changes.iter().map(ChangeRow::from_change).collect();
+        let damaging = self.damaging(changes, &rows).await;
+        let matches = self.notifiers.iter().flat_map(|notifier| {
+            let key = Deliveries::notifier_key(notifier);
+            let damaging = &damaging;
+            rows.iter().enumerate().flat_map(move |(num, row)| {
+                let score = damaging.get(&changes[num].revision_id).copied();
+                let key = key.to_owned();
+                notifier
+                    .rules
+                    .iter()
+                    .filter(move |rule| Self::rule_matches(rule, row, score))
+                    .map(move |rule| (key.to_owned(), Self::rule_name(rule), num))
+            })
+        });
+        let mut merged = Deliveries::merge_matches(changes, matches);
+        let mut chats: Vec<(usize, &NotifierConfig, String)> = vec![];
+        for (num, notifier) in self.notifiers.iter().enumerate() {
+            let key = Deliveries::notifier_key(notifier);
+            if !chats.iter().any(|(_, _, other)| *other == key) {
+                chats.push((num, notifier, key));
+            }
+        }
+        let deliveries = Deliveries::new(self.wdrc);
+        let deliveries = &deliveries;
+        let futures = chats.into_iter().map(|(num, notifier, key)| {
+            let lines = merged
+                .remove(&key)
+                .map(|matching| Self::lines(&matching, self.wdrc.wiki().server()))
+                .unwrap_or_default();
+            let body = (!lines.is_empty()).then(|| json!(lines).to_string());
+            async move {
+                deliveries
+                    .deliver(&key, body, |body| async move {
+                        let lines: Vec<String> = serde_json::from_str(&body)?;
+                        self.send(num, &notifier.channel, lines).await
+                    })
+                    .await
+            }
+        });
+        join_all(futures)
+            .await
+            .into_iter()
+            .filter_map(|result| result.err())
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    /// LiftWing damaging scores of the revisions that rules with `min_damaging` would report.
+    async fn damaging(&self, changes: &[Change], rows: &[ChangeRow]) -> HashMap<RevisionId, f64> {
+        let config = match self.wdrc.liftwing() {
+            Some(config) => config.to_owned(),
+            None => return HashMap::new(),
+        };
+        let mut revisions: Vec<RevisionId> = changes
+            .iter()
+            .zip(rows)
+            .filter(|(_, row)| {
+                self.notifiers
+                    .iter()
+                    .flat_map(|notifier| &notifier.rules)
+                    .any(|rule| rule.min_damaging.is_some() && rule.filter.matches(row))
+            })
+            .map(|(change, _)| change.revision_id)
+            .collect();
+        revisions.sort();
+        revisions.dedup();
+        if revisions.is_empty() {
+            return HashMap::new();
+        }
+        LiftWing::new(self.wdrc.wd().clone(), config)
+            .score(&revisions)
+            .await
+            .into_iter()
+            .filter_map(|score| Some((score.revision, score.damaging?)))
+            .collect()
+    }
+
+    /// Whether a rule reports a change; with `min_damaging`, unscored revisions are left out.
+    fn rule_matches(rule: &NotifyRule, row: &ChangeRow, damaging: Option<f64>) -> bool {
+        rule.filter.matches(row)
+            && rule
+                .min_damaging
+                .is_none_or(|min| damaging.is_some_and(|score| score >= min))
+    }
+
+    /// Names a rule in lines matched by several rules, like `props=P31 damaging≥0.5`.
+    fn rule_name(rule: &NotifyRule) -> String {
+        match rule.min_damaging {
+            Some(min) => format!("{} damaging≥{min}", rule.filter.name()),
+            None => rule.filter.name(),
+        }
+    }
+
+    /// Posts lines to the channel of notifier `num`.
+    async fn send(&self, num: usize, channel: &NotifierChannel, lines: Vec<String>) -> Result<()> {
+        match channel {
+            NotifierChannel::Telegram { bot_token, chat_id } => {
+                let url = format!("{TELEGRAM_API}/bot{bot_token}/sendMessage");
+                for text in Self::messages(&lines, TELEGRAM_MAX_CHARS) {
+                    let body = json!({
+                        "chat_id": chat_id,
+                        "text": text,
+                        "disable_web_page_preview": true,
+                    });
+                    let request = JsonRequest {
+                        name: format!("Telegram chat {chat_id}"),
+                        method: Method::POST,
+                        url: url.to_owned(),
+                        headers: vec![],
+                        body: body.to_string(),
+                    };
+                    self.send_json(&request).await?;
+                }
+                Ok(())
+            }
+            NotifierChannel::Matrix {
+                homeserver,
+                access_token,
+                room_id,
+            } => {
+                for text in Self::messages(&lines, MATRIX_MAX_CHARS) {
+                    let body = json!({"msgtype": "m.notice", "body": text});
+                    let request = JsonRequest {
+                        name: format!("Matrix room {room_id}"),
+                        method: Method::PUT,
+                        url: format!(
+                            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+                            homeserver.trim_end_matches('/'),
+                            Self::encode_path(room_id),
+                            Self::transaction_id()
+                        ),
+                        headers: vec![("authorization", format!("Bearer {access_token}"))],
+                        body: body.to_string(),
+                    };
+                    self.send_json(&request).await?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "irc")]
+            NotifierChannel::Irc(config) => self
+                .wdrc
+                .irc_feed(num, config)
+                .send(Self::first_lines(lines, MAX_IRC_LINES)),
+            #[cfg(not(feature = "irc"))]
+            NotifierChannel::Irc(_) => Err(anyhow!("Notifier {num} needs the irc feature")),
+        }
+    }
+
+    async fn send_json(&self, request: &JsonRequest) -> Result<()> {
+        Webhooks::send_json(
+            self.wdrc.wd(),
+            request,
+            self.wdrc.api_retry(),
+            self.wdrc.api_timeout(),
+        )
+        .await
+    }
+
+    /// The first `max` lines, with a count of the others in place of the last one.
+    #[cfg(feature = "irc")]
+    fn first_lines(mut lines: Vec<String>, max: usize) -> Vec<String> {
+        if lines.len() > max {
+            let more = lines.len() - max + 1;
+            lines.truncate(max - 1);
+            lines.push(format!("… and {more} more changes"));
+        }
+        lines
+    }
+
+    /// Percent-encodes a path segment, like a Matrix room ID.
+    fn encode_path(segment: &str) -> String {
+        segment
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (byte as char).to_string()
+                }
+                _ => format!("%{byte:02X}"),
+            })
+            .collect()
+    }
+
+    /// A Matrix transaction ID unique within the access token, so retries are not posted twice.
+    fn transaction_id() -> String {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        format!("wdrc{nanos}.{}", COUNTER.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// One line per change, or per entity for consecutive changes of one entity; lines of
+    /// changes matched by several rules end with those rules.
+    fn lines(changes: &[MatchedChange], server: &str) -> Vec<String> {
+        changes
+            .chunk_by(|a, b| {
+                a.change.entity_type == b.change.entity_type && a.change.item_id == b.change.item_id
+            })
+            .map(|group| {
+                let line = match group {
+                    [matched] => Self::summary(matched.change, server),
+                    _ => {
+                        let changes: Vec<&Change> = group.iter().map(|m| m.change).collect();
+                        Self::group_summary(&changes, server)
+                    }
+                };
+                let mut rules: Vec<&str> = vec![];
+                for name in group.iter().flat_map(|m| &m.matched) {
+                    if !rules.contains(&name.as_str()) {
+                        rules.push(name);
+                    }
+                }
+                match rules.len() > 1 {
+                    true => format!("{line} (matched {})", rules.join("; ")),
+                    false => line,
+                }
+            })
+            .collect()
+    }
+
+    /// A one-line summary of several changes of one entity, linking to the latest diff, e.g.
+    /// `Q42: 12 changes in 3 revisions (claims, labels) by Alice https://www.wikidata.org/w/index.php?diff=125`.
+    fn group_summary(changes: &[&Change], server: &str) -> String {
+        let mut subjects: Vec<&str> = vec![];
+        let mut users: Vec<&str> = vec![];
+        let mut revisions: Vec<RevisionId> = vec![];
+        for change in changes {
+            if !subjects.contains(&change.subject.as_str()) {
+                subjects.push(change.subject.as_str());
+            }
+            if let Some(user) = &change.user {
+                if !users.contains(&user.as_str()) {
+                    users.push(user);
+                }
+            }
+            if !revisions.contains(&change.revision_id) {
+                revisions.push(change.revision_id);
+            }
+        }
+        let user = match users.as_slice() {
+            [] => String::new(),
+            [user] => format!(" by {user}"),
+            _ => format!(" by {} users", users.len()),
+        };
+        format!(
+            "{}{}: {} changes in {} revisions ({}){user} {server}/w/index.php?diff={}",
+            changes[0].entity_type.id_prefix(),
+            changes[0].item_id,
+            changes.len(),
+            revisions.len(),
+            subjects.join(", "),
+            revisions.iter().max().copied().unwrap_or_default()
+        )
+    }
+
+    /// A one-line summary of a change, linking to the diff.
+    fn summary(change: &Change, server: &str) -> String {
+        let key = match change.subject {
+            ChangeSubject::Claims | ChangeSubject::Qualifiers | ChangeSubject::References => {
+                &change.property
+            }
+            ChangeSubject::Sitelinks | ChangeSubject::Badges => &change.site,
+            _ => &change.language,
+        };
+        let key = match key.is_empty() {
+            true => String::new(),
+            false => format!(" [{key}]"),
+        };
+        let (old, new) = ValueFormat::change_values(change);
+        let (old, new) = (Self::shorten(&old), Self::shorten(&new));
+        let values = match change.change_type {
+            ChangeType::Added => format!("\"{new}\""),
+            ChangeType::Removed => format!("\"{old}\""),
+            _ => format!("\"{old}\" → \"{new}\""),
+        };
+        let user = match &change.user {
+            Some(user) => format!(" by {user}"),
+            None => String::new(),
+        };
+        format!(
+            "{}{} {}{key} {}: {values}{user} {server}/w/index.php?diff={}",
+            change.entity_type.id_prefix(),
+            change.item_id,
+            change.subject.as_str(),
+            change.change_type.as_str(),
+            change.revision_id
+        )
+    }
+
+    fn shorten(value: &str) -> String {
+        match value.char_indices().nth(MAX_VALUE_CHARS) {
+            Some((end, _)) => format!("{}…", &value[..end]),
+            None => value.to_string(),
+        }
+    }
+
+    /// Joins lines into as few messages of at most `max_chars` as possible; longer lines are cut.
+    fn messages(lines: &[String], max_chars: usize) -> Vec<String> {
+        let mut ret: Vec<String> = vec![];
+        let mut chars = 0;
+        for line in lines {
+            let line: String = line.chars().take(max_chars).collect();
+            let line_chars = line.chars().count();
+            match ret.last_mut() {
+                Some(message) if chars + 1 + line_chars <= max_chars => {
+                    message.push('\n');
+                    message.push_str(&line);
+                    chars += 1 + line_chars;
+                }
+                _ => {
+                    ret.push(line);
+                    chars = line_chars;
+                }
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::live::LiveFilter;
+
+    #[test]
+    fn test_summary() {
+        let change = Change {
+            subject: ChangeSubject::Descriptions,
+            change_type: ChangeType::Changed,
+            language: "en".to_string(),
+            item_id: 42,
+            revision_id: 123,
+            user: Some("Alice".to_string()),
+            ..Default::default()
+        }
+        .with_values("writer", "vandal");
+        assert_eq!(
+            Notifiers::summary(&change, "https://www.wikidata.org"),
+            "Q42 descriptions [en] changed: \"writer\" → \"vandal\" by Alice https://www.wikidata.org/w/index.php?diff=123"
+        );
+        let rule = NotifyRule {
+            filter: LiveFilter::from_query("subjects=descriptions").unwrap(),
+            min_damaging: Some(0.5),
+        };
+        let row = ChangeRow::from_change(&change);
+        assert!(Notifiers::rule_matches(&rule, &row, Some(0.9)));
+        assert!(!Notifiers::rule_matches(&rule, &row, Some(0.1)));
+        assert!(!Notifiers::rule_matches(&rule, &row, None));
+        assert!(Notifiers::rule_matches(&NotifyRule::default(), &row, None));
+    }
+
+    #[test]
+    fn test_lines() {
+        let change = |item_id, revision_id, subject, user: &str| Change {
+            subject,
+            change_type: ChangeType::Added,
+            item_id,
+            revision_id,
+            user: Some(user.to_string()),
+            ..Default::default()
+        };
+        let changes = [
+            change(42, 123, ChangeSubject::Claims, "Alice"),
+            change(42, 124, ChangeSubject::Labels, "Alice"),
+            change(42, 124, ChangeSubject::Claims, "Alice"),
+            change(64, 125, ChangeSubject::Labels, "Bob"),
+        ];
+        let matched = |num: usize, rules: &[&str]| MatchedChange {
+            change: &changes[num],
+            matched: rules.iter().map(|rule| rule.to_string()).collect(),
+        };
+        let lines = Notifiers::lines(
+            &[
+                matched(0, &["items=Q42"]),
+                matched(1, &["items=Q42"]),
+                matched(2, &["items=Q42"]),
+                matched(3, &["all"]),
+            ],
+            "https://www.wikidata.org",
+        );
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "Q42: 3 changes in 2 revisions (claims, labels) by Alice https://www.wikidata.org/w/index.php?diff=124"
+        );
+        assert_eq!(
+            lines[1],
+            Notifiers::summary(&changes[3], "https://www.wikidata.org")
+        );
+
+        // A change matching an item rule and a property rule of one chat is posted once
+        let lines = Notifiers::lines(
+            &[matched(3, &["items=Q64", "props=P31 damaging≥0.5"])],
+            "https://www.wikidata.org",
+        );
+        assert_eq!(
+            lines,
+            [format!(
+                "{} (matched items=Q64; props=P31 damaging≥0.5)",
+                Notifiers::summary(&changes[3], "https://www.wikidata.org")
+            )]
+        );
+        let rule = NotifyRule {
+            filter: LiveFilter::from_query("props=P31").unwrap(),
+            min_damaging: Some(0.5),
+        };
+        assert_eq!(Notifiers::rule_name(&rule), "props=P31 damaging≥0.5");
+    }
+
+    #[test]
+    fn test_messages() {
+        let lines = vec!["a".repeat(4), "b".repeat(4), "c".repeat(12)];
+        assert_eq!(
+            Notifiers::messages(&lines, 10),
+            vec!["aaaa\nbbbb".to_string(), "c".repeat(10)]
+        );
+        assert!(Notifiers::messages(&[], 10).is_empty());
+        #[cfg(feature = "irc")]
+        {
+            let lines: Vec<String> = (0..5).map(|num| num.to_string()).collect();
+            assert_eq!(
+                Notifiers::first_lines(lines.clone(), 3),
+                vec!["0", "1", "… and 3 more changes"]
+            );
+            assert_eq!(Notifiers::first_lines(lines.clone(), 5), lines);
+        }
+        assert_eq!(
+            Notifiers::encode_path("!abc:matrix.org"),
+            "%21abc%3Amatrix.org"
+        );
+    }
+}
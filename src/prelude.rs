@@ -0,0 +1,13 @@
+//! The types most library consumers need: the tracker and its configuration, the changes it
+//! logs, and the model of a processed batch. Glob-import it with `use wdrc_rs::prelude::*;`.
+//! Items are only ever added here, so the import keeps compiling across releases.
+
+pub use crate::{
+    change::{Change, ChangeSubject, ChangeType, EntityType},
+    config::Config,
+    drops::DropReason,
+    recent_changes::{ChangedItem, NewItem, RecentChangesResults},
+    revision_compare::RevisionId,
+    sink::SinkType,
+    wdrc::{ItemId, WdRc},
+};
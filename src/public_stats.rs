@@ -0,0 +1,110 @@
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use std::{collections::HashMap, fs::File, path::Path};
+use wikimisc::mysql_async::{from_row, prelude::Queryable};
+
+use crate::{change::EntityType, revision_compare::ENGINE_VERSION, wiki::Wiki, WdRc};
+
+/// Logged changes of one entity type.
+#[derive(Debug, Clone, Serialize)]
+pub struct EntityTypeStats {
+    pub entity_type: String,
+    pub subjects: Vec<String>,
+    /// Approximate row counts of the change tables, from the table statistics.
+    pub rows: HashMap<String, u64>,
+    pub first_change: Option<String>,
+    pub last_change: Option<String>,
+}
+
+/// A summary of what a deployment tracks, for tool authors to find out whether it fits their needs.
+/// Contains no user data or configuration secrets.
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicStats {
+    pub generated: String,
+    pub wiki: Wiki,
+    pub engine_version: u32,
+    pub entity_types: Vec<EntityTypeStats>,
+    /// Seconds the processed recent changes are behind.
+    pub lag_seconds: Option<i64>,
+    /// Optional features, and whether they are on.
+    pub features: HashMap<String, bool>,
+}
+
+impl PublicStats {
+    pub async fn collect(wdrc: &WdRc) -> Result<Self> {
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let table_rows: HashMap<String, u64> = conn
+            .query_iter("SELECT `TABLE_NAME`,`TABLE_ROWS` FROM `information_schema`.`TABLES` WHERE `TABLE_SCHEMA`=DATABASE()")
+            .await?
+            .map_and_drop(from_row::<(String, Option<u64>)>)
+            .await?
+            .into_iter()
+            .map(|(table, rows)| (table, rows.unwrap_or(0)))
+            .collect();
+
+        let mut entity_types = vec![];
+        for entity_type in EntityType::all() {
+            if !wdrc.namespaces().contains(&entity_type.namespace()) {
+                continue;
+            }
+            let rows = [
+                "statements",
+                "qualifiers",
+                "references",
+                "labels",
+                "creations",
+            ]
+            .iter()
+            .map(|name| entity_type.table(name))
+            .filter_map(|table| Some((table.to_owned(), *table_rows.get(&table)?)))
+            .collect();
+            let sql = format!(
+                "SELECT MIN(`timestamp`),MAX(`timestamp`) FROM `{}`",
+                entity_type.table("statements")
+            );
+            let (first_change, last_change) = conn
+                .query_iter(sql)
+                .await?
+                .map_and_drop(from_row::<(Option<String>, Option<String>)>)
+                .await?
+                .pop()
+                .unwrap_or_default();
+            entity_types.push(EntityTypeStats {
+                entity_type: entity_type.as_str().to_string(),
+                subjects: entity_type
+                    .subjects()
+                    .iter()
+                    .map(|s| s.as_str().to_string())
+                    .collect(),
+                rows,
+                first_change,
+                last_change,
+            });
+        }
+
+        let now = Utc::now();
+        let lag_seconds = wdrc
+            .get_key_value("timestamp")
+            .await?
+            .and_then(|ts| NaiveDateTime::parse_from_str(&ts, "%Y%m%d%H%M%S").ok())
+            .map(|ts| (now.naive_utc() - ts).num_seconds());
+        Ok(Self {
+            generated: now.format("%Y%m%d%H%M%S").to_string(),
+            wiki: wdrc.wiki(),
+            engine_version: ENGINE_VERSION,
+            entity_types,
+            lag_seconds,
+            features: wdrc
+                .features()
+                .into_iter()
+                .map(|(name, on)| (name.to_string(), on))
+                .collect(),
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,512 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+use wikimisc::mysql_async::{prelude::Queryable, Row, Value};
+
+use crate::{change::EntityType, object_storage::ObjectStorage, RevisionId, WdRc};
+
+/// Bump whenever columns are added, removed, or change meaning.
+pub(crate) const DATASET_SCHEMA_VERSION: u32 = 1;
+
+/// One file of a published dataset, as listed in the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatasetFile {
+    pub name: String,
+    pub description: String,
+    pub columns: Vec<String>,
+    pub rows: u64,
+    /// Hex SHA-256 of the file.
+    pub sha256: String,
+}
+
+/// The machine-readable description of a published month, written as `manifest.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatasetManifest {
+    pub dataset: String,
+    pub month: String,
+    pub schema_version: u32,
+    pub generated: String,
+    pub format: String,
+    pub files: Vec<DatasetFile>,
+}
+
+/// A published file as recorded in `published_files`, which [`Publisher::verify`] checks
+/// the files and manifests against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishedFile {
+    pub name: String,
+    pub rows: u64,
+    pub sha256: String,
+}
+
+struct DatasetQuery {
+    name: String,
+    description: &'static str,
+    columns: &'static [&'static str],
+    sql: String,
+}
+
+/// Writes monthly dataset files (tab-separated, with header) plus a manifest, and records the
+/// checksums and row counts of the files in the database.
+///
+/// Published months are never overwritten, so a target directory that already
+/// exists is an error; only a redaction writes a month again, with [`Self::republish`].
+/// With `object_storage` configured, published months are also uploaded to a bucket.
+pub struct Publisher<'a> {
+    wdrc: &'a WdRc,
+}
+
+impl<'a> Publisher<'a> {
+    pub fn new(wdrc: &'a WdRc) -> Self {
+        Self { wdrc }
+    }
+
+    pub async fn publish(&self, month: &str, dir: &Path) -> Result<DatasetManifest> {
+        if month.len() != 6 || !month.chars().all(|c| c.is_ascii_digit()) {
+            return Err(anyhow!("Month must be YYYYMM, not {month:?}"));
+        }
+        let dir: PathBuf = dir.join(month);
+        if dir.exists() {
+            return Err(anyhow!("{} already exists, not overwriting", dir.display()));
+        }
+        let manifest = self.write_month(month, &dir).await?;
+        self.record(&manifest, &dir).await?;
+        self.upload_if_configured(&dir).await?;
+        Ok(manifest)
+    }
+
+    /// Writes a published month again from the current tables, after a redaction, replacing its
+    /// files and manifest in `dir` and its records.
+    pub async fn republish(&self, month: &str, dir: &Path) -> Result<DatasetManifest> {
+        let new = dir.with_extension("new");
+        if new.exists() {
+            fs::remove_dir_all(&new)?;
+        }
+        let manifest = self.write_month(month, &new).await?;
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        fs::rename(&new, dir)?;
+        self.record(&manifest, dir).await?;
+        self.upload_if_configured(dir).await?;
+        Ok(manifest)
+    }
+
+    /// Uploads the published month in `dir` to `object_storage` as `<month>/<file>`, replacing
+    /// earlier uploads. The manifest goes last, so a listed file is never missing from a bucket.
+    pub async fn upload(&self, dir: &Path) -> Result<DatasetManifest> {
+        let config = self
+            .wdrc
+            .object_storage()
+            .ok_or_else(|| anyhow!("No object_storage configured"))?;
+        let manifest = Self::read_manifest(dir)?;
+        let storage = ObjectStorage::new(self.wdrc, config);
+        let names = manifest.files.iter().map(|file| file.name.as_str());
+        for name in names.chain(["manifest.json"]) {
+            let key = format!("{}/{name}", manifest.month);
+            storage.put_file(&key, &dir.join(name)).await?;
+        }
+        Ok(manifest)
+    }
+
+    async fn upload_if_configured(&self, dir: &Path) -> Result<()> {
+        if self.wdrc.object_storage().is_none() {
+            return Ok(());
+        }
+        match self.upload(dir).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(anyhow!(
+                "{} was published, but not uploaded: {e}",
+                dir.display()
+            )),
+        }
+    }
+
+    /// The published months with changes of any of `revisions`, and their directories.
+    pub(crate) async fn months_with_revisions(
+        &self,
+        revisions: &[RevisionId],
+    ) -> Result<Vec<(String, PathBuf)>> {
+        let placeholders = vec!["?"; revisions.len()].join(",");
+        let months_sql = Self::revision_tables()
+            .iter()
+            .map(|table| format!("SELECT LEFT(`timestamp`,6) FROM `{table}` WHERE `revision` IN ({placeholders})"))
+            .collect::<Vec<String>>()
+            .join(" UNION ");
+        let sql = format!("SELECT DISTINCT `month`,`directory` FROM `published_files` WHERE `month` IN ({months_sql}) ORDER BY `month`");
+        let params: Vec<Value> = vec![revisions; Self::revision_tables().len()]
+            .concat()
+            .into_iter()
+            .map(Value::from)
+            .collect();
+        let rows: Vec<(String, String)> = self
+            .wdrc
+            .db()
+            .get_connection("wdrc")
+            .await?
+            .exec(sql, params)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(month, dir)| (month, PathBuf::from(dir)))
+            .collect())
+    }
+
+    /// The tables of the datasets that list revisions.
+    fn revision_tables() -> Vec<String> {
+        EntityType::all()
+            .into_iter()
+            .flat_map(|et| [et.table("statements"), et.table("labels")])
+            .collect()
+    }
+
+    /// Writes the files and manifest of a month to `dir`.
+    async fn write_month(&self, month: &str, dir: &Path) -> Result<DatasetManifest> {
+        fs::create_dir_all(dir)?;
+
+        let mut files = vec![];
+        for query in Self::queries() {
+            let rows = self.write_file(dir, &query, month).await?;
+            let name = format!("{}.tsv", query.name);
+            files.push(DatasetFile {
+                sha256: Self::sha256(&dir.join(&name))?,
+                name,
+                description: query.description.to_string(),
+                columns: query.columns.iter().map(|c| c.to_string()).collect(),
+                rows,
+            });
+        }
+
+        let manifest = DatasetManifest {
+            dataset: "wdrc".to_string(),
+            month: month.to_string(),
+            schema_version: DATASET_SCHEMA_VERSION,
+            generated: Utc::now().format("%Y%m%d%H%M%S").to_string(),
+            format: "text/tab-separated-values; header row; timestamps YYYYMMDDHHMMSS UTC"
+                .to_string(),
+            files,
+        };
+        let manifest_file = File::create(dir.join("manifest.json"))?;
+        serde_json::to_writer_pretty(manifest_file, &manifest)?;
+        Ok(manifest)
+    }
+
+    /// Records the files of a published month in `dir` in `published_files`, replacing earlier
+    /// records.
+    async fn record(&self, manifest: &DatasetManifest, dir: &Path) -> Result<()> {
+        let dir = dir.canonicalize()?.to_string_lossy().to_string();
+        let rows: Vec<Vec<Value>> = manifest
+            .files
+            .iter()
+            .map(|file| {
+                vec![
+                    manifest.month.as_str().into(),
+                    file.name.as_str().into(),
+                    file.rows.into(),
+                    file.sha256.as_str().into(),
+                    manifest.generated.as_str().into(),
+                    dir.as_str().into(),
+                ]
+            })
+            .collect();
+        let sql =
+            "REPLACE INTO `published_files` (`month`,`name`,`rows`,`sha256`,`published`,`directory`) VALUES";
+        self.wdrc.insert_rows(sql, &rows).await
+    }
+
+    /// The files recorded for a published month.
+    async fn recorded(&self, month: &str) -> Result<Vec<PublishedFile>> {
+        let sql =
+            "SELECT `name`,`rows`,`sha256` FROM `published_files` WHERE `month`=? ORDER BY `name`";
+        let rows: Vec<(String, u64, String)> = self
+            .wdrc
+            .db()
+            .get_connection("wdrc")
+            .await?
+            .exec(sql, (month,))
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(name, rows, sha256)| PublishedFile { name, rows, sha256 })
+            .collect())
+    }
+
+    fn queries() -> Vec<DatasetQuery> {
+        let mut ret = vec![];
+        for entity_type in EntityType::all() {
+            let prefix = match entity_type {
+                EntityType::Item => String::new(),
+                other => format!("{}_", other.as_str()),
+            };
+            let table = |name: &str| entity_type.table(name);
+            ret.push(DatasetQuery {
+                name: format!("{prefix}statements"),
+                description: "Statements added, removed, or changed",
+                columns: &["entity", "revision", "property", "timestamp", "change_type"],
+                sql: format!("SELECT `item`,`revision`,`property`,`timestamp`,`change_type` FROM `{}` WHERE `timestamp` LIKE ? ORDER BY `timestamp`,`item`",table("statements")),
+            });
+            ret.push(DatasetQuery {
+                name: format!("{prefix}labels"),
+                description: "Labels, descriptions, aliases, lemmas, sitelinks, and datatypes added, removed, or changed; `language` is the site for sitelinks",
+                columns: &["entity", "revision", "type", "timestamp", "change_type", "language"],
+                sql: format!("SELECT `item`,`revision`,`type`,`timestamp`,`change_type`,`value` FROM `{}`,`texts` WHERE `texts`.`id`=`language` AND `timestamp` LIKE ? ORDER BY `timestamp`,`item`",table("labels")),
+            });
+            ret.push(DatasetQuery {
+                name: format!("{prefix}creations"),
+                description: "Entities created",
+                columns: &["entity", "timestamp"],
+                sql: format!("SELECT `q`,`timestamp` FROM `{}` WHERE `timestamp` LIKE ? ORDER BY `timestamp`,`q`",table("creations")),
+            });
+            ret.push(DatasetQuery {
+                name: format!("{prefix}deletions"),
+                description: "Entities deleted",
+                columns: &["entity", "timestamp"],
+                sql: format!("SELECT `q`,`timestamp` FROM `{}` WHERE `timestamp` LIKE ? ORDER BY `timestamp`,`q`",table("deletions")),
+            });
+            ret.push(DatasetQuery {
+                name: format!("{prefix}redirects"),
+                description: "Entities turned into redirects",
+                columns: &["source", "target", "timestamp"],
+                sql: format!("SELECT `source`,`target`,`timestamp` FROM `{}` WHERE `timestamp` LIKE ? ORDER BY `timestamp`,`source`",table("redirects")),
+            });
+        }
+        ret
+    }
+
+    async fn write_file(&self, dir: &Path, query: &DatasetQuery, month: &str) -> Result<u64> {
+        let mut out = BufWriter::new(File::create(dir.join(format!("{}.tsv", query.name)))?);
+        writeln!(out, "{}", query.columns.join("\t"))?;
+        let mut rows = 0;
+        let mut write_error = None;
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        conn.exec_iter(query.sql.as_str(), (format!("{month}%"),))
+            .await?
+            .for_each(|row: Row| {
+                let line: Vec<String> = row.unwrap().iter().map(Self::value_to_field).collect();
+                match writeln!(out, "{}", line.join("\t")) {
+                    Ok(_) => rows += 1,
+                    Err(e) => write_error = Some(e),
+                }
+            })
+            .await?;
+        if let Some(e) = write_error {
+            return Err(e.into());
+        }
+        out.flush()?;
+        Ok(rows)
+    }
+
+    fn sha256(path: &Path) -> Result<String> {
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect())
+    }
+
+    /// Checks the files of a published month, or of all months in a directory of them, against
+    /// the checksums and row counts recorded when they were published, and their manifests
+    /// against the records. Returns the problems found.
+    pub async fn verify(&self, dir: &Path) -> Result<Vec<String>> {
+        let months = Self::month_dirs(dir)?;
+        let mut ret = vec![];
+        for month in months {
+            let manifest = Self::read_manifest(&month)?;
+            let recorded = self.recorded(&manifest.month).await?;
+            ret.extend(Self::verify_month(&month, &manifest, &recorded)?);
+        }
+        Ok(ret)
+    }
+
+    /// `dir` if it is a published month, or the published months in it.
+    fn month_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
+        if dir.join("manifest.json").is_file() {
+            return Ok(vec![dir.to_path_buf()]);
+        }
+        let mut months: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.join("manifest.json").is_file())
+            .collect();
+        if months.is_empty() {
+            return Err(anyhow!("No manifest.json in or below {}", dir.display()));
+        }
+        months.sort();
+        Ok(months)
+    }
+
+    fn read_manifest(dir: &Path) -> Result<DatasetManifest> {
+        Ok(serde_json::from_reader(File::open(
+            dir.join("manifest.json"),
+        )?)?)
+    }
+
+    fn verify_month(
+        dir: &Path,
+        manifest: &DatasetManifest,
+        recorded: &[PublishedFile],
+    ) -> Result<Vec<String>> {
+        let manifest_path = dir.join("manifest.json");
+        if recorded.is_empty() {
+            return Ok(vec![format!(
+                "{}: month {} is not recorded as published",
+                manifest_path.display(),
+                manifest.month
+            )]);
+        }
+        let mut ret = vec![];
+        for file in recorded {
+            match manifest.files.iter().find(|f| f.name == file.name) {
+                Some(listed) if listed.sha256 == file.sha256 && listed.rows == file.rows => {}
+                Some(_) => ret.push(format!(
+                    "{}: {} differs from the recorded checksum or row count",
+                    manifest_path.display(),
+                    file.name
+                )),
+                None => ret.push(format!(
+                    "{}: {} not listed",
+                    manifest_path.display(),
+                    file.name
+                )),
+            }
+            let path = dir.join(&file.name);
+            if !path.is_file() {
+                ret.push(format!("{}: missing", path.display()));
+                continue;
+            }
+            let sha256 = Self::sha256(&path)?;
+            if sha256 != file.sha256 {
+                ret.push(format!(
+                    "{}: SHA-256 is {sha256}, not {}",
+                    path.display(),
+                    file.sha256
+                ));
+            }
+            // Without the header row
+            let rows = BufReader::new(File::open(&path)?)
+                .lines()
+                .count()
+                .saturating_sub(1) as u64;
+            if rows != file.rows {
+                ret.push(format!(
+                    "{}: {rows} rows, not {}",
+                    path.display(),
+                    file.rows
+                ));
+            }
+        }
+        for listed in &manifest.files {
+            if !recorded.iter().any(|file| file.name == listed.name) {
+                ret.push(format!(
+                    "{}: {} not recorded as published",
+                    manifest_path.display(),
+                    listed.name
+                ));
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Renders a DB value as a TSV field, replacing characters that would break the format.
+    fn value_to_field(value: &Value) -> String {
+        let s = match value {
+            Value::NULL => String::new(),
+            Value::Bytes(b) => String::from_utf8_lossy(b).to_string(),
+            Value::Int(i) => i.to_string(),
+            Value::UInt(u) => u.to_string(),
+            other => other.as_sql(true),
+        };
+        s.replace(['\t', '\n', '\r'], " ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_to_field() {
+        assert_eq!(Publisher::value_to_field(&Value::NULL), "");
+        assert_eq!(Publisher::value_to_field(&Value::UInt(42)), "42");
+        assert_eq!(
+            Publisher::value_to_field(&Value::Bytes(b"a\tb\nc".to_vec())),
+            "a b c"
+        );
+    }
+
+    #[test]
+    fn test_verify_month() {
+        let month = std::env::temp_dir().join(format!("wdrc_verify_{}", std::process::id()));
+        fs::create_dir_all(&month).unwrap();
+        fs::write(
+            month.join("deletions.tsv"),
+            "entity\ttimestamp\n42\t20240101000000\n",
+        )
+        .unwrap();
+        let recorded = vec![PublishedFile {
+            name: "deletions.tsv".to_string(),
+            rows: 1,
+            sha256: Publisher::sha256(&month.join("deletions.tsv")).unwrap(),
+        }];
+        let file = DatasetFile {
+            name: "deletions.tsv".to_string(),
+            description: String::new(),
+            columns: vec![],
+            rows: 1,
+            sha256: recorded[0].sha256.clone(),
+        };
+        let manifest = DatasetManifest {
+            dataset: "wdrc".to_string(),
+            month: "202401".to_string(),
+            schema_version: DATASET_SCHEMA_VERSION,
+            generated: String::new(),
+            format: String::new(),
+            files: vec![file],
+        };
+        serde_json::to_writer(
+            File::create(month.join("manifest.json")).unwrap(),
+            &manifest,
+        )
+        .unwrap();
+        assert_eq!(Publisher::month_dirs(&month).unwrap(), vec![month.clone()]);
+        let verify = |manifest: &DatasetManifest, recorded: &[PublishedFile]| {
+            Publisher::verify_month(&month, manifest, recorded).unwrap()
+        };
+        assert!(verify(&Publisher::read_manifest(&month).unwrap(), &recorded).is_empty());
+        assert!(verify(&manifest, &[])[0].contains("not recorded as published"));
+
+        // A file and its manifest entry changed together still differ from the record
+        fs::write(month.join("deletions.tsv"), "entity\ttimestamp\n").unwrap();
+        let mut changed = manifest.clone();
+        changed.files[0].rows = 0;
+        changed.files[0].sha256 = Publisher::sha256(&month.join("deletions.tsv")).unwrap();
+        let problems = verify(&changed, &recorded);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].contains("differs from the recorded"));
+        assert!(problems[1].contains("SHA-256"));
+        assert!(problems[2].contains("0 rows, not 1"));
+
+        let mut missing = recorded.clone();
+        missing[0].name = "redirects.tsv".to_string();
+        let problems = verify(&manifest, &missing);
+        assert!(problems[0].ends_with("redirects.tsv not listed"));
+        assert!(problems[1].ends_with("missing"));
+        assert!(problems[2].ends_with("deletions.tsv not recorded as published"));
+        fs::remove_dir_all(&month).unwrap();
+    }
+}
@@ -0,0 +1,1093 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+use wikimisc::mysql_async::{from_row, prelude::Queryable, Value as SqlValue};
+
+use crate::{
+    change::{Change, ChangeSubject, ChangeType, EntityType},
+    wdqs::WdqsLag,
+    ItemId, RevisionId, WdRc,
+};
+
+const DEFAULT_LIMIT: u64 = 100;
+pub(crate) const MAX_LIMIT: u64 = 5000;
+/// Query API versions this build answers; the newest is used unless `version` asks for another.
+pub const API_VERSIONS: &[u32] = &[1];
+/// Redirect chains longer than this are not followed further.
+const MAX_REDIRECT_DEPTH: usize = 10;
+/// The tables of [`SOURCE_TABLES`] that published months have files of.
+const ARCHIVED_TABLES: &[&str] = &["statements", "labels"];
+
+/// A listed row as selected: item, revision, subject, timestamp, change type, language,
+/// property, source table and ID.
+type SourceRow = (
+    ItemId,
+    RevisionId,
+    String,
+    String,
+    String,
+    Option<String>,
+    Option<u64>,
+    u64,
+    u64,
+);
+
+/// Splits `key=value` pairs separated by `&`, as they are; a key without `=` has an empty value.
+pub(crate) fn query_pairs(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (key.to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// Where a listing, newest first, continues: after the row with this timestamp and these keys
+/// ordering the rows that share it, as `20240101000000:12345:...`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub timestamp: String,
+    pub keys: Vec<u64>,
+}
+
+impl Cursor {
+    fn parse(value: &str, keys: usize) -> Result<Self> {
+        let error = || anyhow!("Bad value for \"continue\": {value:?}");
+        let mut parts = value.split(':');
+        let timestamp = parts.next().unwrap_or_default();
+        if timestamp.len() != 14 || !timestamp.chars().all(|c| c.is_ascii_digit()) {
+            return Err(error());
+        }
+        let ret = Self {
+            timestamp: timestamp.to_string(),
+            keys: parts
+                .map(|key| key.parse().map_err(|_| error()))
+                .collect::<Result<_>>()?,
+        };
+        match ret.keys.len() == keys {
+            true => Ok(ret),
+            false => Err(error()),
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.timestamp)?;
+        self.keys.iter().try_for_each(|key| write!(f, ":{key}"))
+    }
+}
+
+/// A table of logged changes, and how its rows map onto the listing columns.
+pub(crate) struct SourceTable {
+    pub(crate) name: &'static str,
+    /// The subject of all rows, or `None` if the table has a `type` column with the subject.
+    pub(crate) subject: Option<ChangeSubject>,
+    /// The subjects that can appear in the `type` column.
+    types: &'static [&'static str],
+    pub(crate) property: bool,
+    /// Column referencing `texts`, shown as `language`.
+    text_column: Option<&'static str>,
+}
+
+impl SourceTable {
+    pub(crate) fn exists_for(&self, entity_type: EntityType) -> bool {
+        match entity_type {
+            EntityType::Item => self.name != "subentities",
+            EntityType::Property => self.name != "subentities" && self.name != "badges",
+            EntityType::Lexeme => self.name != "badges",
+        }
+    }
+}
+
+pub(crate) const SOURCE_TABLES: &[SourceTable] = &[
+    SourceTable {
+        name: "statements",
+        subject: Some(ChangeSubject::Claims),
+        types: &[],
+        property: true,
+        text_column: None,
+    },
+    SourceTable {
+        name: "qualifiers",
+        subject: Some(ChangeSubject::Qualifiers),
+        types: &[],
+        property: true,
+        text_column: None,
+    },
+    SourceTable {
+        name: "references",
+        subject: Some(ChangeSubject::References),
+        types: &[],
+        property: true,
+        text_column: None,
+    },
+    SourceTable {
+        name: "labels",
+        subject: None,
+        types: &[
+            "labels",
+            "descriptions",
+            "aliases",
+            "sitelinks",
+            "lemmas",
+            "datatype",
+        ],
+        property: false,
+        text_column: Some("language"),
+    },
+    SourceTable {
+        name: "badges",
+        subject: Some(ChangeSubject::Badges),
+        types: &[],
+        property: false,
+        text_column: Some("site"),
+    },
+    SourceTable {
+        name: "subentities",
+        subject: None,
+        types: &["forms", "senses"],
+        property: false,
+        text_column: None,
+    },
+];
+
+/// One logged change, as listed by [`ChangeFilter`].
+///
+/// With `format=legacy`, `change_type` is named `change`, like in the predecessor PHP tool.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChangeRow {
+    pub entity: String,
+    pub revision: RevisionId,
+    pub subject: String,
+    pub timestamp: String,
+    pub change_type: String,
+    /// Language, or site for sitelinks and badges.
+    pub language: Option<String>,
+    pub property: Option<String>,
+    /// Whether the change is likely reflected in the Wikidata Query Service; unknown unless
+    /// `track_wdqs_lag` is on.
+    pub in_wdqs: Option<bool>,
+    /// The item the change was logged under, if it now redirects to `entity`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirected_from: Option<String>,
+    /// The position of the row in a listing; revision, source table and row ID break ties.
+    #[serde(skip)]
+    pub cursor: Option<Cursor>,
+}
+
+impl ChangeRow {
+    /// The row a change is listed as once logged.
+    pub fn from_change(change: &Change) -> Self {
+        let non_empty = |s: &str| (!s.is_empty()).then(|| s.to_string());
+        Self {
+            entity: format!("{}{}", change.entity_type.id_prefix(), change.item_id),
+            revision: change.revision_id,
+            subject: change.subject.as_str().to_string(),
+            timestamp: change.timestamp.to_owned(),
+            change_type: change.change_type.as_str().to_string(),
+            language: match change.subject {
+                ChangeSubject::Sitelinks | ChangeSubject::Badges => non_empty(&change.site),
+                _ => non_empty(&change.language),
+            },
+            property: match change.subject {
+                ChangeSubject::Claims | ChangeSubject::Qualifiers | ChangeSubject::References => {
+                    non_empty(&change.property)
+                }
+                _ => None,
+            },
+            in_wdqs: None,
+            redirected_from: None,
+            cursor: None,
+        }
+    }
+}
+
+/// Filters for listing logged changes, e.g. `subjects=claims,!aliases&types=added,removed&lang=de&prop=P31`.
+///
+/// `continue` takes the value of the same name from the previous page of a listing.
+/// `wdqs=only` leaves out changes the Wikidata Query Service has likely not caught up with yet.
+/// `version=1` fails unless this build supports that API version. With `item` set,
+/// `follow_redirects=true` also lists changes logged under items that now redirect to it.
+///
+/// With `retention_days` set, published months that began before the retention cutoff are
+/// listed from their files instead of the database, which may hold only part of them. These
+/// files have statements and terms, but no qualifiers, references, badges, forms or senses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeFilter {
+    pub entity_type: EntityType,
+    pub subjects: Vec<ChangeSubject>,
+    pub exclude_subjects: Vec<ChangeSubject>,
+    pub change_types: Vec<ChangeType>,
+    pub language: Option<String>,
+    pub property: Option<ItemId>,
+    pub item: Option<ItemId>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// The last row of the previous page.
+    pub after: Option<Cursor>,
+    pub limit: u64,
+    /// Output in the JSON format of the predecessor PHP tool.
+    pub legacy: bool,
+    pub wdqs_only: bool,
+    pub follow_redirects: bool,
+    /// Items redirecting to `item`, looked up when following redirects.
+    redirect_sources: Vec<ItemId>,
+    /// Published months listed from their files, left out of the database query.
+    archived_months: Vec<String>,
+}
+
+impl Default for ChangeFilter {
+    fn default() -> Self {
+        Self {
+            entity_type: EntityType::Item,
+            subjects: vec![],
+            exclude_subjects: vec![],
+            change_types: vec![],
+            language: None,
+            property: None,
+            item: None,
+            since: None,
+            until: None,
+            after: None,
+            limit: DEFAULT_LIMIT,
+            legacy: false,
+            wdqs_only: false,
+            follow_redirects: false,
+            redirect_sources: vec![],
+            archived_months: vec![],
+        }
+    }
+}
+
+impl ChangeFilter {
+    /// Parses `key=value` pairs separated by `&`. Unknown keys are an error.
+    pub fn from_query(query: &str) -> Result<Self> {
+        Self::from_pairs(&query_pairs(query))
+    }
+
+    /// Parses decoded query parameters.
+    pub fn from_pairs(pairs: &[(String, String)]) -> Result<Self> {
+        let mut ret = Self::default();
+        for (key, value) in pairs {
+            ret.set(key, value)?;
+        }
+        if ret.follow_redirects && (ret.item.is_none() || ret.entity_type != EntityType::Item) {
+            return Err(anyhow!("follow_redirects requires an item"));
+        }
+        Ok(ret)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let list = || value.split(',').filter(|v| !v.is_empty());
+        match key {
+            "entity" => {
+                self.entity_type = EntityType::all()
+                    .into_iter()
+                    .find(|et| et.as_str() == value)
+                    .ok_or_else(|| anyhow!("Unknown entity type: {value:?}"))?
+            }
+            "subjects" => {
+                for name in list() {
+                    let (exclude, name) = match name.strip_prefix('!') {
+                        Some(name) => (true, name),
+                        None => (false, name),
+                    };
+                    let subject = ChangeSubject::from_name(name)
+                        .ok_or_else(|| anyhow!("Unknown subject: {name:?}"))?;
+                    match exclude {
+                        true => self.exclude_subjects.push(subject),
+                        false => self.subjects.push(subject),
+                    }
+                }
+            }
+            "types" => {
+                for name in list() {
+                    let change_type = ChangeType::from_name(name)
+                        .ok_or_else(|| anyhow!("Unknown change type: {name:?}"))?;
+                    self.change_types.push(change_type);
+                }
+            }
+            "lang" => self.language = Some(value.to_string()),
+            "prop" => self.property = Some(WdRc::make_id_numeric(value)?),
+            "item" => self.item = Some(WdRc::make_id_numeric(value)?),
+            "since" => self.since = Some(Self::timestamp(value)?),
+            "until" => self.until = Some(Self::timestamp(value)?),
+            "continue" => self.after = Some(Cursor::parse(value, 3)?),
+            "limit" => self.limit = value.parse::<u64>()?.clamp(1, MAX_LIMIT),
+            "format" => {
+                self.legacy = match value {
+                    "json" => false,
+                    "legacy" => true,
+                    _ => return Err(anyhow!("Unknown format: {value:?}")),
+                }
+            }
+            "follow_redirects" => {
+                self.follow_redirects = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(anyhow!("Unknown follow_redirects value: {value:?}")),
+                }
+            }
+            "version" => {
+                let version: u32 = value.parse()?;
+                if !API_VERSIONS.contains(&version) {
+                    return Err(anyhow!(
+                        "Unsupported API version {version}, supported: {API_VERSIONS:?}"
+                    ));
+                }
+            }
+            "wdqs" => {
+                self.wdqs_only = match value {
+                    "all" => false,
+                    "only" => true,
+                    _ => return Err(anyhow!("Unknown wdqs value: {value:?}")),
+                }
+            }
+            other => return Err(anyhow!("Unknown parameter: {other:?}")),
+        }
+        Ok(())
+    }
+
+    fn timestamp(value: &str) -> Result<String> {
+        match value.len() <= 14 && value.chars().all(|c| c.is_ascii_digit()) {
+            true => Ok(value.to_string()),
+            false => Err(anyhow!("Timestamps must be (a prefix of) YYYYMMDDHHMMSS")),
+        }
+    }
+
+    fn includes_subject(&self, subject: &str) -> bool {
+        (self.subjects.is_empty() || self.subjects.iter().any(|s| s.as_str() == subject))
+            && !self.exclude_subjects.iter().any(|s| s.as_str() == subject)
+    }
+
+    /// Builds a `UNION ALL` over the tables that can match, each limited and sorted on its own
+    /// so that the `timestamp` indexes can be used.
+    pub(crate) fn to_sql(&self) -> Option<(String, Vec<SqlValue>)> {
+        let mut parts = vec![];
+        let mut params: Vec<SqlValue> = vec![];
+        for (index, source) in SOURCE_TABLES.iter().enumerate() {
+            if let Some((sql, mut source_params)) = self.source_sql(index as u64, source) {
+                parts.push(format!("({sql})"));
+                params.append(&mut source_params);
+            }
+        }
+        if parts.is_empty() {
+            return None;
+        }
+        let sql = format!(
+            "{} ORDER BY `timestamp` DESC,`revision` DESC,`source` DESC,`id` DESC LIMIT ?",
+            parts.join(" UNION ALL ")
+        );
+        params.push(self.limit.into());
+        Some((sql, params))
+    }
+
+    /// Whether rows of `source` can match, regardless of their subject.
+    fn searches(&self, source: &SourceTable) -> bool {
+        source.exists_for(self.entity_type)
+            && (self.property.is_none() || source.property)
+            && (self.language.is_none() || source.name == "labels")
+    }
+
+    /// The rows of one table; `index` is its position in [`SOURCE_TABLES`].
+    fn source_sql(&self, index: u64, source: &SourceTable) -> Option<(String, Vec<SqlValue>)> {
+        if !self.searches(source) {
+            return None;
+        }
+
+        let mut conditions = vec![];
+        let mut params: Vec<SqlValue> = vec![];
+        let subject = match &source.subject {
+            Some(subject) => {
+                if !self.includes_subject(subject.as_str()) {
+                    return None;
+                }
+                format!("'{}'", subject.as_str())
+            }
+            None => {
+                let types: Vec<&str> = source
+                    .types
+                    .iter()
+                    .filter(|t| self.includes_subject(t))
+                    .copied()
+                    .collect();
+                if types.is_empty() {
+                    return None;
+                }
+                if types.len() < source.types.len() {
+                    conditions.push(format!(
+                        "`t`.`type` IN ({})",
+                        vec!["?"; types.len()].join(",")
+                    ));
+                    params.extend(types.into_iter().map(|t| t.into()));
+                }
+                "`t`.`type`".to_string()
+            }
+        };
+        if let Some(item) = self.item {
+            let items = [vec![item], self.redirect_sources.to_owned()].concat();
+            conditions.push(format!(
+                "`t`.`item` IN ({})",
+                vec!["?"; items.len()].join(",")
+            ));
+            params.extend(items.into_iter().map(|i| i.into()));
+        }
+        if let Some(property) = self.property {
+            conditions.push("`t`.`property`=?".to_string());
+            params.push(property.into());
+        }
+        if let Some(language) = &self.language {
+            conditions.push("`texts`.`value`=?".to_string());
+            params.push(language.as_str().into());
+        }
+        if !self.change_types.is_empty() {
+            let placeholders = vec!["?"; self.change_types.len()].join(",");
+            conditions.push(format!("`t`.`change_type` IN ({placeholders})"));
+            params.extend(self.change_types.iter().map(|ct| ct.as_str().into()));
+        }
+        if let Some(since) = &self.since {
+            conditions.push("`t`.`timestamp`>=?".to_string());
+            params.push(since.as_str().into());
+        }
+        if let Some(until) = &self.until {
+            conditions.push("`t`.`timestamp`<?".to_string());
+            params.push(until.as_str().into());
+        }
+        for month in &self.archived_months {
+            conditions.push("`t`.`timestamp` NOT LIKE ?".to_string());
+            params.push(format!("{month}%").into());
+        }
+        if let Some(after) = &self.after {
+            // Rows are ordered by timestamp, revision, source table and ID, all descending
+            let (revision, after_index, id) = (after.keys[0], after.keys[1], after.keys[2]);
+            let tie = match index.cmp(&after_index) {
+                Ordering::Less => "`t`.`revision`<=?",
+                Ordering::Equal => "(`t`.`revision`<? OR (`t`.`revision`=? AND `t`.`id`<?))",
+                Ordering::Greater => "`t`.`revision`<?",
+            };
+            conditions.push(format!(
+                "`t`.`timestamp`<=? AND (`t`.`timestamp`<? OR {tie})"
+            ));
+            params.push(after.timestamp.as_str().into());
+            params.push(after.timestamp.as_str().into());
+            params.push(revision.into());
+            if index == after_index {
+                params.push(revision.into());
+                params.push(id.into());
+            }
+        }
+
+        let (text, join) = match source.text_column {
+            Some(column) => (
+                "`texts`.`value`",
+                format!(" JOIN `texts` ON `texts`.`id`=`t`.`{column}`"),
+            ),
+            None => ("NULL", String::new()),
+        };
+        let property = match source.property {
+            true => "`t`.`property`",
+            false => "NULL",
+        };
+        let conditions = match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" WHERE {}", conditions.join(" AND ")),
+        };
+        let sql = format!(
+            "SELECT `t`.`item`,`t`.`revision`,{subject} AS `subject`,`t`.`timestamp`,`t`.`change_type`,{text} AS `language`,{property} AS `property`,{index} AS `source`,`t`.`id` FROM `{}` `t`{join}{conditions} ORDER BY `t`.`timestamp` DESC,`t`.`revision` DESC,`t`.`id` DESC LIMIT ?",
+            self.entity_type.table(source.name)
+        );
+        params.push(self.limit.into());
+        Some((sql, params))
+    }
+
+    /// Converts rows to JSON, in the legacy format if requested.
+    pub fn to_json(&self, rows: &[ChangeRow]) -> Result<serde_json::Value> {
+        let mut ret = serde_json::to_value(rows)?;
+        if self.legacy {
+            for row in ret.as_array_mut().into_iter().flatten() {
+                if let Some(row) = row.as_object_mut() {
+                    if let Some(change_type) = row.remove("change_type") {
+                        row.insert("change".to_string(), change_type);
+                    }
+                    row.remove("in_wdqs");
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Items that redirect to `item`, directly or via other redirects.
+    async fn find_redirect_sources(wdrc: &WdRc, item: ItemId) -> Result<Vec<ItemId>> {
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let mut ret: Vec<ItemId> = vec![];
+        let mut targets = vec![item];
+        for _ in 0..MAX_REDIRECT_DEPTH {
+            let sql = format!(
+                "SELECT `source` FROM `redirects` WHERE `target` IN ({})",
+                vec!["?"; targets.len()].join(",")
+            );
+            let sources: Vec<ItemId> = conn
+                .exec_iter(sql, targets)
+                .await?
+                .map_and_drop(from_row::<ItemId>)
+                .await?
+                .into_iter()
+                .filter(|source| *source != item && !ret.contains(source))
+                .collect();
+            if sources.is_empty() {
+                break;
+            }
+            ret.extend(&sources);
+            targets = sources;
+        }
+        Ok(ret)
+    }
+
+    /// The published months listed from their files, with their directories, newest first.
+    async fn archives(&self, wdrc: &WdRc) -> Result<Vec<(String, PathBuf)>> {
+        let cutoff = match wdrc.retention_cutoff() {
+            Some(cutoff) => cutoff,
+            None => return Ok(vec![]),
+        };
+        let sql =
+            "SELECT DISTINCT `month`,`directory` FROM `published_files` ORDER BY `month` DESC";
+        let months: Vec<(String, String)> =
+            wdrc.db().get_connection("wdrc").await?.query(sql).await?;
+        Ok(months
+            .into_iter()
+            .filter(|(month, _)| self.archived(month, &cutoff))
+            .map(|(month, dir)| (month, PathBuf::from(dir)))
+            .collect())
+    }
+
+    /// Whether a published month began before `cutoff`, and may hold rows of the listing.
+    fn archived(&self, month: &str, cutoff: &str) -> bool {
+        let (first, last) = (format!("{month}01000000"), format!("{month}31235959"));
+        first.as_str() < cutoff
+            && self.since.as_ref().is_none_or(|since| last >= *since)
+            && self.until.as_ref().is_none_or(|until| first < *until)
+            && self
+                .after
+                .as_ref()
+                .is_none_or(|after| first <= after.timestamp)
+    }
+
+    /// The matching rows in the files of a published month, up to `limit`. The line number is
+    /// the ID that orders rows of a file with the same timestamp and revision.
+    fn archive_rows(&self, dir: &Path) -> Result<Vec<SourceRow>> {
+        let mut ret = vec![];
+        for (index, source) in SOURCE_TABLES.iter().enumerate() {
+            if !ARCHIVED_TABLES.contains(&source.name) || !self.searches(source) {
+                continue;
+            }
+            let path = dir.join(format!("{}.tsv", self.entity_type.table(source.name)));
+            let file = File::open(&path)
+                .map_err(|e| anyhow!("Cannot read archive {}: {e}", path.display()))?;
+            self.read_archive(BufReader::new(file), index as u64, source, &mut ret)?;
+        }
+        Self::newest_first(&mut ret, self.limit);
+        Ok(ret)
+    }
+
+    /// Adds the matching rows of one archive file, with a header row, to `ret`.
+    fn read_archive(
+        &self,
+        reader: impl BufRead,
+        index: u64,
+        source: &SourceTable,
+        ret: &mut Vec<SourceRow>,
+    ) -> Result<()> {
+        let mut lines = reader.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let columns: Vec<&str> = header.split('\t').collect();
+        let column = |name: &str| columns.iter().position(|column| *column == name);
+        let required = |name: &str| {
+            column(name).ok_or_else(|| anyhow!("Archive of {} has no {name} column", source.name))
+        };
+        let entity = required("entity")?;
+        let revision = required("revision")?;
+        let timestamp = required("timestamp")?;
+        let change_type = required("change_type")?;
+        let (subject_type, language, property) =
+            (column("type"), column("language"), column("property"));
+        let items = [
+            self.item.into_iter().collect(),
+            self.redirect_sources.clone(),
+        ]
+        .concat();
+
+        for (id, line) in lines.enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            let field = |index: Option<usize>| index.and_then(|index| fields.get(index).copied());
+            let bad = || anyhow!("Bad line {} in archive of {}", id + 2, source.name);
+            let row_timestamp = field(Some(timestamp)).ok_or_else(bad)?;
+            let row_change_type = field(Some(change_type)).ok_or_else(bad)?;
+            let item: ItemId = field(Some(entity)).ok_or_else(bad)?.parse()?;
+            let row_revision: RevisionId = field(Some(revision)).ok_or_else(bad)?.parse()?;
+            let subject = match &source.subject {
+                Some(subject) => subject.as_str(),
+                None => field(subject_type).ok_or_else(bad)?,
+            };
+            let row_property = match field(property).filter(|p| !p.is_empty()) {
+                Some(property) => Some(property.parse::<u64>()?),
+                None => None,
+            };
+            let row_language = field(language);
+            let id = id as u64 + 1;
+            let matches = self.includes_subject(subject)
+                && (items.is_empty() || items.contains(&item))
+                && self.property.is_none_or(|p| row_property == Some(p))
+                && self
+                    .language
+                    .as_ref()
+                    .is_none_or(|l| row_language == Some(l.as_str()))
+                && (self.change_types.is_empty()
+                    || self
+                        .change_types
+                        .iter()
+                        .any(|ct| ct.as_str() == row_change_type))
+                && self
+                    .since
+                    .as_ref()
+                    .is_none_or(|s| row_timestamp >= s.as_str())
+                && self
+                    .until
+                    .as_ref()
+                    .is_none_or(|u| row_timestamp < u.as_str())
+                && self.after.as_ref().is_none_or(|after| {
+                    (row_timestamp, row_revision, index, id)
+                        < (
+                            after.timestamp.as_str(),
+                            after.keys[0],
+                            after.keys[1],
+                            after.keys[2],
+                        )
+                });
+            if !matches {
+                continue;
+            }
+            ret.push((
+                item,
+                row_revision,
+                subject.to_string(),
+                row_timestamp.to_string(),
+                row_change_type.to_string(),
+                row_language.map(|l| l.to_string()),
+                row_property,
+                index,
+                id,
+            ));
+            if ret.len() as u64 >= 2 * self.limit {
+                Self::newest_first(ret, self.limit);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sorts rows like the database query does and keeps the first `limit`.
+    fn newest_first(rows: &mut Vec<SourceRow>, limit: u64) {
+        rows.sort_by(|a, b| (&b.3, b.1, b.7, b.8).cmp(&(&a.3, a.1, a.7, a.8)));
+        rows.truncate(limit as usize);
+    }
+
+    /// Lists matching changes, newest first.
+    pub async fn run(&self, wdrc: &WdRc) -> Result<Vec<ChangeRow>> {
+        let wdqs_updated = WdqsLag::updated(wdrc).await?;
+        let mut filter = self.clone();
+        if let (true, Some(item)) = (self.follow_redirects, self.item) {
+            filter.redirect_sources = Self::find_redirect_sources(wdrc, item).await?;
+        }
+        if self.wdqs_only {
+            let updated = wdqs_updated
+                .as_ref()
+                .ok_or_else(|| anyhow!("WDQS lag is not tracked"))?;
+            let until = WdqsLag::until(updated)?;
+            filter.until = match &self.until {
+                Some(until_param) if *until_param < until => Some(until_param.to_owned()),
+                _ => Some(until),
+            };
+        }
+        let archives = filter.archives(wdrc).await?;
+        filter.archived_months = archives.iter().map(|(month, _)| month.clone()).collect();
+        let (sql, params) = match filter.to_sql() {
+            Some(query) => query,
+            None => return Ok(vec![]),
+        };
+        let mut rows: Vec<SourceRow> = wdrc
+            .db()
+            .get_connection("wdrc")
+            .await?
+            .exec_iter(sql, params)
+            .await?
+            .map_and_drop(from_row::<SourceRow>)
+            .await?;
+        // Months are newest first, so older ones cannot make it onto a full page
+        let mut archived = 0;
+        for (_, dir) in &archives {
+            let month_rows = filter.archive_rows(dir)?;
+            archived += month_rows.len() as u64;
+            rows.extend(month_rows);
+            if archived >= self.limit {
+                break;
+            }
+        }
+        Self::newest_first(&mut rows, self.limit);
+        let prefix = self.entity_type.id_prefix();
+        Ok(rows
+            .into_iter()
+            .map(
+                |(
+                    item,
+                    revision,
+                    subject,
+                    timestamp,
+                    change_type,
+                    language,
+                    property,
+                    source,
+                    id,
+                )| ChangeRow {
+                    entity: format!("{prefix}{}", self.item.unwrap_or(item)),
+                    redirected_from: filter
+                        .redirect_sources
+                        .contains(&item)
+                        .then(|| format!("{prefix}{item}")),
+                    revision,
+                    subject,
+                    change_type,
+                    language,
+                    property: property.map(|p| format!("P{p}")),
+                    in_wdqs: wdqs_updated
+                        .as_ref()
+                        .map(|updated| timestamp.as_str() <= updated.as_str()),
+                    cursor: Some(Cursor {
+                        timestamp: timestamp.to_owned(),
+                        keys: vec![revision, source, id],
+                    }),
+                    timestamp,
+                },
+            )
+            .collect())
+    }
+}
+
+/// Which entity events [`EventFilter`] lists.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Creations,
+    Deletions,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Creations => "creations",
+            Self::Deletions => "deletions",
+        }
+    }
+}
+
+/// One creation or deletion, as listed by [`EventFilter`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EventRow {
+    pub entity: String,
+    pub event: String,
+    pub timestamp: String,
+    /// Changes logged for the entity before the event; shows whether a deleted entity was
+    /// substantial or empty.
+    pub prior_changes: u64,
+    /// The position of the row in a listing; the entity ID breaks ties.
+    #[serde(skip)]
+    pub cursor: Cursor,
+}
+
+/// Filters for listing creations or deletions, e.g. `events=deletions&since=20240101&limit=10`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventFilter {
+    pub kind: EventKind,
+    pub entity_type: EntityType,
+    pub item: Option<ItemId>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// The last row of the previous page.
+    pub after: Option<Cursor>,
+    pub limit: u64,
+}
+
+impl Default for EventFilter {
+    fn default() -> Self {
+        Self {
+            kind: EventKind::Creations,
+            entity_type: EntityType::Item,
+            item: None,
+            since: None,
+            until: None,
+            after: None,
+            limit: DEFAULT_LIMIT,
+        }
+    }
+}
+
+impl EventFilter {
+    /// Parses `key=value` pairs separated by `&`. Unknown keys are an error.
+    pub fn from_query(query: &str) -> Result<Self> {
+        Self::from_pairs(&query_pairs(query))
+    }
+
+    /// Parses decoded query parameters.
+    pub fn from_pairs(pairs: &[(String, String)]) -> Result<Self> {
+        let mut ret = Self::default();
+        // Shared parameters have the same meaning as for changes
+        let mut shared = ChangeFilter::default();
+        for (key, value) in pairs {
+            let (key, value) = (key.as_str(), value.as_str());
+            match key {
+                "events" => {
+                    ret.kind = match value {
+                        "creations" => EventKind::Creations,
+                        "deletions" => EventKind::Deletions,
+                        _ => return Err(anyhow!("Unknown events: {value:?}")),
+                    }
+                }
+                "continue" => ret.after = Some(Cursor::parse(value, 1)?),
+                "entity" | "item" | "since" | "until" | "limit" => shared.set(key, value)?,
+                other => return Err(anyhow!("Unknown parameter: {other:?}")),
+            }
+        }
+        ret.entity_type = shared.entity_type;
+        ret.item = shared.item;
+        ret.since = shared.since;
+        ret.until = shared.until;
+        ret.limit = shared.limit;
+        Ok(ret)
+    }
+
+    /// Counts the changes of `e`.`q` before `e`.`timestamp` in each change table.
+    fn prior_changes_sql(&self) -> String {
+        SOURCE_TABLES
+            .iter()
+            .filter(|source| source.exists_for(self.entity_type))
+            .map(|source| {
+                format!(
+                    "(SELECT count(*) FROM `{}` `t` WHERE `t`.`item`=`e`.`q` AND `t`.`timestamp`<`e`.`timestamp`)",
+                    self.entity_type.table(source.name)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("+")
+    }
+
+    fn to_sql(&self) -> (String, Vec<SqlValue>) {
+        let mut conditions = vec![];
+        let mut params: Vec<SqlValue> = vec![];
+        if let Some(item) = self.item {
+            conditions.push("`e`.`q`=?");
+            params.push(item.into());
+        }
+        if let Some(since) = &self.since {
+            conditions.push("`e`.`timestamp`>=?");
+            params.push(since.as_str().into());
+        }
+        if let Some(until) = &self.until {
+            conditions.push("`e`.`timestamp`<?");
+            params.push(until.as_str().into());
+        }
+        if let Some(after) = &self.after {
+            conditions.push("`e`.`timestamp`<=? AND (`e`.`timestamp`<? OR `e`.`q`<?)");
+            params.push(after.timestamp.as_str().into());
+            params.push(after.timestamp.as_str().into());
+            params.push(after.keys[0].into());
+        }
+        let conditions = match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" WHERE {}", conditions.join(" AND ")),
+        };
+        let sql = format!(
+            "SELECT `e`.`q`,`e`.`timestamp`,{} FROM `{}` `e`{conditions} ORDER BY `e`.`timestamp` DESC,`e`.`q` DESC LIMIT ?",
+            self.prior_changes_sql(),
+            self.entity_type.table(self.kind.as_str())
+        );
+        params.push(self.limit.into());
+        (sql, params)
+    }
+
+    /// Lists matching events, newest first.
+    pub async fn run(&self, wdrc: &WdRc) -> Result<Vec<EventRow>> {
+        let (sql, params) = self.to_sql();
+        let rows: Vec<(ItemId, String, u64)> = wdrc
+            .db()
+            .get_connection("wdrc")
+            .await?
+            .exec_iter(sql, params)
+            .await?
+            .map_and_drop(from_row::<(ItemId, String, u64)>)
+            .await?;
+        let prefix = self.entity_type.id_prefix();
+        Ok(rows
+            .into_iter()
+            .map(|(q, timestamp, prior_changes)| EventRow {
+                entity: format!("{prefix}{q}"),
+                event: self.kind.as_str().to_string(),
+                cursor: Cursor {
+                    timestamp: timestamp.to_owned(),
+                    keys: vec![q],
+                },
+                timestamp,
+                prior_changes,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_query() {
+        let filter = ChangeFilter::from_query(
+            "subjects=claims,!aliases&types=added,removed&lang=de&prop=P31",
+        )
+        .unwrap();
+        assert_eq!(filter.subjects, vec![ChangeSubject::Claims]);
+        assert_eq!(filter.exclude_subjects, vec![ChangeSubject::Aliases]);
+        assert_eq!(
+            filter.change_types,
+            vec![ChangeType::Added, ChangeType::Removed]
+        );
+        assert_eq!(filter.language, Some("de".to_string()));
+        assert_eq!(filter.property, Some(31));
+        assert!(ChangeFilter::from_query("subjects=foo").is_err());
+        assert!(ChangeFilter::from_query("colour=red").is_err());
+        assert!(ChangeFilter::from_query("format=legacy").unwrap().legacy);
+        assert!(ChangeFilter::from_query("wdqs=only").unwrap().wdqs_only);
+        assert!(ChangeFilter::from_query("version=1").is_ok());
+        assert!(ChangeFilter::from_query("version=99").is_err());
+        assert!(ChangeFilter::from_query("item=Q42&follow_redirects=true").is_ok());
+        assert!(ChangeFilter::from_query("follow_redirects=true").is_err());
+    }
+
+    #[test]
+    fn test_to_sql() {
+        // A property filter only leaves the tables with a property column
+        let filter = ChangeFilter::from_query("prop=P31&types=added").unwrap();
+        let (sql, params) = filter.to_sql().unwrap();
+        assert_eq!(sql.matches("SELECT").count(), 3);
+        assert!(!sql.contains("`labels`"));
+        assert_eq!(params.len(), 3 * 3 + 1);
+
+        // Negation narrows the `type` column of the labels table
+        let filter = ChangeFilter::from_query("subjects=!aliases,!claims&lang=de").unwrap();
+        let (sql, params) = filter.to_sql().unwrap();
+        assert_eq!(sql.matches("SELECT").count(), 1);
+        assert!(sql.contains("`t`.`type` IN (?,?,?,?,?)"));
+        assert_eq!(params.len(), 5 + 1 + 1 + 1);
+
+        let filter = ChangeFilter::from_query("subjects=forms").unwrap();
+        assert!(filter.to_sql().is_none());
+
+        // Followed redirects widen the item condition
+        let mut filter = ChangeFilter::from_query("item=Q42&subjects=claims").unwrap();
+        filter.redirect_sources = vec![7, 8];
+        let (sql, params) = filter.to_sql().unwrap();
+        assert!(sql.contains("`t`.`item` IN (?,?,?)"));
+        assert_eq!(params.len(), 3 + 1 + 1);
+
+        // A cursor in the qualifiers table compares IDs there only
+        let filter = ChangeFilter::from_query(
+            "subjects=claims,qualifiers,references&continue=20240101000000:7:1:99",
+        )
+        .unwrap();
+        let (sql, params) = filter.to_sql().unwrap();
+        assert_eq!(sql.matches("`t`.`id`<?").count(), 1);
+        assert_eq!(params.len(), (3 + 1) + (5 + 1) + (3 + 1) + 1);
+        assert_eq!(filter.after.unwrap().to_string(), "20240101000000:7:1:99");
+        assert!(ChangeFilter::from_query("continue=20240101000000:7").is_err());
+        assert!(ChangeFilter::from_query("continue=2024:7:1:99").is_err());
+    }
+
+    #[test]
+    fn test_archives() {
+        // Months that began before the cutoff, within the listing's range
+        let filter = ChangeFilter::from_query("since=20240215&until=202404").unwrap();
+        let cutoff = "20240310120000";
+        assert!(!filter.archived("202401", cutoff));
+        assert!(filter.archived("202402", cutoff));
+        assert!(filter.archived("202403", cutoff));
+        assert!(!filter.archived("202404", "20250101000000"));
+        let filter = ChangeFilter::from_query("continue=20240131000000:7:0:1").unwrap();
+        assert!(!filter.archived("202402", cutoff));
+
+        let mut filter = ChangeFilter::from_query("item=Q42&subjects=labels,aliases").unwrap();
+        filter.redirect_sources = vec![7];
+        filter.limit = 2;
+        let labels = &SOURCE_TABLES[3];
+        let archive = "entity\trevision\ttype\ttimestamp\tchange_type\tlanguage
+42\t100\tlabels\t20240201000000\tadded\ten
+42\t101\tdescriptions\t20240202000000\tadded\ten
+7\t102\taliases\t20240203000000\tremoved\tde
+43\t103\tlabels\t20240204000000\tadded\ten
+42\t104\tlabels\t20240205000000\tchanged\tde
+";
+        let mut rows = vec![];
+        filter
+            .read_archive(archive.as_bytes(), 3, labels, &mut rows)
+            .unwrap();
+        ChangeFilter::newest_first(&mut rows, filter.limit);
+        let ids: Vec<u64> = rows.iter().map(|row| row.8).collect();
+        assert_eq!(ids, vec![5, 3]);
+        assert_eq!(rows[1].0, 7);
+        assert_eq!(rows[1].5, Some("de".to_string()));
+
+        // The next page continues after the last row
+        filter.after = Some(Cursor::parse("20240203000000:102:3:3", 3).unwrap());
+        let mut rows = vec![];
+        filter
+            .read_archive(archive.as_bytes(), 3, labels, &mut rows)
+            .unwrap();
+        assert_eq!(rows.iter().map(|row| row.8).collect::<Vec<_>>(), vec![1]);
+
+        let filter = ChangeFilter::from_query("prop=P31").unwrap();
+        let statements = &SOURCE_TABLES[0];
+        let archive = "entity\trevision\tproperty\ttimestamp\tchange_type
+1\t5\t31\t20240201000000\tadded
+1\t6\t279\t20240201000000\tadded
+";
+        let mut rows = vec![];
+        filter
+            .read_archive(archive.as_bytes(), 0, statements, &mut rows)
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].2.as_str(), rows[0].6), ("claims", Some(31)));
+        assert!(filter
+            .read_archive("entity\n1\n".as_bytes(), 0, statements, &mut rows)
+            .is_err());
+    }
+
+    #[test]
+    fn test_event_filter() {
+        let filter =
+            EventFilter::from_query("events=deletions&entity=property&since=2024&limit=10")
+                .unwrap();
+        assert_eq!(filter.kind, EventKind::Deletions);
+        assert_eq!(filter.entity_type, EntityType::Property);
+        assert_eq!(filter.since, Some("2024".to_string()));
+        assert_eq!(filter.limit, 10);
+        let (sql, params) = filter.to_sql();
+        assert!(sql.contains("FROM `property_deletions` `e`"));
+        assert!(sql.contains("`property_statements`"));
+        assert!(!sql.contains("badges"));
+        assert_eq!(params.len(), 2);
+        assert!(EventFilter::from_query("events=merges").is_err());
+    }
+}
@@ -1,32 +1,86 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
 
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use wikimisc::mysql_async::Row;
 
-use crate::{revision_compare::RevisionId, ItemId, WdRc};
+use crate::{
+    change::EntityType, config::TagFilter, drops::DropReason, edit_summary::EditSummary,
+    event_stream::EventStream, revision_compare::RevisionId, wiki::Wiki, ItemId, WdRc,
+};
+
+/// The kind of a `recentchanges` row, from `rc_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RcType {
+    Edit,
+    New,
+    Log,
+    External,
+    Categorize,
+    Other(u8),
+}
+
+impl RcType {
+    pub fn from_number(number: u8) -> Self {
+        match number {
+            0 => Self::Edit,
+            1 => Self::New,
+            3 => Self::Log,
+            5 => Self::External,
+            6 => Self::Categorize,
+            other => Self::Other(other),
+        }
+    }
+
+    /// From the `type` of an EventStreams `recentchange` event.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "edit" => Some(Self::Edit),
+            "new" => Some(Self::New),
+            "log" => Some(Self::Log),
+            "external" => Some(Self::External),
+            "categorize" => Some(Self::Categorize),
+            _ => None,
+        }
+    }
+}
+
+/// Log types whose entries are read from the `logging` table instead.
+const LOG_TYPES_READ_ELSEWHERE: &[&str] = &["delete", "protect", "move", "merge"];
 
 pub struct RecentChanges {
     item_id: ItemId,
-    // rc_id: u64,
+    pub rc_id: u64,
     pub rc_timestamp: String,
-    // pub rc_actor: u64,
+    pub rc_actor: u64,
+    /// User name from the `actor` table.
+    pub actor_name: Option<String>,
+    /// Edit summary from the `comment` table.
+    pub comment_text: Option<String>,
+    /// Change tags, e.g. `OAuth CID: 1776`.
+    pub tags: Vec<String>,
     // pub rc_namespace: u64,
     pub rc_title: String,
     // pub rc_comment_id: String,
     // pub rc_minor: bool,
-    // pub rc_bot: bool,
+    pub rc_bot: bool,
     pub rc_new: bool,
     // pub rc_cur_id: u64,
     pub rc_this_oldid: u64,
     pub rc_last_oldid: u64,
-    // pub rc_type: u64,
+    pub rc_type: RcType,
     // pub rc_source: String,
-    // pub rc_patrolled: bool,
+    /// 0 for unpatrolled, 1 for manually and 2 for autopatrolled edits.
+    pub rc_patrolled: u8,
     // pub rc_ip: Option<String>,
     // pub rc_old_len: Option<u64>,
     // pub rc_new_len: Option<u64>,
     // pub rc_deleted: u64,
     // pub rc_logid: u64,
-    // pub rc_log_type: Option<String>,
+    pub rc_log_type: Option<String>,
     // pub rc_log_action: Option<String>,
     // pub rc_params: Option<String>,
 }
@@ -35,42 +89,124 @@ impl RecentChanges {
     pub fn from_row(row: Row) -> Option<RecentChanges> {
         let mut ret = RecentChanges {
             item_id: 0,
-            // rc_id: row.get("rc_id")?,
+            rc_id: row.get("rc_id")?,
             rc_timestamp: row.get("rc_timestamp")?,
-            // rc_actor: row.get("rc_actor")?,
+            rc_actor: row.get("rc_actor")?,
+            actor_name: row.get::<Option<String>, _>("actor_name").flatten(),
+            comment_text: row.get::<Option<String>, _>("comment_text").flatten(),
+            tags: split_tags(row.get::<Option<String>, _>("tags").flatten()),
             // rc_namespace: row.get("rc_namespace")?,
             rc_title: row.get("rc_title")?,
             // rc_comment_id: row.get("rc_comment_id")?,
             // rc_minor: row.get("rc_minor")?,
-            // rc_bot: row.get("rc_bot")?,
+            rc_bot: row.get("rc_bot")?,
             rc_new: row.get("rc_new")?,
             // rc_cur_id: row.get("rc_cur_id")?,
             rc_this_oldid: row.get("rc_this_oldid")?,
             rc_last_oldid: row.get("rc_last_oldid")?,
-            // rc_type: row.get("rc_type")?,
+            rc_type: RcType::from_number(row.get("rc_type")?),
             // rc_source: row.get("rc_source")?,
-            // rc_patrolled: row.get("rc_patrolled")?,
+            rc_patrolled: row.get("rc_patrolled")?,
             // rc_ip: row.get("rc_ip"),
             // rc_old_len: row.get("rc_old_len"),
             // rc_new_len: row.get("rc_new_len"),
             // rc_deleted: row.get("rc_deleted")?,
             // rc_logid: row.get("rc_logid")?,
-            // rc_log_type: row.get("rc_log_type"),
+            rc_log_type: row.get::<Option<String>, _>("rc_log_type").flatten(),
             // rc_log_action: row.get("rc_log_action"),
             // rc_params: row.get("rc_params"),
         };
         ret.item_id = WdRc::make_id_numeric(&ret.rc_title).ok()?;
         Some(ret)
     }
+
+    /// Creates an entry from an EventStreams `recentchange` event, if it concerns an entity on `wiki`.
+    pub fn from_event(j: &Value, wiki: Wiki) -> Option<RecentChanges> {
+        if j["wiki"].as_str()? != wiki.dbname() {
+            return None;
+        }
+        let entity_type = EntityType::from_namespace(j["namespace"].as_u64()?)?;
+        let rc_type = RcType::from_name(j["type"].as_str()?)?;
+        // Only edits and creations are sure to have a revision
+        let rc_this_oldid = match rc_type {
+            RcType::Edit | RcType::New => j["revision"]["new"].as_u64()?,
+            _ => j["revision"]["new"].as_u64().unwrap_or(0),
+        };
+        // Titles outside the main namespace carry a prefix, unlike `rc_title` on the replica
+        let rc_title = j["title"].as_str()?;
+        let rc_title = rc_title
+            .strip_prefix(entity_type.title_prefix())
+            .unwrap_or(rc_title)
+            .to_string();
+        Some(RecentChanges {
+            item_id: WdRc::make_id_numeric(&rc_title).ok()?,
+            rc_id: j["id"].as_u64().unwrap_or(0),
+            rc_actor: 0,
+            actor_name: j["user"].as_str().map(|user| user.to_string()),
+            comment_text: j["comment"].as_str().map(|comment| comment.to_string()),
+            tags: vec![], // Not part of the events
+            rc_timestamp: EventStream::event_timestamp(j["timestamp"].as_i64()?)?,
+            rc_title,
+            rc_new: rc_type == RcType::New,
+            rc_bot: j["bot"].as_bool().unwrap_or(false),
+            rc_patrolled: match j["patrolled"].as_bool() {
+                Some(true) => 1,
+                _ => 0,
+            },
+            rc_this_oldid,
+            rc_last_oldid: j["revision"]["old"].as_u64().unwrap_or(0),
+            rc_type,
+            rc_log_type: j["log_type"].as_str().map(|log_type| log_type.to_string()),
+        })
+    }
+
+    /// Why a row that is neither an edit nor a creation is not compared. Imports are compared
+    /// like creations, if they name the imported revision.
+    fn ignore_reason(&self) -> Option<DropReason> {
+        match self.rc_type {
+            RcType::Edit | RcType::New => None,
+            RcType::Log => match self.rc_log_type.as_deref() {
+                Some("import") if self.rc_this_oldid > 0 => None,
+                Some(log_type) if LOG_TYPES_READ_ELSEWHERE.contains(&log_type) => {
+                    Some(DropReason::LoggedElsewhere)
+                }
+                _ => Some(DropReason::UnhandledLogEntry),
+            },
+            RcType::External => Some(DropReason::ExternalChange),
+            RcType::Categorize => Some(DropReason::Categorization),
+            RcType::Other(_) => Some(DropReason::UnknownRcType),
+        }
+    }
+
+    /// Whether a row that is not ignored created the entity, by a first edit or an import.
+    fn is_creation(&self) -> bool {
+        self.rc_new || self.rc_type == RcType::Log
+    }
+}
+
+/// Splits a `|`-separated tag list.
+fn split_tags(tags: Option<String>) -> Vec<String> {
+    match tags {
+        Some(tags) if !tags.is_empty() => tags.split('|').map(|tag| tag.to_string()).collect(),
+        _ => vec![],
+    }
 }
 
-#[derive(Debug)]
+/// An item created within the current batch. As JSON: `q` and `timestamp`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NewItem {
     q: String,
     timestamp: String,
 }
 
 impl NewItem {
+    pub fn new(q: &str, timestamp: &str) -> Self {
+        Self {
+            q: q.to_string(),
+            timestamp: timestamp.to_string(),
+        }
+    }
+
     pub fn q(&self) -> &str {
         &self.q
     }
@@ -80,15 +216,83 @@ impl NewItem {
     }
 }
 
-#[derive(Debug)]
+/// An existing item edited within the current batch, with the revision range to compare.
+///
+/// As JSON: `q`, `rev_old`, `rev_new`, `timestamp`, `user`, `comment`, `is_bot` and `tags`,
+/// named like the accessors; the last four are optional when reading.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChangedItem {
     q: String,
+    #[serde(rename = "rev_old")]
     old: RevisionId,
+    #[serde(rename = "rev_new")]
     new: RevisionId,
     timestamp: String,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    comment: Option<String>,
+    #[serde(default)]
+    is_bot: bool,
+    #[serde(default)]
+    tags: Vec<String>,
 }
 
 impl ChangedItem {
+    pub fn new(q: &str, old: RevisionId, new: RevisionId, timestamp: &str) -> Self {
+        Self {
+            q: q.to_string(),
+            old,
+            new,
+            timestamp: timestamp.to_string(),
+            user: None,
+            comment: None,
+            is_bot: false,
+            tags: vec![],
+        }
+    }
+
+    /// Reads an item queued in `failed_items` or `work_queue`.
+    pub fn from_row(row: &Row) -> Option<Self> {
+        let q: String = row.get("q")?;
+        let old: RevisionId = row.get("rev_old")?;
+        let new: RevisionId = row.get("rev_new")?;
+        let timestamp: String = row.get("timestamp")?;
+        let user: Option<String> = row.get::<Option<String>, _>("user").flatten();
+        let comment: Option<String> = row.get::<Option<String>, _>("comment").flatten();
+        Some(
+            Self::new(&q, old, new, &timestamp)
+                .with_user(user.as_deref())
+                .with_comment(comment.as_deref())
+                .with_bot(row.get("is_bot")?)
+                .with_tags(&split_tags(row.get::<Option<String>, _>("tags").flatten())),
+        )
+    }
+
+    /// Sets the user name of the editor.
+    pub fn with_user(mut self, user: Option<&str>) -> Self {
+        self.user = user.map(|user| user.to_string());
+        self
+    }
+
+    /// Sets whether the edit was flagged as a bot edit.
+    pub fn with_bot(mut self, is_bot: bool) -> Self {
+        self.is_bot = is_bot;
+        self
+    }
+
+    /// Sets the change tags.
+    pub fn with_tags(mut self, tags: &[String]) -> Self {
+        self.tags = tags.to_vec();
+        self
+    }
+
+    /// Sets the edit summary.
+    pub fn with_comment(mut self, comment: Option<&str>) -> Self {
+        self.comment = comment.map(|comment| comment.to_string());
+        self
+    }
+
     pub fn q(&self) -> &str {
         &self.q
     }
@@ -104,23 +308,206 @@ impl ChangedItem {
     pub fn timestamp(&self) -> &str {
         &self.timestamp
     }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    pub fn is_bot(&self) -> bool {
+        self.is_bot
+    }
+
+    /// The change tags; for several compared edits, those of any of them.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+}
+
+/// A changed item whose comparison failed, queued in `failed_items` for another attempt.
+#[derive(Debug, Clone)]
+pub struct FailedItem {
+    pub item: ChangedItem,
+    pub attempts: u32,
+}
+
+impl FailedItem {
+    pub fn from_row(row: Row) -> Option<Self> {
+        Some(Self {
+            item: ChangedItem::from_row(&row)?,
+            attempts: row.get("attempts")?,
+        })
+    }
+}
+
+/// How a batch of recent changes is turned into items to compare.
+#[derive(Debug, Clone, Default)]
+pub struct BatchOptions {
+    /// Compare every edit on its own, instead of merging edits to the same item.
+    pub per_revision: bool,
+    pub skip_bot_edits: bool,
+    pub tags: TagFilter,
+}
+
+impl BatchOptions {
+    /// Whether an edit is left out; creations are never left out.
+    fn skips(&self, rc: &RecentChanges) -> bool {
+        (self.skip_bot_edits && rc.rc_bot) || !self.tags.allows(&rc.tags)
+    }
+}
+
+/// The default wall time per batch when adapting.
+const BATCH_TIME_BUDGET: Duration = Duration::from_secs(60);
+/// Weight of the last batch in the measured throughput.
+const THROUGHPUT_WEIGHT: f64 = 0.5;
+/// The smallest time span read per batch when adapting.
+const MIN_BATCH_WINDOW: Duration = Duration::from_secs(60);
+/// How far the adapted window and row limit may grow beyond, or shrink below, the configured ones.
+const BATCH_SIZE_FACTOR: u32 = 16;
+
+/// The time span and row limit of the next batch of recent changes. If adaptive, the limit is
+/// the number of rows the measured throughput gets through within the time budget; sparse
+/// batches widen the window, and slow ones shrink it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchSize {
+    window: Duration,
+    limit: u64,
+    configured_window: Duration,
+    configured_limit: u64,
+    adaptive: bool,
+    time_budget: Duration,
+    /// Rows processed per second, averaged over recent batches.
+    throughput: Option<f64>,
 }
 
-#[derive(Debug)]
+impl BatchSize {
+    pub fn new(window: Duration, limit: u64, adaptive: bool) -> Self {
+        Self {
+            window,
+            limit,
+            configured_window: window,
+            configured_limit: limit,
+            adaptive,
+            time_budget: BATCH_TIME_BUDGET,
+            throughput: None,
+        }
+    }
+
+    pub fn with_time_budget(mut self, time_budget: Duration) -> Self {
+        self.time_budget = time_budget;
+        self
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Adapts to a batch of `rows` recent changes that took `elapsed` to process.
+    pub fn adjust(&mut self, rows: u64, elapsed: Duration) {
+        if !self.adaptive {
+            return;
+        }
+        let factor = BATCH_SIZE_FACTOR as u64;
+        let min_limit = (self.configured_limit / factor).max(1);
+        let max_limit = self.configured_limit.saturating_mul(factor);
+        let min_window = (self.configured_window / BATCH_SIZE_FACTOR).max(MIN_BATCH_WINDOW);
+        let max_window = self.configured_window.saturating_mul(BATCH_SIZE_FACTOR);
+        let over_budget = elapsed > self.time_budget;
+        let sparse = rows < self.limit / 4;
+        // Small batches are dominated by fixed costs, so they tell little about throughput
+        if (over_budget || (rows > 0 && !sparse)) && !elapsed.is_zero() {
+            let measured = rows as f64 / elapsed.as_secs_f64();
+            let throughput = match self.throughput {
+                Some(throughput) => {
+                    throughput * (1.0 - THROUGHPUT_WEIGHT) + measured * THROUGHPUT_WEIGHT
+                }
+                None => measured,
+            };
+            self.throughput = Some(throughput);
+            self.limit =
+                ((throughput * self.time_budget.as_secs_f64()) as u64).clamp(min_limit, max_limit);
+        }
+        if over_budget {
+            self.window = (self.window / 2).max(min_window);
+        } else if sparse {
+            self.window = self.window.saturating_mul(2).min(max_window);
+        }
+    }
+}
+
+/// A batch of recent changes, split into new and changed items.
+///
+/// As JSON: `new_items`, `changed_items`, `creations`, `last_rc_id`, `last_skipped`, `ignored`
+/// (counts by drop reason name) and `rows`, named like the accessors.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RecentChangesResults {
     new_items: Vec<NewItem>,
     changed_items: Vec<ChangedItem>,
+    /// The creation revisions of new items, compared to an empty entity.
+    creations: Vec<ChangedItem>,
+    last_rc_id: Option<u64>,
+    /// Timestamp of the last edit left out.
+    last_skipped: Option<String>,
+    /// Rows that are neither edits nor creations, per reason.
+    ignored: BTreeMap<DropReason, u64>,
+    /// Number of recent changes in the batch.
+    rows: u64,
 }
 
 impl RecentChangesResults {
-    pub fn new(results: &Vec<RecentChanges>) -> Self {
+    /// Splits a batch into new and changed items. Edits to the same item are merged into one
+    /// comparison, unless `per_revision` is set, in which case every edit is compared on its own.
+    /// An edit left out ends the merged range before it, so its changes are not compared; this
+    /// relies on the edits of an item coming in revision order.
+    pub fn new(results: &[RecentChanges], options: &BatchOptions) -> Self {
         let mut new_items: HashMap<String, NewItem> = HashMap::new();
         let mut changed_items: HashMap<String, ChangedItem> = HashMap::new();
+        let mut revisions = vec![];
+        let mut creations = vec![];
+        let mut last_skipped: Option<String> = None;
+        let mut ignored: BTreeMap<DropReason, u64> = BTreeMap::new();
         for result in results {
             let q = result.rc_title.clone();
             let timestamp = result.rc_timestamp.clone();
-            if result.rc_new {
+            let ignore_reason = result.ignore_reason();
+            if let Some(reason) = ignore_reason {
+                *ignored.entry(reason).or_default() += 1;
+            }
+            if ignore_reason.is_none() && result.is_creation() {
+                if !options.skips(result) {
+                    creations.push(
+                        ChangedItem::new(&q, 0, result.rc_this_oldid, &timestamp)
+                            .with_user(result.actor_name.as_deref())
+                            .with_comment(result.comment_text.as_deref())
+                            .with_bot(result.rc_bot)
+                            .with_tags(&result.tags),
+                    );
+                }
                 new_items.insert(q.clone(), NewItem { q, timestamp });
+            } else if ignore_reason.is_some() || options.skips(result) {
+                if ignore_reason.is_none() {
+                    if let Some(ci) = changed_items.remove(&q) {
+                        revisions.push(ci);
+                    }
+                }
+                if last_skipped.as_ref().is_none_or(|t| *t < timestamp) {
+                    last_skipped = Some(timestamp);
+                }
+            } else if options.per_revision {
+                revisions.push(
+                    ChangedItem::new(&q, result.rc_last_oldid, result.rc_this_oldid, &timestamp)
+                        .with_user(result.actor_name.as_deref())
+                        .with_comment(result.comment_text.as_deref())
+                        .with_bot(result.rc_bot)
+                        .with_tags(&result.tags),
+                );
             } else {
                 let old = result.rc_last_oldid;
                 let new = result.rc_this_oldid;
@@ -129,6 +516,18 @@ impl RecentChangesResults {
                         if ci.new < new {
                             ci.new = new;
                         }
+                        if ci.user != result.actor_name {
+                            ci.user = None;
+                        }
+                        if ci.comment != result.comment_text {
+                            ci.comment = None;
+                        }
+                        ci.is_bot &= result.rc_bot;
+                        for tag in &result.tags {
+                            if !ci.tags.contains(tag) {
+                                ci.tags.push(tag.to_owned());
+                            }
+                        }
                     }
                     None => {
                         changed_items.insert(
@@ -138,6 +537,10 @@ impl RecentChangesResults {
                                 timestamp,
                                 new,
                                 old,
+                                user: result.actor_name.to_owned(),
+                                comment: result.comment_text.to_owned(),
+                                is_bot: result.rc_bot,
+                                tags: result.tags.to_owned(),
                             },
                         );
                     }
@@ -146,23 +549,56 @@ impl RecentChangesResults {
         }
         Self {
             new_items: new_items.into_values().collect(),
-            changed_items: changed_items.into_values().collect(),
+            changed_items: changed_items.into_values().chain(revisions).collect(),
+            creations,
+            last_rc_id: results.iter().map(|r| r.rc_id).filter(|id| *id > 0).max(),
+            last_skipped,
+            ignored,
+            rows: results.len() as u64,
         }
     }
 
-    /// Returns the last timestamp of the changed items, or the given oldest timestamp as fallback.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn ignored(&self) -> &BTreeMap<DropReason, u64> {
+        &self.ignored
+    }
+
+    /// Returns the last timestamp of the changed items and skipped edits, if any.
+    pub fn last_timestamp(&self) -> Option<&str> {
+        self.changed_items
+            .iter()
+            .map(|r| r.timestamp.as_str())
+            .chain(self.last_skipped.as_deref())
+            .max()
+    }
+
+    /// Returns the last timestamp of the changed items and skipped edits, or the given oldest timestamp as fallback.
     pub fn get_last_rc_timetamp(&self, oldest: &str) -> String {
-        match self.changed_items.iter().map(|r| &r.timestamp).max() {
-            Some(t) => t.to_owned(),
-            None => oldest.to_string(),
-        }
+        self.last_timestamp().unwrap_or(oldest).to_string()
+    }
+
+    /// Returns the highest `rc_id` in the batch, new items included.
+    pub fn last_rc_id(&self) -> Option<u64> {
+        self.last_rc_id
     }
 
-    pub fn new_items(&self) -> &Vec<NewItem> {
+    /// Returns the timestamp of the last edit left out, if any.
+    pub fn last_skipped(&self) -> Option<&str> {
+        self.last_skipped.as_deref()
+    }
+
+    pub fn new_items(&self) -> &[NewItem] {
         &self.new_items
     }
 
-    pub fn changed_items(&self) -> &Vec<ChangedItem> {
+    pub fn creations(&self) -> &[ChangedItem] {
+        &self.creations
+    }
+
+    pub fn changed_items(&self) -> &[ChangedItem] {
         &self.changed_items
     }
 }
@@ -218,3 +654,350 @@ impl RecentDeletions {
         &self.timestamp
     }
 }
+
+/// A `protect`, `move`, or `merge` log entry for an item.
+#[derive(Debug, Clone)]
+pub struct RecentLogEvents {
+    q: String,
+    log_type: String,
+    action: String,
+    timestamp: String,
+}
+
+impl RecentLogEvents {
+    pub fn from_row(row: Row) -> Option<Self> {
+        Some(Self {
+            q: row.get("q")?,
+            log_type: row.get("log_type")?,
+            action: row.get("action")?,
+            timestamp: row.get("timestamp")?,
+        })
+    }
+
+    pub fn q(&self) -> &str {
+        &self.q
+    }
+
+    pub fn log_type(&self) -> &str {
+        &self.log_type
+    }
+
+    pub fn action(&self) -> &str {
+        &self.action
+    }
+
+    pub fn timestamp(&self) -> &str {
+        &self.timestamp
+    }
+}
+
+/// An edit merging an item into another, on an item that is now a redirect.
+#[derive(Clone, Debug)]
+pub struct RecentMerges {
+    source: String,
+    redirect_target: String,
+    revision: RevisionId,
+    comment: String,
+    timestamp: String,
+}
+
+impl RecentMerges {
+    pub fn from_row(row: Row) -> Option<Self> {
+        Some(Self {
+            source: row.get("source")?,
+            redirect_target: row.get("target")?,
+            revision: row.get("revision")?,
+            comment: row.get("comment")?,
+            timestamp: row.get("timestamp")?,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The entity merged into, if the edit summary is the merge `action` and names the redirect
+    /// target; otherwise the redirect was created separately.
+    pub fn target(&self, action: &str) -> Option<&str> {
+        let summary = EditSummary::parse(&self.comment);
+        if summary.action.as_deref() != Some(action) {
+            return None;
+        }
+        match summary.args.last() {
+            Some(target) if *target == self.redirect_target => Some(&self.redirect_target),
+            _ => None,
+        }
+    }
+
+    pub fn revision(&self) -> RevisionId {
+        self.revision
+    }
+
+    pub fn timestamp(&self) -> &str {
+        &self.timestamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_size() {
+        let hour = Duration::from_secs(60 * 60);
+        let mut fixed = BatchSize::new(hour, 500, false);
+        fixed.adjust(500, Duration::from_secs(1));
+        assert_eq!((fixed.window(), fixed.limit()), (hour, 500));
+
+        let mut batch = BatchSize::new(hour, 500, true);
+        // 100 rows per second fill the 60 second budget with 6000 rows
+        batch.adjust(500, Duration::from_secs(5));
+        assert_eq!((batch.window(), batch.limit()), (hour, 6000));
+        batch.adjust(10, Duration::from_secs(5));
+        assert_eq!((batch.window(), batch.limit()), (hour * 2, 6000));
+        batch.adjust(6000, Duration::from_secs(120));
+        assert_eq!((batch.window(), batch.limit()), (hour, 4500));
+        let mut budget = BatchSize::new(hour, 500, true).with_time_budget(Duration::from_secs(10));
+        budget.adjust(500, Duration::from_secs(5));
+        assert_eq!(budget.limit(), 1000);
+        for _ in 0..20 {
+            batch.adjust(0, Duration::from_secs(600));
+        }
+        assert_eq!(batch.limit(), 31);
+        assert_eq!(batch.window(), Duration::from_secs(225));
+        for _ in 0..20 {
+            batch.adjust(0, Duration::from_secs(1));
+        }
+        assert_eq!(batch.window(), hour * 16);
+    }
+
+    fn edit(rc_id: u64, q: &str, old: u64, new: u64, user: &str) -> RecentChanges {
+        RecentChanges {
+            item_id: WdRc::make_id_numeric(q).unwrap(),
+            rc_id,
+            rc_actor: 0,
+            actor_name: Some(user.to_string()),
+            comment_text: None,
+            tags: vec![],
+            rc_bot: user.ends_with("Bot"),
+            rc_patrolled: 0,
+            rc_timestamp: format!("2024010100000{rc_id}"),
+            rc_title: q.to_string(),
+            rc_new: false,
+            rc_this_oldid: new,
+            rc_last_oldid: old,
+            rc_type: RcType::Edit,
+            rc_log_type: None,
+        }
+    }
+
+    #[test]
+    fn test_recent_changes_results() {
+        let results = vec![
+            edit(1, "Q1", 10, 11, "Alice"),
+            edit(2, "Q1", 11, 12, "Bob"),
+            edit(3, "Q2", 20, 21, "Alice"),
+        ];
+
+        let rc = RecentChangesResults::new(&results, &BatchOptions::default());
+        let mut merged = rc.changed_items().to_owned();
+        merged.sort_by_key(|ci| ci.rev_new());
+        assert_eq!(
+            merged
+                .iter()
+                .map(|ci| (ci.rev_old(), ci.rev_new()))
+                .collect::<Vec<_>>(),
+            vec![(10, 12), (20, 21)]
+        );
+        // Merged edits by several users are not attributed
+        assert_eq!(merged[0].user(), None);
+        assert_eq!(merged[1].user(), Some("Alice"));
+        assert_eq!(rc.last_rc_id(), Some(3));
+
+        let rc = RecentChangesResults::new(
+            &results,
+            &BatchOptions {
+                per_revision: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(rc.changed_items().len(), 3);
+        let second = rc
+            .changed_items()
+            .iter()
+            .find(|ci| ci.rev_new() == 12)
+            .unwrap();
+        assert_eq!(
+            (second.rev_old(), second.timestamp(), second.user()),
+            (11, "20240101000002", Some("Bob"))
+        );
+
+        // New items are also compared to an empty entity
+        let mut creation = edit(4, "Q3", 0, 30, "Carol");
+        creation.rc_new = true;
+        let rc = RecentChangesResults::new(&[creation], &BatchOptions::default());
+        assert_eq!(rc.new_items()[0].q(), "Q3");
+        assert_eq!(
+            (rc.creations()[0].rev_old(), rc.creations()[0].rev_new()),
+            (0, 30)
+        );
+    }
+
+    #[test]
+    fn test_rc_types() {
+        let mut import = edit(1, "Q1", 0, 10, "Alice");
+        import.rc_type = RcType::Log;
+        import.rc_log_type = Some("import".to_string());
+        let mut deletion = edit(2, "Q2", 0, 0, "Alice");
+        deletion.rc_type = RcType::Log;
+        deletion.rc_log_type = Some("delete".to_string());
+        let mut patrol = edit(3, "Q3", 0, 0, "Alice");
+        patrol.rc_type = RcType::Log;
+        patrol.rc_log_type = Some("patrol".to_string());
+        let mut external = edit(4, "Q4", 0, 0, "Alice");
+        external.rc_type = RcType::from_number(5);
+        let rc = RecentChangesResults::new(
+            &[import, deletion, patrol, external],
+            &BatchOptions::default(),
+        );
+        assert_eq!(
+            (rc.creations()[0].q(), rc.creations()[0].rev_new()),
+            ("Q1", 10)
+        );
+        assert!(rc.changed_items().is_empty());
+        assert_eq!(
+            rc.ignored(),
+            &BTreeMap::from([
+                (DropReason::LoggedElsewhere, 1),
+                (DropReason::UnhandledLogEntry, 1),
+                (DropReason::ExternalChange, 1),
+            ])
+        );
+        // Ignored rows still advance the checkpoints
+        assert_eq!(rc.last_timestamp(), Some("20240101000004"));
+        let json = serde_json::to_value(&rc).unwrap();
+        assert_eq!(json["ignored"]["logged_elsewhere"], 1);
+        assert_eq!(json["creations"][0]["rev_new"], 10);
+        assert_eq!(
+            serde_json::from_value::<RecentChangesResults>(json).unwrap(),
+            rc
+        );
+    }
+
+    #[test]
+    fn test_skip_bot_edits() {
+        let skip_bots = BatchOptions {
+            skip_bot_edits: true,
+            ..Default::default()
+        };
+        let results = vec![
+            edit(1, "Q1", 10, 11, "ExampleBot"),
+            edit(2, "Q1", 11, 12, "ExampleBot"),
+            edit(3, "Q2", 20, 21, "Alice"),
+        ];
+        let rc = RecentChangesResults::new(&results, &BatchOptions::default());
+        let bot_edit = rc.changed_items().iter().find(|ci| ci.q() == "Q1").unwrap();
+        assert!(bot_edit.is_bot());
+
+        let rc = RecentChangesResults::new(&results, &skip_bots);
+        assert_eq!(rc.changed_items().len(), 1);
+        assert_eq!(rc.changed_items()[0].q(), "Q2");
+        // Skipped edits still advance the checkpoints
+        assert_eq!(rc.last_rc_id(), Some(3));
+        assert_eq!(rc.last_timestamp(), Some("20240101000003"));
+        let results: Vec<RecentChanges> = results.into_iter().take(2).collect();
+        let rc = RecentChangesResults::new(&results, &skip_bots);
+        assert_eq!(rc.last_timestamp(), Some("20240101000002"));
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut results = vec![
+            edit(1, "Q1", 10, 11, "Alice"),
+            edit(2, "Q1", 11, 12, "Alice"),
+            edit(3, "Q2", 20, 21, "Alice"),
+        ];
+        results[0].tags = vec!["OAuth CID: 1776".to_string()];
+        results[1].tags = vec!["mobile edit".to_string(), "OAuth CID: 1776".to_string()];
+        let rc = RecentChangesResults::new(&results, &BatchOptions::default());
+        let merged = rc.changed_items().iter().find(|ci| ci.q() == "Q1").unwrap();
+        assert_eq!(merged.tags(), ["OAuth CID: 1776", "mobile edit"]);
+
+        let options = BatchOptions {
+            tags: TagFilter {
+                allow: vec![],
+                deny: vec!["mobile edit".to_string()],
+            },
+            ..Default::default()
+        };
+        let rc = RecentChangesResults::new(&results, &options);
+        let q1 = rc.changed_items().iter().find(|ci| ci.q() == "Q1").unwrap();
+        assert_eq!((q1.rev_old(), q1.rev_new()), (10, 11));
+    }
+
+    #[test]
+    fn test_merge_around_skipped() {
+        let mut results = vec![
+            edit(1, "Q1", 10, 11, "Alice"),
+            edit(2, "Q1", 11, 12, "ExampleBot"),
+            edit(3, "Q1", 12, 13, "Alice"),
+            edit(4, "Q1", 13, 14, "Alice"),
+            edit(5, "Q1", 14, 15, "Alice"),
+            edit(6, "Q1", 15, 16, "Alice"),
+        ];
+        results[4].tags = vec!["mobile edit".to_string()];
+        let options = BatchOptions {
+            skip_bot_edits: true,
+            tags: TagFilter {
+                allow: vec![],
+                deny: vec!["mobile edit".to_string()],
+            },
+            ..Default::default()
+        };
+        let rc = RecentChangesResults::new(&results, &options);
+        let mut ranges: Vec<(u64, u64)> = rc
+            .changed_items()
+            .iter()
+            .map(|ci| (ci.rev_old(), ci.rev_new()))
+            .collect();
+        ranges.sort();
+        // The bot edit 11→12 and the mobile edit 14→15 are not compared
+        assert_eq!(ranges, vec![(10, 11), (12, 14), (15, 16)]);
+        assert_eq!(rc.last_timestamp(), Some("20240101000006"));
+    }
+
+    #[test]
+    fn test_changed_item_json() {
+        let ci = ChangedItem::new("Q42", 1, 2, "20240101000000").with_user(Some("A"));
+        let j = serde_json::to_value(&ci).unwrap();
+        assert_eq!(j["rev_old"], 1);
+        assert_eq!(j["rev_new"], 2);
+        assert_eq!(serde_json::from_value::<ChangedItem>(j).unwrap(), ci);
+        let minimal: ChangedItem = serde_json::from_str(
+            r#"{"q":"Q42","rev_old":1,"rev_new":2,"timestamp":"20240101000000"}"#,
+        )
+        .unwrap();
+        assert_eq!(minimal, ChangedItem::new("Q42", 1, 2, "20240101000000"));
+    }
+
+    #[test]
+    fn test_merge_target() {
+        let merge = |comment: &str| RecentMerges {
+            source: "Q1".to_string(),
+            redirect_target: "Q2".to_string(),
+            revision: 10,
+            comment: comment.to_string(),
+            timestamp: "20240101000000".to_string(),
+        };
+        let items = EntityType::Item.merge_action().unwrap();
+        assert_eq!(
+            merge("/* wbmergeitems-to:0||Q2 */").target(items),
+            Some("Q2")
+        );
+        assert_eq!(merge("/* wbmergeitems-to:0||Q3 */").target(items), None);
+        assert_eq!(merge("/* wbcreateredirect:0||Q1|Q2 */").target(items), None);
+        let lexemes = EntityType::Lexeme.merge_action().unwrap();
+        assert_eq!(merge("/* wbmergeitems-to:0||Q2 */").target(lexemes), None);
+        assert_eq!(EntityType::Property.merge_action(), None);
+    }
+}
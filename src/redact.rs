@@ -0,0 +1,292 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use serde::Serialize;
+use wikimisc::mysql_async::{prelude::Queryable, Conn, Value as SqlValue};
+
+use crate::{change::EntityType, publish::Publisher, tombstones::Tombstone, RevisionId, WdRc};
+
+/// Rows removed from one table by a redaction.
+#[derive(Debug, Clone, Serialize)]
+pub struct RedactedTable {
+    pub table: String,
+    pub rows: u64,
+}
+
+/// The outcome of a redaction, as recorded in the `redactions` table.
+#[derive(Debug, Clone, Serialize)]
+pub struct Redaction {
+    pub revisions: Vec<RevisionId>,
+    /// The user redacted; shown, but not recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub reason: String,
+    pub timestamp: String,
+    pub tables: Vec<RedactedTable>,
+    /// Published dataset months written again without the redacted changes.
+    pub republished: Vec<String>,
+}
+
+/// Tables outside the change log that name revisions, with the columns naming them. Their rows
+/// are deleted without tombstones.
+const OTHER_REVISION_TABLES: &[(&str, &[&str])] = &[
+    ("notifications", &["revision"]),
+    ("failed_items", &["rev_old", "rev_new"]),
+    ("work_queue", &["rev_old", "rev_new"]),
+];
+
+/// Like [`OTHER_REVISION_TABLES`], but one table per entity type.
+const OTHER_ENTITY_REVISION_TABLES: &[(&str, &[&str])] = &[("merges", &["revision"])];
+
+/// Change tables with a `user` column.
+const USER_CHANGE_TABLES: &[&str] = &["statements", "labels"];
+
+/// Queues with a `user` column.
+const USER_QUEUE_TABLES: &[&str] = &["failed_items", "work_queue"];
+
+/// Removes all logged changes of suppressed revisions, or the name of a suppressed user, and
+/// records what was removed.
+///
+/// Revisions are removed from the change tables, leaving tombstones, and from notifications,
+/// merges and the queues. A user name is cleared from the change tables and queues, and the
+/// user's editing sessions are removed; the redaction is recorded with revision 0 and without
+/// the name. Published dataset months with changes of redacted revisions are written again,
+/// with new checksums in their manifests and records, and listed in the redaction; datasets
+/// have no user names, so user redactions leave them as they are.
+pub struct Redactor<'a> {
+    wdrc: &'a WdRc,
+}
+
+impl<'a> Redactor<'a> {
+    pub fn new(wdrc: &'a WdRc) -> Self {
+        Self { wdrc }
+    }
+
+    /// Parses revision IDs separated by commas or whitespace.
+    pub fn parse_revisions(s: &str) -> Result<Vec<RevisionId>> {
+        let mut ret = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                part.parse::<RevisionId>()
+                    .map_err(|_| anyhow!("Not a revision ID: {part:?}"))
+            })
+            .collect::<Result<Vec<RevisionId>>>()?;
+        ret.sort();
+        ret.dedup();
+        Ok(ret)
+    }
+
+    /// All change tables, which have `item` and `revision` columns.
+    pub(crate) fn revision_tables() -> Vec<String> {
+        let mut ret = vec![];
+        for entity_type in EntityType::all() {
+            let mut names = vec![
+                "statements",
+                "qualifiers",
+                "references",
+                "labels",
+                "change_values",
+                "value_overflow",
+                "change_tags",
+                "reverts",
+                "revision_scores",
+                "media_changes",
+                "significant_changes",
+            ];
+            match entity_type {
+                EntityType::Item => names.extend(["badges", "sitelink_conflicts"]),
+                EntityType::Lexeme => names.push("subentities"),
+                EntityType::Property => {}
+            }
+            ret.extend(names.into_iter().map(|name| entity_type.table(name)));
+        }
+        ret
+    }
+
+    /// Tables outside the change log that name revisions, with the columns naming them.
+    fn other_revision_tables() -> Vec<(String, &'static [&'static str])> {
+        let mut ret: Vec<(String, &'static [&'static str])> = OTHER_REVISION_TABLES
+            .iter()
+            .map(|(table, columns)| (table.to_string(), *columns))
+            .collect();
+        for entity_type in EntityType::all() {
+            ret.extend(
+                OTHER_ENTITY_REVISION_TABLES
+                    .iter()
+                    .map(|(table, columns)| (entity_type.table(table), *columns)),
+            );
+        }
+        ret
+    }
+
+    pub async fn redact(&self, revisions: &[RevisionId], reason: &str) -> Result<Redaction> {
+        if revisions.is_empty() {
+            return Err(anyhow!("No revisions to redact"));
+        }
+        let placeholders = vec!["?"; revisions.len()].join(",");
+        let condition = format!("`revision` IN ({placeholders})");
+        let params: Vec<SqlValue> = revisions
+            .iter()
+            .map(|revision| (*revision).into())
+            .collect();
+        let publisher = Publisher::new(self.wdrc);
+        let months = publisher.months_with_revisions(revisions).await?;
+        let tombstone = Tombstone::new(reason, None);
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let mut tables = vec![];
+        for table in Self::revision_tables() {
+            let rows = tombstone
+                .delete_rows(self.wdrc, &mut conn, &table, &condition, params.clone())
+                .await?;
+            tables.push(RedactedTable { table, rows });
+        }
+        for (table, columns) in Self::other_revision_tables() {
+            let condition = columns
+                .iter()
+                .map(|column| format!("`{column}` IN ({placeholders})"))
+                .collect::<Vec<String>>()
+                .join(" OR ");
+            let params = vec![params.clone(); columns.len()].concat();
+            conn.exec_drop(format!("DELETE FROM `{table}` WHERE {condition}"), params)
+                .await?;
+            tables.push(RedactedTable {
+                table,
+                rows: conn.affected_rows(),
+            });
+        }
+        let mut republished = vec![];
+        for (month, dir) in months {
+            publisher.republish(&month, &dir).await?;
+            republished.push(month);
+        }
+        self.record(
+            &mut conn,
+            revisions.to_vec(),
+            None,
+            reason,
+            tables,
+            republished,
+        )
+        .await
+    }
+
+    /// Clears a user name wherever it is stored, and removes the user's editing sessions.
+    pub async fn redact_user(&self, user: &str, reason: &str) -> Result<Redaction> {
+        if user.trim().is_empty() {
+            return Err(anyhow!("No user to redact"));
+        }
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let mut tables = vec![];
+        for entity_type in EntityType::all() {
+            // Changes keep their session ID, which would lead back to the user
+            let statements = entity_type.table("statements");
+            let sql = format!("UPDATE `{statements}` SET `session`=NULL WHERE `session` IN (SELECT `id` FROM `sessions` WHERE `user`=?)");
+            conn.exec_drop(sql, (user,)).await?;
+            for name in USER_CHANGE_TABLES {
+                tables.push(
+                    self.clear_user(&mut conn, &entity_type.table(name), user)
+                        .await?,
+                );
+            }
+        }
+        for table in USER_QUEUE_TABLES {
+            tables.push(self.clear_user(&mut conn, table, user).await?);
+        }
+        conn.exec_drop("DELETE FROM `sessions` WHERE `user`=?", (user,))
+            .await?;
+        tables.push(RedactedTable {
+            table: "sessions".to_string(),
+            rows: conn.affected_rows(),
+        });
+        self.record(&mut conn, vec![], Some(user), reason, tables, vec![])
+            .await
+    }
+
+    async fn clear_user(&self, conn: &mut Conn, table: &str, user: &str) -> Result<RedactedTable> {
+        let sql = format!("UPDATE `{table}` SET `user`=NULL WHERE `user`=?");
+        conn.exec_drop(sql, (user,)).await?;
+        Ok(RedactedTable {
+            table: table.to_string(),
+            rows: conn.affected_rows(),
+        })
+    }
+
+    /// Records a redaction in `redactions`, one row per revision, or a single row with revision
+    /// 0 for a user.
+    async fn record(
+        &self,
+        conn: &mut Conn,
+        revisions: Vec<RevisionId>,
+        user: Option<&str>,
+        reason: &str,
+        tables: Vec<RedactedTable>,
+        republished: Vec<String>,
+    ) -> Result<Redaction> {
+        let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+        let rows: u64 = tables.iter().map(|t| t.rows).sum();
+        let recorded = match revisions.is_empty() {
+            true => vec![0],
+            false => revisions.to_owned(),
+        };
+        let months = match republished.is_empty() {
+            true => None,
+            false => Some(republished.join(",")),
+        };
+        let params: Vec<(RevisionId, &str, &str, u64, Option<&str>)> = recorded
+            .iter()
+            .map(|revision| {
+                let months = months.as_deref();
+                (*revision, reason, timestamp.as_str(), rows, months)
+            })
+            .collect();
+        conn.exec_batch(
+            "INSERT INTO `redactions` (`revision`,`reason`,`timestamp`,`rows`,`republished`) VALUES (?,?,?,?,?)",
+            params,
+        )
+        .await?;
+
+        Ok(Redaction {
+            revisions,
+            user: user.map(String::from),
+            reason: reason.to_string(),
+            timestamp,
+            tables,
+            republished,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Schema;
+
+    #[test]
+    fn test_parse_revisions() {
+        assert_eq!(
+            Redactor::parse_revisions("123, 45\n678,123").unwrap(),
+            vec![45, 123, 678]
+        );
+        assert!(Redactor::parse_revisions("123,Q42").is_err());
+        assert!(Redactor::parse_revisions("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_redacted_tables() {
+        let created: Vec<String> = Schema::create_statements()
+            .into_iter()
+            .map(|(table, _)| table)
+            .collect();
+        for (table, _) in Redactor::other_revision_tables() {
+            assert!(created.contains(&table), "{table} missing");
+        }
+        for table in USER_QUEUE_TABLES {
+            assert!(created.contains(&table.to_string()), "{table} missing");
+        }
+        for entity_type in EntityType::all() {
+            for name in USER_CHANGE_TABLES {
+                assert!(created.contains(&entity_type.table(name)));
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::Result;
+use wikimisc::mysql_async::{from_row, prelude::Queryable};
+
+use crate::WdRc;
+
+/// Replica columns that wdrc reads, per table.
+const EXPECTED_COLUMNS: &[(&str, &[&str])] = &[
+    (
+        "recentchanges",
+        &[
+            "rc_id",
+            "rc_timestamp",
+            "rc_namespace",
+            "rc_title",
+            "rc_new",
+            "rc_this_oldid",
+            "rc_last_oldid",
+            "rc_cur_id",
+            "rc_actor",
+            "rc_comment_id",
+            "rc_bot",
+            "rc_patrolled",
+            "rc_type",
+            "rc_log_type",
+        ],
+    ),
+    ("actor", &["actor_id", "actor_name"]),
+    ("comment", &["comment_id", "comment_text"]),
+    ("change_tag", &["ct_rc_id", "ct_tag_id"]),
+    ("change_tag_def", &["ctd_id", "ctd_name"]),
+    (
+        "logging",
+        &[
+            "log_type",
+            "log_action",
+            "log_timestamp",
+            "log_namespace",
+            "log_title",
+        ],
+    ),
+    ("redirect", &["rd_from", "rd_namespace", "rd_title"]),
+];
+
+/// Replica tables with columns missing, compared to what wdrc expects.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplicaSchema {
+    missing: Vec<(String, Vec<String>)>,
+}
+
+impl ReplicaSchema {
+    /// Reads the columns of all expected replica tables; a missing table lacks all columns.
+    pub async fn check(wdrc: &WdRc) -> Result<Self> {
+        let sql = "SELECT `COLUMN_NAME` FROM `information_schema`.`COLUMNS` WHERE `TABLE_SCHEMA`=DATABASE() AND `TABLE_NAME`=?";
+        let mut conn = wdrc.db().get_connection("wikidata").await?;
+        let mut missing = vec![];
+        for (table, expected) in EXPECTED_COLUMNS {
+            let columns: Vec<String> = conn
+                .exec_iter(sql, (table,))
+                .await?
+                .map_and_drop(from_row::<String>)
+                .await?;
+            let missing_columns = Self::missing_columns(expected, &columns);
+            if !missing_columns.is_empty() {
+                missing.push((table.to_string(), missing_columns));
+            }
+        }
+        Ok(Self { missing })
+    }
+
+    fn missing_columns(expected: &[&str], found: &[String]) -> Vec<String> {
+        expected
+            .iter()
+            .filter(|column| !found.iter().any(|f| f == *column))
+            .map(|column| column.to_string())
+            .collect()
+    }
+
+    pub fn is_table_usable(&self, table: &str) -> bool {
+        !self.missing.iter().any(|(t, _)| t == table)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Describes the missing columns, one table per line.
+    pub fn diagnostic(&self) -> String {
+        self.missing
+            .iter()
+            .map(|(table, columns)| {
+                format!(
+                    "Replica table `{table}` is missing expected column(s): {}",
+                    columns.join(", ")
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_columns() {
+        let found: Vec<String> = ["rd_from", "rd_title", "rd_interwiki"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            ReplicaSchema::missing_columns(&["rd_from", "rd_namespace", "rd_title"], &found),
+            vec!["rd_namespace".to_string()]
+        );
+        let schema = ReplicaSchema {
+            missing: vec![("redirect".to_string(), vec!["rd_namespace".to_string()])],
+        };
+        assert!(!schema.is_table_usable("redirect"));
+        assert!(schema.is_table_usable("recentchanges"));
+        assert_eq!(
+            schema.diagnostic(),
+            "Replica table `redirect` is missing expected column(s): rd_namespace"
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use wikimisc::mysql_async::{from_row, prelude::Queryable};
+
+use crate::{change::EntityType, query::SOURCE_TABLES, WdRc};
+
+/// Deltas smaller than this are never significant.
+const MIN_SIGNIFICANT_DELTA: i64 = 100;
+/// Relative change (0.5 = 50%) required for a delta to be significant.
+const MIN_SIGNIFICANT_RATIO: f64 = 0.5;
+
+/// Change counts for one key (property, language, subject) in two consecutive periods.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatsDelta {
+    pub group: String,
+    pub key: String,
+    pub current: i64,
+    pub previous: i64,
+    pub delta: i64,
+    pub significant: bool,
+}
+
+impl StatsDelta {
+    fn new(group: &str, key: &str, current: i64, previous: i64) -> Self {
+        let delta = current - previous;
+        let ratio = match previous {
+            0 => f64::INFINITY,
+            previous => delta.abs() as f64 / previous as f64,
+        };
+        Self {
+            group: group.to_string(),
+            key: key.to_string(),
+            current,
+            previous,
+            delta,
+            significant: delta.abs() >= MIN_SIGNIFICANT_DELTA && ratio >= MIN_SIGNIFICANT_RATIO,
+        }
+    }
+}
+
+/// Compares aggregate change counts of the last week against the week before. Weeks end at the
+/// last midnight in the configured time zone; `start`, `middle` and `end` are UTC timestamps.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsReport {
+    pub start: String,
+    pub middle: String,
+    pub end: String,
+    pub timezone: String,
+    pub deltas: Vec<StatsDelta>,
+}
+
+impl StatsReport {
+    pub async fn weekly(wdrc: &WdRc) -> Result<Self> {
+        let [start, middle, end] = Self::boundaries(Utc::now(), wdrc.timezone());
+
+        let mut deltas = vec![];
+        for (group, sql) in Self::group_queries() {
+            let current = Self::counts(wdrc, &sql, &middle, &end).await?;
+            let previous = Self::counts(wdrc, &sql, &start, &middle).await?;
+            deltas.append(&mut Self::compare_counts(group, &current, &previous));
+        }
+        Ok(Self {
+            start,
+            middle,
+            end,
+            timezone: wdrc.timezone().to_string(),
+            deltas,
+        })
+    }
+
+    /// The midnights in `timezone` 14 days, 7 days and 0 days before the last one at `now`, as
+    /// UTC timestamps.
+    fn boundaries(now: DateTime<Utc>, timezone: Tz) -> [String; 3] {
+        let today = now.with_timezone(&timezone).date_naive();
+        [14, 7, 0].map(|days| {
+            Self::midnight(today - Duration::days(days), timezone)
+                .format("%Y%m%d%H%M%S")
+                .to_string()
+        })
+    }
+
+    /// The start of `date` in `timezone`; if a clock change skips midnight, the first hour after it.
+    fn midnight(date: NaiveDate, timezone: Tz) -> DateTime<Utc> {
+        let midnight = date.and_time(chrono::NaiveTime::MIN);
+        (0..3)
+            .find_map(|hours| {
+                timezone
+                    .from_local_datetime(&(midnight + Duration::hours(hours)))
+                    .earliest()
+            })
+            .map(|dt| dt.with_timezone(&Utc))
+            .unwrap_or_else(|| Utc.from_utc_datetime(&midnight))
+    }
+
+    /// The query counting the keys of each group. Change tables of all entity types are counted
+    /// together; keys in several tables are added up.
+    fn group_queries() -> Vec<(&'static str, String)> {
+        let range = "`timestamp`>=? AND `timestamp`<?";
+        let (mut subject, mut entity_type, mut property, mut language) =
+            (vec![], vec![], vec![], vec![]);
+        for et in EntityType::all() {
+            for source in SOURCE_TABLES.iter().filter(|s| s.exists_for(et)) {
+                let table = et.table(source.name);
+                subject.push(match &source.subject {
+                    Some(s) => format!(
+                        "SELECT '{}',count(*) FROM `{table}` WHERE {range}",
+                        s.as_str()
+                    ),
+                    None => format!(
+                        "SELECT `type`,count(*) FROM `{table}` WHERE {range} GROUP BY `type`"
+                    ),
+                });
+                entity_type.push(format!(
+                    "SELECT '{}',count(*) FROM `{table}` WHERE {range}",
+                    et.as_str()
+                ));
+                if source.property {
+                    property.push(format!("SELECT concat('P',`property`),count(*) FROM `{table}` WHERE {range} GROUP BY `property`"));
+                }
+                if source.name == "labels" {
+                    language.push(format!("SELECT `value`,count(*) FROM `{table}`,`texts` WHERE `{table}`.`language`=`texts`.`id` AND `type`!='sitelinks' AND {range} GROUP BY `value`"));
+                }
+            }
+        }
+        let union = |parts: Vec<String>| parts.join("\nUNION ALL ");
+        vec![
+            ("subject", union(subject)),
+            ("entity_type", union(entity_type)),
+            ("property", union(property)),
+            ("runs", "SELECT 'runs',count(*) FROM `runs` WHERE `started`>=? AND `started`<?
+                UNION SELECT 'failed',count(*) FROM `runs` WHERE `error` IS NOT NULL AND `started`>=? AND `started`<?
+                UNION SELECT 'dropped',coalesce(sum(`dropped`),0) FROM `runs` WHERE `started`>=? AND `started`<?".to_string()),
+            ("language", union(language)),
+        ]
+    }
+
+    async fn counts(wdrc: &WdRc, sql: &str, from: &str, to: &str) -> Result<HashMap<String, i64>> {
+        // Each query consists of one or more (from,to) range conditions
+        let params: Vec<&str> = (0..sql.matches('?').count())
+            .map(|i| if i % 2 == 0 { from } else { to })
+            .collect();
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let result: Vec<(String, i64)> = conn
+            .exec_iter(sql, params)
+            .await?
+            .map_and_drop(from_row::<(String, i64)>)
+            .await?;
+        let mut ret = HashMap::new();
+        for (key, count) in result {
+            *ret.entry(key).or_default() += count;
+        }
+        Ok(ret)
+    }
+
+    /// Returns the deltas for all keys in either period, significant and largest first.
+    fn compare_counts(
+        group: &str,
+        current: &HashMap<String, i64>,
+        previous: &HashMap<String, i64>,
+    ) -> Vec<StatsDelta> {
+        let mut keys: Vec<&String> = current.keys().chain(previous.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        let mut ret: Vec<StatsDelta> = keys
+            .into_iter()
+            .map(|key| {
+                let current = current.get(key).copied().unwrap_or(0);
+                let previous = previous.get(key).copied().unwrap_or(0);
+                StatsDelta::new(group, key, current, previous)
+            })
+            .collect();
+        ret.sort_by(|a, b| {
+            b.significant
+                .cmp(&a.significant)
+                .then(b.delta.abs().cmp(&a.delta.abs()))
+        });
+        ret
+    }
+
+    pub fn to_json(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    /// Renders the significant deltas as a wiki table.
+    pub fn to_wikitext(&self) -> String {
+        let mut ret = format!(
+            "Changes {}–{} compared to {}–{}\n",
+            self.middle, self.end, self.start, self.middle
+        );
+        ret += "{| class=\"wikitable sortable\"\n! Group !! Key !! Previous !! Current !! Delta\n";
+        for d in self.deltas.iter().filter(|d| d.significant) {
+            ret += &format!(
+                "|-\n| {} || {} || {} || {} || {:+}\n",
+                d.group, d.key, d.previous, d.current, d.delta
+            );
+        }
+        ret += "|}\n";
+        ret
+    }
+}
+
+/// Number of changes per hour of the week in the configured time zone, Monday 00:00 first.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Heatmap {
+    pub id: String,
+    pub timezone: String,
+    pub hours: Vec<Vec<u64>>,
+}
+
+impl Heatmap {
+    /// Builds the heatmap for an item (`Q42`) or a lexeme (`L7`), with all its changes, or for a
+    /// property (`P31`), with the statements, qualifiers and references using it on any entity.
+    pub async fn for_entity(wdrc: &WdRc, id: &str) -> Result<Self> {
+        let numeric_id = WdRc::make_id_numeric(id)?;
+        let sql = Self::sql(id).ok_or_else(|| anyhow!("Not an entity: {id}"))?;
+        let params: Vec<u64> = vec![numeric_id; sql.matches('?').count()];
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let rows: Vec<(String, u64)> = conn
+            .exec_iter(sql, params)
+            .await?
+            .map_and_drop(from_row::<(String, u64)>)
+            .await?;
+        Ok(Self::from_rows(id, &rows, wdrc.timezone()))
+    }
+
+    /// The query counting the changes of `id` per UTC hour (`YYYYMMDDHH`), as the database may
+    /// not know the time zone.
+    fn sql(id: &str) -> Option<String> {
+        let parts: Vec<String> = match EntityType::from_id(id)? {
+            EntityType::Property => EntityType::all()
+                .into_iter()
+                .flat_map(|et| {
+                    SOURCE_TABLES
+                        .iter()
+                        .filter(move |s| s.property && s.exists_for(et))
+                        .map(move |s| (et.table(s.name), "property"))
+                })
+                .collect::<Vec<_>>(),
+            et => SOURCE_TABLES
+                .iter()
+                .filter(|s| s.exists_for(et))
+                .map(|s| (et.table(s.name), "item"))
+                .collect(),
+        }
+        .into_iter()
+        .map(|(table, column)| {
+            format!("SELECT LEFT(`timestamp`,10) AS `h`,count(*) AS `cnt` FROM `{table}` WHERE `{column}`=? GROUP BY 1")
+        })
+        .collect();
+        Some(format!(
+            "SELECT `h`,sum(`cnt`) FROM (\n{}\n) t GROUP BY 1",
+            parts.join("\nUNION ALL\n")
+        ))
+    }
+
+    /// Adds up counts per UTC hour by the hour of the week they start in, in `timezone`.
+    fn from_rows(id: &str, rows: &[(String, u64)], timezone: Tz) -> Self {
+        let mut hours = vec![vec![0; 24]; 7];
+        for (hour, count) in rows {
+            let Ok(dt) = NaiveDateTime::parse_from_str(&format!("{hour}0000"), "%Y%m%d%H%M%S")
+            else {
+                continue;
+            };
+            let local = Utc.from_utc_datetime(&dt).with_timezone(&timezone);
+            let weekday = local.weekday().num_days_from_monday() as usize;
+            hours[weekday][local.hour() as usize] += count;
+        }
+        Self {
+            id: id.to_string(),
+            timezone: timezone.to_string(),
+            hours,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_counts() {
+        let current: HashMap<String, i64> = [("P31", 1000), ("P18", 105), ("P569", 400)]
+            .iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect();
+        let previous: HashMap<String, i64> = [("P31", 100), ("P18", 100), ("P21", 300)]
+            .iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect();
+        let deltas = StatsReport::compare_counts("property", &current, &previous);
+        let summary: Vec<(&str, i64, bool)> = deltas
+            .iter()
+            .map(|d| (d.key.as_str(), d.delta, d.significant))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("P31", 900, true),
+                ("P569", 400, true),
+                ("P21", -300, true),
+                ("P18", 5, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_queries() {
+        let queries = StatsReport::group_queries();
+        for (group, sql) in &queries {
+            assert_eq!(sql.matches('?').count() % 2, 0, "{group}");
+        }
+        let sql = |group| &queries.iter().find(|(g, _)| *g == group).unwrap().1;
+        for table in [
+            "`statements`",
+            "`property_qualifiers`",
+            "`lexeme_references`",
+            "`lexeme_subentities`",
+            "`badges`",
+        ] {
+            assert!(sql("subject").contains(table), "{table}");
+        }
+        assert!(sql("entity_type").contains("'lexeme'"));
+        assert!(sql("property").contains("`lexeme_qualifiers`"));
+        assert!(!sql("property").contains("`labels`"));
+        assert!(sql("language").contains("`property_labels`.`language`"));
+    }
+
+    #[test]
+    fn test_heatmap_sql() {
+        let sql = Heatmap::sql("L7").unwrap();
+        assert!(sql.contains("FROM `lexeme_subentities` WHERE `item`=?"));
+        assert!(!sql.contains("`badges`"));
+        let sql = Heatmap::sql("P31").unwrap();
+        assert!(sql.contains("FROM `property_references` WHERE `property`=?"));
+        assert!(!sql.contains("labels"));
+        assert!(Heatmap::sql("X1").is_none());
+    }
+
+    #[test]
+    fn test_boundaries() {
+        let now = Utc.with_ymd_and_hms(2024, 4, 3, 22, 30, 0).unwrap();
+        assert_eq!(
+            StatsReport::boundaries(now, Tz::UTC),
+            ["20240320000000", "20240327000000", "20240403000000"]
+        );
+        // Already April 4th in Berlin; summer time started on March 31st
+        assert_eq!(
+            StatsReport::boundaries(now, Tz::Europe__Berlin),
+            ["20240320230000", "20240327230000", "20240403220000"]
+        );
+    }
+
+    #[test]
+    fn test_heatmap_from_rows() {
+        // Monday 2024-01-01 00:00 UTC, Sunday 2024-01-07 23:00 UTC and Wednesday 12:00 UTC
+        let rows = [
+            ("2024010100".to_string(), 3),
+            ("2024010723".to_string(), 5),
+            ("2024010312".to_string(), 1),
+            ("bad".to_string(), 9),
+        ];
+        let heatmap = Heatmap::from_rows("P31", &rows, Tz::UTC);
+        assert_eq!(heatmap.hours.len(), 7);
+        assert_eq!(heatmap.hours[0][0], 3);
+        assert_eq!(heatmap.hours[6][23], 5);
+        assert_eq!(heatmap.hours[2][12], 1);
+        assert_eq!(heatmap.hours.iter().flatten().sum::<u64>(), 9);
+
+        let heatmap = Heatmap::from_rows("P31", &rows, Tz::Europe__Berlin);
+        assert_eq!(heatmap.timezone, "Europe/Berlin");
+        assert_eq!(heatmap.hours[0][1], 3);
+        assert_eq!(heatmap.hours[0][0], 5);
+        assert_eq!(heatmap.hours[2][13], 1);
+    }
+}
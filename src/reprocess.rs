@@ -0,0 +1,303 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::time::Duration;
+use wikimisc::mysql_async::{from_row, prelude::Queryable, Value as SqlValue};
+
+use crate::{
+    change::EntityType, recent_changes::ChangedItem, redact::Redactor,
+    revision_compare::ENGINE_VERSION, tombstones::Tombstone, ItemId, RevisionId, WdRc,
+};
+
+/// Change tables with an `engine` column.
+const STAMPED_TABLES: &[&str] = &[
+    "statements",
+    "qualifiers",
+    "references",
+    "labels",
+    "badges",
+    "subentities",
+];
+/// Revisions looked up at a time when bulk reprocessing.
+const BULK_BATCH_SIZE: u64 = 100;
+/// Pause between revisions when bulk reprocessing, to keep the load on the API and database low.
+const BULK_DELAY: Duration = Duration::from_millis(500);
+
+/// The outcome of reprocessing a revision range of one entity.
+#[derive(Debug, Clone, Serialize)]
+pub struct Reprocessing {
+    pub entity: String,
+    pub rev_old: RevisionId,
+    pub rev_new: RevisionId,
+    pub timestamp: String,
+    pub deleted: u64,
+    pub changes: usize,
+}
+
+/// The outcome of reprocessing all revisions logged by an older diff engine.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BulkReprocessing {
+    pub revisions: u64,
+    pub failed: u64,
+    pub deleted: u64,
+    pub changes: usize,
+}
+
+/// Replaces the logged changes of an entity between two revisions with those of the current diff engine,
+/// e.g. after fixing a bug in it.
+///
+/// Rows of revisions after `rev_old` up to `rev_new` are deleted first, leaving tombstones, so reprocessing
+/// the same range again gives the same result. User, edit summary, and change tags are not known here, and left empty.
+pub struct Reprocessor<'a> {
+    wdrc: &'a mut WdRc,
+}
+
+impl<'a> Reprocessor<'a> {
+    pub fn new(wdrc: &'a mut WdRc) -> Self {
+        Self { wdrc }
+    }
+
+    /// Uses `timestamp` for the new rows, or the latest one of the rows replaced.
+    pub async fn reprocess(
+        &mut self,
+        entity: &str,
+        rev_old: RevisionId,
+        rev_new: RevisionId,
+        timestamp: Option<&str>,
+    ) -> Result<Reprocessing> {
+        if rev_old >= rev_new {
+            return Err(anyhow!(
+                "Old revision {rev_old} must be before new revision {rev_new}"
+            ));
+        }
+        let entity_type =
+            EntityType::from_id(entity).ok_or_else(|| anyhow!("Unknown entity: {entity:?}"))?;
+        let item = WdRc::make_id_numeric(entity)?;
+        let tables: Vec<String> = Redactor::revision_tables()
+            .into_iter()
+            .filter(|table| Self::belongs_to(table, entity_type))
+            .collect();
+
+        let timestamp = match timestamp {
+            Some(timestamp) => timestamp.to_string(),
+            None => self
+                .stored_timestamp(&tables, item, rev_old, rev_new)
+                .await?
+                .ok_or_else(|| anyhow!("No stored changes to take the timestamp from; pass one"))?,
+        };
+
+        // Compare before deleting, so a failing comparison leaves the stored rows alone
+        let ci = ChangedItem::new(entity, rev_old, rev_new, &timestamp);
+        let changes = self.wdrc.revision_compare().run(&ci).await?;
+
+        let mut deleted = 0;
+        let tombstone = Tombstone::new("reprocess", Some(format!("engine v{ENGINE_VERSION}")));
+        let condition = "`item`=? AND `revision`>? AND `revision`<=?";
+        let params: Vec<SqlValue> = vec![item.into(), rev_old.into(), rev_new.into()];
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        for table in &tables {
+            deleted += tombstone
+                .delete_rows(self.wdrc, &mut conn, table, condition, params.clone())
+                .await?;
+        }
+        drop(conn);
+        self.wdrc.log_changes(&changes).await?;
+
+        Ok(Reprocessing {
+            entity: entity.to_string(),
+            rev_old,
+            rev_new,
+            timestamp,
+            deleted,
+            changes: changes.len(),
+        })
+    }
+
+    /// Parses an engine version like `v2` or `2`.
+    pub fn parse_engine_version(s: &str) -> Result<u32> {
+        s.strip_prefix('v')
+            .unwrap_or(s)
+            .parse()
+            .map_err(|_| anyhow!("Not an engine version: {s:?}"))
+    }
+
+    /// Reprocesses, one at a time, every revision with rows stamped with an engine version below `version`.
+    /// Each revision is compared to the previous logged revision of the entity, or to its parent revision.
+    /// Rows from before engine versions were stamped are left alone.
+    pub async fn reprocess_engine_older_than(&mut self, version: u32) -> Result<BulkReprocessing> {
+        let mut ret = BulkReprocessing::default();
+        let revision_tables = Redactor::revision_tables();
+        for entity_type in EntityType::all() {
+            let tables: Vec<String> = revision_tables
+                .iter()
+                .filter(|table| Self::belongs_to(table, entity_type))
+                .cloned()
+                .collect();
+            for name in STAMPED_TABLES {
+                let table = entity_type.table(name);
+                if !tables.contains(&table) {
+                    continue;
+                }
+                let mut after = 0;
+                loop {
+                    let batch = self.older_revisions(&table, version, after).await?;
+                    if batch.is_empty() {
+                        break;
+                    }
+                    for (item, revision, timestamp) in batch {
+                        after = revision;
+                        let entity = format!("{}{item}", entity_type.id_prefix());
+                        let result = match self.previous_revision(&tables, item, revision).await {
+                            Ok(rev_old) => {
+                                self.reprocess(&entity, rev_old, revision, Some(&timestamp))
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        ret.revisions += 1;
+                        match result {
+                            Ok(result) => {
+                                ret.deleted += result.deleted;
+                                ret.changes += result.changes;
+                            }
+                            Err(e) => {
+                                ret.failed += 1;
+                                eprintln!("Reprocessing {entity} revision {revision} failed: {e}");
+                            }
+                        }
+                        tokio::time::sleep(BULK_DELAY).await;
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn older_revisions(
+        &self,
+        table: &str,
+        version: u32,
+        after: RevisionId,
+    ) -> Result<Vec<(ItemId, RevisionId, String)>> {
+        let sql = format!(
+            "SELECT `item`,`revision`,MAX(`timestamp`) FROM `{table}` WHERE `engine`<? AND `revision`>? GROUP BY `item`,`revision` ORDER BY `revision` LIMIT ?"
+        );
+        let rows = self
+            .wdrc
+            .db()
+            .get_connection("wdrc")
+            .await?
+            .exec_iter(sql, (version, after, BULK_BATCH_SIZE))
+            .await?
+            .map_and_drop(from_row::<(ItemId, RevisionId, String)>)
+            .await?;
+        Ok(rows)
+    }
+
+    /// The latest logged revision of the entity before `revision`, or else its parent revision.
+    async fn previous_revision(
+        &self,
+        tables: &[String],
+        item: ItemId,
+        revision: RevisionId,
+    ) -> Result<RevisionId> {
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let mut ret: Option<RevisionId> = None;
+        for table in tables {
+            let sql =
+                format!("SELECT MAX(`revision`) FROM `{table}` WHERE `item`=? AND `revision`<?");
+            let previous = conn
+                .exec_first::<Option<RevisionId>, _, _>(sql, (item, revision))
+                .await?
+                .flatten();
+            if previous > ret {
+                ret = previous;
+            }
+        }
+        drop(conn);
+        match ret {
+            Some(previous) => Ok(previous),
+            None => self.parent_revision(revision).await,
+        }
+    }
+
+    async fn parent_revision(&self, revision: RevisionId) -> Result<RevisionId> {
+        let url = format!(
+            "{}?action=query&prop=revisions&revids={revision}&rvprop=ids&format=json",
+            self.wdrc.wiki().api_url()
+        );
+        let client = self.wdrc.wd().reqwest_client()?;
+        let j: Value = client.get(url).send().await?.json().await?;
+        Self::parse_parent_id(&j)
+            .ok_or_else(|| anyhow!("No parent revision of revision {revision}"))
+    }
+
+    fn parse_parent_id(j: &Value) -> Option<RevisionId> {
+        j["query"]["pages"].as_object()?.values().next()?["revisions"][0]["parentid"]
+            .as_u64()
+            .filter(|parent| *parent > 0)
+    }
+
+    /// Whether a table from [`Redactor::revision_tables`] holds changes of `entity_type`.
+    fn belongs_to(table: &str, entity_type: EntityType) -> bool {
+        match entity_type {
+            EntityType::Item => EntityType::all()
+                .iter()
+                .filter(|et| **et != EntityType::Item)
+                .all(|et| !table.starts_with(&et.table(""))),
+            other => table.starts_with(&other.table("")),
+        }
+    }
+
+    async fn stored_timestamp(
+        &self,
+        tables: &[String],
+        item: ItemId,
+        rev_old: RevisionId,
+        rev_new: RevisionId,
+    ) -> Result<Option<String>> {
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let mut ret: Option<String> = None;
+        for table in tables {
+            let sql = format!(
+                "SELECT MAX(`timestamp`) FROM `{table}` WHERE `item`=? AND `revision`>? AND `revision`<=?"
+            );
+            let timestamp = conn
+                .exec_first::<Option<String>, _, _>(sql, (item, rev_old, rev_new))
+                .await?
+                .flatten();
+            if timestamp > ret {
+                ret = timestamp;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_belongs_to() {
+        assert!(Reprocessor::belongs_to("statements", EntityType::Item));
+        assert!(!Reprocessor::belongs_to(
+            "property_statements",
+            EntityType::Item
+        ));
+        assert!(Reprocessor::belongs_to(
+            "lexeme_subentities",
+            EntityType::Lexeme
+        ));
+        assert!(!Reprocessor::belongs_to("labels", EntityType::Property));
+    }
+
+    #[test]
+    fn test_engine_version() {
+        assert_eq!(Reprocessor::parse_engine_version("v2").unwrap(), 2);
+        assert_eq!(Reprocessor::parse_engine_version("3").unwrap(), 3);
+        assert!(Reprocessor::parse_engine_version("vX").is_err());
+        let j = serde_json::json!({"query": {"pages": {"138": {"revisions": [{"revid": 1200, "parentid": 1100}]}}}});
+        assert_eq!(Reprocessor::parse_parent_id(&j), Some(1100));
+    }
+}
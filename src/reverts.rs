@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use wikimisc::mysql_async::Row;
+
+use crate::{
+    change::{Change, ChangeType},
+    revision_compare::RevisionId,
+    ItemId,
+};
+
+/// An earlier value change of an entity, from `change_values` or the current batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreviousValue {
+    pub item: ItemId,
+    pub revision: RevisionId,
+    pub subject: String,
+    pub key: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+impl PreviousValue {
+    pub fn from_row(row: Row) -> Option<Self> {
+        Some(Self {
+            item: row.get("item")?,
+            revision: row.get("revision")?,
+            subject: row.get("type")?,
+            key: row.get("key")?,
+            old_value: row.get("old_value")?,
+            new_value: row.get("new_value")?,
+        })
+    }
+
+    fn from_change(change: &Change) -> Self {
+        Self {
+            item: change.item_id,
+            revision: change.revision_id,
+            subject: change.subject.as_str().to_string(),
+            key: change.value_key(),
+            old_value: change.old_text.to_owned(),
+            new_value: change.new_text.to_owned(),
+        }
+    }
+}
+
+/// A change that exactly undid an earlier one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Revert {
+    /// Index of the reverting change in the batch.
+    pub change: usize,
+    pub reverted_revision: RevisionId,
+}
+
+/// Finds changes that restore the value an earlier change replaced, e.g. a label changed back,
+/// or a claim re-added with identical content, and marks them as [`ChangeType::Reverted`].
+/// `previous` holds already logged value changes of the same entities.
+pub fn mark_reverts(previous: &[PreviousValue], changes: &mut [Change]) -> Vec<Revert> {
+    type Key = (ItemId, String, String);
+    let mut latest: HashMap<Key, PreviousValue> = HashMap::new();
+    let mut previous: Vec<&PreviousValue> = previous.iter().collect();
+    previous.sort_by_key(|p| p.revision);
+    for p in previous {
+        latest.insert(
+            (p.item, p.subject.to_owned(), p.key.to_owned()),
+            p.to_owned(),
+        );
+    }
+
+    let mut order: Vec<usize> = (0..changes.len()).collect();
+    order.sort_by_key(|i| (changes[*i].item_id, changes[*i].revision_id));
+    let mut ret = vec![];
+    for i in order {
+        let change = &mut changes[i];
+        if change.old_text.is_empty() && change.new_text.is_empty() {
+            continue; // No values to compare
+        }
+        let current = PreviousValue::from_change(change);
+        let key = (
+            current.item,
+            current.subject.to_owned(),
+            current.key.to_owned(),
+        );
+        if let Some(p) = latest.get(&key) {
+            if p.revision < current.revision
+                && p.old_value == current.new_value
+                && p.new_value == current.old_value
+            {
+                change.change_type = ChangeType::Reverted;
+                ret.push(Revert {
+                    change: i,
+                    reverted_revision: p.revision,
+                });
+            }
+        }
+        latest.insert(key, current);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::change::ChangeSubject;
+
+    fn label(revision: RevisionId, old: &str, new: &str) -> Change {
+        Change {
+            subject: ChangeSubject::Labels,
+            change_type: ChangeType::Changed,
+            language: "en".to_string(),
+            item_id: 1,
+            revision_id: revision,
+            ..Default::default()
+        }
+        .with_values(old, new)
+    }
+
+    #[test]
+    fn test_mark_reverts() {
+        let previous = vec![PreviousValue {
+            item: 1,
+            revision: 10,
+            subject: "labels".to_string(),
+            key: "en".to_string(),
+            old_value: "Douglas Adams".to_string(),
+            new_value: "vandalism".to_string(),
+        }];
+        let mut changes = vec![
+            label(13, "Douglas Adams", "D. Adams"),
+            label(11, "vandalism", "Douglas Adams"),
+            label(12, "Douglas Adams", "Douglas Adams (writer)"),
+        ];
+        let reverts = mark_reverts(&previous, &mut changes);
+        assert_eq!(
+            reverts,
+            vec![Revert {
+                change: 1,
+                reverted_revision: 10
+            }]
+        );
+        assert_eq!(changes[1].change_type, ChangeType::Reverted);
+        assert_eq!(changes[0].change_type, ChangeType::Changed);
+
+        // Reverts within the batch
+        let mut changes = vec![label(21, "a", "b"), label(22, "b", "a")];
+        let reverts = mark_reverts(&[], &mut changes);
+        assert_eq!(reverts[0].reverted_revision, 21);
+    }
+}
@@ -1,19 +1,52 @@
 use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use serde::Deserialize;
 use serde_json::{json, Map, Value};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use wikimisc::wikidata::Wikidata;
 
+#[cfg(feature = "rest")]
+use crate::wikibase_rest::WikibaseRest;
 use crate::{
-    change::{Change, ChangeSubject, ChangeType},
+    change::{Change, ChangeSubject, ChangeType, EntityType},
+    config::ApiRetryConfig,
     recent_changes::ChangedItem,
+    wiki::Wiki,
     ItemId, WdRc,
 };
 
 pub type RevisionId = u64;
 
+/// Version of the diff logic, stored with every change row; bump when changes are derived differently,
+/// so older rows can be found and reprocessed.
+pub const ENGINE_VERSION: u32 = 1;
+/// Timeout of API requests unless set with [`RevisionCompare::with_timeout`].
+const API_TIMEOUT: Duration = Duration::from_secs(60);
+/// Maximum number of revisions with content the API returns per request.
+const REVIDS_PER_REQUEST: usize = 50;
+
+/// Where revision content is loaded from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RevisionBackend {
+    /// The JSON slot content from the action API.
+    #[default]
+    Action,
+    /// The Wikibase REST API, for revisions that are still the latest of their entity; older revisions
+    /// and lexemes still come from the action API. Snak hashes are not compared, nor stored with values.
+    /// Needs the `rest` feature.
+    Rest,
+}
+
+/// Computes the [`Change`]s between two revisions of an item.
 pub struct RevisionCompare {
     wd: Arc<Wikidata>,
+    backend: RevisionBackend,
+    retry: ApiRetryConfig,
+    timeout: Duration,
+    wiki: Wiki,
     item_id: ItemId,
+    entity_type: EntityType,
     revision_id: RevisionId,
     timestamp: String,
 }
@@ -22,32 +55,135 @@ impl RevisionCompare {
     pub fn new(wd: Arc<Wikidata>) -> RevisionCompare {
         RevisionCompare {
             wd,
+            backend: RevisionBackend::default(),
+            retry: ApiRetryConfig::default(),
+            timeout: API_TIMEOUT,
+            wiki: Wiki::default(),
             item_id: 0,
+            entity_type: EntityType::Item,
             revision_id: 0,
             timestamp: "".to_string(),
         }
     }
 
+    pub fn with_backend(mut self, backend: RevisionBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: ApiRetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_wiki(mut self, wiki: Wiki) -> Self {
+        self.wiki = wiki;
+        self
+    }
+
+    /// Loads both revisions of the changed item and compares them. An old revision of 0 stands for
+    /// an empty entity, so all content of a new entity is logged as added.
     pub async fn run(&mut self, ci: &ChangedItem) -> Result<Vec<Change>> {
+        self.run_prefetched(ci, &HashMap::new()).await
+    }
+
+    /// Like [`Self::run`], but takes the revisions from `prefetched` where they are there, and
+    /// only loads the missing ones.
+    pub async fn run_prefetched(
+        &mut self,
+        ci: &ChangedItem,
+        prefetched: &HashMap<RevisionId, Value>,
+    ) -> Result<Vec<Change>> {
+        let created = ci.rev_old() == 0;
+        let first = if created { ci.rev_new() } else { ci.rev_old() };
+        let fetched =
+            match prefetched.contains_key(&first) && prefetched.contains_key(&ci.rev_new()) {
+                true => HashMap::new(),
+                false => {
+                    self.get_revisions_for_item(ci.q(), first, ci.rev_new(), prefetched)
+                        .await?
+                }
+            };
+        let revision =
+            |rev_id: RevisionId| prefetched.get(&rev_id).or_else(|| fetched.get(&rev_id));
+        let empty = json!({});
+        let rev_old = match created {
+            true => &empty,
+            false => revision(ci.rev_old()).ok_or_else(|| {
+                anyhow!("Could not load {} old revision {}", ci.q(), ci.rev_old())
+            })?,
+        };
+        let rev_new = revision(ci.rev_new())
+            .ok_or_else(|| anyhow!("Could not load {} new revision {}", ci.q(), ci.rev_new()))?;
+        self.compare_loaded(ci, rev_old, rev_new)
+    }
+
+    /// Compares two loaded revisions of the entity of `ci`. With the REST backend, snak hashes are
+    /// removed from both first, as either may have come from the action API.
+    fn compare_loaded(
+        &mut self,
+        ci: &ChangedItem,
+        rev_old: &Value,
+        rev_new: &Value,
+    ) -> Result<Vec<Change>> {
+        #[cfg(feature = "rest")]
+        if self.backend == RevisionBackend::Rest {
+            let (mut old, mut new) = (rev_old.clone(), rev_new.clone());
+            WikibaseRest::strip_snak_hashes(&mut old);
+            WikibaseRest::strip_snak_hashes(&mut new);
+            return self.compare_entity(ci, &old, &new);
+        }
+        self.compare_entity(ci, rev_old, rev_new)
+    }
+
+    /// Compares two given versions of the entity of `ci`, e.g. from dumps. The changes get the
+    /// new revision ID and timestamp of `ci`.
+    pub fn compare_entity(
+        &mut self,
+        ci: &ChangedItem,
+        rev_old: &Value,
+        rev_new: &Value,
+    ) -> Result<Vec<Change>> {
         self.item_id = WdRc::make_id_numeric(ci.q())?;
+        self.entity_type =
+            EntityType::from_id(ci.q()).ok_or_else(|| anyhow!("Unsupported entity {}", ci.q()))?;
         self.revision_id = ci.rev_new();
         self.timestamp = ci.timestamp().to_string();
-
-        let revisions = self
-            .get_revisions_for_item(ci.q(), ci.rev_old(), ci.rev_new())
-            .await?;
-        let rev_old = revisions
-            .get(&ci.rev_old())
-            .ok_or_else(|| anyhow!("Could not load {} old revision {}", ci.q(), ci.rev_old()))?;
-        let rev_new = revisions
-            .get(&ci.rev_new())
-            .ok_or_else(|| anyhow!("Could not load {} new revision {}", ci.q(), ci.rev_new()))?;
-        let ret = self.compare_revisions(rev_old, rev_new);
+        let mut ret = self.compare_revisions(rev_old, rev_new);
+        let sitelinks = Self::json_object(rev_new, "sitelinks").len() as u64;
+        let statements = Self::json_object(rev_new, "claims")
+            .values()
+            .filter_map(|claims| claims.as_array())
+            .map(|claims| claims.len() as u64)
+            .sum();
+        ret.iter_mut().for_each(|change| {
+            change.sitelinks = sitelinks;
+            change.statements = statements;
+            change.user = ci.user().map(|user| user.to_string());
+            change.is_bot = ci.is_bot();
+            change.tags = ci.tags().to_vec();
+            change.comment = ci.comment().map(|comment| comment.to_string());
+        });
         Ok(ret)
     }
 
-    fn get_revisions_url(q: &str, rev_id_old: RevisionId, rev_id_new: RevisionId) -> String {
-        format!("https://www.wikidata.org/w/api.php?action=query&prop=revisions&titles={q}&rvprop=ids|content&rvstartid={rev_id_new}&rvendid={rev_id_old}&rvslots=main&format=json")
+    fn get_revisions_url(&self, q: &str, rev_id_old: RevisionId, rev_id_new: RevisionId) -> String {
+        let prefix = EntityType::from_id(q).unwrap_or_default().title_prefix();
+        format!("{}?action=query&prop=revisions&titles={prefix}{q}&rvprop=ids|content&rvstartid={rev_id_new}&rvendid={rev_id_old}&rvslots=main&format=json", self.wiki.api_url())
+    }
+
+    fn get_revisions_by_id_url(&self, revids: &[RevisionId]) -> String {
+        let revids: Vec<String> = revids.iter().map(|revid| revid.to_string()).collect();
+        format!(
+            "{}?action=query&prop=revisions&revids={}&rvprop=ids|content&rvslots=main&format=json",
+            self.wiki.api_url(),
+            revids.join("|")
+        )
     }
 
     fn extract_revisions(
@@ -55,6 +191,13 @@ impl RevisionCompare {
         rev_id_new: RevisionId,
         j: &Value,
     ) -> HashMap<RevisionId, Value> {
+        let mut ret = Self::extract_all_revisions(j);
+        ret.retain(|rev_id, _| *rev_id == rev_id_old || *rev_id == rev_id_new);
+        ret
+    }
+
+    /// Returns the parsed content of every revision in an API response.
+    fn extract_all_revisions(j: &Value) -> HashMap<RevisionId, Value> {
         let mut ret = HashMap::new();
         let pages = match j.get("query") {
             Some(pages) => pages,
@@ -64,11 +207,9 @@ impl RevisionCompare {
         for page in pages.values() {
             for revision in Self::json_array(page, "revisions") {
                 if let Some(rev_id) = revision["revid"].as_u64() {
-                    if rev_id == rev_id_old || rev_id == rev_id_new {
-                        if let Some(text) = revision["slots"]["main"]["*"].as_str() {
-                            if let Ok(j) = serde_json::from_str::<Value>(text) {
-                                ret.insert(rev_id, j);
-                            }
+                    if let Some(text) = revision["slots"]["main"]["*"].as_str() {
+                        if let Ok(j) = serde_json::from_str::<Value>(text) {
+                            ret.insert(rev_id, j);
                         }
                     }
                 }
@@ -77,19 +218,159 @@ impl RevisionCompare {
         ret
     }
 
+    /// Loads the two revisions of an item that are not in `known`.
     async fn get_revisions_for_item(
         &self,
         q: &str,
         rev_id_old: RevisionId,
         rev_id_new: RevisionId,
+        known: &HashMap<RevisionId, Value>,
+    ) -> Result<HashMap<RevisionId, Value>> {
+        let mut ret = HashMap::new();
+        #[cfg(feature = "rest")]
+        if self.backend == RevisionBackend::Rest {
+            if let Some((rev_id, j)) = self.get_latest_revision_rest(q).await? {
+                if rev_id == rev_id_old || rev_id == rev_id_new {
+                    ret.insert(rev_id, j);
+                }
+            }
+            let has = |rev_id| ret.contains_key(rev_id) || known.contains_key(rev_id);
+            if has(&rev_id_old) && has(&rev_id_new) {
+                return Ok(ret);
+            }
+        }
+        for (rev_id, j) in self.get_revisions_action(q, rev_id_old, rev_id_new).await? {
+            if !known.contains_key(&rev_id) {
+                ret.entry(rev_id).or_insert(j);
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn get_revisions_action(
+        &self,
+        q: &str,
+        rev_id_old: RevisionId,
+        rev_id_new: RevisionId,
     ) -> Result<HashMap<RevisionId, Value>> {
-        let url = Self::get_revisions_url(q, rev_id_old, rev_id_new);
-        let client = self.wd.reqwest_client()?;
-        let j = client.get(url).send().await?.json().await?;
+        let url = Self::with_maxlag(
+            self.get_revisions_url(q, rev_id_old, rev_id_new),
+            &self.retry,
+        );
+        let (j, _) = Self::get_json(&self.wd, &url, &self.retry, self.timeout).await?;
         let revisions = Self::extract_revisions(rev_id_old, rev_id_new, &j);
         Ok(revisions)
     }
 
+    /// The latest revision of an entity from the REST API, converted to action API JSON;
+    /// `None` for lexemes.
+    #[cfg(feature = "rest")]
+    async fn get_latest_revision_rest(&self, q: &str) -> Result<Option<(RevisionId, Value)>> {
+        let url = match WikibaseRest::entity_url(self.wiki, q) {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let (j, etag) = Self::get_json(&self.wd, &url, &self.retry, self.timeout).await?;
+        let rev_id = etag
+            .as_deref()
+            .and_then(WikibaseRest::parse_etag)
+            .ok_or_else(|| anyhow!("No revision in REST response for {q}"))?;
+        Ok(Some((rev_id, WikibaseRest::to_entity_json(q, &j))))
+    }
+
+    /// GETs JSON, retrying on connection errors, `maxlag` errors, 429 and 5xx responses after the
+    /// `Retry-After` delay or with exponential backoff. Returns the `ETag` header with it.
+    async fn get_json(
+        wd: &Wikidata,
+        url: &str,
+        retry: &ApiRetryConfig,
+        timeout: Duration,
+    ) -> Result<(Value, Option<String>)> {
+        let client = wd.reqwest_client()?;
+        let mut attempt = 1;
+        loop {
+            let mut retry_after = None;
+            let error = match client.get(url).timeout(timeout).send().await {
+                Err(e) => anyhow!("Request to {url} failed: {e}"),
+                Ok(response) => {
+                    let status = response.status();
+                    retry_after = response
+                        .headers()
+                        .get("retry-after")
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.trim().parse::<u64>().ok());
+                    let etag = response
+                        .headers()
+                        .get("etag")
+                        .and_then(|v| v.to_str().ok())
+                        .map(|v| v.to_string());
+                    if status.as_u16() == 429 || status.is_server_error() {
+                        anyhow!("HTTP {status} from {url}")
+                    } else {
+                        let j: Value = response.error_for_status()?.json().await?;
+                        if j["error"]["code"].as_str() != Some("maxlag") {
+                            return Ok((j, etag));
+                        }
+                        anyhow!("Wikidata API lagged: {}", j["error"]["info"])
+                    }
+                }
+            };
+            if attempt >= retry.max_attempts {
+                return Err(error);
+            }
+            tokio::time::sleep(Self::retry_delay(retry, attempt, retry_after)).await;
+            attempt += 1;
+        }
+    }
+
+    /// The server's `Retry-After` seconds if given, or else the initial backoff doubled per earlier
+    /// attempt; at most the maximum backoff.
+    pub(crate) fn retry_delay(
+        retry: &ApiRetryConfig,
+        attempt: u32,
+        retry_after: Option<u64>,
+    ) -> Duration {
+        let max = Duration::from_millis(retry.max_backoff_ms);
+        let delay = match retry_after {
+            Some(secs) => Duration::from_secs(secs),
+            None => Duration::from_millis(retry.initial_backoff_ms)
+                .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))),
+        };
+        delay.min(max)
+    }
+
+    /// Adds `maxlag` to an action API URL, so requests back off while the database replicas lag.
+    fn with_maxlag(url: String, retry: &ApiRetryConfig) -> String {
+        match retry.maxlag {
+            0 => url,
+            maxlag => format!("{url}&maxlag={maxlag}"),
+        }
+    }
+
+    /// Loads revisions of any entities, `REVIDS_PER_REQUEST` per API call and up to `max_concurrent`
+    /// calls at a time. Revisions that could not be loaded are left out.
+    pub async fn get_revisions(
+        &self,
+        revids: &[RevisionId],
+        max_concurrent: usize,
+    ) -> HashMap<RevisionId, Value> {
+        let futures = revids.chunks(REVIDS_PER_REQUEST).map(|chunk| {
+            let url = Self::with_maxlag(self.get_revisions_by_id_url(chunk), &self.retry);
+            async move {
+                let (j, _) = Self::get_json(&self.wd, &url, &self.retry, self.timeout).await?;
+                Ok::<_, anyhow::Error>(Self::extract_all_revisions(&j))
+            }
+        });
+        futures::stream::iter(futures)
+            .buffer_unordered(max_concurrent.max(1))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect()
+    }
+
     fn create_label_change(
         &self,
         subject: &ChangeSubject,
@@ -99,6 +380,7 @@ impl RevisionCompare {
     ) -> Change {
         Change {
             item_id: self.item_id,
+            entity_type: self.entity_type,
             revision_id: self.revision_id,
             timestamp: self.timestamp.to_owned(),
             subject: subject.to_owned(),
@@ -129,15 +411,16 @@ impl RevisionCompare {
                     None => continue,
                 };
                 if label != new_label {
-                    ret.push(self.create_label_change(
-                        &key,
-                        ChangeType::Changed,
-                        language,
-                        new_label,
-                    ));
+                    ret.push(
+                        self.create_label_change(&key, ChangeType::Changed, language, new_label)
+                            .with_values(label, new_label),
+                    );
                 }
             } else {
-                ret.push(self.create_label_change(&key, ChangeType::Removed, language, label));
+                ret.push(
+                    self.create_label_change(&key, ChangeType::Removed, language, label)
+                        .with_values(label, ""),
+                );
             }
         }
         for (language, label) in new.iter() {
@@ -146,12 +429,83 @@ impl RevisionCompare {
                     Some(label) => label,
                     None => continue,
                 };
-                ret.push(self.create_label_change(&key, ChangeType::Added, language, label));
+                ret.push(
+                    self.create_label_change(&key, ChangeType::Added, language, label)
+                        .with_values("", label),
+                );
+            }
+        }
+        ret
+    }
+
+    fn compare_lemmas(&self, rev_old: &Value, rev_new: &Value) -> Vec<Change> {
+        self.compare_labels_descriptions(rev_old, rev_new, ChangeSubject::Lemmas)
+    }
+
+    fn create_subentity_change(
+        &self,
+        subject: &ChangeSubject,
+        change_type: ChangeType,
+        id: &str,
+    ) -> Change {
+        Change {
+            item_id: self.item_id,
+            entity_type: self.entity_type,
+            revision_id: self.revision_id,
+            timestamp: self.timestamp.to_owned(),
+            subject: subject.to_owned(),
+            change_type,
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Compares lexeme forms or senses by ID.
+    fn compare_subentities(
+        &self,
+        rev_old: &Value,
+        rev_new: &Value,
+        key: ChangeSubject,
+    ) -> Vec<Change> {
+        let mut ret = vec![];
+        let old = Self::json_array(rev_old, key.as_str());
+        let new = Self::json_array(rev_new, key.as_str());
+        let find =
+            |list: &[Value], id: &str| list.iter().find(|v| v["id"].as_str() == Some(id)).cloned();
+        for old_sub in &old {
+            let id = match old_sub["id"].as_str() {
+                Some(id) => id,
+                None => continue,
+            };
+            match find(&new, id) {
+                Some(new_sub) => {
+                    if old_sub != &new_sub {
+                        ret.push(self.create_subentity_change(&key, ChangeType::Changed, id));
+                    }
+                }
+                None => ret.push(self.create_subentity_change(&key, ChangeType::Removed, id)),
+            }
+        }
+        for new_sub in &new {
+            let id = match new_sub["id"].as_str() {
+                Some(id) => id,
+                None => continue,
+            };
+            if find(&old, id).is_none() {
+                ret.push(self.create_subentity_change(&key, ChangeType::Added, id));
             }
         }
         ret
     }
 
+    fn compare_forms(&self, rev_old: &Value, rev_new: &Value) -> Vec<Change> {
+        self.compare_subentities(rev_old, rev_new, ChangeSubject::Forms)
+    }
+
+    fn compare_senses(&self, rev_old: &Value, rev_new: &Value) -> Vec<Change> {
+        self.compare_subentities(rev_old, rev_new, ChangeSubject::Senses)
+    }
+
     fn compare_labels(&self, rev_old: &Value, rev_new: &Value) -> Vec<Change> {
         self.compare_labels_descriptions(rev_old, rev_new, ChangeSubject::Labels)
     }
@@ -172,22 +526,28 @@ impl RevisionCompare {
         }
         for alias in old_aliases {
             if !new_aliases.contains(alias) {
-                ret.push(self.create_label_change(
-                    &ChangeSubject::Aliases,
-                    ChangeType::Removed,
-                    language,
-                    alias,
-                ));
+                ret.push(
+                    self.create_label_change(
+                        &ChangeSubject::Aliases,
+                        ChangeType::Removed,
+                        language,
+                        alias,
+                    )
+                    .with_values(alias, ""),
+                );
             }
         }
         for alias in new_aliases {
             if !old_aliases.contains(alias) {
-                ret.push(self.create_label_change(
-                    &ChangeSubject::Aliases,
-                    ChangeType::Added,
-                    language,
-                    alias,
-                ));
+                ret.push(
+                    self.create_label_change(
+                        &ChangeSubject::Aliases,
+                        ChangeType::Added,
+                        language,
+                        alias,
+                    )
+                    .with_values("", alias),
+                );
             }
         }
         ret
@@ -217,6 +577,7 @@ impl RevisionCompare {
     fn create_sitelink_change(&self, change_type: ChangeType, site: &str, title: &str) -> Change {
         Change {
             item_id: self.item_id,
+            entity_type: self.entity_type,
             revision_id: self.revision_id,
             timestamp: self.timestamp.to_owned(),
             subject: ChangeSubject::Sitelinks,
@@ -242,10 +603,16 @@ impl RevisionCompare {
                     None => continue,
                 };
                 if link != new_link {
-                    ret.push(self.create_sitelink_change(ChangeType::Changed, site, new_link));
+                    ret.push(
+                        self.create_sitelink_change(ChangeType::Changed, site, new_link)
+                            .with_values(link, new_link),
+                    );
                 }
             } else {
-                ret.push(self.create_sitelink_change(ChangeType::Removed, site, link));
+                ret.push(
+                    self.create_sitelink_change(ChangeType::Removed, site, link)
+                        .with_values(link, ""),
+                );
             }
         }
         for (site, link) in new.iter() {
@@ -254,13 +621,61 @@ impl RevisionCompare {
                     Some(link) => link,
                     None => continue,
                 };
-                ret.push(self.create_sitelink_change(ChangeType::Added, site, link));
+                ret.push(
+                    self.create_sitelink_change(ChangeType::Added, site, link)
+                        .with_values("", link),
+                );
             }
         }
 
         ret
     }
 
+    fn create_badge_change(&self, change_type: ChangeType, site: &str, badge: &str) -> Change {
+        Change {
+            item_id: self.item_id,
+            entity_type: self.entity_type,
+            revision_id: self.revision_id,
+            timestamp: self.timestamp.to_owned(),
+            subject: ChangeSubject::Badges,
+            change_type,
+            site: site.to_owned(),
+            badge: badge.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Compares sitelink badges per site; badges of added or removed sitelinks count as added or removed.
+    fn compare_badges(&self, rev_old: &Value, rev_new: &Value) -> Vec<Change> {
+        let mut ret = vec![];
+        let old = Self::json_object(rev_old, "sitelinks");
+        let new = Self::json_object(rev_new, "sitelinks");
+        let mut all_sites: Vec<&String> = old.keys().chain(new.keys()).collect();
+        all_sites.sort();
+        all_sites.dedup();
+        for site in all_sites {
+            let old_badges = old
+                .get(site)
+                .map(|l| Self::json_array(l, "badges"))
+                .unwrap_or_default();
+            let new_badges = new
+                .get(site)
+                .map(|l| Self::json_array(l, "badges"))
+                .unwrap_or_default();
+            for badge in old_badges.iter().filter(|b| !new_badges.contains(b)) {
+                if let Some(badge) = badge.as_str() {
+                    ret.push(self.create_badge_change(ChangeType::Removed, site, badge));
+                }
+            }
+            for badge in new_badges.iter().filter(|b| !old_badges.contains(b)) {
+                if let Some(badge) = badge.as_str() {
+                    ret.push(self.create_badge_change(ChangeType::Added, site, badge));
+                }
+            }
+        }
+        ret
+    }
+
     fn get_claim_by_id(claim_id: &str, claims: &Map<String, Value>) -> Option<Value> {
         for (_property, prop_claims) in claims.iter() {
             for claim in prop_claims.as_array().unwrap_or(&vec![]) {
@@ -275,6 +690,7 @@ impl RevisionCompare {
     fn create_claim_change(&self, change_type: ChangeType, property: &str, id: &str) -> Change {
         Change {
             item_id: self.item_id,
+            entity_type: self.entity_type,
             revision_id: self.revision_id,
             timestamp: self.timestamp.to_owned(),
             subject: ChangeSubject::Claims,
@@ -285,6 +701,141 @@ impl RevisionCompare {
         }
     }
 
+    fn create_qualifier_change(
+        &self,
+        change_type: ChangeType,
+        property: &str,
+        id: &str,
+        qualifier: &str,
+    ) -> Change {
+        Change {
+            item_id: self.item_id,
+            entity_type: self.entity_type,
+            revision_id: self.revision_id,
+            timestamp: self.timestamp.to_owned(),
+            subject: ChangeSubject::Qualifiers,
+            change_type,
+            property: property.to_owned(),
+            id: id.to_string(),
+            qualifier: qualifier.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Compares the qualifiers of two versions of the same claim, per qualifier property.
+    fn compare_qualifiers(
+        &self,
+        property: &str,
+        claim_id: &str,
+        old_claim: &Value,
+        new_claim: &Value,
+    ) -> Vec<Change> {
+        let mut ret = vec![];
+        let old = Self::json_object(old_claim, "qualifiers");
+        let new = Self::json_object(new_claim, "qualifiers");
+        for (qualifier, old_snaks) in old.iter() {
+            match new.get(qualifier) {
+                Some(new_snaks) => {
+                    if old_snaks != new_snaks {
+                        ret.push(
+                            self.create_qualifier_change(
+                                ChangeType::Changed,
+                                property,
+                                claim_id,
+                                qualifier,
+                            )
+                            .with_values(&old_snaks.to_string(), &new_snaks.to_string()),
+                        );
+                    }
+                }
+                None => ret.push(
+                    self.create_qualifier_change(
+                        ChangeType::Removed,
+                        property,
+                        claim_id,
+                        qualifier,
+                    )
+                    .with_values(&old_snaks.to_string(), ""),
+                ),
+            }
+        }
+        for (qualifier, new_snaks) in new.iter() {
+            if !old.contains_key(qualifier) {
+                ret.push(
+                    self.create_qualifier_change(ChangeType::Added, property, claim_id, qualifier)
+                        .with_values("", &new_snaks.to_string()),
+                );
+            }
+        }
+        ret
+    }
+
+    fn create_reference_change(
+        &self,
+        change_type: ChangeType,
+        property: &str,
+        id: &str,
+        hash: &str,
+    ) -> Change {
+        Change {
+            item_id: self.item_id,
+            entity_type: self.entity_type,
+            revision_id: self.revision_id,
+            timestamp: self.timestamp.to_owned(),
+            subject: ChangeSubject::References,
+            change_type,
+            property: property.to_owned(),
+            id: id.to_string(),
+            hash: hash.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Compares the references of two versions of the same claim by hash.
+    /// A reference replaced by a different one at the same position counts as changed.
+    fn compare_references(
+        &self,
+        property: &str,
+        claim_id: &str,
+        old_claim: &Value,
+        new_claim: &Value,
+    ) -> Vec<Change> {
+        let mut ret = vec![];
+        let old = Self::json_array(old_claim, "references");
+        let new = Self::json_array(new_claim, "references");
+        let old_hashes: Vec<&str> = old.iter().filter_map(|r| r["hash"].as_str()).collect();
+        let new_hashes: Vec<&str> = new.iter().filter_map(|r| r["hash"].as_str()).collect();
+        let mut changed_positions = vec![];
+        for (pos, hash) in old_hashes.iter().enumerate() {
+            if new_hashes.contains(hash) {
+                continue;
+            }
+            match new_hashes.get(pos) {
+                Some(new_hash) if !old_hashes.contains(new_hash) => {
+                    changed_positions.push(pos);
+                    ret.push(self.create_reference_change(
+                        ChangeType::Changed,
+                        property,
+                        claim_id,
+                        new_hash,
+                    ));
+                }
+                _ => ret.push(self.create_reference_change(
+                    ChangeType::Removed,
+                    property,
+                    claim_id,
+                    hash,
+                )),
+            }
+        }
+        for (pos, hash) in new_hashes.iter().enumerate() {
+            if !old_hashes.contains(hash) && !changed_positions.contains(&pos) {
+                ret.push(self.create_reference_change(ChangeType::Added, property, claim_id, hash));
+            }
+        }
+        ret
+    }
+
     fn compare_statements(&self, rev_old: &Value, rev_new: &Value) -> Vec<Change> {
         let mut ret = vec![];
         let old_claims = Self::json_object(rev_old, "claims");
@@ -298,13 +849,26 @@ impl RevisionCompare {
         for (property, prop_claims) in old_claims.iter() {
             for claim in prop_claims.as_array().unwrap_or(&vec![]) {
                 let claim_id = claim.get("id").unwrap().as_str().unwrap();
-                let new_claim = Self::get_claim_by_id(claim_id, &new_claims);
-                if new_claim.is_none() {
-                    ret.push(self.create_claim_change(ChangeType::Removed, property, claim_id));
-                } else {
-                    let new_claim = new_claim.unwrap();
-                    if claim != &new_claim {
-                        ret.push(self.create_claim_change(ChangeType::Changed, property, claim_id));
+                let old_value = claim["mainsnak"].to_string();
+                match Self::get_claim_by_id(claim_id, &new_claims) {
+                    None => ret.push(
+                        self.create_claim_change(ChangeType::Removed, property, claim_id)
+                            .with_values(&old_value, ""),
+                    ),
+                    Some(new_claim) => {
+                        if claim != &new_claim {
+                            let new_value = new_claim["mainsnak"].to_string();
+                            ret.push(
+                                self.create_claim_change(ChangeType::Changed, property, claim_id)
+                                    .with_values(&old_value, &new_value),
+                            );
+                            ret.append(
+                                &mut self.compare_qualifiers(property, claim_id, claim, &new_claim),
+                            );
+                            ret.append(
+                                &mut self.compare_references(property, claim_id, claim, &new_claim),
+                            );
+                        }
                     }
                 }
             }
@@ -314,7 +878,10 @@ impl RevisionCompare {
                 let claim_id = claim.get("id").unwrap().as_str().unwrap();
                 let old_claim = Self::get_claim_by_id(claim_id, &old_claims);
                 if old_claim.is_none() {
-                    ret.push(self.create_claim_change(ChangeType::Added, property, claim_id));
+                    ret.push(
+                        self.create_claim_change(ChangeType::Added, property, claim_id)
+                            .with_values("", &claim["mainsnak"].to_string()),
+                    );
                 }
             }
         }
@@ -322,13 +889,51 @@ impl RevisionCompare {
         ret
     }
 
+    fn compare_datatype(&self, rev_old: &Value, rev_new: &Value) -> Vec<Change> {
+        let old = rev_old["datatype"].as_str().unwrap_or_default();
+        let new = rev_new["datatype"].as_str().unwrap_or_default();
+        if old == new {
+            return vec![];
+        }
+        vec![Change {
+            item_id: self.item_id,
+            entity_type: self.entity_type,
+            revision_id: self.revision_id,
+            timestamp: self.timestamp.to_owned(),
+            subject: ChangeSubject::Datatype,
+            change_type: ChangeType::Changed,
+            text: new.to_string(),
+            old_text: old.to_string(),
+            new_text: new.to_string(),
+            ..Default::default()
+        }]
+    }
+
     fn compare_revisions(&self, rev_old: &Value, rev_new: &Value) -> Vec<Change> {
         let mut ret = vec![];
-        ret.append(&mut self.compare_labels(rev_old, rev_new));
-        ret.append(&mut self.compare_descriptions(rev_old, rev_new));
-        ret.append(&mut self.compare_aliases(rev_old, rev_new));
-        ret.append(&mut self.compare_statements(rev_old, rev_new));
-        ret.append(&mut self.compare_sitelinks(rev_old, rev_new));
+        match self.entity_type {
+            EntityType::Item => {
+                ret.append(&mut self.compare_labels(rev_old, rev_new));
+                ret.append(&mut self.compare_descriptions(rev_old, rev_new));
+                ret.append(&mut self.compare_aliases(rev_old, rev_new));
+                ret.append(&mut self.compare_statements(rev_old, rev_new));
+                ret.append(&mut self.compare_sitelinks(rev_old, rev_new));
+                ret.append(&mut self.compare_badges(rev_old, rev_new));
+            }
+            EntityType::Property => {
+                ret.append(&mut self.compare_labels(rev_old, rev_new));
+                ret.append(&mut self.compare_descriptions(rev_old, rev_new));
+                ret.append(&mut self.compare_aliases(rev_old, rev_new));
+                ret.append(&mut self.compare_statements(rev_old, rev_new));
+                ret.append(&mut self.compare_datatype(rev_old, rev_new));
+            }
+            EntityType::Lexeme => {
+                ret.append(&mut self.compare_lemmas(rev_old, rev_new));
+                ret.append(&mut self.compare_statements(rev_old, rev_new));
+                ret.append(&mut self.compare_forms(rev_old, rev_new));
+                ret.append(&mut self.compare_senses(rev_old, rev_new));
+            }
+        }
         ret
     }
 
@@ -382,7 +987,7 @@ mod tests {
         let rev_id_old = 2208025531;
         let rev_id_new = 2208025540;
         let revisions = wdrc
-            .get_revisions_for_item(q, rev_id_old, rev_id_new)
+            .get_revisions_for_item(q, rev_id_old, rev_id_new, &HashMap::new())
             .await
             .unwrap();
         assert_eq!(revisions.len(), 2);
@@ -396,6 +1001,86 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_retry_delay() {
+        let retry = ApiRetryConfig {
+            max_attempts: 5,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 5000,
+            maxlag: 5,
+        };
+        let delay =
+            |attempt, retry_after| RevisionCompare::retry_delay(&retry, attempt, retry_after);
+        assert_eq!(delay(1, None), Duration::from_secs(1));
+        assert_eq!(delay(2, None), Duration::from_secs(2));
+        assert_eq!(delay(4, None), Duration::from_secs(5));
+        assert_eq!(delay(1, Some(3)), Duration::from_secs(3));
+        assert_eq!(delay(1, Some(60)), Duration::from_secs(5));
+        assert_eq!(
+            RevisionCompare::with_maxlag("https://x/w/api.php?action=query".to_string(), &retry),
+            "https://x/w/api.php?action=query&maxlag=5"
+        );
+    }
+
+    #[test]
+    fn test_extract_all_revisions() {
+        assert_eq!(
+            RevisionCompare::new(Arc::new(Wikidata::new())).get_revisions_by_id_url(&[11, 12]),
+            "https://www.wikidata.org/w/api.php?action=query&prop=revisions&revids=11|12&rvprop=ids|content&rvslots=main&format=json"
+        );
+        let j = json!({"query": {"pages": {
+            "1": {"revisions": [{"revid": 11, "slots": {"main": {"*": "{\"id\":\"Q1\"}"}}}]},
+            "2": {"revisions": [{"revid": 12, "slots": {"main": {"*": "{\"id\":\"Q2\"}"}}}, {"revid": 13}]},
+        }}});
+        let revisions = RevisionCompare::extract_all_revisions(&j);
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(revisions[&12]["id"], "Q2");
+    }
+
+    #[cfg(feature = "rest")]
+    #[test]
+    fn test_compare_rest_with_action() {
+        // The same revision, as the REST API and as the action API serve it
+        let rest = json!({
+            "id": "Q42",
+            "labels": {"en": "Douglas Adams"},
+            "descriptions": {"en": "English writer"},
+            "aliases": {"en": ["DNA"]},
+            "sitelinks": {"enwiki": {"title": "Douglas Adams", "badges": []}},
+            "statements": {"P569": [{
+                "id": "Q42$D8404CDA-25E4-4334-AF13-A3290BCD9C0F",
+                "rank": "preferred",
+                "property": {"id": "P569", "data_type": "time"},
+                "value": {"type": "value", "content": {"time": "+1952-03-11T00:00:00Z", "precision": 11, "calendarmodel": "http://www.wikidata.org/entity/Q1985727"}},
+                "qualifiers": [{"property": {"id": "P31", "data_type": "wikibase-item"}, "value": {"type": "value", "content": "Q5"}}],
+                "references": [{"hash": "r1", "parts": [{"property": {"id": "P248", "data_type": "wikibase-item"}, "value": {"type": "value", "content": "Q36578"}}]}],
+            }]},
+        });
+        let action = json!({
+            "id": "Q42",
+            "labels": {"en": {"language": "en", "value": "Douglas Adams"}},
+            "descriptions": {"en": {"language": "en", "value": "English writer"}},
+            "aliases": {"en": [{"language": "en", "value": "DNA"}]},
+            "sitelinks": {"enwiki": {"site": "enwiki", "title": "Douglas Adams", "badges": []}},
+            "claims": {"P569": [{
+                "id": "Q42$D8404CDA-25E4-4334-AF13-A3290BCD9C0F",
+                "rank": "preferred",
+                "type": "statement",
+                "mainsnak": {"snaktype": "value", "property": "P569", "hash": "h1", "datatype": "time", "datavalue": {"type": "time", "value": {"time": "+1952-03-11T00:00:00Z", "timezone": 0, "before": 0, "after": 0, "precision": 11, "calendarmodel": "http://www.wikidata.org/entity/Q1985727"}}},
+                "qualifiers": {"P31": [{"snaktype": "value", "property": "P31", "hash": "h2", "datatype": "wikibase-item", "datavalue": {"type": "wikibase-entityid", "value": {"entity-type": "item", "numeric-id": 5, "id": "Q5"}}}]},
+                "qualifiers-order": ["P31"],
+                "references": [{"hash": "r1", "snaks": {"P248": [{"snaktype": "value", "property": "P248", "hash": "h3", "datatype": "wikibase-item", "datavalue": {"type": "wikibase-entityid", "value": {"entity-type": "item", "numeric-id": 36578, "id": "Q36578"}}}]}, "snaks-order": ["P248"]}],
+            }]},
+        });
+        let rest = WikibaseRest::to_entity_json("Q42", &rest);
+        let ci = ChangedItem::new("Q42", 1, 2, "20240101000000");
+        let mut rc =
+            RevisionCompare::new(Arc::new(Wikidata::new())).with_backend(RevisionBackend::Rest);
+        // An old revision prefetched from the action API, the new one from the REST API, and back
+        assert_eq!(rc.compare_loaded(&ci, &action, &rest).unwrap(), vec![]);
+        assert_eq!(rc.compare_loaded(&ci, &rest, &action).unwrap(), vec![]);
+    }
+
     #[test]
     fn test_compare_labels() {
         let old = json!({"labels":{
@@ -418,21 +1103,24 @@ mod tests {
                 language: "en".to_string(),
                 text: "new".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("old", "new"),
             Change {
                 subject: ChangeSubject::Labels,
                 change_type: ChangeType::Removed,
                 language: "fr".to_string(),
                 text: "ancien".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("ancien", ""),
             Change {
                 subject: ChangeSubject::Labels,
                 change_type: ChangeType::Added,
                 language: "it".to_string(),
                 text: "nuovo".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("", "nuovo"),
             // json!({"change":"changed","language":"en","text":"new","subject":"labels"}),
             //     json!({"change":"removed","language":"fr","text":"ancien","subject":"labels"}),
             //     json!({"change":"added","language":"it","text":"nuovo","subject":"labels"}),
@@ -440,6 +1128,19 @@ mod tests {
         assert_eq!(changes, expected);
     }
 
+    #[test]
+    fn test_compare_creation() {
+        let new = json!({
+            "labels": {"en": {"value": "new"}},
+            "claims": {"P31": [{"id": "Q1$1", "mainsnak": {"snaktype": "value", "datavalue": {"value": "x"}}}]},
+        });
+        let wd = Arc::new(Wikidata::new());
+        let rc = RevisionCompare::new(wd);
+        let changes = rc.compare_revisions(&json!({}), &new);
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(|c| c.change_type == ChangeType::Added));
+    }
+
     #[test]
     fn test_compare_descriptions() {
         let old = json!({"descriptions":{
@@ -462,21 +1163,24 @@ mod tests {
                 language: "en".to_string(),
                 text: "new".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("old", "new"),
             Change {
                 subject: ChangeSubject::Descriptions,
                 change_type: ChangeType::Removed,
                 language: "fr".to_string(),
                 text: "ancien".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("ancien", ""),
             Change {
                 subject: ChangeSubject::Descriptions,
                 change_type: ChangeType::Added,
                 language: "it".to_string(),
                 text: "nuovo".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("", "nuovo"),
             // json!({"change":"changed","language":"en","text":"new","subject":"descriptions"}),
             // json!({"change":"removed","language":"fr","text":"ancien","subject":"descriptions"}),
             // json!({"change":"added","language":"it","text":"nuovo","subject":"descriptions"}),
@@ -506,28 +1210,32 @@ mod tests {
                 language: "en".to_string(),
                 text: "old".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("old", ""),
             Change {
                 subject: ChangeSubject::Aliases,
                 change_type: ChangeType::Added,
                 language: "en".to_string(),
                 text: "new".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("", "new"),
             Change {
                 subject: ChangeSubject::Aliases,
                 change_type: ChangeType::Removed,
                 language: "fr".to_string(),
                 text: "ancien".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("ancien", ""),
             Change {
                 subject: ChangeSubject::Aliases,
                 change_type: ChangeType::Added,
                 language: "it".to_string(),
                 text: "nuovo".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("", "nuovo"),
             // json!({"change": "removed","language": "en","text": "old","subject": "aliases"}),
             // json!({"change": "added","language": "en","text": "new","subject": "aliases"}),
             // json!({"change": "removed","language": "fr","text": "ancien","subject": "aliases"}),
@@ -558,21 +1266,24 @@ mod tests {
                 site: "enwiki".to_string(),
                 title: "new".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("old", "new"),
             Change {
                 subject: ChangeSubject::Sitelinks,
                 change_type: ChangeType::Removed,
                 site: "frwiki".to_string(),
                 title: "ancien".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("ancien", ""),
             Change {
                 subject: ChangeSubject::Sitelinks,
                 change_type: ChangeType::Added,
                 site: "itwiki".to_string(),
                 title: "nuovo".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("", "nuovo"),
             // json!({"change":"changed","site":"enwiki","title":"new","subject":"sitelinks"}),
             //    json!({"change":"removed","site":"frwiki","title":"ancien","subject":"sitelinks"}),
             //    json!({"change":"added","site":"itwiki","title":"nuovo","subject":"sitelinks"}),
@@ -580,6 +1291,36 @@ mod tests {
         assert_eq!(changes, expected);
     }
 
+    #[test]
+    fn test_compare_badges() {
+        let old = json!({"sitelinks":{
+            "enwiki": {"title":"A", "badges": ["Q17437796"]},
+            "dewiki": {"title":"A", "badges": ["Q17437798"]},
+            "frwiki": {"title":"A", "badges": ["Q17437796"]}}
+        });
+        let new = json!({"sitelinks":{
+            "enwiki": {"title":"A", "badges": []},
+            "dewiki": {"title":"A", "badges": ["Q17437798"]},
+            "itwiki": {"title":"A", "badges": ["Q17437796"]}}
+        });
+        let wd = Arc::new(Wikidata::new());
+        let rc = RevisionCompare::new(wd);
+        let changes = rc.compare_badges(&old, &new);
+        let badge_change = |change_type, site: &str| Change {
+            subject: ChangeSubject::Badges,
+            change_type,
+            site: site.to_string(),
+            badge: "Q17437796".to_string(),
+            ..Default::default()
+        };
+        let expected = vec![
+            badge_change(ChangeType::Removed, "enwiki"),
+            badge_change(ChangeType::Removed, "frwiki"),
+            badge_change(ChangeType::Added, "itwiki"),
+        ];
+        assert_eq!(changes, expected);
+    }
+
     #[test]
     fn test_compare_claims() {
         let old = json!({"claims":{
@@ -605,6 +1346,8 @@ mod tests {
         let wd = Arc::new(Wikidata::new());
         let rc = RevisionCompare::new(wd);
         let changes = rc.compare_statements(&old, &new);
+        let snak =
+            |value: &str| json!({"snaktype": "value", "datavalue": {"value": value}}).to_string();
         let expected = vec![
             Change {
                 subject: ChangeSubject::Claims,
@@ -612,35 +1355,40 @@ mod tests {
                 property: "P1".to_string(),
                 id: "Q1$123".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values(&snak("old"), &snak("new")),
             Change {
                 subject: ChangeSubject::Claims,
                 change_type: ChangeType::Removed,
                 property: "P1".to_string(),
                 id: "Q1$125".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values(&snak("old3"), ""),
             Change {
                 subject: ChangeSubject::Claims,
                 change_type: ChangeType::Removed,
                 property: "P2".to_string(),
                 id: "Q1$126".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values(&snak("old"), ""),
             Change {
                 subject: ChangeSubject::Claims,
                 change_type: ChangeType::Added,
                 property: "P1".to_string(),
                 id: "Q1$127".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("", &snak("new2")),
             Change {
                 subject: ChangeSubject::Claims,
                 change_type: ChangeType::Added,
                 property: "P3".to_string(),
                 id: "Q1$128".to_string(),
                 ..Default::default()
-            },
+            }
+            .with_values("", &snak("new")),
             // json!({"subject": "claims","change": "changed","property": "P1","id": "Q1$123"}),
             // json!({"subject": "claims","change": "removed","property": "P1","id": "Q1$125"}),
             // json!({"subject": "claims","change": "removed","property": "P2","id": "Q1$126"}),
@@ -649,4 +1397,161 @@ mod tests {
         ];
         assert_eq!(changes, expected);
     }
+
+    #[test]
+    fn test_compare_qualifiers() {
+        let old = json!({"claims":{
+            "P1": [
+                {"id": "Q1$123", "mainsnak": {"snaktype": "value", "datavalue": {"value": "x"}}, "qualifiers": {
+                    "P580": [{"hash": "a", "snaktype": "value", "datavalue": {"value": "old"}}],
+                    "P582": [{"hash": "b", "snaktype": "value", "datavalue": {"value": "end"}}],
+                }},
+            ],
+        }});
+        let new = json!({"claims":{
+            "P1": [
+                {"id": "Q1$123", "mainsnak": {"snaktype": "value", "datavalue": {"value": "x"}}, "qualifiers": {
+                    "P580": [{"hash": "c", "snaktype": "value", "datavalue": {"value": "new"}}],
+                    "P585": [{"hash": "d", "snaktype": "value", "datavalue": {"value": "then"}}],
+                }},
+            ],
+        }});
+        let wd = Arc::new(Wikidata::new());
+        let rc = RevisionCompare::new(wd);
+        let changes = rc.compare_statements(&old, &new);
+        let snaks = |entity: &Value, qualifier: &str| {
+            entity["claims"]["P1"][0]["qualifiers"][qualifier].to_string()
+        };
+        let mainsnak = old["claims"]["P1"][0]["mainsnak"].to_string();
+        let qualifier_change = |change_type, qualifier: &str| Change {
+            subject: ChangeSubject::Qualifiers,
+            change_type,
+            property: "P1".to_string(),
+            id: "Q1$123".to_string(),
+            qualifier: qualifier.to_string(),
+            ..Default::default()
+        };
+        let expected = vec![
+            Change {
+                subject: ChangeSubject::Claims,
+                change_type: ChangeType::Changed,
+                property: "P1".to_string(),
+                id: "Q1$123".to_string(),
+                ..Default::default()
+            }
+            .with_values(&mainsnak, &mainsnak),
+            qualifier_change(ChangeType::Changed, "P580")
+                .with_values(&snaks(&old, "P580"), &snaks(&new, "P580")),
+            qualifier_change(ChangeType::Removed, "P582").with_values(&snaks(&old, "P582"), ""),
+            qualifier_change(ChangeType::Added, "P585").with_values("", &snaks(&new, "P585")),
+        ];
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn test_compare_references() {
+        let old = json!({"claims":{
+            "P1": [
+                {"id": "Q1$123", "references": [{"hash": "aa"}, {"hash": "bb"}, {"hash": "cc"}]},
+            ],
+        }});
+        let new = json!({"claims":{
+            "P1": [
+                {"id": "Q1$123", "references": [{"hash": "aa"}, {"hash": "dd"}]},
+            ],
+        }});
+        let wd = Arc::new(Wikidata::new());
+        let rc = RevisionCompare::new(wd);
+        let claim_id = "Q1$123";
+        let old_claim = &old["claims"]["P1"][0];
+        let new_claim = &new["claims"]["P1"][0];
+        let changes = rc.compare_references("P1", claim_id, old_claim, new_claim);
+        let reference_change = |change_type, hash: &str| Change {
+            subject: ChangeSubject::References,
+            change_type,
+            property: "P1".to_string(),
+            id: claim_id.to_string(),
+            hash: hash.to_string(),
+            ..Default::default()
+        };
+        let expected = vec![
+            reference_change(ChangeType::Changed, "dd"),
+            reference_change(ChangeType::Removed, "cc"),
+        ];
+        assert_eq!(changes, expected);
+
+        let changes = rc.compare_references("P1", claim_id, new_claim, old_claim);
+        let expected = vec![
+            reference_change(ChangeType::Changed, "bb"),
+            reference_change(ChangeType::Added, "cc"),
+        ];
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn test_compare_lexeme() {
+        let old = json!({
+            "lemmas": {"en": {"language": "en", "value": "colour"}},
+            "forms": [
+                {"id": "L1-F1", "representations": {"en": {"language": "en", "value": "colours"}}},
+                {"id": "L1-F2", "representations": {"en": {"language": "en", "value": "coloured"}}},
+            ],
+            "senses": [{"id": "L1-S1", "glosses": {"en": {"language": "en", "value": "hue"}}}],
+        });
+        let new = json!({
+            "lemmas": {"en": {"language": "en", "value": "color"}},
+            "forms": [
+                {"id": "L1-F1", "representations": {"en": {"language": "en", "value": "colors"}}},
+            ],
+            "senses": [
+                {"id": "L1-S1", "glosses": {"en": {"language": "en", "value": "hue"}}},
+                {"id": "L1-S2", "glosses": {"en": {"language": "en", "value": "pigment"}}},
+            ],
+        });
+        let wd = Arc::new(Wikidata::new());
+        let mut rc = RevisionCompare::new(wd);
+        rc.entity_type = EntityType::Lexeme;
+        let changes = rc.compare_revisions(&old, &new);
+        let subentity_change = |subject, change_type, id: &str| Change {
+            subject,
+            change_type,
+            id: id.to_string(),
+            entity_type: EntityType::Lexeme,
+            ..Default::default()
+        };
+        let expected = vec![
+            Change {
+                subject: ChangeSubject::Lemmas,
+                change_type: ChangeType::Changed,
+                language: "en".to_string(),
+                text: "color".to_string(),
+                entity_type: EntityType::Lexeme,
+                ..Default::default()
+            }
+            .with_values("colour", "color"),
+            subentity_change(ChangeSubject::Forms, ChangeType::Changed, "L1-F1"),
+            subentity_change(ChangeSubject::Forms, ChangeType::Removed, "L1-F2"),
+            subentity_change(ChangeSubject::Senses, ChangeType::Added, "L1-S2"),
+        ];
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn test_compare_property_datatype() {
+        let old = json!({"datatype": "string", "labels": {"en": {"value": "ID"}}});
+        let new = json!({"datatype": "external-id", "labels": {"en": {"value": "ID"}}});
+        let wd = Arc::new(Wikidata::new());
+        let mut rc = RevisionCompare::new(wd);
+        rc.entity_type = EntityType::Property;
+        let changes = rc.compare_revisions(&old, &new);
+        let expected = vec![Change {
+            subject: ChangeSubject::Datatype,
+            change_type: ChangeType::Changed,
+            text: "external-id".to_string(),
+            entity_type: EntityType::Property,
+            ..Default::default()
+        }
+        .with_values("string", "external-id")];
+        assert_eq!(changes, expected);
+    }
 }
@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Result};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+
+/// Days searched back for the last time a schedule fired; Feb 29 fires at least every 8 years.
+const MAX_DAYS_BACK: u32 = 8 * 366;
+
+/// A cron-style schedule: `minute hour day-of-month month day-of-week`, each field `*`, a number,
+/// a range like `1-5`, a step like `*/15` or `8-18/2`, or a list of these like `0,30`. Sunday is
+/// 0 or 7. If both day fields are restricted, either one matching is enough, like in cron.
+/// `@hourly`, `@daily` and `@weekly` stand for `0 * * * *`, `0 0 * * *` and `0 0 * * 0`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month is `*`.
+    any_day: bool,
+    /// Whether the day of week is `*`.
+    any_weekday: bool,
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Self> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            other => other,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(anyhow!(
+                "Schedule must have 5 fields or be @hourly, @daily or @weekly: {expression:?}"
+            ));
+        };
+        let mut weekdays = Self::field(weekday, 0, 7)?;
+        // Sunday is 0 or 7
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: Self::field(minute, 0, 59)?,
+            hours: Self::field(hour, 0, 23)?,
+            days: Self::field(day, 1, 31)?,
+            months: Self::field(month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    /// The values of a field between `min` and `max`, as bits.
+    fn field(field: &str, min: u32, max: u32) -> Result<u64> {
+        let mut ret = 0;
+        for part in field.split(',') {
+            let bad = || anyhow!("Bad schedule field {field:?}");
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|_| bad())?),
+                None => (part, 1),
+            };
+            let (first, last) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((first, last)) => (
+                        first.parse().map_err(|_| bad())?,
+                        last.parse().map_err(|_| bad())?,
+                    ),
+                    None => {
+                        let value = range.parse().map_err(|_| bad())?;
+                        // `5/15` is `5-max/15`, like in cron
+                        match part.contains('/') {
+                            true => (value, max),
+                            false => (value, value),
+                        }
+                    }
+                },
+            };
+            if step == 0 || first < min || last > max || first > last {
+                return Err(bad());
+            }
+            for value in (first..=last).step_by(step as usize) {
+                ret |= 1 << value;
+            }
+        }
+        Ok(ret)
+    }
+
+    fn has(bits: u64, value: u32) -> bool {
+        bits & (1 << value) != 0
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = Self::has(self.days, date.day());
+        let weekday = Self::has(self.weekdays, date.weekday().num_days_from_sunday());
+        Self::has(self.months, date.month())
+            && match (self.any_day, self.any_weekday) {
+                (false, false) => day || weekday,
+                _ => day && weekday,
+            }
+    }
+
+    /// The last time the schedule fired in `timezone` up to `now`, both in UTC. Local times
+    /// skipped by a clock change do not fire; repeated ones fire the first time.
+    pub fn last_fired(&self, timezone: Tz, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let local = timezone.from_utc_datetime(&now).naive_local();
+        let mut date = local.date();
+        for _ in 0..MAX_DAYS_BACK {
+            if self.matches_day(date) {
+                for hour in (0..24).rev().filter(|h| Self::has(self.hours, *h)) {
+                    for minute in (0..60).rev().filter(|m| Self::has(self.minutes, *m)) {
+                        let time = date.and_hms_opt(hour, minute, 0)?;
+                        if time > local {
+                            continue;
+                        }
+                        if let Some(fired) = timezone.from_local_datetime(&time).earliest() {
+                            return Some(fired.naive_utc());
+                        }
+                    }
+                }
+            }
+            date = date.pred_opt()?;
+        }
+        None
+    }
+
+    /// Whether the schedule fired since `last_sent`; always if nothing was sent yet.
+    pub fn is_due(
+        &self,
+        timezone: Tz,
+        last_sent: Option<NaiveDateTime>,
+        now: NaiveDateTime,
+    ) -> bool {
+        match self.last_fired(timezone, now) {
+            Some(fired) => last_sent.is_none_or(|sent| sent < fired),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(timestamp: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Schedule::parse("@daily").unwrap(),
+            Schedule::parse("0 0 * * *").unwrap()
+        );
+        let schedule = Schedule::parse("*/15 8-18/2 1,15 * 1-5").unwrap();
+        assert_eq!(schedule.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(
+            schedule.hours,
+            (8..=18).step_by(2).map(|h| 1 << h).sum::<u64>()
+        );
+        assert_eq!(Schedule::parse("0 0 * * 7").unwrap().weekdays, 1 | 1 << 7);
+        assert!(Schedule::parse("0 0 * *").is_err());
+        assert!(Schedule::parse("60 0 * * *").is_err());
+        assert!(Schedule::parse("0 0 0 * *").is_err());
+        assert!(Schedule::parse("*/0 * * * *").is_err());
+        assert!(Schedule::parse("5-1 * * * *").is_err());
+        assert!(Schedule::parse("@monthly").is_err());
+    }
+
+    #[test]
+    fn test_last_fired() {
+        let daily = Schedule::parse("0 8 * * *").unwrap();
+        assert_eq!(
+            daily.last_fired(Tz::UTC, at("2024-03-05 07:59")),
+            Some(at("2024-03-04 08:00"))
+        );
+        assert_eq!(
+            daily.last_fired(Tz::UTC, at("2024-03-05 08:00")),
+            Some(at("2024-03-05 08:00"))
+        );
+        // 08:00 in Berlin is 07:00 UTC in winter
+        assert_eq!(
+            daily.last_fired(Tz::Europe__Berlin, at("2024-03-05 07:30")),
+            Some(at("2024-03-05 07:00"))
+        );
+        // 2024-03-04 is a Monday
+        let weekly = Schedule::parse("@weekly").unwrap();
+        assert_eq!(
+            weekly.last_fired(Tz::UTC, at("2024-03-05 12:00")),
+            Some(at("2024-03-03 00:00"))
+        );
+        // The 1st of the month or any Friday
+        let either = Schedule::parse("0 0 1 * 5").unwrap();
+        assert_eq!(
+            either.last_fired(Tz::UTC, at("2024-03-05 12:00")),
+            Some(at("2024-03-01 00:00"))
+        );
+        assert_eq!(
+            either.last_fired(Tz::UTC, at("2024-03-09 12:00")),
+            Some(at("2024-03-08 00:00"))
+        );
+        let leap = Schedule::parse("0 0 29 2 *").unwrap();
+        assert_eq!(
+            leap.last_fired(Tz::UTC, at("2027-01-01 00:00")),
+            Some(at("2024-02-29 00:00"))
+        );
+        // 02:30 does not exist in Berlin on 2024-03-31
+        let night = Schedule::parse("30 2 * * *").unwrap();
+        assert_eq!(
+            night.last_fired(Tz::Europe__Berlin, at("2024-03-31 12:00")),
+            Some(at("2024-03-30 01:30"))
+        );
+    }
+
+    #[test]
+    fn test_is_due() {
+        let hourly = Schedule::parse("@hourly").unwrap();
+        let now = at("2024-03-05 10:20");
+        assert!(hourly.is_due(Tz::UTC, None, now));
+        assert!(hourly.is_due(Tz::UTC, Some(at("2024-03-05 09:59")), now));
+        assert!(!hourly.is_due(Tz::UTC, Some(at("2024-03-05 10:00")), now));
+        assert!(!hourly.is_due(Tz::UTC, Some(at("2024-03-05 10:05")), now));
+    }
+}
@@ -0,0 +1,473 @@
+use anyhow::Result;
+use wikimisc::mysql_async::prelude::Queryable;
+
+use crate::{change::EntityType, WdRc};
+
+/// Column type of change types.
+pub(crate) const CHANGE_TYPE: &str = "enum('added','changed','removed','reverted') NOT NULL";
+
+/// Tables shared by all entity types, with their column and index definitions.
+const SHARED_TABLES: &[(&str, &str)] = &[
+    (
+        "meta",
+        "`key` varchar(64) NOT NULL PRIMARY KEY,
+  `value` varchar(255) NOT NULL",
+    ),
+    (
+        "runs",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `started` varchar(14) NOT NULL,
+  `finished` varchar(14) NOT NULL,
+  `error` text,
+  `dropped` int unsigned NOT NULL DEFAULT 0,
+  `dropped_reasons` text,
+  KEY `started` (`started`)",
+    ),
+    (
+        "texts",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `value` varchar(255) NOT NULL,
+  UNIQUE KEY `value` (`value`)",
+    ),
+    (
+        "failed_items",
+        "`q` varchar(32) NOT NULL,
+  `rev_old` int unsigned NOT NULL,
+  `rev_new` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  `user` varchar(255),
+  `comment` text,
+  `is_bot` tinyint(1) NOT NULL DEFAULT 0,
+  `tags` text,
+  `error` text,
+  `attempts` int unsigned NOT NULL DEFAULT 1,
+  PRIMARY KEY (`q`,`rev_new`)",
+    ),
+    (
+        "work_queue",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `q` varchar(32) NOT NULL,
+  `rev_old` int unsigned NOT NULL,
+  `rev_new` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  `user` varchar(255),
+  `comment` text,
+  `is_bot` tinyint(1) NOT NULL DEFAULT 0,
+  `tags` text,
+  UNIQUE KEY `q_rev_new` (`q`,`rev_new`)",
+    ),
+    (
+        "deliveries",
+        "`id` bigint unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `subscription` varchar(64) NOT NULL,
+  `body` mediumtext NOT NULL,
+  `status` enum('pending','delivered','dead') NOT NULL DEFAULT 'pending',
+  `attempts` int unsigned NOT NULL DEFAULT 0,
+  `error` text,
+  `created` varchar(14) NOT NULL,
+  `updated` varchar(14) NOT NULL,
+  KEY `subscription_status` (`subscription`,`status`,`id`),
+  KEY `status_updated` (`status`,`updated`)",
+    ),
+    (
+        "watchers",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `name` varchar(255) NOT NULL,
+  `url` varchar(2048),
+  `secret` varchar(255),
+  `token_hash` char(64),
+  UNIQUE KEY `name` (`name`)",
+    ),
+    (
+        "watchlist",
+        "`watcher` int unsigned NOT NULL,
+  `entity` varchar(32) NOT NULL,
+  PRIMARY KEY (`watcher`,`entity`),
+  KEY `entity` (`entity`)",
+    ),
+    (
+        "notifications",
+        "`id` bigint unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `watcher` int unsigned NOT NULL,
+  `entity` varchar(32) NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `subject` varchar(32) NOT NULL,
+  `change_type` varchar(16) NOT NULL,
+  `language` varchar(64),
+  `property` varchar(32),
+  `timestamp` varchar(14) NOT NULL,
+  KEY `watcher` (`watcher`,`id`),
+  KEY `timestamp` (`timestamp`)",
+    ),
+    (
+        "sessions",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `entity_type` varchar(16) NOT NULL,
+  `item` int unsigned NOT NULL,
+  `user` varchar(255) NOT NULL,
+  `started` varchar(14) NOT NULL,
+  `last_edit` varchar(14) NOT NULL,
+  `revisions` int unsigned NOT NULL DEFAULT 0,
+  `changes` int unsigned NOT NULL DEFAULT 0,
+  KEY `item_user` (`entity_type`,`item`,`user`,`last_edit`)",
+    ),
+    (
+        "weekly_stats",
+        "`end` varchar(14) NOT NULL,
+  `group` varchar(32) NOT NULL,
+  `key` varchar(255) NOT NULL,
+  `current` bigint NOT NULL,
+  `previous` bigint NOT NULL,
+  PRIMARY KEY (`end`,`group`,`key`)",
+    ),
+    (
+        "redactions",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `revision` int unsigned NOT NULL,
+  `reason` text NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  `rows` int unsigned NOT NULL,
+  `republished` varchar(255),
+  KEY `revision` (`revision`)",
+    ),
+    (
+        "tombstones",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `table` varchar(64) NOT NULL,
+  `item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `reason` varchar(32) NOT NULL,
+  `superseded_by` varchar(255),
+  `timestamp` varchar(14) NOT NULL,
+  `rows` int unsigned NOT NULL,
+  KEY `item` (`item`,`revision`),
+  KEY `timestamp` (`timestamp`)",
+    ),
+    (
+        "published_files",
+        "`month` varchar(6) NOT NULL,
+  `name` varchar(64) NOT NULL,
+  `rows` bigint unsigned NOT NULL,
+  `sha256` char(64) NOT NULL,
+  `published` varchar(14) NOT NULL,
+  `directory` varchar(255) NOT NULL,
+  PRIMARY KEY (`month`,`name`)",
+    ),
+];
+
+/// Tables of each entity type, created with the type prefix. `{change_type}` is replaced by
+/// [`CHANGE_TYPE`].
+const ENTITY_TABLES: &[(&str, &str)] = &[
+    (
+        "creations",
+        "`q` int unsigned NOT NULL PRIMARY KEY,
+  `timestamp` varchar(14) NOT NULL,
+  KEY `timestamp` (`timestamp`)",
+    ),
+    (
+        "deletions",
+        "`q` int unsigned NOT NULL PRIMARY KEY,
+  `timestamp` varchar(14) NOT NULL,
+  KEY `timestamp` (`timestamp`)",
+    ),
+    (
+        "redirects",
+        "`source` int unsigned NOT NULL PRIMARY KEY,
+  `target` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  KEY `target` (`target`),
+  KEY `timestamp` (`timestamp`)",
+    ),
+    (
+        "log_events",
+        "`q` int unsigned NOT NULL,
+  `type` varchar(32) NOT NULL,
+  `action` varchar(32) NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  UNIQUE KEY `event` (`q`,`type`,`action`,`timestamp`),
+  KEY `timestamp` (`timestamp`)",
+    ),
+    (
+        "protections",
+        "`q` int unsigned NOT NULL,
+  `action` varchar(32) NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  UNIQUE KEY `event` (`q`,`action`,`timestamp`),
+  KEY `timestamp` (`timestamp`)",
+    ),
+    (
+        "merges",
+        "`source` int unsigned NOT NULL,
+  `target` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  `revision` int unsigned NOT NULL,
+  UNIQUE KEY `merge` (`source`,`revision`),
+  KEY `timestamp` (`timestamp`)",
+    ),
+    (
+        "statements",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `property` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  `change_type` {change_type},
+  `sitelinks` int unsigned NOT NULL DEFAULT 0,
+  `is_bot` tinyint(1) NOT NULL DEFAULT 0,
+  `user` varchar(255),
+  `engine` int unsigned NOT NULL,
+  `detail` text,
+  `summary` int unsigned,
+  `session` int unsigned,
+  UNIQUE KEY `change` (`item`,`revision`,`property`,`change_type`),
+  KEY `timestamp` (`timestamp`),
+  KEY `property_timestamp` (`property`,`timestamp`)",
+    ),
+    (
+        "qualifiers",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `property` int unsigned NOT NULL,
+  `qualifier` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  `change_type` {change_type},
+  `sitelinks` int unsigned NOT NULL DEFAULT 0,
+  `is_bot` tinyint(1) NOT NULL DEFAULT 0,
+  `engine` int unsigned NOT NULL,
+  UNIQUE KEY `change` (`item`,`revision`,`property`,`qualifier`,`change_type`),
+  KEY `timestamp` (`timestamp`),
+  KEY `property_timestamp` (`property`,`timestamp`)",
+    ),
+    (
+        "references",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `property` int unsigned NOT NULL,
+  `hash` varchar(40) NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  `change_type` {change_type},
+  `sitelinks` int unsigned NOT NULL DEFAULT 0,
+  `is_bot` tinyint(1) NOT NULL DEFAULT 0,
+  `engine` int unsigned NOT NULL,
+  UNIQUE KEY `change` (`item`,`revision`,`property`,`hash`,`change_type`),
+  KEY `timestamp` (`timestamp`),
+  KEY `property_timestamp` (`property`,`timestamp`)",
+    ),
+    (
+        "labels",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `type` varchar(16) NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  `change_type` {change_type},
+  `language` int unsigned NOT NULL,
+  `sitelinks` int unsigned NOT NULL DEFAULT 0,
+  `is_bot` tinyint(1) NOT NULL DEFAULT 0,
+  `user` varchar(255),
+  `engine` int unsigned NOT NULL,
+  `detail` text,
+  `summary` int unsigned,
+  `session` int unsigned,
+  UNIQUE KEY `change` (`item`,`revision`,`type`,`language`,`change_type`),
+  KEY `timestamp` (`timestamp`),
+  KEY `language_timestamp` (`language`,`timestamp`)",
+    ),
+    (
+        "change_tags",
+        "`item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `tag` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  PRIMARY KEY (`revision`,`tag`),
+  KEY `item` (`item`,`revision`)",
+    ),
+    (
+        "reverts",
+        "`item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `type` varchar(16) NOT NULL,
+  `key` varchar(255) NOT NULL,
+  `reverted_revision` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  UNIQUE KEY `revert` (`revision`,`type`,`key`),
+  KEY `item` (`item`,`revision`)",
+    ),
+    (
+        "change_values",
+        "`item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `type` varchar(16) NOT NULL,
+  `key` varchar(255) NOT NULL,
+  `old_value` text NOT NULL,
+  `new_value` text NOT NULL,
+  `truncated` tinyint(1) NOT NULL DEFAULT 0,
+  `timestamp` varchar(14) NOT NULL,
+  UNIQUE KEY `value` (`item`,`revision`,`type`,`key`),
+  KEY `timestamp` (`timestamp`)",
+    ),
+    (
+        "value_overflow",
+        "`item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `type` varchar(16) NOT NULL,
+  `key` varchar(255) NOT NULL,
+  `old_value` mediumtext NOT NULL,
+  `new_value` mediumtext NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  UNIQUE KEY `value` (`item`,`revision`,`type`,`key`)",
+    ),
+    (
+        "revision_scores",
+        "`item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL PRIMARY KEY,
+  `damaging` float NOT NULL,
+  `goodfaith` float NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  KEY `item` (`item`,`revision`)",
+    ),
+    (
+        "media_changes",
+        "`item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `subject` varchar(16) NOT NULL,
+  `property` varchar(16) NOT NULL,
+  `file` varchar(255) NOT NULL,
+  `file_exists` tinyint(1) NOT NULL,
+  `usage` int unsigned NOT NULL,
+  `wikis` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  UNIQUE KEY `media` (`item`,`revision`,`property`,`file`),
+  KEY `file` (`file`)",
+    ),
+    (
+        "significant_changes",
+        "`item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL PRIMARY KEY,
+  `timestamp` varchar(14) NOT NULL,
+  `sitelinks` int unsigned NOT NULL,
+  `statements` int unsigned NOT NULL,
+  `subjects` varchar(255) NOT NULL,
+  KEY `item` (`item`,`revision`),
+  KEY `timestamp` (`timestamp`)",
+    ),
+];
+
+/// Tables of items only.
+const ITEM_TABLES: &[(&str, &str)] = &[
+    (
+        "badges",
+        "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `site` int unsigned NOT NULL,
+  `badge` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  `change_type` {change_type},
+  `sitelinks` int unsigned NOT NULL DEFAULT 0,
+  `is_bot` tinyint(1) NOT NULL DEFAULT 0,
+  `engine` int unsigned NOT NULL,
+  UNIQUE KEY `change` (`item`,`revision`,`site`,`badge`,`change_type`),
+  KEY `timestamp` (`timestamp`)",
+    ),
+    (
+        "sitelink_conflicts",
+        "`item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `site` varchar(64) NOT NULL,
+  `title` varchar(255) NOT NULL,
+  `other_item` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  UNIQUE KEY `conflict` (`item`,`revision`,`site`),
+  KEY `timestamp` (`timestamp`)",
+    ),
+];
+
+/// Tables of lexemes only.
+const LEXEME_TABLES: &[(&str, &str)] = &[(
+    "subentities",
+    "`id` int unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `item` int unsigned NOT NULL,
+  `revision` int unsigned NOT NULL,
+  `type` varchar(16) NOT NULL,
+  `subentity` int unsigned NOT NULL,
+  `timestamp` varchar(14) NOT NULL,
+  `change_type` {change_type},
+  `sitelinks` int unsigned NOT NULL DEFAULT 0,
+  `is_bot` tinyint(1) NOT NULL DEFAULT 0,
+  `engine` int unsigned NOT NULL,
+  UNIQUE KEY `change` (`item`,`revision`,`type`,`subentity`,`change_type`),
+  KEY `timestamp` (`timestamp`)",
+)];
+
+/// The wdrc tables, as `CREATE TABLE IF NOT EXISTS` statements. Tables of all entity types are
+/// created, since maintenance covers them regardless of the tracked namespaces.
+pub struct Schema;
+
+impl Schema {
+    /// Table names and their `CREATE TABLE IF NOT EXISTS` statements.
+    pub fn create_statements() -> Vec<(String, String)> {
+        let mut tables: Vec<(String, &str)> = SHARED_TABLES
+            .iter()
+            .map(|(name, columns)| (name.to_string(), *columns))
+            .collect();
+        for entity_type in EntityType::all() {
+            let own_tables = match entity_type {
+                EntityType::Item => ITEM_TABLES,
+                EntityType::Property => &[],
+                EntityType::Lexeme => LEXEME_TABLES,
+            };
+            tables.extend(
+                ENTITY_TABLES
+                    .iter()
+                    .chain(own_tables)
+                    .map(|(name, columns)| (entity_type.table(name), *columns)),
+            );
+        }
+        tables
+            .into_iter()
+            .map(|(table, columns)| {
+                let columns = columns.replace("{change_type}", CHANGE_TYPE);
+                let sql = format!("CREATE TABLE IF NOT EXISTS `{table}` (\n  {columns}\n)");
+                (table, sql)
+            })
+            .collect()
+    }
+
+    /// Creates all missing tables; existing ones are left as they are. Returns the table names.
+    pub async fn init(wdrc: &WdRc) -> Result<Vec<String>> {
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let mut ret = vec![];
+        for (table, sql) in Self::create_statements() {
+            conn.query_drop(sql).await?;
+            ret.push(table);
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redact::Redactor;
+
+    #[test]
+    fn test_create_statements() {
+        let statements = Schema::create_statements();
+        let tables: Vec<&str> = statements.iter().map(|(t, _)| t.as_str()).collect();
+        // Every table that maintenance and redaction touch is created
+        for table in Redactor::revision_tables() {
+            assert!(tables.contains(&table.as_str()), "{table} missing");
+        }
+        assert!(tables.contains(&"lexeme_subentities"));
+        assert!(!tables.contains(&"property_badges"));
+        let (_, sql) = &statements[0];
+        assert_eq!(
+            sql,
+            "CREATE TABLE IF NOT EXISTS `meta` (\n  `key` varchar(64) NOT NULL PRIMARY KEY,\n  `value` varchar(255) NOT NULL\n)"
+        );
+        assert!(statements.iter().all(|(_, sql)| !sql.contains('{')));
+    }
+}
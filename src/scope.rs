@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use wikimisc::wikidata::Wikidata;
+
+use crate::{change::EntityType, config::ScopeConfig};
+
+/// The entities returned by the `scope` SPARQL query, the only ones whose changes are tracked.
+/// The query runs again once the set is older than `refresh_minutes`; if that fails, the old set
+/// stays in use until the next attempt.
+#[derive(Debug)]
+pub struct ItemScope {
+    config: ScopeConfig,
+    /// The entity IDs, and when they were queried.
+    items: RwLock<Option<(Instant, HashSet<String>)>>,
+}
+
+impl ItemScope {
+    pub fn new(config: ScopeConfig) -> Self {
+        Self {
+            config,
+            items: RwLock::new(None),
+        }
+    }
+
+    /// Runs the query unless the set is recent enough; returns the size of a new set.
+    pub async fn refresh(&self, wd: &Wikidata) -> Result<Option<usize>> {
+        let max_age = Duration::from_secs(self.config.refresh_minutes * 60);
+        let fresh = self
+            .items
+            .read()
+            .map_err(|_| anyhow!("Item scope lock poisoned"))?
+            .as_ref()
+            .is_some_and(|(queried, _)| queried.elapsed() < max_age);
+        if fresh {
+            return Ok(None);
+        }
+        let j: Value = wd
+            .reqwest_client()?
+            .get(&self.config.endpoint)
+            .timeout(Duration::from_secs(self.config.timeout_secs))
+            .header("accept", "application/sparql-results+json")
+            .query(&[("query", self.config.query.as_str()), ("format", "json")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let items = Self::parse_response(&j)?;
+        let count = items.len();
+        *self
+            .items
+            .write()
+            .map_err(|_| anyhow!("Item scope lock poisoned"))? = Some((Instant::now(), items));
+        Ok(Some(count))
+    }
+
+    /// Whether an entity is in scope; unknown until the query has succeeded once.
+    pub fn contains(&self, id: &str) -> Option<bool> {
+        let items = self.items.read().ok()?;
+        items.as_ref().map(|(_, items)| items.contains(id))
+    }
+
+    /// The entity IDs bound to the first variable of the results, like
+    /// `http://www.wikidata.org/entity/Q42`; other values are ignored.
+    fn parse_response(j: &Value) -> Result<HashSet<String>> {
+        let var = j["head"]["vars"][0]
+            .as_str()
+            .ok_or_else(|| anyhow!("No variables in SPARQL response"))?;
+        let bindings = j["results"]["bindings"]
+            .as_array()
+            .ok_or_else(|| anyhow!("No bindings in SPARQL response"))?;
+        Ok(bindings
+            .iter()
+            .filter_map(|binding| binding[var]["value"].as_str())
+            .filter_map(|uri| uri.rsplit_once("/entity/"))
+            .map(|(_, id)| id)
+            .filter(|id| EntityType::from_id(id).is_some())
+            .map(String::from)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_response() {
+        let uri = |value: &str| json!({"item": {"type": "uri", "value": value}});
+        let j = json!({
+            "head": {"vars": ["item", "label"]},
+            "results": {"bindings": [
+                uri("http://www.wikidata.org/entity/Q42"),
+                uri("http://www.wikidata.org/entity/P31"),
+                uri("http://www.wikidata.org/entity/statement/Q42-abc"),
+                uri("https://example.org/Q1"),
+                {"label": {"type": "literal", "value": "Q5"}},
+            ]},
+        });
+        let items = ItemScope::parse_response(&j).unwrap();
+        assert_eq!(items, HashSet::from(["Q42".to_string(), "P31".to_string()]));
+        assert!(ItemScope::parse_response(&json!({})).is_err());
+
+        let scope = ItemScope::new(ScopeConfig::default());
+        assert_eq!(scope.contains("Q42"), None);
+        *scope.items.write().unwrap() = Some((Instant::now(), items));
+        assert_eq!(scope.contains("Q42"), Some(true));
+        assert_eq!(scope.contains("Q1"), Some(false));
+    }
+}
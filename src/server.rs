@@ -0,0 +1,534 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::get,
+    Extension, Json, Router,
+};
+use futures::{Stream, StreamExt};
+use serde_json::{json, Value};
+use std::{future::IntoFuture, sync::Arc};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    capabilities::Capabilities,
+    change::EntityType,
+    config::Role,
+    deliveries::Deliveries,
+    feeds::{Feed, FeedFormat, FeedSlice},
+    jobs::Job,
+    live::{LiveFeed, LiveFilter},
+    query::{ChangeFilter, ChangeRow, Cursor, EventFilter, MAX_LIMIT},
+    time_travel::EntityState,
+    watchlist::Watchlist,
+    WdRc,
+};
+
+pub const DEFAULT_ADDRESS: &str = "0.0.0.0:8000";
+
+/// An error response, as `{"error": "..."}`.
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(e: anyhow::Error) -> Self {
+        Self(StatusCode::BAD_REQUEST, e.to_string())
+    }
+
+    fn internal(e: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+
+    fn unauthorized() -> Self {
+        Self(
+            StatusCode::UNAUTHORIZED,
+            "Missing or wrong token".to_string(),
+        )
+    }
+
+    fn forbidden(required: Role) -> Self {
+        Self(
+            StatusCode::FORBIDDEN,
+            format!("Requires an API key with role {:?}", required.as_str()),
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({"error": self.1}))).into_response()
+    }
+}
+
+type ApiResult = std::result::Result<Json<Value>, ApiError>;
+
+/// Query parameters, decoded.
+type Params = Query<Vec<(String, String)>>;
+
+/// A read-only HTTP API over the change log. The query parameters are those of the `changes`
+/// and `events` commands, with `events` set by the path for `/creations` and `/deletions`.
+/// Lists are paginated newest first: if a response has a `continue` value, pass it as
+/// `continue` to get the next page.
+///
+/// New changes are streamed as Server-Sent Events from `/events`, filtered by query parameters,
+/// and over a WebSocket at `/ws`, filtered by a [`LiveFilter`] the client sends as JSON. They
+/// come from the bot loop if it runs alongside, and from polling the database otherwise.
+///
+/// `/deliveries` counts the pending, delivered and dead payloads of each webhook and notifier.
+///
+/// `/feed/{kind}/{key}` is an Atom feed of the latest changes of an item, a property or a
+/// language, or an RSS feed with `format=rss`.
+///
+/// Requests send an API key from `api_keys` as `Authorization: Bearer <key>`. Everything but
+/// `/capabilities` and watchlist notifications needs the `reader` role, and `/deliveries` the
+/// `admin` role; see [`Role`].
+pub struct Server;
+
+impl Server {
+    pub async fn serve(wdrc: WdRc, address: &str, bot: Option<WdRc>) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind(address).await?;
+        println!("Listening on {address}");
+        let wdrc = Arc::new(wdrc);
+        match bot {
+            Some(mut bot) => {
+                bot.set_live(wdrc.live().clone());
+                let server = axum::serve(listener, Self::router(wdrc)).into_future();
+                tokio::select! {
+                    result = server => result?,
+                    result = Job::Bot.run(&mut bot) => result?,
+                }
+            }
+            None => {
+                tokio::spawn(LiveFeed::run(wdrc.clone()));
+                axum::serve(listener, Self::router(wdrc)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn router(wdrc: Arc<WdRc>) -> Router {
+        let reader = Router::new()
+            .route("/changes", get(Self::changes))
+            .route("/item/{id}/changes", get(Self::item_changes))
+            .route("/property/{id}/changes", get(Self::property_changes))
+            .route("/creations", get(Self::creations))
+            .route("/deletions", get(Self::deletions))
+            .route("/events", get(Self::live))
+            .route("/ws", get(Self::websocket))
+            .route("/state/{id}/{at}", get(Self::state))
+            .route("/feed/{kind}/{key}", get(Self::feed))
+            .route_layer(middleware::from_fn_with_state(Role::Reader, Self::require));
+        let admin = Router::new()
+            .route("/deliveries", get(Self::deliveries))
+            .route_layer(middleware::from_fn_with_state(Role::Admin, Self::require));
+        Router::new()
+            .route("/capabilities", get(Self::capabilities))
+            .route(
+                "/watchlist/{watcher}/notifications",
+                get(Self::notifications),
+            )
+            .merge(reader)
+            .merge(admin)
+            .layer(middleware::from_fn_with_state(wdrc.clone(), Self::identify))
+            .with_state(wdrc)
+    }
+
+    /// Sets the [`Role`] of the request's API key as an extension; `public` without a key, or
+    /// with a key not in `api_keys`, like a watcher's token.
+    async fn identify(State(wdrc): State<Arc<WdRc>>, mut request: Request, next: Next) -> Response {
+        let role = Self::bearer(request.headers()).map_or(Role::Public, |key| wdrc.role(key));
+        request.extensions_mut().insert(role);
+        next.run(request).await
+    }
+
+    /// Rejects requests with roles below `required`.
+    async fn require(
+        State(required): State<Role>,
+        Extension(role): Extension<Role>,
+        request: Request,
+        next: Next,
+    ) -> std::result::Result<Response, ApiError> {
+        Self::check(required, role)?;
+        Ok(next.run(request).await)
+    }
+
+    fn check(required: Role, role: Role) -> std::result::Result<(), ApiError> {
+        match role {
+            _ if role >= required => Ok(()),
+            Role::Public => Err(ApiError::unauthorized()),
+            _ => Err(ApiError::forbidden(required)),
+        }
+    }
+
+    /// The key or token of an `Authorization: Bearer` header.
+    fn bearer(headers: &HeaderMap) -> Option<&str> {
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+    }
+
+    /// A new random API key, and its entry for `api_keys`.
+    pub fn new_api_key(name: &str, role: &str) -> Result<(String, Value)> {
+        let role = Role::from_name(role).ok_or_else(|| anyhow!("Unknown role: {role:?}"))?;
+        if role == Role::Public {
+            return Err(anyhow!("The public role needs no key"));
+        }
+        let key = Watchlist::random_token()?;
+        let entry = json!({"name": name, "sha256": Watchlist::token_hash(&key), "role": role});
+        Ok((key, entry))
+    }
+
+    async fn changes(State(wdrc): State<Arc<WdRc>>, Query(params): Params) -> ApiResult {
+        Self::list_changes(&wdrc, &params).await
+    }
+
+    async fn item_changes(
+        State(wdrc): State<Arc<WdRc>>,
+        Path(id): Path<String>,
+        Query(params): Params,
+    ) -> ApiResult {
+        let params =
+            Self::entity_params(EntityType::Item, &id, params).map_err(ApiError::bad_request)?;
+        Self::list_changes(&wdrc, &params).await
+    }
+
+    async fn property_changes(
+        State(wdrc): State<Arc<WdRc>>,
+        Path(id): Path<String>,
+        Query(params): Params,
+    ) -> ApiResult {
+        let params = Self::entity_params(EntityType::Property, &id, params)
+            .map_err(ApiError::bad_request)?;
+        Self::list_changes(&wdrc, &params).await
+    }
+
+    async fn creations(State(wdrc): State<Arc<WdRc>>, Query(params): Params) -> ApiResult {
+        Self::list_events(&wdrc, "creations", &params).await
+    }
+
+    async fn deletions(State(wdrc): State<Arc<WdRc>>, Query(params): Params) -> ApiResult {
+        Self::list_events(&wdrc, "deletions", &params).await
+    }
+
+    async fn live(
+        State(wdrc): State<Arc<WdRc>>,
+        Query(params): Params,
+    ) -> std::result::Result<
+        Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>>,
+        ApiError,
+    > {
+        let filter = LiveFilter::from_pairs(&params).map_err(ApiError::bad_request)?;
+        let stream = futures::stream::unfold(wdrc.subscribe(), |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(row) => Some((Some(row), receiver)),
+                // Changes missed by a slow subscriber are skipped
+                Err(RecvError::Lagged(_)) => Some((None, receiver)),
+                Err(RecvError::Closed) => None,
+            }
+        })
+        .filter_map(move |row| {
+            let event = row
+                .filter(|row| filter.matches(row))
+                .map(|row| Event::default().event("change").json_data(row));
+            async move { event }
+        });
+        Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+    }
+
+    async fn websocket(State(wdrc): State<Arc<WdRc>>, upgrade: WebSocketUpgrade) -> Response {
+        let receiver = wdrc.subscribe();
+        upgrade.on_upgrade(move |socket| Self::live_socket(socket, receiver))
+    }
+
+    /// Sends the changes matching the last filter the client sent; nothing before the first.
+    async fn live_socket(mut socket: WebSocket, mut receiver: broadcast::Receiver<ChangeRow>) {
+        let mut filter: Option<LiveFilter> = None;
+        loop {
+            let reply = tokio::select! {
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Text(text))) => match LiveFilter::from_json(&text) {
+                        Ok(new_filter) => {
+                            filter = Some(new_filter);
+                            continue;
+                        }
+                        Err(e) => json!({"error": e.to_string()}),
+                    },
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue, // Pings are answered by axum
+                },
+                row = receiver.recv() => match row {
+                    Ok(row) if filter.as_ref().is_some_and(|filter| filter.matches(&row)) => {
+                        json!(row)
+                    }
+                    // Changes missed by a slow client are skipped
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                },
+            };
+            if socket
+                .send(Message::Text(reply.to_string().into()))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    async fn list_events(wdrc: &WdRc, events: &str, params: &[(String, String)]) -> ApiResult {
+        if params.iter().any(|(key, _)| key == "events") {
+            return Err(ApiError::bad_request(anyhow!(
+                "Parameter \"events\" is set by the path"
+            )));
+        }
+        let params: Vec<(String, String)> =
+            std::iter::once(("events".to_string(), events.to_string()))
+                .chain(params.iter().cloned())
+                .collect();
+        let filter = EventFilter::from_pairs(&params).map_err(ApiError::bad_request)?;
+        let rows = filter.run(wdrc).await.map_err(ApiError::internal)?;
+        let next = Self::next_page(&rows, filter.limit, |row| Some(&row.cursor));
+        Ok(Json(json!({"events": rows, "continue": next})))
+    }
+
+    async fn state(
+        State(wdrc): State<Arc<WdRc>>,
+        Path((id, at)): Path<(String, String)>,
+        Query(params): Params,
+    ) -> ApiResult {
+        let mut properties = vec![];
+        for (key, value) in &params {
+            match key.as_str() {
+                "properties" => {
+                    properties.extend(value.split(',').filter(|p| !p.is_empty()).map(String::from))
+                }
+                other => {
+                    return Err(ApiError::bad_request(anyhow!(
+                        "Unknown parameter: {other:?}"
+                    )))
+                }
+            }
+        }
+        let entity_state = EntityState::at(&wdrc, &id, &at, &properties)
+            .await
+            .map_err(ApiError::bad_request)?;
+        Ok(Json(json!(entity_state)))
+    }
+
+    /// Notifications of a watcher, oldest first; `after` is the ID of the last one seen. Requires
+    /// the watcher's token, from `watchlist <config> <watcher> token`, or an admin API key, as
+    /// `Authorization: Bearer`.
+    async fn notifications(
+        State(wdrc): State<Arc<WdRc>>,
+        Path(watcher): Path<String>,
+        Extension(role): Extension<Role>,
+        headers: HeaderMap,
+        Query(params): Params,
+    ) -> ApiResult {
+        let watchlist = Watchlist::new(&wdrc);
+        if role != Role::Admin {
+            let token = Self::bearer(&headers).ok_or_else(ApiError::unauthorized)?;
+            if !watchlist
+                .authorize(&watcher, token)
+                .await
+                .map_err(ApiError::internal)?
+            {
+                return Err(ApiError::unauthorized());
+            }
+        }
+        let mut after = 0;
+        for (key, value) in &params {
+            match key.as_str() {
+                "after" => {
+                    after = value.parse().map_err(|_| {
+                        ApiError::bad_request(anyhow!("Bad value for \"after\": {value:?}"))
+                    })?
+                }
+                other => {
+                    return Err(ApiError::bad_request(anyhow!(
+                        "Unknown parameter: {other:?}"
+                    )))
+                }
+            }
+        }
+        let notifications = watchlist
+            .notifications(&watcher, after)
+            .await
+            .map_err(ApiError::bad_request)?;
+        let next = match notifications.len() as u64 >= MAX_LIMIT {
+            true => notifications.last().map(|n| n.id),
+            false => None,
+        };
+        Ok(Json(
+            json!({"notifications": notifications, "continue": next}),
+        ))
+    }
+
+    async fn feed(
+        State(wdrc): State<Arc<WdRc>>,
+        Path((kind, key)): Path<(String, String)>,
+        Query(params): Params,
+    ) -> std::result::Result<Response, ApiError> {
+        let mut format = FeedFormat::Atom;
+        for (key, value) in &params {
+            match key.as_str() {
+                "format" => {
+                    format = FeedFormat::from_name(value).ok_or_else(|| {
+                        ApiError::bad_request(anyhow!("Unknown format: {value:?}"))
+                    })?
+                }
+                other => {
+                    return Err(ApiError::bad_request(anyhow!(
+                        "Unknown parameter: {other:?}"
+                    )))
+                }
+            }
+        }
+        let slice = FeedSlice::new(&kind, &key).map_err(ApiError::bad_request)?;
+        let feed = Feed::collect(&wdrc, slice)
+            .await
+            .map_err(ApiError::internal)?;
+        let body = feed.render(format, wdrc.wiki().server(), wdrc.wiki().dbname());
+        Ok(([(header::CONTENT_TYPE, format.content_type())], body).into_response())
+    }
+
+    async fn capabilities(State(wdrc): State<Arc<WdRc>>) -> ApiResult {
+        Ok(Json(json!(Capabilities::new(&wdrc))))
+    }
+
+    async fn deliveries(State(wdrc): State<Arc<WdRc>>) -> ApiResult {
+        let status = Deliveries::new(&wdrc)
+            .status()
+            .await
+            .map_err(ApiError::internal)?;
+        Ok(Json(json!({"deliveries": status})))
+    }
+
+    async fn list_changes(wdrc: &WdRc, params: &[(String, String)]) -> ApiResult {
+        let filter = ChangeFilter::from_pairs(params).map_err(ApiError::bad_request)?;
+        let rows = filter.run(wdrc).await.map_err(ApiError::internal)?;
+        let next = Self::next_page(&rows, filter.limit, |row| row.cursor.as_ref());
+        let changes = filter.to_json(&rows).map_err(ApiError::internal)?;
+        Ok(Json(json!({"changes": changes, "continue": next})))
+    }
+
+    /// The parameters for the changes of one entity of `entity_type`.
+    fn entity_params(
+        entity_type: EntityType,
+        id: &str,
+        params: Vec<(String, String)>,
+    ) -> Result<Vec<(String, String)>> {
+        if EntityType::from_id(id) != Some(entity_type) {
+            return Err(anyhow!("Not a {} ID: {id:?}", entity_type.as_str()));
+        }
+        if let Some((key, _)) = params
+            .iter()
+            .find(|(key, _)| key == "entity" || key == "item")
+        {
+            return Err(anyhow!("Parameter {key:?} is set by the path"));
+        }
+        let path = [
+            ("entity".to_string(), entity_type.as_str().to_string()),
+            ("item".to_string(), id.to_string()),
+        ];
+        Ok(path.into_iter().chain(params).collect())
+    }
+
+    /// The `continue` value after a full page of `rows`, newest first: the cursor of the last.
+    fn next_page<T>(
+        rows: &[T],
+        limit: u64,
+        cursor: impl Fn(&T) -> Option<&Cursor>,
+    ) -> Option<String> {
+        if (rows.len() as u64) < limit {
+            return None;
+        }
+        rows.last()
+            .and_then(cursor)
+            .map(|cursor| cursor.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entity_params() {
+        let pair = |key: &str, value: &str| (key.to_string(), value.to_string());
+        assert_eq!(
+            Server::entity_params(EntityType::Item, "Q42", vec![pair("subjects", "labels")])
+                .unwrap(),
+            vec![
+                pair("entity", "item"),
+                pair("item", "Q42"),
+                pair("subjects", "labels")
+            ]
+        );
+        assert!(Server::entity_params(EntityType::Property, "Q42", vec![]).is_err());
+        assert!(Server::entity_params(EntityType::Item, "Q42", vec![pair("item", "Q1")]).is_err());
+        // A decoded `&` stays part of the value
+        let params = Server::entity_params(
+            EntityType::Item,
+            "Q42",
+            vec![pair("subjects", "labels&item=Q1")],
+        )
+        .unwrap();
+        assert!(ChangeFilter::from_pairs(&params).is_err());
+    }
+
+    #[test]
+    fn test_roles() {
+        assert!(Server::check(Role::Reader, Role::Reader).is_ok());
+        assert!(Server::check(Role::Reader, Role::Admin).is_ok());
+        assert_eq!(
+            Server::check(Role::Reader, Role::Public).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            Server::check(Role::Admin, Role::Writer).unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(Server::bearer(&headers), None);
+        headers.insert(header::AUTHORIZATION, "Bearer abc ".parse().unwrap());
+        assert_eq!(Server::bearer(&headers), Some("abc"));
+        headers.insert(header::AUTHORIZATION, "Basic abc".parse().unwrap());
+        assert_eq!(Server::bearer(&headers), None);
+
+        let (key, entry) = Server::new_api_key("monitoring", "reader").unwrap();
+        assert_eq!(entry["sha256"], Watchlist::token_hash(&key));
+        assert_eq!(entry["role"], "reader");
+        assert!(Server::new_api_key("monitoring", "public").is_err());
+        assert!(Server::new_api_key("monitoring", "root").is_err());
+    }
+
+    #[test]
+    fn test_next_page() {
+        let rows: Vec<Cursor> = [3, 2, 1]
+            .into_iter()
+            .map(|id| Cursor {
+                timestamp: "20240101000000".to_string(),
+                keys: vec![id],
+            })
+            .collect();
+        assert_eq!(Server::next_page(&rows, 4, |row| Some(row)), None);
+        // Rows sharing a timestamp are told apart by their keys
+        assert_eq!(
+            Server::next_page(&rows, 3, |row| Some(row)),
+            Some("20240101000000:1".to_string())
+        );
+        let filter = EventFilter::from_query("continue=20240101000000:1").unwrap();
+        assert_eq!(filter.after.as_ref(), rows.last());
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use wikimisc::mysql_async::{from_row, prelude::Queryable};
+
+use crate::{change::Change, ItemId, RevisionId, WdRc};
+
+/// An editing session: consecutive edits of one user on one entity.
+#[derive(Debug, Clone, PartialEq)]
+struct Session {
+    id: u64,
+    last_edit: String,
+}
+
+/// Groups changes into editing sessions, kept in `sessions`. An edit continues the latest session of
+/// its user on the same entity if it was made within `session_minutes` of that session's last edit,
+/// and starts a new one otherwise. Edits without a known user are not grouped.
+pub struct Sessions;
+
+impl Sessions {
+    /// Whether an edit at `timestamp` continues a session last edited at `last_edit`.
+    fn continues(last_edit: &str, timestamp: &str, minutes: u64) -> bool {
+        let parse = |ts: &str| NaiveDateTime::parse_from_str(ts, "%Y%m%d%H%M%S").ok();
+        match (parse(last_edit), parse(timestamp)) {
+            (Some(last), Some(ts)) => {
+                ts >= last && ts - last <= chrono::Duration::minutes(minutes as i64)
+            }
+            _ => false,
+        }
+    }
+
+    /// The revisions in `changes` with a known user, oldest first, with their number of changes.
+    fn revisions(changes: &[Change]) -> Vec<(&Change, u64)> {
+        let mut ret: Vec<(&Change, u64)> = vec![];
+        for change in changes.iter().filter(|c| c.user.is_some()) {
+            match ret.iter_mut().find(|(c, _)| {
+                c.entity_type == change.entity_type && c.revision_id == change.revision_id
+            }) {
+                Some((_, count)) => *count += 1,
+                None => ret.push((change, 1)),
+            }
+        }
+        ret.sort_by(|(a, _), (b, _)| {
+            (&a.timestamp, a.revision_id).cmp(&(&b.timestamp, b.revision_id))
+        });
+        ret
+    }
+
+    /// Sets the session of each change, starting or extending sessions as needed.
+    pub async fn assign(wdrc: &WdRc, changes: &mut [Change], minutes: u64) -> Result<()> {
+        let revisions: Vec<(String, ItemId, String, RevisionId, String, u64)> =
+            Self::revisions(changes)
+                .into_iter()
+                .map(|(c, count)| {
+                    (
+                        c.entity_type.as_str().to_string(),
+                        c.item_id,
+                        c.user.to_owned().unwrap_or_default(),
+                        c.revision_id,
+                        c.timestamp.to_owned(),
+                        count,
+                    )
+                })
+                .collect();
+        if revisions.is_empty() {
+            return Ok(());
+        }
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let mut open: HashMap<(String, ItemId, String), Option<Session>> = HashMap::new();
+        let mut assigned: HashMap<(String, RevisionId), u64> = HashMap::new();
+        for (entity_type, item, user, revision, timestamp, count) in revisions {
+            let key = (entity_type.to_owned(), item, user.to_owned());
+            if !open.contains_key(&key) {
+                let sql = "SELECT `id`,`last_edit` FROM `sessions` WHERE `entity_type`=? AND `item`=? AND `user`=? ORDER BY `last_edit` DESC LIMIT 1";
+                let latest = conn
+                    .exec_iter(sql, (&entity_type, item, &user))
+                    .await?
+                    .map_and_drop(from_row::<(u64, String)>)
+                    .await?
+                    .pop()
+                    .map(|(id, last_edit)| Session { id, last_edit });
+                open.insert(key.to_owned(), latest);
+            }
+            let session = open.get_mut(&key).expect("inserted above");
+            let id = match session {
+                Some(s) if Self::continues(&s.last_edit, &timestamp, minutes) => {
+                    let sql = "UPDATE `sessions` SET `last_edit`=?,`revisions`=`revisions`+1,`changes`=`changes`+? WHERE `id`=?";
+                    conn.exec_drop(sql, (&timestamp, count, s.id)).await?;
+                    s.last_edit = timestamp.to_owned();
+                    s.id
+                }
+                _ => {
+                    let sql = "INSERT INTO `sessions` (`entity_type`,`item`,`user`,`started`,`last_edit`,`revisions`,`changes`) VALUES (?,?,?,?,?,1,?)";
+                    conn.exec_drop(
+                        sql,
+                        (&entity_type, item, &user, &timestamp, &timestamp, count),
+                    )
+                    .await?;
+                    let id = conn.last_insert_id().unwrap_or_default();
+                    *session = Some(Session {
+                        id,
+                        last_edit: timestamp.to_owned(),
+                    });
+                    id
+                }
+            };
+            assigned.insert((entity_type, revision), id);
+        }
+        for change in changes.iter_mut() {
+            let key = (change.entity_type.as_str().to_string(), change.revision_id);
+            change.session = assigned.get(&key).copied();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        assert!(Sessions::continues("20240101120000", "20240101122959", 30));
+        assert!(!Sessions::continues("20240101120000", "20240101123001", 30));
+        assert!(!Sessions::continues("20240101120000", "20240101115959", 30));
+
+        let change = |revision_id, user: Option<&str>, timestamp: &str| Change {
+            revision_id,
+            user: user.map(|u| u.to_string()),
+            timestamp: timestamp.to_string(),
+            ..Default::default()
+        };
+        let changes = vec![
+            change(12, Some("A"), "20240101120500"),
+            change(11, Some("A"), "20240101120000"),
+            change(12, Some("A"), "20240101120500"),
+            change(13, None, "20240101121000"),
+        ];
+        let revisions: Vec<(RevisionId, u64)> = Sessions::revisions(&changes)
+            .into_iter()
+            .map(|(c, count)| (c.revision_id, count))
+            .collect();
+        assert_eq!(revisions, vec![(11, 1), (12, 2)]);
+    }
+}
@@ -0,0 +1,286 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use wikimisc::mysql_async::{prelude::Queryable, Row, Value as SqlValue};
+
+use crate::WdRc;
+
+/// Example rows kept per kind of divergence and table.
+const MAX_EXAMPLES: usize = 20;
+
+/// A table logged by both the predecessor tool and wdrc; rows are matched by their key columns.
+struct ShadowTable {
+    name: &'static str,
+    key: &'static [&'static str],
+    fields: &'static [&'static str],
+    /// Columns referencing `texts`, compared by value as IDs differ between the databases.
+    text_columns: &'static [&'static str],
+}
+
+const SHADOW_TABLES: &[ShadowTable] = &[
+    ShadowTable {
+        name: "statements",
+        key: &["item", "revision", "property"],
+        fields: &["change_type"],
+        text_columns: &[],
+    },
+    ShadowTable {
+        name: "labels",
+        key: &["item", "revision", "type", "language"],
+        fields: &["change_type"],
+        text_columns: &["language"],
+    },
+    ShadowTable {
+        name: "creations",
+        key: &["q"],
+        fields: &[],
+        text_columns: &[],
+    },
+    ShadowTable {
+        name: "deletions",
+        key: &["q"],
+        fields: &[],
+        text_columns: &[],
+    },
+    ShadowTable {
+        name: "redirects",
+        key: &["source"],
+        fields: &["target"],
+        text_columns: &[],
+    },
+];
+
+impl ShadowTable {
+    fn select_sql(&self) -> String {
+        let columns: Vec<String> = self
+            .key
+            .iter()
+            .chain(self.fields.iter())
+            .map(|column| match self.text_columns.contains(column) {
+                true => {
+                    format!("(SELECT `value` FROM `texts` WHERE `id`=`{column}`) AS `{column}`")
+                }
+                false => format!("`{column}`"),
+            })
+            .collect();
+        format!(
+            "SELECT {} FROM `{}` WHERE `timestamp`>=? AND `timestamp`<?",
+            columns.join(","),
+            self.name
+        )
+    }
+}
+
+/// A row whose key is logged by both tools, with differing fields.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Mismatch {
+    pub key: Vec<String>,
+    pub legacy: Vec<Vec<String>>,
+    pub shadow: Vec<Vec<String>>,
+}
+
+/// Differences between the rows of one table, logged by the predecessor tool and by wdrc.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TableDivergence {
+    pub table: String,
+    pub legacy_rows: usize,
+    pub shadow_rows: usize,
+    pub only_legacy: usize,
+    pub only_shadow: usize,
+    pub mismatched: usize,
+    pub only_legacy_examples: Vec<Vec<String>>,
+    pub only_shadow_examples: Vec<Vec<String>>,
+    pub mismatch_examples: Vec<Mismatch>,
+}
+
+impl TableDivergence {
+    /// Compares rows, each starting with `key_len` key columns.
+    fn compare(
+        table: &str,
+        key_len: usize,
+        legacy: Vec<Vec<String>>,
+        shadow: Vec<Vec<String>>,
+    ) -> Self {
+        let mut ret = Self {
+            table: table.to_string(),
+            legacy_rows: legacy.len(),
+            shadow_rows: shadow.len(),
+            only_legacy: 0,
+            only_shadow: 0,
+            mismatched: 0,
+            only_legacy_examples: vec![],
+            only_shadow_examples: vec![],
+            mismatch_examples: vec![],
+        };
+        let legacy = Self::by_key(key_len, legacy);
+        let mut shadow = Self::by_key(key_len, shadow);
+        for (key, legacy_fields) in legacy {
+            match shadow.remove(&key) {
+                Some(shadow_fields) if shadow_fields == legacy_fields => {}
+                Some(shadow_fields) => {
+                    ret.mismatched += 1;
+                    if ret.mismatch_examples.len() < MAX_EXAMPLES {
+                        ret.mismatch_examples.push(Mismatch {
+                            key,
+                            legacy: legacy_fields,
+                            shadow: shadow_fields,
+                        });
+                    }
+                }
+                None => {
+                    ret.only_legacy += 1;
+                    if ret.only_legacy_examples.len() < MAX_EXAMPLES {
+                        ret.only_legacy_examples.push(key);
+                    }
+                }
+            }
+        }
+        ret.only_shadow = shadow.len();
+        ret.only_shadow_examples = shadow.into_keys().take(MAX_EXAMPLES).collect();
+        ret
+    }
+
+    /// Groups the non-key fields by key; a key can occur in several rows.
+    fn by_key(key_len: usize, rows: Vec<Vec<String>>) -> BTreeMap<Vec<String>, Vec<Vec<String>>> {
+        let mut ret: BTreeMap<Vec<String>, Vec<Vec<String>>> = BTreeMap::new();
+        for mut row in rows {
+            let fields = row.split_off(key_len.min(row.len()));
+            ret.entry(row).or_default().push(fields);
+        }
+        ret.values_mut().for_each(|fields| fields.sort());
+        ret
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.only_legacy == 0 && self.only_shadow == 0 && self.mismatched == 0
+    }
+}
+
+/// Compares what the predecessor tool (the `legacy` database) and wdrc logged in the same time range,
+/// for cutting over once wdrc ran in shadow mode without unexplained divergences.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShadowReport {
+    pub start: String,
+    pub end: String,
+    pub tables: Vec<TableDivergence>,
+}
+
+impl ShadowReport {
+    /// Compares rows from `start` up to `end`, or up to the last change the predecessor tool logged if earlier.
+    pub async fn run(wdrc: &WdRc, start: &str, end: &str) -> Result<Self> {
+        let end = match Self::legacy_last_timestamp(wdrc).await? {
+            Some(last) if last.as_str() < end => last,
+            _ => end.to_string(),
+        };
+        let mut tables = vec![];
+        for table in SHADOW_TABLES {
+            let legacy = Self::get_rows(wdrc, "legacy", table, start, &end).await?;
+            let shadow = Self::get_rows(wdrc, "wdrc", table, start, &end).await?;
+            tables.push(TableDivergence::compare(
+                table.name,
+                table.key.len(),
+                legacy,
+                shadow,
+            ));
+        }
+        Ok(Self {
+            start: start.to_string(),
+            end,
+            tables,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.iter().all(|table| table.is_empty())
+    }
+
+    /// The timestamp of the last statement change the predecessor tool logged; in shadow mode,
+    /// wdrc does not process recent changes past it.
+    pub(crate) async fn legacy_last_timestamp(wdrc: &WdRc) -> Result<Option<String>> {
+        let last = wdrc
+            .db()
+            .get_connection("legacy")
+            .await?
+            .exec_first::<Option<String>, _, _>("SELECT MAX(`timestamp`) FROM `statements`", ())
+            .await?
+            .flatten();
+        Ok(last)
+    }
+
+    async fn get_rows(
+        wdrc: &WdRc,
+        db: &str,
+        table: &ShadowTable,
+        start: &str,
+        end: &str,
+    ) -> Result<Vec<Vec<String>>> {
+        let rows = wdrc
+            .db()
+            .get_connection(db)
+            .await?
+            .exec_iter(table.select_sql(), (start, end))
+            .await?
+            .map_and_drop(|row: Row| {
+                row.unwrap()
+                    .into_iter()
+                    .map(Self::value_to_string)
+                    .collect()
+            })
+            .await?;
+        Ok(rows)
+    }
+
+    fn value_to_string(value: SqlValue) -> String {
+        match value {
+            SqlValue::NULL => String::new(),
+            SqlValue::Bytes(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+            SqlValue::Int(i) => i.to_string(),
+            SqlValue::UInt(u) => u.to_string(),
+            other => format!("{other:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter()
+            .map(|row| row.iter().map(|s| s.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_table_divergence() {
+        let legacy = rows(&[&["1", "10", "31", "added"], &["1", "11", "31", "removed"]]);
+        let shadow = rows(&[
+            &["1", "10", "31", "added"],
+            &["1", "11", "31", "changed"],
+            &["2", "12", "17", "added"],
+        ]);
+        let divergence = TableDivergence::compare("statements", 3, legacy, shadow);
+        assert_eq!(divergence.only_legacy, 0);
+        assert_eq!(divergence.only_shadow, 1);
+        assert_eq!(divergence.mismatched, 1);
+        assert_eq!(divergence.only_shadow_examples, rows(&[&["2", "12", "17"]]));
+        assert_eq!(
+            divergence.mismatch_examples[0].legacy,
+            rows(&[&["removed"]])
+        );
+        assert!(!divergence.is_empty());
+
+        let same = rows(&[&["1", "10", "31", "added"], &["1", "10", "31", "removed"]]);
+        let reordered = rows(&[&["1", "10", "31", "removed"], &["1", "10", "31", "added"]]);
+        assert!(TableDivergence::compare("statements", 3, same, reordered).is_empty());
+    }
+
+    #[test]
+    fn test_select_sql() {
+        let labels = SHADOW_TABLES.iter().find(|t| t.name == "labels").unwrap();
+        assert_eq!(
+            labels.select_sql(),
+            "SELECT `item`,`revision`,`type`,(SELECT `value` FROM `texts` WHERE `id`=`language`) AS `language`,`change_type` FROM `labels` WHERE `timestamp`>=? AND `timestamp`<?"
+        );
+    }
+}
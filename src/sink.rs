@@ -0,0 +1,267 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use wikimisc::mysql_async::{prelude::Queryable, Value as SqlValue};
+
+use crate::{
+    change::{Change, EntityType},
+    ItemId, WdRc,
+};
+
+/// An entity created at `timestamp`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Creation {
+    pub q: ItemId,
+    pub timestamp: String,
+}
+
+/// An entity turned into a redirect to `target`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub source: ItemId,
+    pub target: ItemId,
+    pub timestamp: String,
+}
+
+/// An entity deleted at `timestamp`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deletion {
+    pub q: ItemId,
+    pub timestamp: String,
+}
+
+/// Where the pipeline writes what it found. `wdrc` gives access to the configured pools and caches,
+/// for sinks that need them.
+#[allow(async_fn_in_trait)]
+pub trait ChangeSink {
+    async fn log_changes(&self, wdrc: &mut WdRc, changes: &[Change]) -> Result<()>;
+    async fn log_creations(
+        &self,
+        wdrc: &WdRc,
+        entity_type: EntityType,
+        creations: &[Creation],
+    ) -> Result<()>;
+    async fn log_redirects(
+        &self,
+        wdrc: &WdRc,
+        entity_type: EntityType,
+        redirects: &[Redirect],
+    ) -> Result<()>;
+    async fn log_deletions(
+        &self,
+        wdrc: &WdRc,
+        entity_type: EntityType,
+        deletions: &[Deletion],
+    ) -> Result<()>;
+}
+
+/// The configured sink; `mysql` writes the wdrc tables, `ndjson` writes JSON lines to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkType {
+    #[default]
+    Mysql,
+    Ndjson,
+}
+
+impl ChangeSink for SinkType {
+    async fn log_changes(&self, wdrc: &mut WdRc, changes: &[Change]) -> Result<()> {
+        match self {
+            Self::Mysql => MysqlSink.log_changes(wdrc, changes).await,
+            Self::Ndjson => NdjsonSink.log_changes(wdrc, changes).await,
+        }
+    }
+
+    async fn log_creations(
+        &self,
+        wdrc: &WdRc,
+        entity_type: EntityType,
+        creations: &[Creation],
+    ) -> Result<()> {
+        match self {
+            Self::Mysql => MysqlSink.log_creations(wdrc, entity_type, creations).await,
+            Self::Ndjson => NdjsonSink.log_creations(wdrc, entity_type, creations).await,
+        }
+    }
+
+    async fn log_redirects(
+        &self,
+        wdrc: &WdRc,
+        entity_type: EntityType,
+        redirects: &[Redirect],
+    ) -> Result<()> {
+        match self {
+            Self::Mysql => MysqlSink.log_redirects(wdrc, entity_type, redirects).await,
+            Self::Ndjson => NdjsonSink.log_redirects(wdrc, entity_type, redirects).await,
+        }
+    }
+
+    async fn log_deletions(
+        &self,
+        wdrc: &WdRc,
+        entity_type: EntityType,
+        deletions: &[Deletion],
+    ) -> Result<()> {
+        match self {
+            Self::Mysql => MysqlSink.log_deletions(wdrc, entity_type, deletions).await,
+            Self::Ndjson => NdjsonSink.log_deletions(wdrc, entity_type, deletions).await,
+        }
+    }
+}
+
+/// Writes one JSON object per line to stdout, for piping the live feed into other tools. Changes
+/// are serialized as they are; creations, redirects, and deletions carry an `event` key instead.
+pub struct NdjsonSink;
+
+impl NdjsonSink {
+    fn write_lines(lines: impl Iterator<Item = Result<String>>) -> Result<()> {
+        let mut out = io::stdout().lock();
+        for line in lines {
+            writeln!(out, "{}", line?)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    fn event_lines(events: Vec<Value>) -> Result<()> {
+        Self::write_lines(events.iter().map(|e| Ok(e.to_string())))
+    }
+}
+
+impl ChangeSink for NdjsonSink {
+    async fn log_changes(&self, _wdrc: &mut WdRc, changes: &[Change]) -> Result<()> {
+        Self::write_lines(
+            changes
+                .iter()
+                .map(|c| serde_json::to_string(c).map_err(|e| e.into())),
+        )
+    }
+
+    async fn log_creations(
+        &self,
+        _wdrc: &WdRc,
+        entity_type: EntityType,
+        creations: &[Creation],
+    ) -> Result<()> {
+        Self::event_lines(
+            creations
+                .iter()
+                .map(|c| json!({"event": "creation", "entity": format!("{}{}", entity_type.id_prefix(), c.q), "timestamp": c.timestamp}))
+                .collect(),
+        )
+    }
+
+    async fn log_redirects(
+        &self,
+        _wdrc: &WdRc,
+        entity_type: EntityType,
+        redirects: &[Redirect],
+    ) -> Result<()> {
+        let prefix = entity_type.id_prefix();
+        Self::event_lines(
+            redirects
+                .iter()
+                .map(|r| json!({"event": "redirect", "entity": format!("{prefix}{}", r.source), "target": format!("{prefix}{}", r.target), "timestamp": r.timestamp}))
+                .collect(),
+        )
+    }
+
+    async fn log_deletions(
+        &self,
+        _wdrc: &WdRc,
+        entity_type: EntityType,
+        deletions: &[Deletion],
+    ) -> Result<()> {
+        let prefix = entity_type.id_prefix();
+        Self::event_lines(
+            deletions
+                .iter()
+                .map(|d| json!({"event": "deletion", "entity": format!("{prefix}{}", d.q), "timestamp": d.timestamp}))
+                .collect(),
+        )
+    }
+}
+
+/// Writes to the wdrc tables, via the `wdrc` pool.
+pub struct MysqlSink;
+
+impl ChangeSink for MysqlSink {
+    async fn log_changes(&self, wdrc: &mut WdRc, changes: &[Change]) -> Result<()> {
+        wdrc.write_changes(changes).await
+    }
+
+    /// Creations replace earlier deletions of the same entity.
+    async fn log_creations(
+        &self,
+        wdrc: &WdRc,
+        entity_type: EntityType,
+        creations: &[Creation],
+    ) -> Result<()> {
+        if creations.is_empty() {
+            return Ok(());
+        }
+        let rows: Vec<Vec<SqlValue>> = creations
+            .iter()
+            .map(|c| vec![c.q.into(), c.timestamp.as_str().into()])
+            .collect();
+        let sql = format!(
+            "REPLACE INTO `{}` (`q`,`timestamp`) VALUES",
+            entity_type.table("creations")
+        );
+        wdrc.insert_rows(&sql, &rows).await?;
+
+        let placeholders = vec!["?"; creations.len()].join(",");
+        let sql = format!(
+            "DELETE FROM `{}` WHERE `q` IN ({placeholders})",
+            entity_type.table("deletions")
+        );
+        let params: Vec<SqlValue> = creations.iter().map(|c| c.q.into()).collect();
+        wdrc.db()
+            .get_connection("wdrc")
+            .await?
+            .exec_drop(&sql, params)
+            .await?;
+        Ok(())
+    }
+
+    async fn log_redirects(
+        &self,
+        wdrc: &WdRc,
+        entity_type: EntityType,
+        redirects: &[Redirect],
+    ) -> Result<()> {
+        let rows: Vec<Vec<SqlValue>> = redirects
+            .iter()
+            .map(|r| {
+                vec![
+                    r.source.into(),
+                    r.target.into(),
+                    r.timestamp.as_str().into(),
+                ]
+            })
+            .collect();
+        let sql = format!(
+            "REPLACE INTO `{}` (`source`,`target`,`timestamp`) VALUES",
+            entity_type.table("redirects")
+        );
+        wdrc.insert_rows(&sql, &rows).await
+    }
+
+    async fn log_deletions(
+        &self,
+        wdrc: &WdRc,
+        entity_type: EntityType,
+        deletions: &[Deletion],
+    ) -> Result<()> {
+        let rows: Vec<Vec<SqlValue>> = deletions
+            .iter()
+            .map(|d| vec![d.q.into(), d.timestamp.as_str().into()])
+            .collect();
+        let sql = format!(
+            "REPLACE INTO `{}` (`q`,`timestamp`) VALUES",
+            entity_type.table("deletions")
+        );
+        wdrc.insert_rows(&sql, &rows).await
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use wikimisc::mysql_async::{from_row, prelude::Queryable, Value as SqlValue};
+
+use crate::{
+    change::{Change, ChangeSubject, ChangeType},
+    ItemId, WdRc,
+};
+
+/// Site and title pairs looked up per query.
+const PAIRS_PER_QUERY: usize = 500;
+
+/// A sitelink added to an item while another item holds the same site and title in the sitelink index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SitelinkConflict<'a> {
+    pub change: &'a Change,
+    pub other_item: ItemId,
+}
+
+/// Finds sitelinks that are linked from two items at once, which the on-wiki UI prevents but
+/// concurrent bot edits sometimes cause. Uses the `wb_items_per_site` sitelink index on the replica.
+pub struct SitelinkConflicts;
+
+impl SitelinkConflicts {
+    /// Sitelink changes that set a new title; only these can conflict.
+    fn candidates(changes: &[Change]) -> Vec<&Change> {
+        changes
+            .iter()
+            .filter(|c| c.subject == ChangeSubject::Sitelinks)
+            .filter(|c| matches!(c.change_type, ChangeType::Added | ChangeType::Changed))
+            .filter(|c| !c.site.is_empty() && !c.title.is_empty())
+            .collect()
+    }
+
+    pub async fn find<'a>(wdrc: &WdRc, changes: &'a [Change]) -> Result<Vec<SitelinkConflict<'a>>> {
+        let candidates = Self::candidates(changes);
+        if candidates.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut linked: HashMap<(String, String), ItemId> = HashMap::new();
+        let mut conn = wdrc.db().get_connection("wikidata").await?;
+        for chunk in candidates.chunks(PAIRS_PER_QUERY) {
+            let placeholders = vec!["(?,?)"; chunk.len()].join(",");
+            let sql = format!("SELECT `ips_site_id`,`ips_site_page`,`ips_item_id` FROM `wb_items_per_site` WHERE (`ips_site_id`,`ips_site_page`) IN ({placeholders})");
+            let params: Vec<SqlValue> = chunk
+                .iter()
+                .flat_map(|c| [c.site.as_str().into(), c.title.as_str().into()])
+                .collect();
+            let rows = conn
+                .exec_iter(sql, params)
+                .await?
+                .map_and_drop(from_row::<(String, String, ItemId)>)
+                .await?;
+            for (site, title, item) in rows {
+                linked.insert((site, title), item);
+            }
+        }
+        Ok(Self::conflicts(candidates, &linked))
+    }
+
+    fn conflicts<'a>(
+        candidates: Vec<&'a Change>,
+        linked: &HashMap<(String, String), ItemId>,
+    ) -> Vec<SitelinkConflict<'a>> {
+        candidates
+            .into_iter()
+            .filter_map(|change| {
+                let other_item = *linked.get(&(change.site.to_owned(), change.title.to_owned()))?;
+                match other_item == change.item_id {
+                    true => None,
+                    false => Some(SitelinkConflict { change, other_item }),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conflicts() {
+        let sitelink = |item_id, change_type, title: &str| Change {
+            subject: ChangeSubject::Sitelinks,
+            change_type,
+            item_id,
+            site: "enwiki".to_string(),
+            title: title.to_string(),
+            ..Default::default()
+        };
+        let changes = vec![
+            sitelink(1, ChangeType::Added, "Berlin"),
+            sitelink(2, ChangeType::Changed, "Paris"),
+            sitelink(3, ChangeType::Removed, "Rome"),
+            sitelink(4, ChangeType::Added, "Oslo"),
+        ];
+        let candidates = SitelinkConflicts::candidates(&changes);
+        assert_eq!(candidates.len(), 3);
+        let linked: HashMap<(String, String), ItemId> = [
+            (("enwiki".to_string(), "Berlin".to_string()), 64),
+            (("enwiki".to_string(), "Paris".to_string()), 2),
+            (("enwiki".to_string(), "Rome".to_string()), 220),
+        ]
+        .into_iter()
+        .collect();
+        let conflicts = SitelinkConflicts::conflicts(candidates, &linked);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].change.item_id, 1);
+        assert_eq!(conflicts[0].other_item, 64);
+    }
+}
@@ -0,0 +1,283 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use wikimisc::mysql_async::{from_row, prelude::Queryable, Conn, Value as SqlValue};
+
+use crate::{change::EntityType, value_format::ValueFormat, ItemId, WdRc};
+
+/// A statement as of the requested time, from the logged old and new values.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatementState {
+    pub id: String,
+    pub property: Option<String>,
+    pub value: Option<String>,
+    /// When the statement got this state.
+    pub since: String,
+}
+
+/// Statements of one property as of the requested time, from the last logged change. Without
+/// stored values, only the last change of the property as a whole is known.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PropertyState {
+    pub property: String,
+    pub last_change_type: String,
+    pub last_change: String,
+}
+
+/// What wdrc knows about an entity at a point in time: whether it existed, and which of the
+/// requested statements it had. History before `history_from` was not logged, and only the
+/// latest creation, deletion, and redirect of an entity are kept.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EntityState {
+    pub entity: String,
+    pub at: String,
+    /// Unknown if neither a creation nor any change up to `at` was logged.
+    pub live: Option<bool>,
+    pub created: Option<String>,
+    pub deleted: Option<String>,
+    pub redirected_to: Option<String>,
+    pub history_from: Option<String>,
+    /// Per statement, if values are stored; empty otherwise.
+    pub statements: Vec<StatementState>,
+    pub properties: Vec<PropertyState>,
+}
+
+impl EntityState {
+    /// The state of `entity` at `at` (a prefix of `YYYYMMDDHHMMSS`), limited to `properties` if
+    /// given, else to the watched properties if configured.
+    pub async fn at(wdrc: &WdRc, entity: &str, at: &str, properties: &[String]) -> Result<Self> {
+        let entity_type =
+            EntityType::from_id(entity).ok_or_else(|| anyhow!("Not an entity ID: {entity}"))?;
+        let q = WdRc::make_id_numeric(entity)?;
+        // A timestamp prefix covers the whole period it names
+        let at = format!("{at:9<14}");
+        let properties: Vec<String> = match (properties, wdrc.watch_pages()) {
+            ([], Some(watch_pages)) => watch_pages.properties.to_owned(),
+            (properties, _) => properties.to_vec(),
+        };
+
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        let sql = format!(
+            "SELECT `timestamp` FROM `{}` WHERE `q`=?",
+            entity_type.table("creations")
+        );
+        let created = Self::first_timestamp(&mut conn, sql, q).await?;
+        let sql = format!(
+            "SELECT `timestamp` FROM `{}` WHERE `q`=?",
+            entity_type.table("deletions")
+        );
+        let deleted = Self::first_timestamp(&mut conn, sql, q).await?;
+        let sql = format!(
+            "SELECT MIN(`timestamp`) FROM `{}` WHERE `item`=?",
+            entity_type.table("statements")
+        );
+        let history_from = Self::first_timestamp(&mut conn, sql, q).await?;
+        let sql = format!(
+            "SELECT `target`,`timestamp` FROM `{}` WHERE `source`=?",
+            entity_type.table("redirects")
+        );
+        let redirect: Option<(ItemId, String)> = conn.exec_first(sql, (q,)).await?;
+        let redirect = redirect.filter(|(_, timestamp)| *timestamp <= at);
+
+        let mut conditions = "`item`=? AND `timestamp`<=?".to_string();
+        let mut params: Vec<SqlValue> = vec![q.into(), at.as_str().into()];
+        if !properties.is_empty() {
+            let numeric = properties
+                .iter()
+                .map(|p| WdRc::make_id_numeric(p))
+                .collect::<Result<Vec<ItemId>>>()?;
+            conditions += &format!(
+                " AND `property` IN ({})",
+                vec!["?"; numeric.len()].join(",")
+            );
+            params.extend(numeric.into_iter().map(|p| p.into()));
+        }
+        let sql = format!(
+            "SELECT `property`,`change_type`,`timestamp` FROM `{}` WHERE {conditions} ORDER BY `timestamp`,`revision`",
+            entity_type.table("statements")
+        );
+        let property_changes: Vec<(ItemId, String, String)> = conn
+            .exec_iter(sql, params)
+            .await?
+            .map_and_drop(from_row::<(ItemId, String, String)>)
+            .await?;
+
+        let statements = match wdrc.store_values() {
+            true => {
+                let sql = format!(
+                    "SELECT `key`,`new_value`,`timestamp` FROM `{}` WHERE `item`=? AND `type`='claims' AND `timestamp`<=? ORDER BY `timestamp`,`revision`",
+                    entity_type.table("change_values")
+                );
+                let values: Vec<(String, String, String)> = conn
+                    .exec_iter(sql, (q, &at))
+                    .await?
+                    .map_and_drop(from_row::<(String, String, String)>)
+                    .await?;
+                Self::statements(values, &properties)
+            }
+            false => vec![],
+        };
+
+        let has_changes = !property_changes.is_empty();
+        Ok(Self {
+            entity: entity.to_string(),
+            live: Self::live(
+                &at,
+                created.as_deref(),
+                deleted.as_deref(),
+                redirect.is_some(),
+                has_changes,
+            ),
+            at,
+            created,
+            deleted,
+            redirected_to: redirect.map(|(target, _)| format!("Q{target}")),
+            history_from,
+            statements,
+            properties: Self::properties(property_changes),
+        })
+    }
+
+    async fn first_timestamp(conn: &mut Conn, sql: String, q: ItemId) -> Result<Option<String>> {
+        let row: Option<(Option<String>,)> = conn.exec_first(sql, (q,)).await?;
+        Ok(row.and_then(|(timestamp,)| timestamp))
+    }
+
+    fn live(
+        at: &str,
+        created: Option<&str>,
+        deleted: Option<&str>,
+        redirected: bool,
+        has_changes: bool,
+    ) -> Option<bool> {
+        if redirected || deleted.is_some_and(|deleted| deleted <= at) {
+            return Some(false);
+        }
+        match created {
+            Some(created) => Some(created <= at),
+            None if has_changes => Some(true),
+            None => None,
+        }
+    }
+
+    /// The last state of each property, from its changes in chronological order.
+    fn properties(changes: Vec<(ItemId, String, String)>) -> Vec<PropertyState> {
+        let mut last: BTreeMap<ItemId, (String, String)> = BTreeMap::new();
+        for (property, change_type, timestamp) in changes {
+            last.insert(property, (change_type, timestamp));
+        }
+        last.into_iter()
+            .map(
+                |(property, (last_change_type, last_change))| PropertyState {
+                    property: format!("P{property}"),
+                    last_change_type,
+                    last_change,
+                },
+            )
+            .collect()
+    }
+
+    /// Statements that existed after their last logged change, from `(key, new_value, timestamp)`
+    /// in chronological order.
+    fn statements(
+        values: Vec<(String, String, String)>,
+        properties: &[String],
+    ) -> Vec<StatementState> {
+        let mut last: BTreeMap<String, (String, String)> = BTreeMap::new();
+        for (key, new_value, timestamp) in values {
+            last.insert(key, (new_value, timestamp));
+        }
+        last.into_iter()
+            .filter(|(_, (new_value, _))| !new_value.is_empty())
+            .map(|(id, (new_value, since))| {
+                let snak: Option<Value> = serde_json::from_str(&new_value).ok();
+                StatementState {
+                    id,
+                    property: snak
+                        .as_ref()
+                        .and_then(|snak| snak["property"].as_str())
+                        .map(|p| p.to_string()),
+                    value: snak.as_ref().map(ValueFormat::snak),
+                    since,
+                }
+            })
+            .filter(|s| {
+                properties.is_empty() || s.property.as_ref().is_some_and(|p| properties.contains(p))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_live() {
+        let at = "20240601000000";
+        assert_eq!(
+            EntityState::live(at, Some("20240101000000"), None, false, true),
+            Some(true)
+        );
+        assert_eq!(
+            EntityState::live(at, Some("20240701000000"), None, false, false),
+            Some(false)
+        );
+        assert_eq!(
+            EntityState::live(
+                at,
+                Some("20240101000000"),
+                Some("20240501000000"),
+                false,
+                true
+            ),
+            Some(false)
+        );
+        assert_eq!(
+            EntityState::live(
+                at,
+                Some("20240101000000"),
+                Some("20240701000000"),
+                false,
+                true
+            ),
+            Some(true)
+        );
+        assert_eq!(EntityState::live(at, None, None, true, true), Some(false));
+        assert_eq!(EntityState::live(at, None, None, false, false), None);
+    }
+
+    #[test]
+    fn test_statements() {
+        let snak = r#"{"snaktype":"value","property":"P31","datavalue":{"value":{"entity-type":"item","id":"Q5"},"type":"wikibase-entityid"}}"#;
+        let values = vec![
+            (
+                "Q42$a".to_string(),
+                snak.to_string(),
+                "20240101000000".to_string(),
+            ),
+            (
+                "Q42$b".to_string(),
+                snak.to_string(),
+                "20240102000000".to_string(),
+            ),
+            (
+                "Q42$b".to_string(),
+                String::new(),
+                "20240103000000".to_string(),
+            ),
+        ];
+        let statements = EntityState::statements(values.clone(), &[]);
+        assert_eq!(
+            statements,
+            vec![StatementState {
+                id: "Q42$a".to_string(),
+                property: Some("P31".to_string()),
+                value: Some("Q5".to_string()),
+                since: "20240101000000".to_string(),
+            }]
+        );
+        assert!(EntityState::statements(values, &["P18".to_string()]).is_empty());
+    }
+}
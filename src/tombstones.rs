@@ -0,0 +1,61 @@
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use wikimisc::mysql_async::{prelude::Queryable, Conn, Value as SqlValue};
+
+use crate::WdRc;
+
+/// Why rows were removed, recorded per table, entity, and revision in `tombstones` so that mirrors
+/// can learn about corrections.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tombstone {
+    pub reason: String,
+    /// What replaced the rows, e.g. `engine v2` when reprocessing; unset if they were removed outright.
+    pub superseded_by: Option<String>,
+}
+
+impl Tombstone {
+    pub fn new(reason: &str, superseded_by: Option<String>) -> Self {
+        Self {
+            reason: reason.to_string(),
+            superseded_by,
+        }
+    }
+
+    /// Deletes the rows of `table` matching `condition`, leaving a tombstone per entity and revision
+    /// unless tombstones are turned off. Returns the number of rows deleted.
+    pub(crate) async fn delete_rows(
+        &self,
+        wdrc: &WdRc,
+        conn: &mut Conn,
+        table: &str,
+        condition: &str,
+        params: Vec<SqlValue>,
+    ) -> Result<u64> {
+        if wdrc.keep_tombstones() {
+            let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+            let sql = format!("INSERT INTO `tombstones` (`table`,`item`,`revision`,`reason`,`superseded_by`,`timestamp`,`rows`) SELECT ?,`item`,`revision`,?,?,?,count(*) FROM `{table}` WHERE {condition} GROUP BY `item`,`revision`");
+            let mut tombstone_params: Vec<SqlValue> = vec![
+                table.into(),
+                self.reason.as_str().into(),
+                self.superseded_by.as_deref().into(),
+                timestamp.into(),
+            ];
+            tombstone_params.extend(params.iter().cloned());
+            conn.exec_drop(sql, tombstone_params).await?;
+        }
+        let sql = format!("DELETE FROM `{table}` WHERE {condition}");
+        conn.exec_drop(sql, params).await?;
+        Ok(conn.affected_rows())
+    }
+
+    /// Removes tombstones older than `days`; returns the number removed.
+    pub async fn compact(wdrc: &WdRc, days: u64) -> Result<u64> {
+        let cutoff = (Utc::now() - Duration::days(days as i64))
+            .format("%Y%m%d%H%M%S")
+            .to_string();
+        let mut conn = wdrc.db().get_connection("wdrc").await?;
+        conn.exec_drop("DELETE FROM `tombstones` WHERE `timestamp`<?", (cutoff,))
+            .await?;
+        Ok(conn.affected_rows())
+    }
+}
@@ -0,0 +1,215 @@
+use serde_json::Value;
+
+use crate::change::{Change, ChangeSubject};
+
+/// Entity URI prefix of units and globes.
+const ENTITY_URI: &str = "http://www.wikidata.org/entity/";
+/// The proleptic Julian calendar model.
+const JULIAN_CALENDAR: &str = "Q1985786";
+/// Earth, the default globe of coordinates.
+const EARTH: &str = "Q2";
+
+/// Renders values of changes for people, per datatype: quantities with units, times at their
+/// precision, coordinates, and monolingual text, instead of raw snak JSON.
+pub struct ValueFormat;
+
+impl ValueFormat {
+    /// The old and new value of a change. Snaks of claims and qualifiers are rendered; other
+    /// values, like label texts and sitelink titles, are already readable.
+    pub fn change_values(change: &Change) -> (String, String) {
+        match change.subject {
+            ChangeSubject::Claims | ChangeSubject::Qualifiers => (
+                Self::snak_text(&change.old_text),
+                Self::snak_text(&change.new_text),
+            ),
+            _ => (change.old_text.to_owned(), change.new_text.to_owned()),
+        }
+    }
+
+    /// A snak, or qualifier snaks separated by `; `, given as JSON.
+    fn snak_text(json: &str) -> String {
+        let j: Value = match serde_json::from_str(json) {
+            Ok(j) => j,
+            Err(_) => return json.to_string(),
+        };
+        match j.as_array() {
+            Some(snaks) => snaks
+                .iter()
+                .map(Self::snak)
+                .collect::<Vec<String>>()
+                .join("; "),
+            None => Self::snak(&j),
+        }
+    }
+
+    pub fn snak(snak: &Value) -> String {
+        match snak["snaktype"].as_str() {
+            Some("novalue") => "no value".to_string(),
+            Some("somevalue") => "unknown value".to_string(),
+            _ => Self::datavalue(&snak["datavalue"]),
+        }
+    }
+
+    pub fn datavalue(datavalue: &Value) -> String {
+        let value = &datavalue["value"];
+        match datavalue["type"].as_str() {
+            Some("string") => value.as_str().unwrap_or_default().to_string(),
+            Some("wikibase-entityid") => value["id"].as_str().unwrap_or_default().to_string(),
+            Some("monolingualtext") => format!(
+                "{} ({})",
+                value["text"].as_str().unwrap_or_default(),
+                value["language"].as_str().unwrap_or_default()
+            ),
+            Some("quantity") => Self::quantity(value),
+            Some("time") => Self::time(value),
+            Some("globecoordinate") => Self::coordinate(value),
+            _ => datavalue.to_string(),
+        }
+    }
+
+    /// `Q11573` for `http://www.wikidata.org/entity/Q11573`; `None` for `1`, the unit of unitless quantities.
+    fn entity_from_uri(uri: &str) -> Option<&str> {
+        match uri {
+            "1" | "" => None,
+            uri => Some(uri.strip_prefix(ENTITY_URI).unwrap_or(uri)),
+        }
+    }
+
+    fn number(amount: &Value) -> Option<f64> {
+        amount.as_str()?.parse().ok()
+    }
+
+    /// `1.85±0.01 Q11573`, with the bounds only if they are symmetric.
+    fn quantity(value: &Value) -> String {
+        let amount = value["amount"].as_str().unwrap_or_default();
+        let mut ret = amount.strip_prefix('+').unwrap_or(amount).to_string();
+        let bounds = (
+            Self::number(&value["amount"]),
+            Self::number(&value["lowerBound"]),
+            Self::number(&value["upperBound"]),
+        );
+        if let (Some(amount), Some(lower), Some(upper)) = bounds {
+            let (below, above) = (amount - lower, upper - amount);
+            if below > 0.0 && (below - above).abs() < 1e-9 * above.abs().max(1.0) {
+                ret += &format!("±{}", Self::trim_float(above));
+            }
+        }
+        if let Some(unit) = Self::entity_from_uri(value["unit"].as_str().unwrap_or_default()) {
+            ret += &format!(" {unit}");
+        }
+        ret
+    }
+
+    /// Up to ten decimals, without trailing zeros.
+    fn trim_float(number: f64) -> String {
+        let ret = format!("{number:.10}");
+        ret.trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+
+    /// `1952-03-11` at day precision, `1950s` at decade precision, `20th century`, and so on.
+    fn time(value: &Value) -> String {
+        let time = value["time"].as_str().unwrap_or_default();
+        let bce = time.starts_with('-');
+        let digits = time.trim_start_matches(['+', '-']);
+        let mut parts = digits.split(['-', 'T']);
+        let year: u64 = parts.next().and_then(|y| y.parse().ok()).unwrap_or(0);
+        let month = parts.next().unwrap_or("00");
+        let day = parts.next().unwrap_or("00");
+        let ordinal = |n: u64| {
+            let suffix = match (n % 10, n % 100) {
+                (1, 11) | (2, 12) | (3, 13) => "th",
+                (1, _) => "st",
+                (2, _) => "nd",
+                (3, _) => "rd",
+                _ => "th",
+            };
+            format!("{n}{suffix}")
+        };
+        let mut ret = match value["precision"].as_u64().unwrap_or(11) {
+            11.. => format!("{year}-{month}-{day}"),
+            10 => format!("{year}-{month}"),
+            9 => year.to_string(),
+            8 => format!("{}s", year / 10 * 10),
+            7 => format!("{} century", ordinal(year.div_ceil(100))),
+            6 => format!("{} millennium", ordinal(year.div_ceil(1000))),
+            _ => format!("{year} years"),
+        };
+        if bce {
+            ret += " BCE";
+        }
+        if value["calendarmodel"]
+            .as_str()
+            .and_then(Self::entity_from_uri)
+            == Some(JULIAN_CALENDAR)
+        {
+            ret += " (Julian)";
+        }
+        ret
+    }
+
+    /// `52.5167, 13.3833`, with the globe unless it is Earth.
+    fn coordinate(value: &Value) -> String {
+        let mut ret = format!(
+            "{}, {}",
+            value["latitude"].as_f64().unwrap_or_default(),
+            value["longitude"].as_f64().unwrap_or_default()
+        );
+        match value["globe"].as_str().and_then(Self::entity_from_uri) {
+            Some(EARTH) | None => {}
+            Some(globe) => ret += &format!(" ({globe})"),
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_datavalue() {
+        let quantity = json!({"type": "quantity", "value": {"amount": "+1.85", "lowerBound": "+1.84", "upperBound": "+1.86", "unit": "http://www.wikidata.org/entity/Q11573"}});
+        assert_eq!(ValueFormat::datavalue(&quantity), "1.85±0.01 Q11573");
+        let count = json!({"type": "quantity", "value": {"amount": "+3", "unit": "1"}});
+        assert_eq!(ValueFormat::datavalue(&count), "3");
+
+        let time = |time: &str, precision: u64| json!({"type": "time", "value": {"time": time, "precision": precision, "calendarmodel": "http://www.wikidata.org/entity/Q1985727"}});
+        assert_eq!(
+            ValueFormat::datavalue(&time("+1952-03-11T00:00:00Z", 11)),
+            "1952-03-11"
+        );
+        assert_eq!(
+            ValueFormat::datavalue(&time("+1952-00-00T00:00:00Z", 8)),
+            "1950s"
+        );
+        assert_eq!(
+            ValueFormat::datavalue(&time("+1901-00-00T00:00:00Z", 7)),
+            "20th century"
+        );
+        assert_eq!(
+            ValueFormat::datavalue(&time("-0044-03-15T00:00:00Z", 9)),
+            "44 BCE"
+        );
+
+        let coordinate = json!({"type": "globecoordinate", "value": {"latitude": 52.5167, "longitude": 13.3833, "globe": "http://www.wikidata.org/entity/Q2"}});
+        assert_eq!(ValueFormat::datavalue(&coordinate), "52.5167, 13.3833");
+        let text =
+            json!({"type": "monolingualtext", "value": {"text": "Berlin", "language": "de"}});
+        assert_eq!(ValueFormat::datavalue(&text), "Berlin (de)");
+    }
+
+    #[test]
+    fn test_change_values() {
+        let change = Change {
+            subject: ChangeSubject::Qualifiers,
+            old_text: String::new(),
+            new_text: r#"[{"snaktype":"value","property":"P642","datavalue":{"value":{"entity-type":"item","id":"Q5"},"type":"wikibase-entityid"}},{"snaktype":"somevalue","property":"P642"}]"#.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            ValueFormat::change_values(&change),
+            (String::new(), "Q5; unknown value".to_string())
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Utc};
+use serde_json::Value;
+use wikimisc::mysql_async::{from_row, prelude::Queryable};
+
+use crate::{config::WatchPagesConfig, revision_compare::RevisionId, ItemId, WdRc};
+
+/// An added or removed statement, as listed on a watch page.
+#[derive(Debug, Clone, PartialEq)]
+struct WatchedChange {
+    item: ItemId,
+    revision: RevisionId,
+    change_type: String,
+    timestamp: String,
+}
+
+/// Writes recent statement additions and removals per property to an on-wiki page,
+/// e.g. `Property talk:P569/Recent changes`, for property maintainers working on-wiki.
+pub struct WatchPages<'a> {
+    wdrc: &'a WdRc,
+    config: &'a WatchPagesConfig,
+}
+
+impl<'a> WatchPages<'a> {
+    pub fn new(wdrc: &'a WdRc, config: &'a WatchPagesConfig) -> Self {
+        Self { wdrc, config }
+    }
+
+    /// Updates the pages of all configured properties; returns the titles of the pages edited.
+    pub async fn update(&self) -> Result<Vec<String>> {
+        let token = self.csrf_token().await?;
+        let mut ret = vec![];
+        for property in &self.config.properties {
+            let changes = self.get_changes(property).await?;
+            let title = self.config.page.replace("$1", property);
+            self.edit(&title, &Self::to_wikitext(property, &changes), &token)
+                .await?;
+            ret.push(title);
+        }
+        Ok(ret)
+    }
+
+    async fn get_changes(&self, property: &str) -> Result<Vec<WatchedChange>> {
+        let property = WdRc::make_id_numeric(property)?;
+        let since = (Utc::now() - Duration::days(self.config.days as i64))
+            .format("%Y%m%d%H%M%S")
+            .to_string();
+        let sql = "SELECT `item`,`revision`,`change_type`,`timestamp` FROM `statements` WHERE `property`=? AND `timestamp`>=? AND `change_type` IN ('added','removed') ORDER BY `timestamp` DESC LIMIT ?";
+        let changes = self
+            .wdrc
+            .db()
+            .get_connection("wdrc")
+            .await?
+            .exec_iter(sql, (property, since, self.config.max_rows))
+            .await?
+            .map_and_drop(from_row::<(ItemId, RevisionId, String, String)>)
+            .await?
+            .into_iter()
+            .map(|(item, revision, change_type, timestamp)| WatchedChange {
+                item,
+                revision,
+                change_type,
+                timestamp,
+            })
+            .collect();
+        Ok(changes)
+    }
+
+    fn to_wikitext(property: &str, changes: &[WatchedChange]) -> String {
+        let mut ret = format!(
+            "Statements with [[Property:{property}]] added or removed recently, newest first. This page is updated automatically; edits will be overwritten.\n"
+        );
+        ret += "{| class=\"wikitable sortable\"\n! Time !! Item !! Change !! Diff\n";
+        for c in changes {
+            ret += &format!(
+                "|-\n| {} || [[Q{}]] || {} || [[Special:Diff/{}|diff]]\n",
+                Self::format_timestamp(&c.timestamp),
+                c.item,
+                c.change_type,
+                c.revision
+            );
+        }
+        ret += "|}\n";
+        ret
+    }
+
+    /// `20240102030405` as `2024-01-02 03:04`.
+    fn format_timestamp(timestamp: &str) -> String {
+        match timestamp.len() >= 12 {
+            true => format!(
+                "{}-{}-{} {}:{}",
+                &timestamp[0..4],
+                &timestamp[4..6],
+                &timestamp[6..8],
+                &timestamp[8..10],
+                &timestamp[10..12]
+            ),
+            false => timestamp.to_string(),
+        }
+    }
+
+    async fn api(&self, params: &[(&str, &str)], post: bool) -> Result<Value> {
+        let client = self.wdrc.wd().reqwest_client()?;
+        let api_url = self.wdrc.wiki().api_url();
+        let request = match post {
+            true => client.post(api_url).form(params),
+            false => client.get(api_url).query(params),
+        };
+        let j: Value = request
+            .bearer_auth(&self.config.access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match j.get("error") {
+            Some(error) => Err(anyhow!("Wikidata API error: {error}")),
+            None => Ok(j),
+        }
+    }
+
+    async fn csrf_token(&self) -> Result<String> {
+        let params = [
+            ("action", "query"),
+            ("meta", "tokens"),
+            ("type", "csrf"),
+            ("format", "json"),
+        ];
+        let j = self.api(&params, false).await?;
+        j["query"]["tokens"]["csrftoken"]
+            .as_str()
+            .map(|token| token.to_string())
+            .ok_or_else(|| anyhow!("No CSRF token in {j}"))
+    }
+
+    async fn edit(&self, title: &str, text: &str, token: &str) -> Result<()> {
+        let params = [
+            ("action", "edit"),
+            ("title", title),
+            ("text", text),
+            ("summary", "Updating recent changes"),
+            ("bot", "1"),
+            ("token", token),
+            ("format", "json"),
+        ];
+        self.api(&params, true).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_wikitext() {
+        let changes = vec![WatchedChange {
+            item: 42,
+            revision: 12345,
+            change_type: "added".to_string(),
+            timestamp: "20240102030405".to_string(),
+        }];
+        let wikitext = WatchPages::to_wikitext("P569", &changes);
+        assert!(wikitext.starts_with("Statements with [[Property:P569]]"));
+        assert!(wikitext.contains(
+            "|-\n| 2024-01-02 03:04 || [[Q42]] || added || [[Special:Diff/12345|diff]]\n"
+        ));
+        assert!(wikitext.ends_with("|}\n"));
+    }
+}
@@ -0,0 +1,384 @@
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, fs::File, io::Read};
+use wikimisc::mysql_async::{from_row, prelude::Queryable, Value as SqlValue};
+
+use crate::{
+    change::{Change, EntityType},
+    config::WebhookConfig,
+    deliveries::Deliveries,
+    live::LiveFilter,
+    query::{ChangeRow, MAX_LIMIT},
+    webhooks::Webhooks,
+    RevisionId, WdRc,
+};
+
+/// Watched entity IDs looked up per query.
+const IDS_PER_QUERY: usize = 1000;
+
+/// Someone registered in `watchers`, with the entities on their watchlist. A watched entity ID
+/// matches changes to that entity; a watched property also matches statements, qualifiers and
+/// references using it, on any entity.
+#[derive(Debug, Clone, PartialEq)]
+struct Watcher {
+    id: u64,
+    url: Option<String>,
+    secret: Option<String>,
+    entities: Vec<String>,
+}
+
+impl Watcher {
+    /// The watched entity IDs matching `row`.
+    fn matched(&self, row: &ChangeRow) -> Vec<String> {
+        self.entities
+            .iter()
+            .filter(|id| **id == row.entity || row.property.as_ref() == Some(id))
+            .cloned()
+            .collect()
+    }
+
+    /// Groups `(watcher, url, secret, entity)` rows by watcher.
+    fn group(rows: Vec<(u64, Option<String>, Option<String>, String)>) -> Vec<Self> {
+        let mut ret: BTreeMap<u64, Self> = BTreeMap::new();
+        for (id, url, secret, entity) in rows {
+            ret.entry(id)
+                .or_insert_with(|| Self {
+                    id,
+                    url,
+                    secret,
+                    entities: vec![],
+                })
+                .entities
+                .push(entity);
+        }
+        ret.into_values().collect()
+    }
+}
+
+/// A logged change recorded for a watcher in `notifications`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Notification {
+    /// Increasing; list notifications after the last one seen to get new ones.
+    pub id: u64,
+    pub entity: String,
+    pub revision: RevisionId,
+    pub subject: String,
+    pub change_type: String,
+    /// Language, or site for sitelinks and badges.
+    pub language: Option<String>,
+    pub property: Option<String>,
+    pub timestamp: String,
+}
+
+/// Per-watcher watchlists of items and properties. Logged changes to watched entities are
+/// recorded in `notifications`, and also posted to the watcher's URL if set, like webhooks.
+pub struct Watchlist<'a> {
+    wdrc: &'a WdRc,
+}
+
+impl<'a> Watchlist<'a> {
+    pub fn new(wdrc: &'a WdRc) -> Self {
+        Self { wdrc }
+    }
+
+    /// Adds entities to the watchlist of `watcher`, registering the watcher if new.
+    pub async fn watch(&self, watcher: &str, ids: &[String]) -> Result<()> {
+        Self::check_ids(ids)?;
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        conn.exec_drop(
+            "INSERT IGNORE INTO `watchers` (`name`) VALUES (?)",
+            (watcher,),
+        )
+        .await?;
+        let id = self.watcher_id(watcher).await?;
+        let rows: Vec<Vec<SqlValue>> = ids
+            .iter()
+            .map(|entity| vec![id.into(), entity.as_str().into()])
+            .collect();
+        self.wdrc
+            .insert_rows(
+                "INSERT IGNORE INTO `watchlist` (`watcher`,`entity`) VALUES",
+                &rows,
+            )
+            .await
+    }
+
+    /// Removes entities from the watchlist of `watcher`; returns how many were on it.
+    pub async fn unwatch(&self, watcher: &str, ids: &[String]) -> Result<u64> {
+        Self::check_ids(ids)?;
+        let id = self.watcher_id(watcher).await?;
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let mut removed = 0;
+        for entity in ids {
+            conn.exec_drop(
+                "DELETE FROM `watchlist` WHERE `watcher`=? AND `entity`=?",
+                (id, entity),
+            )
+            .await?;
+            removed += conn.affected_rows();
+        }
+        Ok(removed)
+    }
+
+    /// Sets the URL matching changes are posted to, signed with `secret` if given; without a
+    /// URL, changes are only recorded.
+    pub async fn set_url(
+        &self,
+        watcher: &str,
+        url: Option<&str>,
+        secret: Option<&str>,
+    ) -> Result<()> {
+        if let Some(url) = url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(anyhow!("URL must start with http:// or https://: {url:?}"));
+            }
+        }
+        let id = self.watcher_id(watcher).await?;
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        conn.exec_drop(
+            "UPDATE `watchers` SET `url`=?,`secret`=? WHERE `id`=?",
+            (url, secret, id),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Sets a new token for reading the notifications of `watcher` over the API, replacing the
+    /// old one. Only its hash is stored, so it is returned just this once.
+    pub async fn new_token(&self, watcher: &str) -> Result<String> {
+        let id = self.watcher_id(watcher).await?;
+        let token = Self::random_token()?;
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        conn.exec_drop(
+            "UPDATE `watchers` SET `token_hash`=? WHERE `id`=?",
+            (Self::token_hash(&token), id),
+        )
+        .await?;
+        Ok(token)
+    }
+
+    /// Whether `token` is the token of `watcher`; false for unknown watchers and watchers
+    /// without a token.
+    pub async fn authorize(&self, watcher: &str, token: &str) -> Result<bool> {
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let hash: Option<Option<String>> = conn
+            .exec_first(
+                "SELECT `token_hash` FROM `watchers` WHERE `name`=?",
+                (watcher,),
+            )
+            .await?;
+        Ok(hash.flatten() == Some(Self::token_hash(token)))
+    }
+
+    /// 24 random bytes, in hex.
+    pub(crate) fn random_token() -> Result<String> {
+        let mut bytes = [0; 24];
+        File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        Ok(Self::hex(&bytes))
+    }
+
+    /// SHA-256 of a token, in hex; also of API keys.
+    pub(crate) fn token_hash(token: &str) -> String {
+        Self::hex(&Sha256::digest(token.as_bytes()))
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Up to `MAX_LIMIT` notifications of `watcher` with IDs greater than `after`, oldest first.
+    pub async fn notifications(&self, watcher: &str, after: u64) -> Result<Vec<Notification>> {
+        let id = self.watcher_id(watcher).await?;
+        let sql = "SELECT `id`,`entity`,`revision`,`subject`,`change_type`,`language`,`property`,`timestamp` FROM `notifications` WHERE `watcher`=? AND `id`>? ORDER BY `id` LIMIT ?";
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let rows = conn
+            .exec_iter(sql, (id, after, MAX_LIMIT))
+            .await?
+            .map_and_drop(|row| {
+                let (id, entity, revision, subject, change_type, language, property, timestamp) =
+                    from_row(row);
+                Notification {
+                    id,
+                    entity,
+                    revision,
+                    subject,
+                    change_type,
+                    language,
+                    property,
+                    timestamp,
+                }
+            })
+            .await?;
+        Ok(rows)
+    }
+
+    /// Records changes for the watchers watching them, and posts them to those with a URL, each
+    /// change once per URL with the watched IDs it `matched`. Returns the errors of posts that
+    /// still failed after retries.
+    pub async fn dispatch(&self, changes: &[Change]) -> Result<Vec<String>> {
+        let rows: Vec<ChangeRow> = changes.iter().map(ChangeRow::from_change).collect();
+        let watchers = self.watchers_of(&rows).await?;
+        let mut notifications: Vec<Vec<SqlValue>> = vec![];
+        let mut matches = vec![];
+        let mut urls: Vec<(String, WebhookConfig)> = vec![];
+        for watcher in watchers {
+            let matching: Vec<(usize, Vec<String>)> = rows
+                .iter()
+                .map(|row| watcher.matched(row))
+                .enumerate()
+                .filter(|(_, matched)| !matched.is_empty())
+                .collect();
+            if matching.is_empty() {
+                continue;
+            }
+            notifications.extend(matching.iter().map(|(num, _)| {
+                let row = &rows[*num];
+                vec![
+                    watcher.id.into(),
+                    row.entity.as_str().into(),
+                    row.revision.into(),
+                    row.subject.as_str().into(),
+                    row.change_type.as_str().into(),
+                    row.language.to_owned().into(),
+                    row.property.to_owned().into(),
+                    row.timestamp.as_str().into(),
+                ]
+            }));
+            if let Some(url) = watcher.url {
+                for (num, matched) in matching {
+                    matches.extend(matched.into_iter().map(|id| (url.to_owned(), id, num)));
+                }
+                if !urls.iter().any(|(_, webhook)| webhook.url == url) {
+                    let webhook = WebhookConfig {
+                        url,
+                        secret: watcher.secret,
+                        filter: LiveFilter::default(),
+                    };
+                    urls.push((format!("URL of watcher {}", watcher.id), webhook));
+                }
+            }
+        }
+        let mut merged = Deliveries::merge_matches(changes, matches);
+        let posts = urls.into_iter().filter_map(|(name, webhook)| {
+            let matching = merged.remove(&webhook.url)?;
+            Some((
+                name,
+                webhook,
+                Webhooks::body(self.wdrc.wiki().dbname(), &matching),
+            ))
+        });
+        self.wdrc
+            .insert_rows(
+                "INSERT INTO `notifications` (`watcher`,`entity`,`revision`,`subject`,`change_type`,`language`,`property`,`timestamp`) VALUES",
+                &notifications,
+            )
+            .await?;
+        let futures = posts.map(|(name, webhook, body)| async move {
+            Webhooks::post(
+                self.wdrc.wd(),
+                name,
+                &webhook,
+                body,
+                self.wdrc.api_retry(),
+                self.wdrc.api_timeout(),
+            )
+            .await
+        });
+        Ok(join_all(futures)
+            .await
+            .into_iter()
+            .filter_map(|result| result.err())
+            .map(|e| e.to_string())
+            .collect())
+    }
+
+    /// The watchers watching any entity or property of `rows`.
+    async fn watchers_of(&self, rows: &[ChangeRow]) -> Result<Vec<Watcher>> {
+        let mut ids: Vec<&String> = rows
+            .iter()
+            .flat_map(|row| std::iter::once(&row.entity).chain(row.property.as_ref()))
+            .collect();
+        ids.sort();
+        ids.dedup();
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let mut watched = vec![];
+        for chunk in ids.chunks(IDS_PER_QUERY) {
+            let sql = format!(
+                "SELECT `watchers`.`id`,`url`,`secret`,`entity` FROM `watchlist` JOIN `watchers` ON `watchers`.`id`=`watcher` WHERE `entity` IN ({})",
+                vec!["?"; chunk.len()].join(",")
+            );
+            watched.extend(
+                conn.exec_iter(sql, chunk.to_vec())
+                    .await?
+                    .map_and_drop(from_row::<(u64, Option<String>, Option<String>, String)>)
+                    .await?,
+            );
+        }
+        Ok(Watcher::group(watched))
+    }
+
+    async fn watcher_id(&self, watcher: &str) -> Result<u64> {
+        let mut conn = self.wdrc.db().get_connection("wdrc").await?;
+        let id: Option<u64> = conn
+            .exec_first("SELECT `id` FROM `watchers` WHERE `name`=?", (watcher,))
+            .await?;
+        id.ok_or_else(|| anyhow!("Unknown watcher: {watcher:?}"))
+    }
+
+    fn check_ids(ids: &[String]) -> Result<()> {
+        for id in ids {
+            EntityType::from_id(id)
+                .and_then(|_| WdRc::make_id_numeric(id).ok())
+                .ok_or_else(|| anyhow!("Not an entity ID: {id:?}"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchers() {
+        let watchers = Watcher::group(vec![
+            (1, None, None, "Q42".to_string()),
+            (
+                2,
+                Some("https://example.org".to_string()),
+                None,
+                "P31".to_string(),
+            ),
+            (1, None, None, "Q64".to_string()),
+        ]);
+        assert_eq!(watchers.len(), 2);
+        assert_eq!(watchers[0].entities, ["Q42", "Q64"]);
+        let change = Change {
+            subject: crate::ChangeSubject::Claims,
+            property: "P31".to_string(),
+            item_id: 1,
+            ..Default::default()
+        };
+        let row = ChangeRow::from_change(&change);
+        assert!(watchers[0].matched(&row).is_empty());
+        assert_eq!(watchers[1].matched(&row), ["P31"]);
+        let row = ChangeRow {
+            entity: "Q42".to_string(),
+            ..row
+        };
+        assert_eq!(watchers[0].matched(&row), ["Q42"]);
+        let both = Watcher {
+            entities: vec!["P31".to_string(), "Q42".to_string()],
+            ..watchers[0].clone()
+        };
+        assert_eq!(both.matched(&row), ["P31", "Q42"]);
+        assert!(Watchlist::check_ids(&["Q42".to_string(), "P31".to_string()]).is_ok());
+        assert!(Watchlist::check_ids(&["Q42x".to_string()]).is_err());
+        assert_eq!(
+            Watchlist::token_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, NaiveDateTime};
+use serde_json::Value;
+
+use crate::WdRc;
+
+pub(crate) const WDQS_SPARQL_URL: &str = "https://query.wikidata.org/sparql";
+/// The time of the last Wikidata edit the query service has processed.
+const WDQS_LAG_QUERY: &str = "SELECT ?t { <http://www.wikidata.org> schema:dateModified ?t }";
+/// `meta` key holding the WDQS position as `YYYYMMDDHHMMSS`.
+pub const WDQS_UPDATED_KEY: &str = "wdqs_updated";
+
+/// Tracks how far the Wikidata Query Service has caught up, so changes can be told apart by
+/// whether they are likely reflected in SPARQL results yet.
+pub struct WdqsLag;
+
+impl WdqsLag {
+    /// Queries WDQS for its position and stores it in `meta`; returns the position.
+    pub async fn update(wdrc: &WdRc) -> Result<String> {
+        let client = wdrc.wd().reqwest_client()?;
+        let j: Value = client
+            .get(WDQS_SPARQL_URL)
+            .query(&[("query", WDQS_LAG_QUERY), ("format", "json")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let updated = Self::parse_response(&j)?;
+        wdrc.set_key_value(WDQS_UPDATED_KEY, &updated).await?;
+        Ok(updated)
+    }
+
+    /// The stored WDQS position, if tracked.
+    pub async fn updated(wdrc: &WdRc) -> Result<Option<String>> {
+        wdrc.get_key_value(WDQS_UPDATED_KEY).await
+    }
+
+    fn parse_response(j: &Value) -> Result<String> {
+        let modified = j["results"]["bindings"][0]["t"]["value"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No dateModified in WDQS response: {j}"))?;
+        let modified = DateTime::parse_from_rfc3339(modified)
+            .map_err(|e| anyhow!("Bad WDQS dateModified {modified:?}: {e}"))?;
+        Ok(modified.format("%Y%m%d%H%M%S").to_string())
+    }
+
+    /// The exclusive upper bound for timestamps of changes reflected in WDQS.
+    pub fn until(updated: &str) -> Result<String> {
+        let updated = NaiveDateTime::parse_from_str(updated, "%Y%m%d%H%M%S")
+            .map_err(|e| anyhow!("Bad WDQS timestamp {updated:?}: {e}"))?;
+        Ok((updated + Duration::seconds(1))
+            .format("%Y%m%d%H%M%S")
+            .to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_response() {
+        let j = json!({"head": {"vars": ["t"]}, "results": {"bindings": [{"t": {"datatype": "http://www.w3.org/2001/XMLSchema#dateTime", "type": "literal", "value": "2024-01-02T03:04:05Z"}}]}});
+        assert_eq!(WdqsLag::parse_response(&j).unwrap(), "20240102030405");
+        assert!(WdqsLag::parse_response(&json!({})).is_err());
+        assert_eq!(WdqsLag::until("20240102235959").unwrap(), "20240103000000");
+    }
+}
@@ -1,14 +1,55 @@
+#[cfg(feature = "serve")]
+use crate::config::{ApiKeyConfig, Role};
+#[cfg(feature = "notifiers")]
+use crate::notifiers::Notifiers;
 use crate::{
-    change::{Change, ChangeSubject},
-    recent_changes::{RecentChanges, RecentChangesResults, RecentDeletions, RecentRedirects},
-    revision_compare::RevisionCompare,
+    catch_up::CatchUpPlan,
+    change::{Change, ChangeSubject, EntityType},
+    commons_media::CommonsMedia,
+    config::{
+        ApiRetryConfig, Config, DigestConfig, LiftWingConfig, LogRule, NotifierConfig,
+        ObjectStorageConfig, SignificanceThresholds, SmtpConfig, WatchPagesConfig, WebhookConfig,
+    },
+    deliveries::NotifyWindow,
+    drops::{DropCounts, DropReason},
+    edit_summary::EditSummary,
+    event_stream::EventStream,
+    liftwing::LiftWing,
+    live::LiveFeed,
+    query::ChangeRow,
+    recent_changes::{
+        BatchOptions, BatchSize, ChangedItem, FailedItem, RecentChanges, RecentChangesResults,
+        RecentDeletions, RecentLogEvents, RecentMerges, RecentRedirects,
+    },
+    redact::Redactor,
+    replica_schema::ReplicaSchema,
+    reverts::{self, PreviousValue, Revert},
+    revision_compare::{RevisionBackend, RevisionCompare, RevisionId},
+    scope::ItemScope,
+    sessions::Sessions,
+    shadow::ShadowReport,
+    sink::{ChangeSink, Creation, Deletion, Redirect, SinkType},
+    sitelink_conflicts::SitelinkConflicts,
+    watchlist::Watchlist,
+    wdqs::WdqsLag,
+    webhooks::Webhooks,
+    wiki::Wiki,
 };
+#[cfg(feature = "irc")]
+use crate::{config::IrcConfig, irc::IrcFeed};
 use anyhow::{anyhow, Result};
-use futures::{join, StreamExt};
-use serde_json::{json, Value};
-use std::{collections::HashMap, fs::File, io::BufReader, sync::Arc, time::Duration};
+use chrono::Utc;
+use chrono_tz::Tz;
+use futures::{future::join_all, join, StreamExt};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
 use wikimisc::{
-    mysql_async::{from_row, prelude::Queryable},
+    mysql_async::{from_row, prelude::Queryable, Value as SqlValue},
     timestamp::TimeStamp,
     toolforge_db::ToolforgeDB,
     wikidata::Wikidata,
@@ -17,52 +58,512 @@ use wikimisc::{
 pub type TextId = u64;
 pub type ItemId = u64;
 
-const MAX_RECENT_CHANGES: u64 = 500;
-const MAX_API_CONCURRENT: u64 = 50;
+/// Random extra delay in bot mode, as a fraction of the delay.
+const BOT_DELAY_JITTER: f64 = 0.1;
+/// Failed item comparisons are retried on later runs until they failed this often.
+const MAX_COMPARE_ATTEMPTS: u32 = 5;
+/// Logged value changes this recent are checked for being reverted.
+const REVERT_WINDOW_DAYS: i64 = 30;
+/// Rows per multi-row INSERT, keeping well below the placeholder limit.
+const MAX_ROWS_PER_INSERT: usize = 1000;
+
+/// Replica `recentchanges` rows with user name, edit summary, and `|`-separated change tags.
+const RECENT_CHANGES_SELECT: &str = "SELECT `recentchanges`.*,`actor_name`,`comment_text`,(SELECT GROUP_CONCAT(`ctd_name` SEPARATOR '|') FROM `change_tag` JOIN `change_tag_def` ON `ctd_id`=`ct_tag_id` WHERE `ct_rc_id`=`rc_id`) AS `tags` FROM `recentchanges` LEFT JOIN `actor` ON `actor_id`=`rc_actor` LEFT JOIN `comment` ON `comment_id`=`rc_comment_id`";
+
+/// Item comparisons of a batch, before their changes are logged.
+pub(crate) struct ComparedItems {
+    changes: Vec<Change>,
+    succeeded: Vec<ChangedItem>,
+    failed: Vec<(ChangedItem, String)>,
+    /// Not compared yet, without counting as an attempt.
+    held: Vec<ChangedItem>,
+}
+
+/// Where recent changes are read from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeSource {
+    /// The `recentchanges` table on the Wikidata replica.
+    #[default]
+    Replica,
+    /// The Wikimedia EventStreams `recentchange` feed.
+    EventStreams,
+}
+
+/// How the position in `recentchanges` is remembered between runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Checkpoint {
+    /// The highest processed `rc_id`, so no change with an equal timestamp is skipped.
+    #[default]
+    RcId,
+    /// The timestamp of the last changed item, in windows of `query_window_secs`.
+    Timestamp,
+}
 
+/// The change tracking pipeline, reading from the Wikidata replica and writing to the wdrc database.
 #[derive(Debug)]
 pub struct WdRc {
     text_cache: HashMap<String, usize>,
     wd: Arc<Wikidata>,
     db: ToolforgeDB,
     logging: bool,
-    max_recent_changes: u64,
+    shadow: bool,
+    track_wdqs_lag: bool,
+    batch_size: BatchSize,
     max_api_concurrent: usize,
+    api_retry: ApiRetryConfig,
+    wiki: Wiki,
+    sink: SinkType,
+    api_timeout: Duration,
+    change_source: ChangeSource,
+    revision_backend: RevisionBackend,
+    checkpoint: Checkpoint,
+    namespaces: Vec<u64>,
+    store_values: bool,
+    store_details: bool,
+    store_summaries: bool,
+    max_value_bytes: usize,
+    batch_options: BatchOptions,
+    poll_interval: Duration,
+    max_backoff: Duration,
+    retention_days: Option<u64>,
+    session_minutes: Option<u64>,
+    keep_tombstones: bool,
+    tombstone_days: u64,
+    replica_schema: ReplicaSchema,
+    failed_items: Option<Vec<FailedItem>>,
+    /// `meta` values set since [`detach`](Self::detach), kept in memory only.
+    detached_meta: Option<Mutex<HashMap<String, String>>>,
+    drops: DropCounts,
+    /// Subscribers to changes as they are logged.
+    live: broadcast::Sender<ChangeRow>,
+    catch_up_windows: Option<usize>,
+    work_queue: bool,
+    significant_items: Option<SignificanceThresholds>,
+    liftwing: Option<LiftWingConfig>,
+    annotate_media: bool,
+    detect_sitelink_conflicts: bool,
+    watch_pages: Option<WatchPagesConfig>,
+    public_stats: Option<String>,
+    webhooks: Vec<WebhookConfig>,
+    notifiers: Vec<NotifierConfig>,
+    /// Connections of IRC notifiers, by notifier, opened on first use.
+    #[cfg(feature = "irc")]
+    irc_feeds: Mutex<HashMap<usize, IrcFeed>>,
+    watchlist: bool,
+    #[cfg(feature = "serve")]
+    api_keys: Vec<ApiKeyConfig>,
+    /// Logged changes waiting for webhooks, notifiers and watchlists; only collected by the bot.
+    outbox: Option<Vec<Change>>,
+    /// Collected changes held back to be sent together with later changes of their entities.
+    notify_window: NotifyWindow,
+    digests: Vec<DigestConfig>,
+    smtp: Option<SmtpConfig>,
+    object_storage: Option<ObjectStorageConfig>,
+    log_rules: Vec<LogRule>,
+    scope: Option<ItemScope>,
+    feeds: Vec<String>,
+    feeds_dir: Option<String>,
+    timezone: Tz,
+    /// When a run stops starting new steps; the current step always finishes.
+    deadline: Option<Instant>,
 }
 
 impl WdRc {
-    pub fn new(config_file: &str) -> WdRc {
-        let config = Self::read_config(config_file);
-        WdRc {
+    /// Creates a new instance from a JSON config file.
+    pub fn new(config_file: &str) -> Result<WdRc> {
+        let config = Config::from_file(config_file)?;
+        Ok(WdRc {
             text_cache: HashMap::new(),
-            wd: Self::prepare_wd(),
-            db: Self::prepare_db(&config),
-            logging: config
-                .get("logging")
-                .unwrap_or(&json!(false))
-                .as_bool()
-                .unwrap_or(false),
-            max_recent_changes: config
-                .get("max_recent_changes")
-                .and_then(|j| j.as_u64())
-                .unwrap_or(MAX_RECENT_CHANGES),
-            max_api_concurrent: config
-                .get("max_api_concurrent")
-                .and_then(|j| j.as_u64())
-                .unwrap_or(MAX_API_CONCURRENT) as usize,
+            wd: Arc::new(Self::prepare_wd()),
+            db: Self::prepare_db(&config)?,
+            logging: config.logging,
+            shadow: config.shadow,
+            track_wdqs_lag: config.track_wdqs_lag,
+            batch_size: BatchSize::new(
+                config.query_window(),
+                config.max_recent_changes,
+                config.adaptive_batches,
+            )
+            .with_time_budget(config.batch_time_budget()),
+            max_api_concurrent: config.max_api_concurrent,
+            api_retry: config.api_retry(),
+            wiki: config.wiki,
+            sink: config.sink,
+            api_timeout: config.api_timeout(),
+            change_source: config.change_source,
+            revision_backend: config.revision_backend,
+            checkpoint: config.checkpoint,
+            namespaces: config.namespaces.to_owned(),
+            store_values: config.store_values,
+            store_details: config.store_details,
+            store_summaries: config.store_summaries,
+            max_value_bytes: config.max_value_bytes,
+            batch_options: BatchOptions {
+                per_revision: config.per_revision,
+                skip_bot_edits: config.skip_bot_edits,
+                tags: config.tags.to_owned(),
+            },
+            poll_interval: config.poll_interval(),
+            max_backoff: config.max_backoff(),
+            retention_days: config.retention_days,
+            session_minutes: config.session_minutes,
+            keep_tombstones: config.keep_tombstones,
+            tombstone_days: config.tombstone_days,
+            replica_schema: ReplicaSchema::default(),
+            failed_items: None,
+            detached_meta: None,
+            drops: DropCounts::default(),
+            live: LiveFeed::channel(),
+            catch_up_windows: config.catch_up_windows,
+            work_queue: config.work_queue,
+            significant_items: config.significant_items.to_owned(),
+            liftwing: config.liftwing.to_owned(),
+            annotate_media: config.commons.is_some(),
+            detect_sitelink_conflicts: config.detect_sitelink_conflicts,
+            watch_pages: config.watch_pages.to_owned(),
+            public_stats: config.public_stats.to_owned(),
+            webhooks: config.webhooks.to_owned(),
+            notifiers: config.notifiers.to_owned(),
+            #[cfg(feature = "irc")]
+            irc_feeds: Mutex::new(HashMap::new()),
+            watchlist: config.watchlist,
+            #[cfg(feature = "serve")]
+            api_keys: config.api_keys.to_owned(),
+            outbox: None,
+            notify_window: NotifyWindow::new(config.notify_window()),
+            digests: config.digests.to_owned(),
+            smtp: config.smtp.to_owned(),
+            object_storage: config.object_storage.to_owned(),
+            log_rules: config.log_rules.to_owned(),
+            scope: config.scope.to_owned().map(ItemScope::new),
+            feeds: config.feeds.to_owned(),
+            feeds_dir: config.feeds_dir.to_owned(),
+            timezone: config.timezone(),
+            deadline: None,
+        })
+    }
+
+    /// Returns how long bot mode waits before the next run, after `errors` consecutive failed runs.
+    pub fn bot_delay(&self, errors: u32) -> Duration {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        let jitter = (nanos % 1000) as f64 / 1000.0;
+        Self::backoff_delay(self.poll_interval, self.max_backoff, errors, jitter)
+    }
+
+    /// Doubles `interval` per consecutive error up to `max`, then adds up to `BOT_DELAY_JITTER` of it, scaled by `jitter` (0..1).
+    fn backoff_delay(interval: Duration, max: Duration, errors: u32, jitter: f64) -> Duration {
+        let delay = match errors {
+            0 => interval,
+            errors => interval
+                .saturating_mul(2u32.saturating_pow(errors))
+                .min(max.max(interval)),
+        };
+        delay.mul_f64(1.0 + BOT_DELAY_JITTER * jitter.clamp(0.0, 1.0))
+    }
+
+    /// Checks the replica for expected columns. Fails if recent changes can't be read from it;
+    /// deletions and redirects are skipped if their tables are unusable.
+    pub async fn check_replica_schema(&mut self) -> Result<()> {
+        let schema = match ReplicaSchema::check(self).await {
+            Ok(schema) => schema,
+            Err(_) if self.change_source == ChangeSource::EventStreams => return Ok(()), // The replica is optional
+            Err(e) => return Err(e),
+        };
+        if !schema.is_ok() {
+            eprintln!("{}", schema.diagnostic());
+        }
+        if self.change_source == ChangeSource::Replica
+            && ![
+                "recentchanges",
+                "actor",
+                "comment",
+                "change_tag",
+                "change_tag_def",
+            ]
+            .iter()
+            .all(|table| schema.is_table_usable(table))
+        {
+            return Err(anyhow!(
+                "Replica schema has changed, can not read recent changes:\n{}",
+                schema.diagnostic()
+            ));
+        }
+        self.replica_schema = schema;
+        Ok(())
+    }
+
+    pub(crate) fn db(&self) -> &ToolforgeDB {
+        &self.db
+    }
+
+    pub(crate) fn wd(&self) -> &Arc<Wikidata> {
+        &self.wd
+    }
+
+    pub(crate) fn namespaces(&self) -> &[u64] {
+        &self.namespaces
+    }
+
+    pub(crate) fn wiki(&self) -> Wiki {
+        self.wiki
+    }
+
+    /// Receives changes as they are logged.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeRow> {
+        self.live.subscribe()
+    }
+
+    /// Broadcasts logged changes to the subscribers of another instance instead.
+    pub fn set_live(&mut self, live: broadcast::Sender<ChangeRow>) {
+        self.live = live;
+    }
+
+    pub(crate) fn live(&self) -> &broadcast::Sender<ChangeRow> {
+        &self.live
+    }
+
+    pub(crate) fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    /// The feed of IRC notifier `num`, connecting on first use.
+    #[cfg(feature = "irc")]
+    pub(crate) fn irc_feed(&self, num: usize, config: &IrcConfig) -> IrcFeed {
+        let mut feeds = self.irc_feeds.lock().unwrap_or_else(|e| e.into_inner());
+        feeds
+            .entry(num)
+            .or_insert_with(|| IrcFeed::spawn(config.to_owned()))
+            .clone()
+    }
+
+    /// The role of an API key; `public` for unknown keys.
+    #[cfg(feature = "serve")]
+    pub(crate) fn role(&self, key: &str) -> Role {
+        let hash = Watchlist::token_hash(key);
+        self.api_keys
+            .iter()
+            .find(|api_key| api_key.sha256 == hash)
+            .map_or(Role::Public, |api_key| api_key.role)
+    }
+
+    pub(crate) fn webhooks(&self) -> &[WebhookConfig] {
+        &self.webhooks
+    }
+
+    pub(crate) fn notifiers(&self) -> &[NotifierConfig] {
+        &self.notifiers
+    }
+
+    #[cfg(feature = "notifiers")]
+    pub(crate) fn liftwing(&self) -> Option<&LiftWingConfig> {
+        self.liftwing.as_ref()
+    }
+
+    pub(crate) fn api_retry(&self) -> &ApiRetryConfig {
+        &self.api_retry
+    }
+
+    pub(crate) fn api_timeout(&self) -> Duration {
+        self.api_timeout
+    }
+
+    /// A [`RevisionCompare`] with the configured wiki, backend, retries and timeout.
+    pub(crate) fn revision_compare(&self) -> RevisionCompare {
+        RevisionCompare::new(self.wd.clone())
+            .with_wiki(self.wiki)
+            .with_backend(self.revision_backend)
+            .with_retry(self.api_retry.clone())
+            .with_timeout(self.api_timeout)
+    }
+
+    pub(crate) fn keep_tombstones(&self) -> bool {
+        self.keep_tombstones
+    }
+
+    pub(crate) fn tombstone_days(&self) -> u64 {
+        self.tombstone_days
+    }
+
+    pub(crate) fn watch_pages(&self) -> Option<&WatchPagesConfig> {
+        self.watch_pages.as_ref()
+    }
+
+    pub(crate) fn store_values(&self) -> bool {
+        self.store_values
+    }
+
+    pub(crate) fn change_source(&self) -> ChangeSource {
+        self.change_source
+    }
+
+    pub(crate) fn digests(&self) -> &[DigestConfig] {
+        &self.digests
+    }
+
+    pub(crate) fn smtp(&self) -> Option<&SmtpConfig> {
+        self.smtp.as_ref()
+    }
+
+    pub(crate) fn object_storage(&self) -> Option<&ObjectStorageConfig> {
+        self.object_storage.as_ref()
+    }
+
+    /// The time zone of reports and digests.
+    pub(crate) fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    pub(crate) fn feeds(&self) -> &[String] {
+        &self.feeds
+    }
+
+    pub(crate) fn feeds_dir(&self) -> Option<&str> {
+        self.feeds_dir.as_deref()
+    }
+
+    pub(crate) fn public_stats(&self) -> Option<&str> {
+        self.public_stats.as_deref()
+    }
+
+    /// Optional features, and whether they are on.
+    pub(crate) fn features(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("values", self.store_values),
+            ("details", self.store_details),
+            ("summaries", self.store_summaries),
+            ("sessions", self.session_minutes.is_some()),
+            ("significant_items", self.significant_items.is_some()),
+            ("revision_scores", self.liftwing.is_some()),
+            ("media", self.annotate_media),
+            ("sitelink_conflicts", self.detect_sitelink_conflicts),
+            ("wdqs_lag", self.track_wdqs_lag),
+            ("skip_bot_edits", self.batch_options.skip_bot_edits),
+            ("webhooks", !self.webhooks.is_empty()),
+            ("notifiers", !self.notifiers.is_empty()),
+            ("watchlist", self.watchlist),
+            ("digests", !self.digests.is_empty()),
+            ("log_rules", !self.log_rules.is_empty()),
+            ("scope", self.scope.is_some()),
+            ("feeds", !self.feeds.is_empty()),
+        ]
+    }
+
+    /// Collects logged changes for [`notify`](Self::notify), if webhooks, notifiers or
+    /// watchlists are configured. Off by default, so replaying history does not notify again.
+    pub fn collect_notifications(&mut self) {
+        if !self.webhooks.is_empty() || !self.notifiers.is_empty() || self.watchlist {
+            self.outbox = Some(vec![]);
+        }
+    }
+
+    /// Drops the collected changes, e.g. after a failed run that will log them again.
+    pub fn discard_notifications(&mut self) {
+        if let Some(outbox) = &mut self.outbox {
+            outbox.clear();
+        }
+    }
+
+    /// Posts the collected changes to webhooks, notifiers and watchlists, and payloads that
+    /// failed before to webhooks and notifiers. Errors are only logged, since the changes are
+    /// logged already and failed payloads are kept in `deliveries`.
+    pub async fn notify(&mut self) {
+        let changes = match &mut self.outbox {
+            Some(outbox) => std::mem::take(outbox),
+            None => return,
+        };
+        let changes = self.notify_window.release(changes, Instant::now());
+        if !self.webhooks.is_empty() {
+            let errors = Webhooks::dispatch(self, &self.webhooks, &changes).await;
+            for error in errors {
+                self.log(error);
+            }
+        }
+        #[cfg(feature = "notifiers")]
+        if !self.notifiers.is_empty() {
+            let errors = Notifiers::new(self, &self.notifiers)
+                .dispatch(&changes)
+                .await;
+            for error in errors {
+                self.log(error);
+            }
+        }
+        if self.watchlist && !changes.is_empty() {
+            match Watchlist::new(self).dispatch(&changes).await {
+                Ok(errors) => errors.into_iter().for_each(|error| self.log(error)),
+                Err(e) => self.log(format!("Watchlist: {e}")),
+            }
+        }
+    }
+
+    /// Keeps checkpoints and the retry queue in memory from now on, and stops recording runs, so
+    /// a second pipeline can run next to the bot without moving its checkpoints. Checkpoints not
+    /// set yet are read from `meta`, so it starts where the bot is.
+    pub fn detach(&mut self) {
+        self.detached_meta = Some(Mutex::new(HashMap::new()));
+        self.failed_items = Some(vec![]);
+    }
+
+    /// Lets runs end early once `deadline` has passed, between steps, leaving the rest to the
+    /// next run.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Whether the deadline has passed, so `next` is left for the next run.
+    fn past_deadline(&self, next: &str) -> bool {
+        let past = self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+        if past {
+            self.log(format!("DEADLINE: {next} left for the next run"));
         }
+        past
     }
 
+    /// Sets where changes are written, overriding the config.
+    pub fn set_sink(&mut self, sink: SinkType) {
+        self.sink = sink;
+    }
+
+    /// Logs to stdout, or to stderr if stdout carries the change stream.
     fn log(&self, msg: String) {
-        if self.logging {
-            println!("{}", msg);
+        match (self.logging, self.sink) {
+            (false, _) => {}
+            (true, SinkType::Ndjson) => eprintln!("{}", msg),
+            (true, _) => println!("{}", msg),
         }
     }
 
     pub async fn get_recent_changes(&self) -> Result<RecentChangesResults> {
         let oldest = self.get_key_value("timestamp").await?.unwrap_or_default();
-        let results = self.get_next_recent_changes_batch(&oldest).await?;
-        let rc = RecentChangesResults::new(&results);
+        let mut results = match self.change_source {
+            ChangeSource::Replica => self.get_next_recent_changes_batch(&oldest).await?,
+            ChangeSource::EventStreams => {
+                EventStream::new(self.wd.clone(), self.wiki)
+                    .get_recent_changes(
+                        &oldest,
+                        self.batch_size.limit(),
+                        &self.namespaces,
+                        &self.drops,
+                    )
+                    .await?
+            }
+        };
+        if self.shadow {
+            // Stop at the first change the predecessor tool has not logged yet
+            let last = ShadowReport::legacy_last_timestamp(self)
+                .await?
+                .unwrap_or_default();
+            if let Some(pos) = results.iter().position(|rc| rc.rc_timestamp > last) {
+                results.truncate(pos);
+            }
+        }
+        let rc = self.recent_changes_results(&results);
         self.log(format!(
             "New: {}, changed:{}",
             rc.new_items().len(),
@@ -73,24 +574,58 @@ impl WdRc {
         Ok(rc)
     }
 
-    async fn get_next_recent_changes_batch(&self, oldest: &String) -> Result<Vec<RecentChanges>> {
+    /// Splits a batch into new and changed items, counting the rows ignored.
+    fn recent_changes_results(&self, results: &[RecentChanges]) -> RecentChangesResults {
+        let rc = RecentChangesResults::new(results, &self.batch_options);
+        for (reason, count) in rc.ignored() {
+            self.drops.add(*reason, *count);
+        }
+        rc
+    }
+
+    /// Reads the next batch from the replica, after the stored `rc_id` if checkpointing by `rc_id`.
+    /// Without a stored `rc_id` yet, the batch is read by timestamp.
+    async fn get_next_recent_changes_batch(&self, oldest: &str) -> Result<Vec<RecentChanges>> {
+        let last_rc_id = match self.checkpoint {
+            Checkpoint::RcId => self.get_key_value("rc_id").await?,
+            Checkpoint::Timestamp => None,
+        };
+        if let Some(last_rc_id) = last_rc_id.and_then(|id| id.parse::<u64>().ok()) {
+            return self.get_recent_changes_after_rc_id(last_rc_id).await;
+        }
         let upper_limit = TimeStamp::from_str(oldest)
-            .map(|dt| dt + Duration::from_secs(60 * 60))
+            .map(|dt| dt + self.batch_size.window())
             .map(|dt| TimeStamp::datetime(&dt))
             .unwrap_or("99991231235900".to_string());
-        let sql = "SELECT * FROM `recentchanges` WHERE `rc_namespace`=0 AND `rc_timestamp`>=? AND rc_timestamp<=? ORDER BY `rc_timestamp`,`rc_title`,`rc_id` LIMIT ?";
+        self.get_recent_changes_between(oldest, &upper_limit).await
+    }
+
+    /// Reads up to a batch of replica changes between two timestamps, both inclusive.
+    async fn get_recent_changes_between(&self, from: &str, to: &str) -> Result<Vec<RecentChanges>> {
+        let namespaces: Vec<String> = self.namespaces.iter().map(|ns| ns.to_string()).collect();
+        let sql = format!("{RECENT_CHANGES_SELECT} WHERE `rc_namespace` IN ({}) AND `rc_timestamp`>=? AND rc_timestamp<=? ORDER BY `rc_timestamp`,`rc_title`,`rc_id` LIMIT ?",namespaces.join(","));
         let mut conn = self.db.get_connection("wikidata").await?;
-        let results: Vec<RecentChanges> = conn
-            .exec_iter(sql, (oldest, &upper_limit, &self.max_recent_changes))
+        let rows = conn
+            .exec_iter(sql, (from, to, self.batch_size.limit()))
             .await?
             .map_and_drop(RecentChanges::from_row)
+            .await?;
+        Ok(self.keep_parsed(rows, DropReason::BadRecentChange))
+    }
+
+    async fn get_recent_changes_after_rc_id(&self, last_rc_id: u64) -> Result<Vec<RecentChanges>> {
+        let namespaces: Vec<String> = self.namespaces.iter().map(|ns| ns.to_string()).collect();
+        let sql = format!("{RECENT_CHANGES_SELECT} WHERE `rc_namespace` IN ({}) AND `rc_id`>? ORDER BY `rc_id` LIMIT ?",namespaces.join(","));
+        let mut conn = self.db.get_connection("wikidata").await?;
+        let rows = conn
+            .exec_iter(sql, (last_rc_id, self.batch_size.limit()))
             .await?
-            .into_iter()
-            .flatten()
-            .collect();
-        Ok(results)
+            .map_and_drop(RecentChanges::from_row)
+            .await?;
+        Ok(self.keep_parsed(rows, DropReason::BadRecentChange))
     }
 
+    /// Converts an entity ID like `Q42` into its numeric part.
     pub fn make_id_numeric(id: &str) -> Result<ItemId> {
         let q = &id[1..];
         let q = q.parse::<ItemId>()?;
@@ -101,115 +636,461 @@ impl WdRc {
     }
 
     pub async fn log_new_items(&self, rc: &RecentChangesResults) -> Result<()> {
-        if rc.new_items().is_empty() {
-            return Ok(());
+        for entity_type in EntityType::all() {
+            self.log_new_entities(rc, entity_type).await?;
         }
-        let mut updates = vec![];
-        let mut delete_from_deleted = vec![];
-        for new_item in rc.new_items() {
-            let q = Self::make_id_numeric(new_item.q())?;
-            delete_from_deleted.push(format!("{q}"));
-            updates.push(format!("({q},'{}')", new_item.timestamp()));
-        }
-        let updates = updates.join(",");
-        let delete_from_deleted = delete_from_deleted.join(",");
+        Ok(())
+    }
 
-        // Write changes to DB
-        let mut conn = self.db.get_connection("wdrc").await?;
+    async fn log_new_entities(
+        &self,
+        rc: &RecentChangesResults,
+        entity_type: EntityType,
+    ) -> Result<()> {
+        let new_items: Vec<_> = rc
+            .new_items()
+            .iter()
+            .filter(|new_item| EntityType::from_id(new_item.q()) == Some(entity_type))
+            .collect();
+        let mut creations = vec![];
+        for new_item in new_items {
+            creations.push(Creation {
+                q: Self::make_id_numeric(new_item.q())?,
+                timestamp: new_item.timestamp().to_string(),
+            });
+        }
+        self.sink
+            .log_creations(self, entity_type, &creations)
+            .await?;
 
-        let sql = format!("REPLACE INTO `creations` (`q`,`timestamp`) VALUES {updates}");
-        conn.exec_drop(&sql, ()).await?;
+        Ok(())
+    }
 
-        let sql = format!("DELETE FROM `deletions` WHERE `q` IN  ({delete_from_deleted})");
-        conn.exec_drop(&sql, ()).await?;
+    pub async fn log_recent_changes(&mut self, rc: &RecentChangesResults) -> Result<()> {
+        if self.work_queue {
+            return self.enqueue_recent_changes(rc).await;
+        }
+        let retries = self.get_failed_items().await?;
+        if rc.changed_items().is_empty() && rc.creations().is_empty() && retries.is_empty() {
+            if let Some(new_oldest) = rc.last_timestamp() {
+                let _ = self.set_key_value("timestamp", new_oldest).await; // Only bot edits
+            }
+            return Ok(());
+        }
+        let items: Vec<ChangedItem> = rc
+            .changed_items()
+            .iter()
+            .chain(rc.creations().iter())
+            .chain(retries.iter().map(|failed| &failed.item))
+            .cloned()
+            .collect();
+        self.compare_and_log(&items).await?;
+        if !rc.changed_items().is_empty() {
+            let new_oldest = rc.get_last_rc_timetamp("20000101000000");
+            let _ = self.set_key_value("timestamp", &new_oldest).await;
+        }
+        Ok(())
+    }
 
+    /// Adds the changed and new items of a batch to `work_queue`, and advances the timestamp
+    /// checkpoint past them.
+    async fn enqueue_recent_changes(&self, rc: &RecentChangesResults) -> Result<()> {
+        let params: Vec<Vec<SqlValue>> = rc
+            .changed_items()
+            .iter()
+            .chain(rc.creations().iter())
+            .map(|ci| {
+                vec![
+                    ci.q().into(),
+                    ci.rev_old().into(),
+                    ci.rev_new().into(),
+                    ci.timestamp().into(),
+                    ci.user().into(),
+                    ci.comment().into(),
+                    ci.is_bot().into(),
+                    ci.tags().join("|").into(),
+                ]
+            })
+            .collect();
+        if !params.is_empty() {
+            self.log(format!("QUEUED: {}", params.len()));
+            self.db
+                .get_connection("wdrc")
+                .await?
+                .exec_batch(
+                    "INSERT IGNORE INTO `work_queue` (`q`,`rev_old`,`rev_new`,`timestamp`,`user`,`comment`,`is_bot`,`tags`) VALUES (?,?,?,?,?,?,?,?)",
+                    params,
+                )
+                .await?;
+        }
+        if let Some(new_oldest) = rc.last_timestamp() {
+            self.set_key_value("timestamp", new_oldest).await?;
+        }
         Ok(())
     }
 
-    pub async fn log_recent_changes(&mut self, rc: &RecentChangesResults) -> Result<()> {
-        if rc.changed_items().is_empty() {
+    /// Compares and logs up to a batch of queued items, oldest first, along with the retries
+    /// due. Items that fail move from `work_queue` to the retry queue.
+    async fn drain_work_queue(&mut self) -> Result<()> {
+        let sql = "SELECT `id`,`q`,`rev_old`,`rev_new`,`timestamp`,`user`,`comment`,`is_bot`,`tags` FROM `work_queue` ORDER BY `id` LIMIT ?";
+        let queued: Vec<(u64, ChangedItem)> = self
+            .db
+            .get_connection("wdrc")
+            .await?
+            .exec_iter(sql, (self.batch_size.limit(),))
+            .await?
+            .map_and_drop(|row| Some((row.get("id")?, ChangedItem::from_row(&row)?)))
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        let retries = self.get_failed_items().await?;
+        if queued.is_empty() && retries.is_empty() {
             return Ok(());
         }
-        let mut rcs = vec![];
-        for _ci in rc.changed_items() {
-            let revision_compare = RevisionCompare::new(self.wd.clone());
-            rcs.push(revision_compare);
+        let items: Vec<ChangedItem> = queued
+            .iter()
+            .map(|(_, ci)| ci.to_owned())
+            .chain(retries.into_iter().map(|failed| failed.item))
+            .collect();
+        self.compare_and_log(&items).await?;
+        if !queued.is_empty() {
+            let ids: Vec<u64> = queued.iter().map(|(id, _)| *id).collect();
+            let sql = format!(
+                "DELETE FROM `work_queue` WHERE `id` IN ({})",
+                vec!["?"; ids.len()].join(",")
+            );
+            self.db
+                .get_connection("wdrc")
+                .await?
+                .exec_drop(sql, ids)
+                .await?;
         }
+        Ok(())
+    }
+
+    /// Compares the items and logs their changes and reverts; items that could not be compared
+    /// go to the retry queue.
+    pub(crate) async fn compare_and_log(&mut self, items: &[ChangedItem]) -> Result<()> {
+        let compared = self.compare(items).await;
+        self.log_compared(compared).await
+    }
+
+    /// Compares the items, without logging anything yet.
+    async fn compare(&self, items: &[ChangedItem]) -> ComparedItems {
+        let (in_scope, mut succeeded, held) = self.split_by_scope(items).await;
+        let items = in_scope.as_deref().unwrap_or(items);
+        let mut rcs: Vec<RevisionCompare> = items.iter().map(|_| self.revision_compare()).collect();
+
+        // The REST API serves latest revisions only, one entity per request, so only the old
+        // revisions are prefetched from the action API for it
+        let mut revids: Vec<RevisionId> = items
+            .iter()
+            .flat_map(|ci| match self.revision_backend {
+                RevisionBackend::Action => [ci.rev_old(), ci.rev_new()],
+                RevisionBackend::Rest => [ci.rev_old(), 0],
+            })
+            .filter(|revid| *revid > 0)
+            .collect();
+        revids.sort();
+        revids.dedup();
+        let revisions = self
+            .revision_compare()
+            .get_revisions(&revids, self.max_api_concurrent)
+            .await;
+        let revisions = &revisions;
 
         let mut futures = vec![];
-        for (ci, revision_compare) in rc.changed_items().iter().zip(rcs.iter_mut()) {
-            let future = revision_compare.run(ci);
+        for (num, (ci, revision_compare)) in items.iter().zip(rcs.iter_mut()).enumerate() {
+            let future = async move { (num, revision_compare.run_prefetched(ci, revisions).await) };
             futures.push(future);
         }
         let stream = futures::stream::iter(futures).buffer_unordered(self.max_api_concurrent);
-        let changes = stream
-            .collect::<Vec<_>>()
-            .await
-            .into_iter()
-            .filter_map(|r| r.ok())
-            .flatten()
-            .collect::<Vec<_>>();
+        let mut changes = vec![];
+        let mut failed = vec![];
+        for (num, result) in stream.collect::<Vec<_>>().await {
+            match result {
+                Ok(mut item_changes) => {
+                    changes.append(&mut item_changes);
+                    succeeded.push(items[num].to_owned());
+                }
+                Err(e) => failed.push((items[num].to_owned(), e.to_string())),
+            }
+        }
         self.log(format!("CHANGES: {}", changes.len()));
+        self.drops
+            .add(DropReason::FailedCompare, failed.len() as u64);
+        ComparedItems {
+            changes,
+            succeeded,
+            failed,
+            held,
+        }
+    }
+
+    /// With a `scope`, refreshes it if due and returns the items in it, those outside it, which
+    /// count as done, and, until the scope query has succeeded once, all items to hold back.
+    async fn split_by_scope(
+        &self,
+        items: &[ChangedItem],
+    ) -> (Option<Vec<ChangedItem>>, Vec<ChangedItem>, Vec<ChangedItem>) {
+        let scope = match &self.scope {
+            Some(scope) => scope,
+            None => return (None, vec![], vec![]),
+        };
+        match scope.refresh(&self.wd).await {
+            Ok(Some(count)) => self.log(format!("SCOPE: {count} entities")),
+            Ok(None) => {}
+            Err(e) => self.log(format!("Scope query failed: {e}")),
+        }
+        let mut in_scope = vec![];
+        let mut outside = vec![];
+        let mut held = vec![];
+        for ci in items {
+            match scope.contains(ci.q()) {
+                Some(true) => in_scope.push(ci.to_owned()),
+                Some(false) => outside.push(ci.to_owned()),
+                None => held.push(ci.to_owned()),
+            }
+        }
+        self.drops.add(DropReason::OutOfScope, outside.len() as u64);
+        (Some(in_scope), outside, held)
+    }
 
+    /// Logs the changes and reverts of compared items, and updates the retry queue.
+    async fn log_compared(&mut self, compared: ComparedItems) -> Result<()> {
+        let ComparedItems {
+            mut changes,
+            succeeded,
+            failed,
+            held,
+        } = compared;
+        let reverts = self.mark_reverts(&mut changes).await?;
+        if let Some(minutes) = self.session_minutes {
+            Sessions::assign(self, &mut changes, minutes).await?;
+        }
         self.log_changes(&changes).await?;
-        let new_oldest = rc.get_last_rc_timetamp("20000101000000");
-        let _ = self.set_key_value("timestamp", &new_oldest).await;
-        Ok(())
+        self.log_reverts(&changes, &reverts).await?;
+        self.update_failed_items(&succeeded, &failed, &held).await
     }
 
-    pub async fn update_recent_redirects(&self) -> Result<()> {
-        let (updates, new_ts) = self.update_recent_redirects_get_updates().await?;
-        if updates.is_empty() {
+    /// Returns the failed items due for another attempt, loading the queue from the database on first use.
+    async fn get_failed_items(&mut self) -> Result<Vec<FailedItem>> {
+        if self.failed_items.is_none() {
+            let sql = "SELECT `q`,`rev_old`,`rev_new`,`timestamp`,`user`,`comment`,`is_bot`,`tags`,`attempts` FROM `failed_items` WHERE `attempts`<?";
+            let failed_items: Vec<FailedItem> = self
+                .db
+                .get_connection("wdrc")
+                .await?
+                .exec_iter(sql, (MAX_COMPARE_ATTEMPTS,))
+                .await?
+                .map_and_drop(FailedItem::from_row)
+                .await?
+                .into_iter()
+                .flatten()
+                .collect();
+            self.failed_items = Some(failed_items);
+        }
+        Ok(self.failed_items.to_owned().unwrap_or_default())
+    }
+
+    /// Held items are queued without counting an attempt.
+    fn update_retry_queue(
+        mut queue: Vec<FailedItem>,
+        succeeded: &[ChangedItem],
+        failed: &[ChangedItem],
+        held: &[ChangedItem],
+    ) -> Vec<FailedItem> {
+        queue.retain(|f| !succeeded.contains(&f.item));
+        for item in held {
+            if !queue.iter().any(|f| f.item == *item) {
+                queue.push(FailedItem {
+                    item: item.to_owned(),
+                    attempts: 0,
+                });
+            }
+        }
+        for item in failed {
+            match queue.iter_mut().find(|f| f.item == *item) {
+                Some(f) => f.attempts += 1,
+                None => queue.push(FailedItem {
+                    item: item.to_owned(),
+                    attempts: 1,
+                }),
+            }
+        }
+        queue.retain(|f| f.attempts < MAX_COMPARE_ATTEMPTS);
+        queue
+    }
+
+    /// Removes items that were compared successfully from the retry queue, and adds or counts up failed ones.
+    /// Items that failed `MAX_COMPARE_ATTEMPTS` times stay in `failed_items`, but are not retried.
+    async fn update_failed_items(
+        &mut self,
+        succeeded: &[ChangedItem],
+        failed: &[(ChangedItem, String)],
+        held: &[ChangedItem],
+    ) -> Result<()> {
+        let queue = self.failed_items.take().unwrap_or_default();
+        let failed_items: Vec<ChangedItem> = failed.iter().map(|(ci, _)| ci.to_owned()).collect();
+        self.failed_items = Some(Self::update_retry_queue(
+            queue,
+            succeeded,
+            &failed_items,
+            held,
+        ));
+        if self.detached_meta.is_some() {
             return Ok(());
         }
-        self.log(format!("REDIRECTS: {} changes", updates.len()));
 
-        let updates = updates.join(",");
-        let sql =
-            format!("REPLACE INTO `redirects` (`source`,`target`,`timestamp`) VALUES {updates}");
-        self.db
-            .get_connection("wdrc")
-            .await?
-            .exec_drop(&sql, ())
+        let mut conn = self.db.get_connection("wdrc").await?;
+        if !succeeded.is_empty() {
+            let params: Vec<(&str, RevisionId)> =
+                succeeded.iter().map(|ci| (ci.q(), ci.rev_new())).collect();
+            conn.exec_batch(
+                "DELETE FROM `failed_items` WHERE `q`=? AND `rev_new`=?",
+                params,
+            )
+            .await?;
+        }
+        if !failed.is_empty() {
+            self.log(format!("FAILED: {}", failed.len()));
+            let params: Vec<Vec<SqlValue>> = failed
+                .iter()
+                .map(|(ci, error)| {
+                    vec![
+                        ci.q().into(),
+                        ci.rev_old().into(),
+                        ci.rev_new().into(),
+                        ci.timestamp().into(),
+                        ci.user().into(),
+                        ci.comment().into(),
+                        ci.is_bot().into(),
+                        ci.tags().join("|").into(),
+                        error.as_str().into(),
+                    ]
+                })
+                .collect();
+            conn.exec_batch(
+                "INSERT INTO `failed_items` (`q`,`rev_old`,`rev_new`,`timestamp`,`user`,`comment`,`is_bot`,`tags`,`error`,`attempts`) VALUES (?,?,?,?,?,?,?,?,?,1)
+                ON DUPLICATE KEY UPDATE `attempts`=`attempts`+1,`error`=VALUES(`error`)",
+                params,
+            )
             .await?;
-        self.set_key_value("timestamp_redirect", &new_ts).await?;
+        }
+        if !held.is_empty() {
+            self.log(format!("HELD: {}", held.len()));
+            let params: Vec<Vec<SqlValue>> = held
+                .iter()
+                .map(|ci| {
+                    vec![
+                        ci.q().into(),
+                        ci.rev_old().into(),
+                        ci.rev_new().into(),
+                        ci.timestamp().into(),
+                        ci.user().into(),
+                        ci.comment().into(),
+                        ci.is_bot().into(),
+                        ci.tags().join("|").into(),
+                    ]
+                })
+                .collect();
+            conn.exec_batch(
+                "INSERT IGNORE INTO `failed_items` (`q`,`rev_old`,`rev_new`,`timestamp`,`user`,`comment`,`is_bot`,`tags`,`error`,`attempts`) VALUES (?,?,?,?,?,?,?,?,'Scope not queried yet',0)",
+                params,
+            )
+            .await?;
+        }
         Ok(())
     }
 
-    async fn update_recent_redirects_get_updates(&self) -> Result<(Vec<String>, String)> {
+    /// The entity types of the tracked namespaces.
+    fn tracked_entity_types(&self) -> Vec<EntityType> {
+        self.namespaces
+            .iter()
+            .filter_map(|namespace| EntityType::from_namespace(*namespace))
+            .collect()
+    }
+
+    pub async fn update_recent_redirects(&self) -> Result<()> {
+        if !self.replica_schema.is_table_usable("redirect") {
+            return Ok(());
+        }
         let oldest = self
             .get_key_value("timestamp_redirect")
             .await?
             .unwrap_or_else(|| "20000101000000".to_string());
-        let results = self.get_recent_redirects(&oldest).await?;
+        let mut new_ts = oldest.clone();
+        for entity_type in self.tracked_entity_types() {
+            let (updates, ts) = self
+                .update_recent_redirects_get_updates(entity_type, &oldest)
+                .await?;
+            new_ts = new_ts.max(ts);
+            if updates.is_empty() {
+                continue;
+            }
+            self.log(format!(
+                "REDIRECTS: {} {} changes",
+                updates.len(),
+                entity_type.as_str()
+            ));
+            self.sink.log_redirects(self, entity_type, &updates).await?;
+        }
+        if new_ts != oldest {
+            self.set_key_value("timestamp_redirect", &new_ts).await?;
+        }
+        Ok(())
+    }
+
+    async fn update_recent_redirects_get_updates(
+        &self,
+        entity_type: EntityType,
+        oldest: &str,
+    ) -> Result<(Vec<Redirect>, String)> {
+        let results = self.get_recent_redirects(entity_type, oldest).await?;
         let mut updates = vec![];
-        let mut new_ts = oldest;
+        let mut new_ts = oldest.to_string();
         for result in &results {
-            let source = match Self::make_id_numeric(result.source()) {
-                Ok(q) => q,
-                Err(_) => continue,
+            let source = match self.keep(
+                Self::make_id_numeric(result.source()),
+                DropReason::BadEntityId,
+            ) {
+                Some(q) => q,
+                None => continue,
             };
-            let target = match Self::make_id_numeric(result.target()) {
-                Ok(q) => q,
-                Err(_) => continue,
+            let target = match self.keep(
+                Self::make_id_numeric(result.target()),
+                DropReason::BadEntityId,
+            ) {
+                Some(q) => q,
+                None => continue,
             };
             let ts = result.timestamp().to_string();
             if new_ts < ts {
                 new_ts = ts;
             }
-            updates.push(format!("({source},{target},'{}')", result.timestamp()));
+            updates.push(Redirect {
+                source,
+                target,
+                timestamp: result.timestamp().to_string(),
+            });
         }
         Ok((updates, new_ts))
     }
 
-    async fn get_recent_redirects(&self, oldest: &String) -> Result<Vec<RecentRedirects>> {
+    async fn get_recent_redirects(
+        &self,
+        entity_type: EntityType,
+        oldest: &str,
+    ) -> Result<Vec<RecentRedirects>> {
         let sql = "SELECT `rc_title` AS `source`,`rd_title` AS `target`,max(`rc_timestamp`) AS `timestamp` FROM `recentchanges`,`redirect`
-			WHERE `rc_namespace`=0 AND `rd_from`=`rc_cur_id` AND `rd_namespace`=0 AND `rc_timestamp`>=? GROUP BY `source`,`target`";
+			WHERE `rc_namespace`=? AND `rd_from`=`rc_cur_id` AND `rd_namespace`=? AND `rc_timestamp`>=? GROUP BY `source`,`target`";
+        let namespace = entity_type.namespace();
         let results: Vec<RecentRedirects> = self
             .db
             .get_connection("wikidata")
             .await?
-            .exec_iter(sql, (oldest,))
+            .exec_iter(sql, (namespace, namespace, oldest))
             .await?
             .map_and_drop(RecentRedirects::from_row)
             .await?
@@ -220,52 +1101,255 @@ impl WdRc {
     }
 
     pub async fn update_recent_deletions(&self) -> Result<()> {
-        let (updates, new_ts) = self.update_recent_deletions_get_updates().await?;
-        if updates.is_empty() {
+        if !self.replica_schema.is_table_usable("logging") {
             return Ok(());
         }
-        self.log(format!("DELETIONS: {} changes", updates.len()));
-
-        let updates = updates.join(",");
-        let sql = format!("REPLACE INTO `deletions` (`q`,`timestamp`) VALUES {updates}");
-        self.db
-            .get_connection("wdrc")
-            .await?
-            .exec_drop(&sql, ())
-            .await?;
-        self.set_key_value("timestamp_deletion", &new_ts).await?;
-        Ok(())
-    }
-
-    async fn update_recent_deletions_get_updates(&self) -> Result<(Vec<String>, String)> {
         let oldest = self
             .get_key_value("timestamp_deletion")
             .await?
             .unwrap_or_else(|| "20000101000000".to_string());
-        let results = self.get_recent_deletions(&oldest).await?;
+        let mut new_ts = oldest.clone();
+        for entity_type in self.tracked_entity_types() {
+            let (updates, ts) = self
+                .update_recent_deletions_get_updates(entity_type, &oldest)
+                .await?;
+            new_ts = new_ts.max(ts);
+            if updates.is_empty() {
+                continue;
+            }
+            self.log(format!(
+                "DELETIONS: {} {} changes",
+                updates.len(),
+                entity_type.as_str()
+            ));
+            self.sink.log_deletions(self, entity_type, &updates).await?;
+        }
+        if new_ts != oldest {
+            self.set_key_value("timestamp_deletion", &new_ts).await?;
+        }
+        Ok(())
+    }
+
+    async fn update_recent_deletions_get_updates(
+        &self,
+        entity_type: EntityType,
+        oldest: &str,
+    ) -> Result<(Vec<Deletion>, String)> {
+        let results = self.get_recent_deletions(entity_type, oldest).await?;
         let mut updates = vec![];
-        let mut new_ts = oldest;
+        let mut new_ts = oldest.to_string();
         for result in &results {
-            let q = match Self::make_id_numeric(result.q()) {
-                Ok(q) => q,
-                Err(_) => continue,
+            let q = match self.keep(Self::make_id_numeric(result.q()), DropReason::BadEntityId) {
+                Some(q) => q,
+                None => continue,
             };
             let ts = result.timestamp().to_string();
             if new_ts < ts {
                 new_ts = ts;
             }
-            updates.push(format!("({q},'{}')", result.timestamp()));
+            updates.push(Deletion {
+                q,
+                timestamp: result.timestamp().to_string(),
+            });
         }
         Ok((updates, new_ts))
     }
 
-    async fn get_recent_deletions(&self, oldest: &String) -> Result<Vec<RecentDeletions>> {
-        let sql = "SELECT `log_title` AS `q`,`log_timestamp` AS `timestamp` FROM `logging` WHERE `log_type`='delete' AND `log_action`='delete' AND `log_timestamp`>=? AND `log_namespace`=0";
+    /// Logs protections, moves, and merges of entities in the tracked namespaces, with
+    /// protections also in `protections`; starts from the recent changes checkpoint on first run.
+    pub async fn update_recent_log_events(&self) -> Result<()> {
+        if !self.replica_schema.is_table_usable("logging") {
+            return Ok(());
+        }
+        let oldest = match self.get_key_value("timestamp_log_event").await? {
+            Some(ts) => ts,
+            None => self.get_key_value("timestamp").await?.unwrap_or_default(),
+        };
+        let mut new_ts = oldest.clone();
+        for entity_type in self.tracked_entity_types() {
+            let ts = self
+                .update_recent_log_events_of_type(entity_type, &oldest)
+                .await?;
+            new_ts = new_ts.max(ts);
+        }
+        if new_ts != oldest {
+            self.set_key_value("timestamp_log_event", &new_ts).await?;
+        }
+        Ok(())
+    }
+
+    /// Logs the log events of one entity type since `oldest`, and returns the latest timestamp.
+    async fn update_recent_log_events_of_type(
+        &self,
+        entity_type: EntityType,
+        oldest: &str,
+    ) -> Result<String> {
+        let sql = "SELECT `log_title` AS `q`,`log_type`,`log_action` AS `action`,`log_timestamp` AS `timestamp` FROM `logging` WHERE `log_type` IN ('protect','move','merge') AND `log_timestamp`>=? AND `log_namespace`=?";
+        let results: Vec<RecentLogEvents> = self
+            .db
+            .get_connection("wikidata")
+            .await?
+            .exec_iter(sql, (oldest, entity_type.namespace()))
+            .await?
+            .map_and_drop(RecentLogEvents::from_row)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        let mut updates = vec![];
+        let mut protections = vec![];
+        let mut new_ts = oldest.to_string();
+        for result in &results {
+            let q = match self.keep(Self::make_id_numeric(result.q()), DropReason::BadEntityId) {
+                Some(q) => q,
+                None => continue,
+            };
+            if new_ts.as_str() < result.timestamp() {
+                new_ts = result.timestamp().to_string();
+            }
+            if result.log_type() == "protect" {
+                protections.push(vec![
+                    q.into(),
+                    result.action().into(),
+                    result.timestamp().into(),
+                ]);
+            }
+            updates.push(vec![
+                q.into(),
+                result.log_type().into(),
+                result.action().into(),
+                result.timestamp().into(),
+            ]);
+        }
+        if updates.is_empty() {
+            return Ok(new_ts);
+        }
+        self.log(format!(
+            "LOG EVENTS: {} {} changes",
+            updates.len(),
+            entity_type.as_str()
+        ));
+
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`q`,`type`,`action`,`timestamp`) VALUES",
+            entity_type.table("log_events")
+        );
+        self.insert_rows(&sql, &updates).await?;
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`q`,`action`,`timestamp`) VALUES",
+            entity_type.table("protections")
+        );
+        self.insert_rows(&sql, &protections).await?;
+        Ok(new_ts)
+    }
+
+    /// Logs merges: edits summarized with the merge action of their entity type, on entities now
+    /// redirecting to the merge target.
+    pub async fn update_recent_merges(&self) -> Result<()> {
+        if !["redirect", "comment"]
+            .iter()
+            .all(|table| self.replica_schema.is_table_usable(table))
+        {
+            return Ok(());
+        }
+        let oldest = match self.get_key_value("timestamp_merge").await? {
+            Some(ts) => ts,
+            None => self.get_key_value("timestamp").await?.unwrap_or_default(),
+        };
+        let mut new_ts = oldest.clone();
+        for entity_type in self.tracked_entity_types() {
+            let ts = self
+                .update_recent_merges_of_type(entity_type, &oldest)
+                .await?;
+            new_ts = new_ts.max(ts);
+        }
+        if new_ts != oldest {
+            self.set_key_value("timestamp_merge", &new_ts).await?;
+        }
+        Ok(())
+    }
+
+    /// Logs the merges of one entity type since `oldest`, and returns the latest timestamp.
+    async fn update_recent_merges_of_type(
+        &self,
+        entity_type: EntityType,
+        oldest: &str,
+    ) -> Result<String> {
+        let action = match entity_type.merge_action() {
+            Some(action) => action,
+            None => return Ok(oldest.to_string()),
+        };
+        let sql = "SELECT `rc_title` AS `source`,`rd_title` AS `target`,`rc_this_oldid` AS `revision`,`comment_text` AS `comment`,`rc_timestamp` AS `timestamp` FROM `recentchanges` JOIN `redirect` ON `rd_from`=`rc_cur_id` JOIN `comment` ON `comment_id`=`rc_comment_id`
+			WHERE `rc_namespace`=? AND `rd_namespace`=? AND `rc_timestamp`>=? AND `comment_text` LIKE ?";
+        let namespace = entity_type.namespace();
+        let pattern = format!("/* {action}:%");
+        let results: Vec<RecentMerges> = self
+            .db
+            .get_connection("wikidata")
+            .await?
+            .exec_iter(sql, (namespace, namespace, oldest, pattern))
+            .await?
+            .map_and_drop(RecentMerges::from_row)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        let mut updates = vec![];
+        let mut new_ts = oldest.to_string();
+        for result in &results {
+            let target = match result.target(action) {
+                Some(target) => target,
+                None => continue,
+            };
+            let source = match self.keep(
+                Self::make_id_numeric(result.source()),
+                DropReason::BadEntityId,
+            ) {
+                Some(q) => q,
+                None => continue,
+            };
+            let target = match self.keep(Self::make_id_numeric(target), DropReason::BadEntityId) {
+                Some(q) => q,
+                None => continue,
+            };
+            if new_ts.as_str() < result.timestamp() {
+                new_ts = result.timestamp().to_string();
+            }
+            updates.push(vec![
+                source.into(),
+                target.into(),
+                result.timestamp().into(),
+                result.revision().into(),
+            ]);
+        }
+        if updates.is_empty() {
+            return Ok(new_ts);
+        }
+        self.log(format!(
+            "MERGES: {} {} changes",
+            updates.len(),
+            entity_type.as_str()
+        ));
+
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`source`,`target`,`timestamp`,`revision`) VALUES",
+            entity_type.table("merges")
+        );
+        self.insert_rows(&sql, &updates).await?;
+        Ok(new_ts)
+    }
+
+    async fn get_recent_deletions(
+        &self,
+        entity_type: EntityType,
+        oldest: &str,
+    ) -> Result<Vec<RecentDeletions>> {
+        let sql = "SELECT `log_title` AS `q`,`log_timestamp` AS `timestamp` FROM `logging` WHERE `log_type`='delete' AND `log_action`='delete' AND `log_timestamp`>=? AND `log_namespace`=?";
         let results: Vec<RecentDeletions> = self
             .db
             .get_connection("wikidata")
             .await?
-            .exec_iter(sql, (oldest,))
+            .exec_iter(sql, (oldest, entity_type.namespace()))
             .await?
             .map_and_drop(RecentDeletions::from_row)
             .await?
@@ -275,91 +1359,627 @@ impl WdRc {
         Ok(results)
     }
 
-    async fn log_statement_changes(&self, changes: &[Change]) -> Result<()> {
-        let values = changes
+    async fn log_statement_changes(
+        &mut self,
+        entity_type: EntityType,
+        changes: &[Change],
+    ) -> Result<()> {
+        let mut values = vec![];
+        for c in changes
             .iter()
             .filter(|c| c.subject == ChangeSubject::Claims)
-            .filter_map(|c| c.get_statement_log().ok())
-            .collect::<Vec<String>>();
-        if !values.is_empty() {
-            let sql = format!("INSERT IGNORE INTO `statements` (`item`,`revision`,`property`,`timestamp`,`change_type`) VALUES {}",values.join(",")) ;
-            self.db
-                .get_connection("wdrc")
-                .await?
-                .exec_drop(&sql, ())
-                .await?;
+        {
+            let mut row = match self.keep(c.get_statement_log(), DropReason::BadChange) {
+                Some(row) => row,
+                None => continue,
+            };
+            row.push(self.detail(c));
+            row.push(self.summary(c).await);
+            row.push(c.session.into());
+            values.push(row);
         }
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`property`,`timestamp`,`change_type`,`sitelinks`,`is_bot`,`user`,`engine`,`detail`,`summary`,`session`) VALUES",
+            entity_type.table("statements")
+        );
+        self.insert_rows(&sql, &values).await?;
+        Ok(())
+    }
+
+    async fn log_qualifier_changes(
+        &self,
+        entity_type: EntityType,
+        changes: &[Change],
+    ) -> Result<()> {
+        let values = changes
+            .iter()
+            .filter(|c| c.subject == ChangeSubject::Qualifiers)
+            .filter_map(|c| self.keep(c.get_qualifier_log(), DropReason::BadChange))
+            .collect::<Vec<Vec<SqlValue>>>();
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`property`,`qualifier`,`timestamp`,`change_type`,`sitelinks`,`is_bot`,`engine`) VALUES",
+            entity_type.table("qualifiers")
+        );
+        self.insert_rows(&sql, &values).await?;
+        Ok(())
+    }
+
+    async fn log_reference_changes(
+        &self,
+        entity_type: EntityType,
+        changes: &[Change],
+    ) -> Result<()> {
+        let values = changes
+            .iter()
+            .filter(|c| c.subject == ChangeSubject::References)
+            .filter_map(|c| self.keep(c.get_reference_log(), DropReason::BadChange))
+            .collect::<Vec<Vec<SqlValue>>>();
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`property`,`hash`,`timestamp`,`change_type`,`sitelinks`,`is_bot`,`engine`) VALUES",
+            entity_type.table("references")
+        );
+        self.insert_rows(&sql, &values).await?;
         Ok(())
     }
 
-    async fn log_sitelinks_changes(&mut self, changes: &[Change]) -> Result<()> {
+    async fn log_sitelinks_changes(
+        &mut self,
+        entity_type: EntityType,
+        changes: &[Change],
+    ) -> Result<()> {
         let changes: Vec<&Change> = changes
             .iter()
             .filter(|c| c.subject == ChangeSubject::Sitelinks)
             .collect();
         let mut parts = vec![];
         for ci in changes {
-            let text_id = match self.get_or_create_text_id(&ci.site).await {
-                Ok(text_id) => text_id,
-                Err(_) => continue,
+            let text_id = self.get_or_create_text_id(&ci.site).await;
+            let text_id = match self.keep(text_id, DropReason::TextIdError) {
+                Some(text_id) => text_id,
+                None => continue,
             };
-            let part = ci.get_label_log(text_id);
+            let mut part = ci.get_label_log(text_id);
+            part.push(self.detail(ci));
+            part.push(self.summary(ci).await);
+            part.push(ci.session.into());
             parts.push(part);
         }
-        if !parts.is_empty() {
-            let sql = format!(
-				"INSERT IGNORE INTO `labels` (`item`,`revision`,`type`,`timestamp`,`change_type`,`language`) VALUES {}",
-				parts.join(",")
-			);
-            self.db
-                .get_connection("wdrc")
-                .await?
-                .exec_drop(&sql, ())
-                .await?;
-        }
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`type`,`timestamp`,`change_type`,`language`,`sitelinks`,`is_bot`,`user`,`engine`,`detail`,`summary`,`session`) VALUES",
+            entity_type.table("labels")
+        );
+        self.insert_rows(&sql, &parts).await?;
         Ok(())
     }
 
-    async fn log_label_changes(&mut self, changes: &[Change]) -> Result<()> {
+    async fn log_label_changes(
+        &mut self,
+        entity_type: EntityType,
+        changes: &[Change],
+    ) -> Result<()> {
         let changes: Vec<&Change> = changes
             .iter()
             .filter(|c| {
                 c.subject == ChangeSubject::Labels
                     || c.subject == ChangeSubject::Descriptions
                     || c.subject == ChangeSubject::Aliases
+                    || c.subject == ChangeSubject::Lemmas
             })
             .collect();
         let mut parts = vec![];
         for ci in changes {
-            let text_id = match self.get_or_create_text_id(&ci.language).await {
-                Ok(text_id) => text_id,
-                Err(_) => continue,
+            let text_id = self.get_or_create_text_id(&ci.language).await;
+            let text_id = match self.keep(text_id, DropReason::TextIdError) {
+                Some(text_id) => text_id,
+                None => continue,
+            };
+            let mut part = ci.get_label_log(text_id);
+            part.push(self.detail(ci));
+            part.push(self.summary(ci).await);
+            part.push(ci.session.into());
+            parts.push(part);
+        }
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`type`,`timestamp`,`change_type`,`language`,`sitelinks`,`is_bot`,`user`,`engine`,`detail`,`summary`,`session`) VALUES",
+            entity_type.table("labels")
+        );
+        self.insert_rows(&sql, &parts).await?;
+        Ok(())
+    }
+
+    async fn log_badge_changes(
+        &mut self,
+        entity_type: EntityType,
+        changes: &[Change],
+    ) -> Result<()> {
+        let changes: Vec<&Change> = changes
+            .iter()
+            .filter(|c| c.subject == ChangeSubject::Badges)
+            .collect();
+        let mut parts = vec![];
+        for ci in changes {
+            let text_id = self.get_or_create_text_id(&ci.site).await;
+            let text_id = match self.keep(text_id, DropReason::TextIdError) {
+                Some(text_id) => text_id,
+                None => continue,
+            };
+            if let Some(part) = self.keep(ci.get_badge_log(text_id), DropReason::BadChange) {
+                parts.push(part);
+            }
+        }
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`site`,`badge`,`timestamp`,`change_type`,`sitelinks`,`is_bot`,`engine`) VALUES",
+            entity_type.table("badges")
+        );
+        self.insert_rows(&sql, &parts).await?;
+        Ok(())
+    }
+
+    /// Logs property datatype changes; like sites for sitelinks, the datatype goes into the `language` text column.
+    async fn log_datatype_changes(
+        &mut self,
+        entity_type: EntityType,
+        changes: &[Change],
+    ) -> Result<()> {
+        let changes: Vec<&Change> = changes
+            .iter()
+            .filter(|c| c.subject == ChangeSubject::Datatype)
+            .collect();
+        let mut parts = vec![];
+        for ci in changes {
+            let text_id = self.get_or_create_text_id(&ci.text).await;
+            let text_id = match self.keep(text_id, DropReason::TextIdError) {
+                Some(text_id) => text_id,
+                None => continue,
             };
-            let part = ci.get_label_log(text_id);
+            let mut part = ci.get_label_log(text_id);
+            part.push(self.detail(ci));
+            part.push(self.summary(ci).await);
+            part.push(ci.session.into());
             parts.push(part);
         }
-        if !parts.is_empty() {
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`type`,`timestamp`,`change_type`,`language`,`sitelinks`,`is_bot`,`user`,`engine`,`detail`,`summary`,`session`) VALUES",
+            entity_type.table("labels")
+        );
+        self.insert_rows(&sql, &parts).await?;
+        Ok(())
+    }
+
+    async fn log_subentity_changes(
+        &self,
+        entity_type: EntityType,
+        changes: &[Change],
+    ) -> Result<()> {
+        let values = changes
+            .iter()
+            .filter(|c| c.subject == ChangeSubject::Forms || c.subject == ChangeSubject::Senses)
+            .filter_map(|c| self.keep(c.get_subentity_log(), DropReason::BadChange))
+            .collect::<Vec<Vec<SqlValue>>>();
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`type`,`subentity`,`timestamp`,`change_type`,`sitelinks`,`is_bot`,`engine`) VALUES",
+            entity_type.table("subentities")
+        );
+        self.insert_rows(&sql, &values).await?;
+        Ok(())
+    }
+
+    /// Returns the value of `result`, or counts the record as dropped.
+    fn keep<T>(&self, result: Result<T>, reason: DropReason) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.drops.add(reason, 1);
+                None
+            }
+        }
+    }
+
+    /// Returns the rows that could be parsed, counting the others as dropped.
+    fn keep_parsed<T>(&self, rows: Vec<Option<T>>, reason: DropReason) -> Vec<T> {
+        let total = rows.len();
+        let ret: Vec<T> = rows.into_iter().flatten().collect();
+        self.drops.add(reason, (total - ret.len()) as u64);
+        ret
+    }
+
+    /// Marks changes that undo an earlier change in the batch, or a logged one if values are stored.
+    async fn mark_reverts(&self, changes: &mut [Change]) -> Result<Vec<Revert>> {
+        let mut previous = vec![];
+        if self.store_values {
+            let since = (Utc::now() - chrono::Duration::days(REVERT_WINDOW_DAYS))
+                .format("%Y%m%d%H%M%S")
+                .to_string();
+            for entity_type in EntityType::all() {
+                let mut items: Vec<ItemId> = changes
+                    .iter()
+                    .filter(|c| c.entity_type == entity_type)
+                    .map(|c| c.item_id)
+                    .collect();
+                items.sort();
+                items.dedup();
+                if items.is_empty() {
+                    continue;
+                }
+                let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+                let sql = format!(
+                    "SELECT `item`,`revision`,`type`,`key`,`old_value`,`new_value` FROM `{}` WHERE `item` IN ({}) AND `timestamp`>=? AND `truncated`=0",
+                    entity_type.table("change_values"),
+                    items.join(",")
+                );
+                let mut rows = self
+                    .db
+                    .get_connection("wdrc")
+                    .await?
+                    .exec_iter(sql, (&since,))
+                    .await?
+                    .map_and_drop(PreviousValue::from_row)
+                    .await?
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                previous.append(&mut rows);
+            }
+        }
+        Ok(reverts::mark_reverts(&previous, changes))
+    }
+
+    async fn log_reverts(&self, changes: &[Change], reverts: &[Revert]) -> Result<()> {
+        for entity_type in EntityType::all() {
+            let rows: Vec<Vec<SqlValue>> = reverts
+                .iter()
+                .map(|revert| (&changes[revert.change], revert.reverted_revision))
+                .filter(|(change, _)| change.entity_type == entity_type)
+                .map(|(change, reverted_revision)| {
+                    vec![
+                        change.item_id.into(),
+                        change.revision_id.into(),
+                        change.subject.as_str().into(),
+                        change.value_key().into(),
+                        reverted_revision.into(),
+                        change.timestamp.as_str().into(),
+                    ]
+                })
+                .collect();
             let sql = format!(
-				"INSERT IGNORE INTO `labels` (`item`,`revision`,`type`,`timestamp`,`change_type`,`language`) VALUES {}",
-				parts.join(",")
-			);
-            self.db
-                .get_connection("wdrc")
-                .await?
-                .exec_drop(&sql, ())
-                .await?;
+                "INSERT IGNORE INTO `{}` (`item`,`revision`,`type`,`key`,`reverted_revision`,`timestamp`) VALUES",
+                entity_type.table("reverts")
+            );
+            self.insert_rows(&sql, &rows).await?;
         }
         Ok(())
     }
 
-    async fn log_changes(&mut self, changes: &[Change]) -> Result<()> {
-        self.log_statement_changes(changes).await?;
-        self.log_sitelinks_changes(changes).await?;
-        self.log_label_changes(changes).await?;
+    /// Logs the change tags of each changed revision.
+    async fn log_tag_changes(&mut self, entity_type: EntityType, changes: &[Change]) -> Result<()> {
+        let mut revisions: Vec<&Change> = changes.iter().filter(|c| !c.tags.is_empty()).collect();
+        revisions.dedup_by_key(|c| (c.item_id, c.revision_id));
+        let mut rows = vec![];
+        for change in revisions {
+            for tag in &change.tags {
+                let text_id = self.get_or_create_text_id(tag).await;
+                let text_id = match self.keep(text_id, DropReason::TextIdError) {
+                    Some(text_id) => text_id,
+                    None => continue,
+                };
+                rows.push(vec![
+                    change.item_id.into(),
+                    change.revision_id.into(),
+                    text_id.into(),
+                    change.timestamp.as_str().into(),
+                ]);
+            }
+        }
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`tag`,`timestamp`) VALUES",
+            entity_type.table("change_tags")
+        );
+        self.insert_rows(&sql, &rows).await
+    }
+
+    /// Logs LiftWing scores of each changed revision, if enabled via `liftwing` in the config.
+    async fn log_revision_scores(&self, entity_type: EntityType, changes: &[Change]) -> Result<()> {
+        let config = match &self.liftwing {
+            Some(config) => config.to_owned(),
+            None => return Ok(()),
+        };
+        let mut revisions: Vec<(RevisionId, ItemId, &str)> = changes
+            .iter()
+            .map(|c| (c.revision_id, c.item_id, c.timestamp.as_str()))
+            .collect();
+        revisions.sort();
+        revisions.dedup();
+        let revision_ids: Vec<RevisionId> = revisions.iter().map(|r| r.0).collect();
+        let scores = LiftWing::new(self.wd.clone(), config)
+            .score(&revision_ids)
+            .await;
+        let rows: Vec<Vec<SqlValue>> = scores
+            .into_iter()
+            .filter(|score| score.damaging.is_some() || score.goodfaith.is_some())
+            .filter_map(|score| {
+                let (revision, item, timestamp) =
+                    revisions.iter().find(|r| r.0 == score.revision)?;
+                Some(vec![
+                    (*item).into(),
+                    (*revision).into(),
+                    score.damaging.into(),
+                    score.goodfaith.into(),
+                    (*timestamp).into(),
+                ])
+            })
+            .collect();
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`damaging`,`goodfaith`,`timestamp`) VALUES",
+            entity_type.table("revision_scores")
+        );
+        self.insert_rows(&sql, &rows).await
+    }
+
+    /// Logs changes pointing at Commons files, with whether the file exists and how much it is used.
+    async fn log_media_changes(&self, entity_type: EntityType, changes: &[Change]) -> Result<()> {
+        if !self.annotate_media {
+            return Ok(());
+        }
+        let media: Vec<(&Change, String)> = changes
+            .iter()
+            .flat_map(|change| {
+                CommonsMedia::files(change)
+                    .into_iter()
+                    .map(move |file| (change, file))
+            })
+            .collect();
+        let mut files: Vec<String> = media.iter().map(|(_, file)| file.to_owned()).collect();
+        files.sort();
+        files.dedup();
+        let usage = CommonsMedia::usage(self, &files).await?;
+        let rows: Vec<Vec<SqlValue>> = media
+            .into_iter()
+            .map(|(change, file)| {
+                let file_usage = usage.get(&file).cloned().unwrap_or_default();
+                vec![
+                    change.item_id.into(),
+                    change.revision_id.into(),
+                    change.subject.as_str().into(),
+                    CommonsMedia::property(change).into(),
+                    file.into(),
+                    file_usage.exists.into(),
+                    file_usage.usage.into(),
+                    file_usage.wikis.into(),
+                    change.timestamp.as_str().into(),
+                ]
+            })
+            .collect();
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`subject`,`property`,`file`,`file_exists`,`usage`,`wikis`,`timestamp`) VALUES",
+            entity_type.table("media_changes")
+        );
+        self.insert_rows(&sql, &rows).await
+    }
+
+    async fn log_sitelink_conflicts(
+        &self,
+        entity_type: EntityType,
+        changes: &[Change],
+    ) -> Result<()> {
+        if !self.detect_sitelink_conflicts || entity_type != EntityType::Item {
+            return Ok(());
+        }
+        let rows: Vec<Vec<SqlValue>> = SitelinkConflicts::find(self, changes)
+            .await?
+            .into_iter()
+            .map(|conflict| {
+                vec![
+                    conflict.change.item_id.into(),
+                    conflict.change.revision_id.into(),
+                    conflict.change.site.as_str().into(),
+                    conflict.change.title.as_str().into(),
+                    conflict.other_item.into(),
+                    conflict.change.timestamp.as_str().into(),
+                ]
+            })
+            .collect();
+        let sql = "INSERT IGNORE INTO `sitelink_conflicts` (`item`,`revision`,`site`,`title`,`other_item`,`timestamp`) VALUES";
+        self.insert_rows(sql, &rows).await
+    }
+
+    /// The `detail` column value of a change, if enabled via `store_details` in the config.
+    fn detail(&self, change: &Change) -> SqlValue {
+        match self.store_details {
+            true => change.detail_json(self.max_value_bytes).into(),
+            false => SqlValue::NULL,
+        }
+    }
+
+    /// The `summary` column value of a change: the text ID of the edit summary action, e.g. `wbsetlabel-add`,
+    /// if enabled via `store_summaries` in the config.
+    async fn summary(&mut self, change: &Change) -> SqlValue {
+        if !self.store_summaries {
+            return SqlValue::NULL;
+        }
+        let action = match change.comment.as_deref().map(EditSummary::parse) {
+            Some(EditSummary {
+                action: Some(action),
+                ..
+            }) => action,
+            _ => return SqlValue::NULL,
+        };
+        match self.get_or_create_text_id(&action).await {
+            Ok(text_id) => text_id.into(),
+            Err(_) => SqlValue::NULL,
+        }
+    }
+
+    /// Logs old and new values of changes, if enabled via `store_values` in the config.
+    async fn log_value_changes(&self, entity_type: EntityType, changes: &[Change]) -> Result<()> {
+        if !self.store_values {
+            return Ok(());
+        }
+        let changes: Vec<&Change> = changes
+            .iter()
+            .filter(|c| !c.old_text.is_empty() || !c.new_text.is_empty())
+            .collect();
+        let values: Vec<Vec<SqlValue>> = changes
+            .iter()
+            .map(|c| c.get_value_log(self.max_value_bytes))
+            .collect();
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`type`,`key`,`old_value`,`new_value`,`truncated`,`timestamp`) VALUES",
+            entity_type.table("change_values")
+        );
+        self.insert_rows(&sql, &values).await?;
+
+        // Full values that were too long for `change_values`
+        let overflow: Vec<Vec<SqlValue>> = changes
+            .iter()
+            .filter(|c| c.is_truncated(self.max_value_bytes))
+            .map(|c| c.get_value_overflow_log())
+            .collect();
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`type`,`key`,`old_value`,`new_value`,`timestamp`) VALUES",
+            entity_type.table("value_overflow")
+        );
+        self.insert_rows(&sql, &overflow).await?;
+        Ok(())
+    }
+
+    /// Logs one row per revision of significant entities, listing the changed subjects.
+    async fn log_significant_changes(
+        &self,
+        entity_type: EntityType,
+        changes: &[Change],
+    ) -> Result<()> {
+        let thresholds = match &self.significant_items {
+            Some(thresholds) => thresholds,
+            None => return Ok(()),
+        };
+        let values: Vec<Vec<SqlValue>> = Self::significant_revisions(thresholds, changes)
+            .into_iter()
+            .map(|(change, subjects)| {
+                vec![
+                    change.item_id.into(),
+                    change.revision_id.into(),
+                    change.timestamp.as_str().into(),
+                    change.sitelinks.into(),
+                    change.statements.into(),
+                    subjects.join(",").into(),
+                ]
+            })
+            .collect();
+        let sql = format!(
+            "INSERT IGNORE INTO `{}` (`item`,`revision`,`timestamp`,`sitelinks`,`statements`,`subjects`) VALUES",
+            entity_type.table("significant_changes")
+        );
+        self.insert_rows(&sql, &values).await
+    }
+
+    /// Returns the first change of each revision of a significant entity, with all changed subjects.
+    fn significant_revisions<'a>(
+        thresholds: &SignificanceThresholds,
+        changes: &'a [Change],
+    ) -> Vec<(&'a Change, Vec<&'a str>)> {
+        let mut ret: Vec<(&Change, Vec<&str>)> = vec![];
+        for change in changes {
+            if !thresholds.is_significant(change.sitelinks, change.statements) {
+                continue;
+            }
+            let subject = change.subject.as_str();
+            match ret
+                .iter_mut()
+                .find(|(c, _)| c.item_id == change.item_id && c.revision_id == change.revision_id)
+            {
+                Some((_, subjects)) => {
+                    if !subjects.contains(&subject) {
+                        subjects.push(subject);
+                    }
+                }
+                None => ret.push((change, vec![subject])),
+            }
+        }
+        ret
+    }
+
+    /// Hands the changes passing the `log_rules` to the configured sink, then to live subscribers,
+    /// and collects them for [`notify`](Self::notify).
+    pub(crate) async fn log_changes(&mut self, changes: &[Change]) -> Result<()> {
+        let kept: Vec<Change>;
+        let changes = match self.log_rules.is_empty() {
+            true => changes,
+            false => {
+                kept = changes
+                    .iter()
+                    .filter(|change| LogRule::logs(&self.log_rules, change))
+                    .cloned()
+                    .collect();
+                self.drops
+                    .add(DropReason::FilteredOut, (changes.len() - kept.len()) as u64);
+                &kept
+            }
+        };
+        let sink = self.sink;
+        sink.log_changes(self, changes).await?;
+        if self.live.receiver_count() > 0 {
+            for change in changes {
+                let _ = self.live.send(ChangeRow::from_change(change)); // Fails only without subscribers
+            }
+        }
+        if let Some(outbox) = &mut self.outbox {
+            outbox.extend_from_slice(changes);
+        }
+        Ok(())
+    }
+
+    /// Writes changes to the wdrc tables, for [`MysqlSink`](crate::sink::MysqlSink).
+    pub(crate) async fn write_changes(&mut self, changes: &[Change]) -> Result<()> {
+        for entity_type in EntityType::all() {
+            let changes: Vec<Change> = changes
+                .iter()
+                .filter(|c| c.entity_type == entity_type)
+                .cloned()
+                .collect();
+            if changes.is_empty() {
+                continue;
+            }
+            self.log_statement_changes(entity_type, &changes).await?;
+            self.log_qualifier_changes(entity_type, &changes).await?;
+            self.log_reference_changes(entity_type, &changes).await?;
+            self.log_sitelinks_changes(entity_type, &changes).await?;
+            self.log_badge_changes(entity_type, &changes).await?;
+            self.log_label_changes(entity_type, &changes).await?;
+            self.log_subentity_changes(entity_type, &changes).await?;
+            self.log_datatype_changes(entity_type, &changes).await?;
+            self.log_value_changes(entity_type, &changes).await?;
+            self.log_tag_changes(entity_type, &changes).await?;
+            self.log_revision_scores(entity_type, &changes).await?;
+            self.log_media_changes(entity_type, &changes).await?;
+            self.log_sitelink_conflicts(entity_type, &changes).await?;
+            self.log_significant_changes(entity_type, &changes).await?;
+        }
         Ok(())
     }
 
-    async fn get_or_create_text_id(&mut self, text: &str) -> Result<TextId> {
+    /// Returns `(?,?),(?,?)` style placeholders for multi-row statements.
+    fn value_placeholders(rows: &[Vec<SqlValue>]) -> String {
+        rows.iter()
+            .map(|row| format!("({})", vec!["?"; row.len()].join(",")))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    /// Runs `sql`, which ends in `VALUES`, for all rows with bound parameters, many rows per statement.
+    pub(crate) async fn insert_rows(&self, sql: &str, rows: &[Vec<SqlValue>]) -> Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.db.get_connection("wdrc").await?;
+        for chunk in rows.chunks(MAX_ROWS_PER_INSERT) {
+            let sql = format!("{sql} {}", Self::value_placeholders(chunk));
+            let params: Vec<SqlValue> = chunk.iter().flatten().cloned().collect();
+            conn.exec_drop(sql, params).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn get_or_create_text_id(&mut self, text: &str) -> Result<TextId> {
         self.chache_texts_in_memory().await?;
         match self.text_cache.get(text) {
             Some(id) => Ok(*id as TextId),
@@ -392,7 +2012,13 @@ impl WdRc {
         Ok(())
     }
 
-    async fn get_key_value(&self, key: &str) -> Result<Option<String>> {
+    pub(crate) async fn get_key_value(&self, key: &str) -> Result<Option<String>> {
+        if let Some(meta) = &self.detached_meta {
+            let meta = meta.lock().map_err(|_| anyhow!("meta lock poisoned"))?;
+            if let Some(value) = meta.get(key) {
+                return Ok(Some(value.to_owned()));
+            }
+        }
         let sql = "SELECT value FROM `meta` WHERE `key`=?";
         let mut conn = self.db.get_connection("wdrc").await?;
         let result: Vec<String> = conn
@@ -403,53 +2029,241 @@ impl WdRc {
         Ok(result.first().map(|s| s.to_string()))
     }
 
-    async fn set_key_value(&self, key: &str, value: &str) -> Result<()> {
-        let sql = "UPDATE `meta` SET `value`=? WHERE `key`=?";
+    pub(crate) async fn set_key_value(&self, key: &str, value: &str) -> Result<()> {
+        if let Some(meta) = &self.detached_meta {
+            let mut meta = meta.lock().map_err(|_| anyhow!("meta lock poisoned"))?;
+            meta.insert(key.to_string(), value.to_string());
+            return Ok(());
+        }
+        let sql = "INSERT INTO `meta` (`key`,`value`) VALUES (?,?) ON DUPLICATE KEY UPDATE `value`=VALUES(`value`)";
         let mut conn = self.db.get_connection("wdrc").await?;
-        conn.exec_drop(sql, (value, key)).await?;
+        conn.exec_drop(sql, (key, value)).await?;
         Ok(())
     }
 
-    fn read_config(config_file: &str) -> Value {
-        let file = File::open(config_file).expect("Reading {config_file} failed");
-        let reader = BufReader::new(file);
-        serde_json::from_reader(reader).expect("Parsing {config_file} failed")
-    }
-
-    fn prepare_wd() -> Arc<Wikidata> {
+    /// Returns a Wikidata API client with the wdrc user agent.
+    pub fn prepare_wd() -> Wikidata {
         let mut wd = Wikidata::new();
         wd.set_user_agent("wdrc-rs/0.1.0");
-        Arc::new(wd)
+        wd
     }
 
-    fn prepare_db(config: &Value) -> ToolforgeDB {
+    fn prepare_db(config: &Config) -> Result<ToolforgeDB> {
         let mut db = ToolforgeDB::default();
-        let config_wikidata = config.get("wikidata").expect("Missing wikidata config");
-        let config_wdrc = config.get("wdrc").expect("Missing wdrc config");
-        db.add_mysql_pool("wikidata", config_wikidata)
-            .expect("Adding wikidata pool failed");
+        // The replica is optional when reading from EventStreams; deletions and redirects are skipped without it
+        if let Some(config_wikidata) = &config.wikidata {
+            db.add_mysql_pool("wikidata", config_wikidata)
+                .map_err(|e| anyhow!("Adding wikidata pool failed: {e}"))?;
+        }
+        let config_wdrc = config
+            .wdrc
+            .as_ref()
+            .ok_or_else(|| anyhow!("Missing wdrc config"))?;
         db.add_mysql_pool("wdrc", config_wdrc)
-            .expect("Adding wdrc pool failed");
-        db
+            .map_err(|e| anyhow!("Adding wdrc pool failed: {e}"))?;
+        if let Some(config_commons) = &config.commons {
+            db.add_mysql_pool("commons", config_commons)
+                .map_err(|e| anyhow!("Adding commons pool failed: {e}"))?;
+        }
+        if let Some(config_legacy) = &config.legacy {
+            db.add_mysql_pool("legacy", config_legacy)
+                .map_err(|e| anyhow!("Adding legacy pool failed: {e}"))?;
+        }
+        Ok(db)
     }
 
+    /// Processes one batch of deletions, redirects, and recent changes, and records the run
+    /// with its dropped records in `runs`.
     pub async fn run_once(&mut self) -> Result<()> {
+        let started = Utc::now().format("%Y%m%d%H%M%S").to_string();
+        self.drops.take();
+        let result = self.run_pipeline().await;
+        let finished = Utc::now().format("%Y%m%d%H%M%S").to_string();
+        let dropped = self.drops.take();
+        if !dropped.is_empty() {
+            self.log(format!("DROPPED: {}", DropCounts::to_json(&dropped)));
+        }
+        if self.detached_meta.is_some() {
+            return result;
+        }
+        let sql = "INSERT INTO `runs` (`started`,`finished`,`error`,`dropped`,`dropped_reasons`) VALUES (?,?,?,?,?)";
+        let params: Vec<SqlValue> = vec![
+            started.into(),
+            finished.into(),
+            result.as_ref().err().map(|e| e.to_string()).into(),
+            dropped
+                .iter()
+                .filter(|(reason, _)| !reason.is_ignored())
+                .map(|(_, count)| count)
+                .sum::<u64>()
+                .into(),
+            DropCounts::to_json(&dropped).to_string().into(),
+        ];
+        let recorded = match self.db.get_connection("wdrc").await {
+            Ok(mut conn) => conn.exec_drop(sql, params).await.map_err(|e| e.into()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = recorded {
+            self.log(format!("Could not record run: {e}"));
+        }
+        result
+    }
+
+    async fn run_pipeline(&mut self) -> Result<()> {
         let future1 = self.update_recent_deletions();
         let future2 = self.update_recent_redirects();
-        let _ = join!(future1, future2); // Ignore errors
+        let future3 = self.update_recent_log_events();
+        let future4 = self.update_recent_merges();
+        let future5 = async {
+            match self.track_wdqs_lag {
+                true => WdqsLag::update(self).await.map(|_| ()),
+                false => Ok(()),
+            }
+        };
+        let _ = join!(future1, future2, future3, future4, future5); // Ignore errors
 
+        if self.past_deadline("recent changes") {
+            return Ok(());
+        }
+        if let Some(plan) = self.catch_up_plan().await? {
+            return self.catch_up(&plan).await;
+        }
+
+        let started = Instant::now();
         let rc = self.get_recent_changes().await?;
         self.log_recent_changes(&rc).await?;
 
         self.log_new_items(&rc).await?;
+        self.batch_size.adjust(rc.rows(), started.elapsed());
 
-        // self.purge_old_entries().await?;
+        // Only advanced once the whole batch is logged, or queued
+        if let Some(rc_id) = rc.last_rc_id() {
+            self.set_key_value("rc_id", &rc_id.to_string()).await?;
+        }
+
+        if self.work_queue && !self.past_deadline("work queue") {
+            self.drain_work_queue().await?;
+        }
         Ok(())
     }
 
-    // pub async fn purge_old_entries(&self) -> Result<()> {
-    //     todo!()
-    // }
+    /// Plans a concurrent catch-up if configured and the replica backlog spans enough windows.
+    async fn catch_up_plan(&self) -> Result<Option<CatchUpPlan>> {
+        let windows = match self.catch_up_windows {
+            // The work queue already keeps comparisons from holding back reading
+            Some(windows)
+                if self.change_source == ChangeSource::Replica
+                    && !self.shadow
+                    && !self.work_queue =>
+            {
+                windows
+            }
+            _ => return Ok(None),
+        };
+        let oldest = self.get_key_value("timestamp").await?.unwrap_or_default();
+        let now = Utc::now().naive_utc();
+        Ok(CatchUpPlan::new(
+            &oldest,
+            now,
+            self.batch_size.window(),
+            windows,
+        ))
+    }
+
+    /// Reads the windows of `plan` concurrently, then compares and logs them in order, advancing
+    /// the checkpoints after each; comparisons stay within `max_api_concurrent`. Windows after
+    /// one that hit the row limit are left for later runs, since they would leave a gap.
+    async fn catch_up(&mut self, plan: &CatchUpPlan) -> Result<()> {
+        self.log(format!("CATCH-UP: {} windows", plan.windows().len()));
+        let fetched = join_all(
+            plan.windows()
+                .iter()
+                .map(|(from, to)| self.get_recent_changes_between(from, to)),
+        )
+        .await;
+        let mut batches = vec![];
+        for ((from, to), rows) in plan.windows().iter().zip(fetched) {
+            let rows = rows?;
+            let full = rows.len() as u64 >= self.batch_size.limit();
+            let rc = self.recent_changes_results(&rows);
+            // The next run continues from the last change of a window cut short, and rc_id
+            // only moves to the last row read rather than the highest one
+            let (checkpoint, rc_id) = match full {
+                true => (
+                    rc.get_last_rc_timetamp(from),
+                    rows.iter().rev().map(|row| row.rc_id).find(|id| *id > 0),
+                ),
+                false => (to.to_owned(), rc.last_rc_id()),
+            };
+            batches.push((rc, checkpoint, rc_id));
+            if full {
+                break;
+            }
+        }
+
+        self.get_failed_items().await?; // Loads the retry queue before it is updated
+        for (rc, checkpoint, rc_id) in batches {
+            if self.past_deadline("catch-up window") {
+                break;
+            }
+            let items: Vec<ChangedItem> = rc
+                .changed_items()
+                .iter()
+                .chain(rc.creations().iter())
+                .cloned()
+                .collect();
+            let compared = self.compare(&items).await;
+            self.log_compared(compared).await?;
+            self.log_new_items(&rc).await?;
+            self.set_key_value("timestamp", &checkpoint).await?;
+            if let Some(rc_id) = rc_id {
+                self.set_key_value("rc_id", &rc_id.to_string()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The timestamp before which entries are removed, if `retention_days` is configured.
+    pub fn retention_cutoff(&self) -> Option<String> {
+        self.retention_days.map(|days| {
+            (Utc::now() - chrono::Duration::days(days as i64))
+                .format("%Y%m%d%H%M%S")
+                .to_string()
+        })
+    }
+
+    /// Removes entries older than `retention_days` from all change tables, if configured.
+    /// Returns the number of rows removed.
+    pub async fn purge_old_entries(&self) -> Result<u64> {
+        let cutoff = match self.retention_cutoff() {
+            Some(cutoff) => cutoff,
+            None => return Ok(0),
+        };
+        let mut tables = Redactor::revision_tables();
+        for entity_type in EntityType::all() {
+            for table in [
+                "creations",
+                "deletions",
+                "redirects",
+                "log_events",
+                "merges",
+                "protections",
+            ] {
+                tables.push(entity_type.table(table));
+            }
+        }
+        tables.push("notifications".to_string());
+
+        let mut conn = self.db.get_connection("wdrc").await?;
+        let mut rows = 0;
+        for table in tables {
+            let sql = format!("DELETE FROM `{table}` WHERE `timestamp`<?");
+            conn.exec_drop(sql, (&cutoff,)).await?;
+            rows += conn.affected_rows();
+        }
+        self.log(format!("PURGED: {rows} rows before {cutoff}"));
+        Ok(rows)
+    }
 }
 
 #[cfg(test)]
@@ -458,9 +2272,79 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_or_create_text_id() {
-        let mut wdrc = WdRc::new("config.json");
+        let mut wdrc = WdRc::new("config.json").unwrap();
         let text = "aawikibooks";
         let id = wdrc.get_or_create_text_id(text).await.unwrap();
         assert_eq!(id, 1252);
     }
+
+    #[test]
+    fn test_backoff_delay() {
+        let interval = Duration::from_secs(10);
+        let max = Duration::from_secs(60);
+        let delay = |errors, jitter| WdRc::backoff_delay(interval, max, errors, jitter);
+        assert_eq!(delay(0, 0.0), interval);
+        assert_eq!(delay(1, 0.0), Duration::from_secs(20));
+        assert_eq!(delay(2, 0.0), Duration::from_secs(40));
+        assert_eq!(delay(3, 0.0), max);
+        assert_eq!(delay(100, 0.0), max);
+        assert_eq!(delay(0, 1.0), Duration::from_secs(11));
+    }
+
+    #[test]
+    fn test_update_retry_queue() {
+        let item = |q: &str| ChangedItem::new(q, 1, 2, "20240101000000");
+        let queue = vec![
+            FailedItem {
+                item: item("Q1"),
+                attempts: 1,
+            },
+            FailedItem {
+                item: item("Q2"),
+                attempts: MAX_COMPARE_ATTEMPTS - 1,
+            },
+            FailedItem {
+                item: item("Q3"),
+                attempts: 2,
+            },
+        ];
+        let failed = [item("Q2"), item("Q3"), item("Q4")];
+        let held = [item("Q3"), item("Q5")];
+        let queue = WdRc::update_retry_queue(queue, &[item("Q1")], &failed, &held);
+        let summary: Vec<(&str, u32)> = queue.iter().map(|f| (f.item.q(), f.attempts)).collect();
+        // Held items do not count an attempt
+        assert_eq!(summary, vec![("Q3", 3), ("Q5", 0), ("Q4", 1)]);
+    }
+
+    #[test]
+    fn test_significant_revisions() {
+        let change = |item_id, subject, sitelinks| Change {
+            subject,
+            item_id,
+            revision_id: item_id * 10,
+            sitelinks,
+            ..Default::default()
+        };
+        let changes = vec![
+            change(1, ChangeSubject::Labels, 100),
+            change(1, ChangeSubject::Claims, 100),
+            change(1, ChangeSubject::Labels, 100),
+            change(2, ChangeSubject::Claims, 3),
+        ];
+        let thresholds = SignificanceThresholds {
+            min_sitelinks: Some(50),
+            min_statements: None,
+        };
+        let revisions = WdRc::significant_revisions(&thresholds, &changes);
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(revisions[0].0.revision_id, 10);
+        assert_eq!(revisions[0].1, vec!["labels", "claims"]);
+    }
+
+    #[test]
+    fn test_value_placeholders() {
+        let rows: Vec<Vec<SqlValue>> = vec![vec![1.into(), "a".into()], vec![2.into(), "b".into()]];
+        assert_eq!(WdRc::value_placeholders(&rows), "(?,?),(?,?)");
+        assert_eq!(WdRc::value_placeholders(&[]), "");
+    }
 }
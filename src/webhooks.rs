@@ -0,0 +1,280 @@
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use hmac::{Hmac, Mac};
+use http::Method;
+use serde_json::json;
+use sha2::Sha256;
+use std::{collections::BTreeMap, time::Duration};
+use wikimisc::wikidata::Wikidata;
+
+use crate::{
+    change::Change,
+    config::{ApiRetryConfig, WebhookConfig},
+    deliveries::{Deliveries, MatchedChange},
+    query::ChangeRow,
+    revision_compare::RevisionCompare,
+    WdRc,
+};
+
+/// Header carrying the HMAC-SHA256 of the request body, as `sha256=<hex>`.
+const SIGNATURE_HEADER: &str = "X-Wdrc-Signature";
+
+/// An HTTP request with a JSON body, for [`Webhooks::send_json`].
+pub(crate) struct JsonRequest {
+    /// Names the request in errors instead of the URL, which may hold a token.
+    pub name: String,
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+/// Posts logged changes to the configured webhooks, as `{"wiki": "wikidatawiki", "changes": [...]}`
+/// with changes in the `ndjson` sink format, each with the `matched` filters of the webhooks
+/// sharing its URL.
+pub struct Webhooks;
+
+impl Webhooks {
+    /// Posts the changes matching each webhook's filter to it, to all webhooks at once, through
+    /// [`Deliveries`], so bodies that failed before are posted first. Webhooks with the same URL
+    /// get each change once, signed with the secret of the first of them. Returns the errors of
+    /// URLs that still failed after retries.
+    pub async fn dispatch(
+        wdrc: &WdRc,
+        webhooks: &[WebhookConfig],
+        changes: &[Change],
+    ) -> Vec<String> {
+        let mut merged = Self::merged(webhooks, changes);
+        let mut endpoints: Vec<(usize, &WebhookConfig, String)> = vec![];
+        for (num, webhook) in webhooks.iter().enumerate() {
+            let key = Deliveries::webhook_key(webhook);
+            if !endpoints.iter().any(|(_, _, other)| *other == key) {
+                endpoints.push((num, webhook, key));
+            }
+        }
+        let deliveries = Deliveries::new(wdrc);
+        let deliveries = &deliveries;
+        let futures = endpoints.into_iter().map(|(num, webhook, key)| {
+            let body = merged
+                .remove(&key)
+                .map(|matching| Self::body(wdrc.wiki().dbname(), &matching));
+            async move {
+                deliveries
+                    .deliver(&key, body, |body| {
+                        let name = format!("webhook {num}");
+                        Self::post(
+                            wdrc.wd(),
+                            name,
+                            webhook,
+                            body,
+                            wdrc.api_retry(),
+                            wdrc.api_timeout(),
+                        )
+                    })
+                    .await
+            }
+        });
+        join_all(futures)
+            .await
+            .into_iter()
+            .filter_map(|result| result.err())
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    /// The changes matching any webhook, per webhook URL key.
+    fn merged<'c>(
+        webhooks: &[WebhookConfig],
+        changes: &'c [Change],
+    ) -> BTreeMap<String, Vec<MatchedChange<'c>>> {
+        let rows: Vec<ChangeRow> = changes.iter().map(ChangeRow::from_change).collect();
+        let rows = &rows;
+        Deliveries::merge_matches(
+            changes,
+            webhooks.iter().flat_map(|webhook| {
+                let key = Deliveries::webhook_key(webhook);
+                let name = webhook.filter.name();
+                Self::matching(webhook, rows).map(move |num| (key.to_owned(), name.to_owned(), num))
+            }),
+        )
+    }
+
+    /// The indexes of the rows matching a webhook's filter.
+    fn matching<'a>(
+        webhook: &'a WebhookConfig,
+        rows: &'a [ChangeRow],
+    ) -> impl Iterator<Item = usize> + 'a {
+        (0..rows.len()).filter(|num| webhook.filter.matches(&rows[*num]))
+    }
+
+    /// The request body for `changes`.
+    pub(crate) fn body(wiki: &str, changes: &[MatchedChange]) -> String {
+        json!({"wiki": wiki, "changes": changes}).to_string()
+    }
+
+    /// Posts `body` to a webhook, signed if it has a secret; `name` stands for it in errors.
+    pub(crate) async fn post(
+        wd: &Wikidata,
+        name: String,
+        webhook: &WebhookConfig,
+        body: String,
+        retry: &ApiRetryConfig,
+        timeout: Duration,
+    ) -> Result<()> {
+        let request = JsonRequest {
+            name,
+            method: Method::POST,
+            url: webhook.url.to_owned(),
+            headers: webhook
+                .secret
+                .as_ref()
+                .map(|secret| (SIGNATURE_HEADER, Self::signature(secret, &body)))
+                .into_iter()
+                .collect(),
+            body,
+        };
+        Self::send_json(wd, &request, retry, timeout).await
+    }
+
+    /// Sends a JSON request, retrying on connection errors, HTTP 429 and server errors like API
+    /// calls.
+    pub(crate) async fn send_json(
+        wd: &Wikidata,
+        request: &JsonRequest,
+        retry: &ApiRetryConfig,
+        timeout: Duration,
+    ) -> Result<()> {
+        let client = wd.reqwest_client()?;
+        let name = &request.name;
+        let mut attempt = 1;
+        loop {
+            let mut builder = client
+                .request(request.method.to_owned(), &request.url)
+                .timeout(timeout)
+                .header("content-type", "application/json")
+                .body(request.body.to_owned());
+            for (header, value) in &request.headers {
+                builder = builder.header(*header, value);
+            }
+            let mut retry_after = None;
+            let error = match builder.send().await {
+                Err(e) => anyhow!("Request to {name} failed: {}", e.without_url()),
+                Ok(response) => {
+                    let status = response.status();
+                    retry_after = response
+                        .headers()
+                        .get("retry-after")
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.trim().parse::<u64>().ok());
+                    if status.is_success() {
+                        return Ok(());
+                    }
+                    let error = anyhow!("HTTP {status} from {name}");
+                    if status.as_u16() != 429 && !status.is_server_error() {
+                        return Err(error);
+                    }
+                    error
+                }
+            };
+            if attempt >= retry.max_attempts {
+                return Err(error);
+            }
+            tokio::time::sleep(RevisionCompare::retry_delay(retry, attempt, retry_after)).await;
+            attempt += 1;
+        }
+    }
+
+    /// The signature header value of `body` for `secret`.
+    fn signature(secret: &str, body: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(body.as_bytes());
+        let hex: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!("sha256={hex}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        change::{ChangeSubject, ChangeType},
+        live::LiveFilter,
+    };
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            Webhooks::signature("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_matching() {
+        let change = |item_id, change_type| Change {
+            subject: ChangeSubject::Claims,
+            change_type,
+            property: "P31".to_string(),
+            item_id,
+            ..Default::default()
+        };
+        let changes = [
+            change(42, ChangeType::Added),
+            change(42, ChangeType::Removed),
+            change(64, ChangeType::Added),
+        ];
+        let webhook = WebhookConfig {
+            url: "https://example.org/hook".to_string(),
+            secret: None,
+            filter: LiveFilter::from_query("items=Q42&types=added").unwrap(),
+        };
+        let rows: Vec<ChangeRow> = changes.iter().map(ChangeRow::from_change).collect();
+        assert_eq!(
+            Webhooks::matching(&webhook, &rows).collect::<Vec<_>>(),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn test_overlapping_webhooks() {
+        let change = |item_id, property: &str| Change {
+            subject: ChangeSubject::Claims,
+            change_type: ChangeType::Added,
+            property: property.to_string(),
+            item_id,
+            ..Default::default()
+        };
+        let changes = [change(42, "P31"), change(64, "P31"), change(42, "P18")];
+        let webhook = |url: &str, filter| WebhookConfig {
+            url: url.to_string(),
+            secret: None,
+            filter: LiveFilter::from_query(filter).unwrap(),
+        };
+        let webhooks = [
+            webhook("https://example.org/hook", "items=Q42"),
+            webhook("https://example.org/hook", "props=P31"),
+            webhook("https://example.net/hook", "props=P31"),
+        ];
+        let merged = Webhooks::merged(&webhooks, &changes);
+        let body: serde_json::Value = serde_json::from_str(&Webhooks::body(
+            "wikidatawiki",
+            &merged[&Deliveries::webhook_key(&webhooks[0])],
+        ))
+        .unwrap();
+        let body = body["changes"].as_array().unwrap();
+        assert_eq!(body.len(), 3);
+        assert_eq!(body[0]["matched"], json!(["items=Q42", "props=P31"]));
+        assert_eq!(body[1]["matched"], json!(["props=P31"]));
+        assert_eq!(body[2]["matched"], json!(["items=Q42"]));
+        let other = &merged[&Deliveries::webhook_key(&webhooks[2])];
+        assert_eq!(other.len(), 2);
+        assert_eq!(other[0].matched, ["props=P31"]);
+    }
+}
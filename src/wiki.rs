@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::ApiRetryConfig;
+
+/// The wiki a deployment tracks. `testwikidata` points API calls and stream filtering at
+/// test.wikidata.org, for staging and feature development against a safe live wiki. `local` is
+/// the Wikibase started by `tests/integration/docker-compose.yml`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Wiki {
+    #[default]
+    Wikidata,
+    TestWikidata,
+    Local,
+}
+
+impl Wiki {
+    /// The database name, as used by replicas, EventStreams and LiftWing.
+    pub fn dbname(&self) -> &'static str {
+        match self {
+            Self::Wikidata => "wikidatawiki",
+            Self::TestWikidata => "testwikidatawiki",
+            Self::Local => "my_wiki",
+        }
+    }
+
+    pub fn server(&self) -> &'static str {
+        match self {
+            Self::Wikidata => "https://www.wikidata.org",
+            Self::TestWikidata => "https://test.wikidata.org",
+            Self::Local => "http://localhost:8181",
+        }
+    }
+
+    pub fn api_url(&self) -> String {
+        format!("{}/w/api.php", self.server())
+    }
+
+    pub fn rest_url(&self) -> String {
+        format!("{}/w/rest.php/wikibase/v1", self.server())
+    }
+
+    /// Retries of API calls unless configured: test wikis get fewer, shorter retries and no
+    /// `maxlag`, so failures show up quickly during development.
+    pub fn default_api_retry(&self) -> ApiRetryConfig {
+        match self {
+            Self::Wikidata => ApiRetryConfig::default(),
+            Self::TestWikidata | Self::Local => ApiRetryConfig {
+                max_attempts: 2,
+                initial_backoff_ms: 500,
+                max_backoff_ms: 5000,
+                maxlag: 0,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wiki() {
+        let wiki: Wiki = serde_json::from_str("\"testwikidata\"").unwrap();
+        assert_eq!(wiki, Wiki::TestWikidata);
+        assert_eq!(wiki.api_url(), "https://test.wikidata.org/w/api.php");
+        assert_eq!(wiki.dbname(), "testwikidatawiki");
+        assert_eq!(
+            Wiki::default().rest_url(),
+            "https://www.wikidata.org/w/rest.php/wikibase/v1"
+        );
+        assert_eq!(wiki.default_api_retry().maxlag, 0);
+    }
+}
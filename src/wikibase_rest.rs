@@ -0,0 +1,278 @@
+use serde_json::{json, Map, Value};
+
+use crate::{change::EntityType, revision_compare::RevisionId, wiki::Wiki};
+
+/// Reads entities from the Wikibase REST API, and converts them to the entity JSON of the action API
+/// that [`crate::RevisionCompare`] compares. Snak hashes are not part of REST responses.
+pub struct WikibaseRest;
+
+impl WikibaseRest {
+    /// The REST URL of an entity; lexemes are not served by the REST API.
+    pub fn entity_url(wiki: Wiki, q: &str) -> Option<String> {
+        let path = match EntityType::from_id(q)? {
+            EntityType::Item => "items",
+            EntityType::Property => "properties",
+            EntityType::Lexeme => return None,
+        };
+        Some(format!("{}/entities/{path}/{q}", wiki.rest_url()))
+    }
+
+    /// The revision of an entity response, from its `ETag` header like `"12345"`.
+    pub fn parse_etag(etag: &str) -> Option<RevisionId> {
+        etag.trim_start_matches("W/").trim_matches('"').parse().ok()
+    }
+
+    /// Converts a REST entity to action API entity JSON.
+    pub fn to_entity_json(q: &str, j: &Value) -> Value {
+        let mut ret = Map::new();
+        ret.insert("id".to_string(), json!(q));
+        for key in ["labels", "descriptions"] {
+            let terms: Map<String, Value> = Self::object(j, key)
+                .into_iter()
+                .map(|(language, value)| {
+                    let term = json!({"language": language, "value": value});
+                    (language, term)
+                })
+                .collect();
+            ret.insert(key.to_string(), Value::Object(terms));
+        }
+        let aliases: Map<String, Value> = Self::object(j, "aliases")
+            .into_iter()
+            .map(|(language, values)| {
+                let values: Vec<Value> = Self::array(&values)
+                    .into_iter()
+                    .map(|value| json!({"language": language, "value": value}))
+                    .collect();
+                (language, Value::Array(values))
+            })
+            .collect();
+        ret.insert("aliases".to_string(), Value::Object(aliases));
+        let sitelinks: Map<String, Value> = Self::object(j, "sitelinks")
+            .into_iter()
+            .map(|(site, sitelink)| {
+                let sitelink =
+                    json!({"site": site, "title": sitelink["title"], "badges": sitelink["badges"]});
+                (site, sitelink)
+            })
+            .collect();
+        if EntityType::from_id(q) == Some(EntityType::Item) {
+            ret.insert("sitelinks".to_string(), Value::Object(sitelinks));
+        }
+        if let Some(datatype) = j.get("data_type") {
+            ret.insert("datatype".to_string(), datatype.clone());
+        }
+        let claims: Map<String, Value> = Self::object(j, "statements")
+            .into_iter()
+            .map(|(property, statements)| {
+                let statements = Self::array(&statements)
+                    .iter()
+                    .map(Self::statement)
+                    .collect();
+                (property, Value::Array(statements))
+            })
+            .collect();
+        ret.insert("claims".to_string(), Value::Object(claims));
+        Value::Object(ret)
+    }
+
+    /// Removes the hashes of all snaks of an action API entity, so it compares equal to a converted REST entity.
+    /// Reference hashes are kept.
+    pub fn strip_snak_hashes(entity: &mut Value) {
+        let claims = match entity.get_mut("claims").and_then(|c| c.as_object_mut()) {
+            Some(claims) => claims,
+            None => return,
+        };
+        for claim in claims
+            .values_mut()
+            .filter_map(|c| c.as_array_mut())
+            .flatten()
+        {
+            if let Some(mainsnak) = claim.get_mut("mainsnak").and_then(|s| s.as_object_mut()) {
+                mainsnak.remove("hash");
+            }
+            if let Some(qualifiers) = claim.get_mut("qualifiers") {
+                Self::strip_snaks(qualifiers);
+            }
+            let references = claim.get_mut("references").and_then(|r| r.as_array_mut());
+            for reference in references.into_iter().flatten() {
+                if let Some(snaks) = reference.get_mut("snaks") {
+                    Self::strip_snaks(snaks);
+                }
+            }
+        }
+    }
+
+    fn strip_snaks(snaks: &mut Value) {
+        let snaks = snaks
+            .as_object_mut()
+            .into_iter()
+            .flat_map(|s| s.values_mut());
+        for snak in snaks.filter_map(|s| s.as_array_mut()).flatten() {
+            if let Some(snak) = snak.as_object_mut() {
+                snak.remove("hash");
+            }
+        }
+    }
+
+    fn statement(j: &Value) -> Value {
+        let mut ret = json!({
+            "id": j["id"],
+            "rank": j["rank"],
+            "type": "statement",
+            "mainsnak": Self::snak(j),
+        });
+        let (qualifiers, order) = Self::snaks(&Self::array(&j["qualifiers"]));
+        if !order.is_empty() {
+            ret["qualifiers"] = qualifiers;
+            ret["qualifiers-order"] = json!(order);
+        }
+        let references: Vec<Value> = Self::array(&j["references"])
+            .iter()
+            .map(|reference| {
+                let (snaks, order) = Self::snaks(&Self::array(&reference["parts"]));
+                json!({"hash": reference["hash"], "snaks": snaks, "snaks-order": order})
+            })
+            .collect();
+        if !references.is_empty() {
+            ret["references"] = json!(references);
+        }
+        ret
+    }
+
+    /// Groups REST property-value pairs by property, with the properties in order of appearance.
+    fn snaks(parts: &[Value]) -> (Value, Vec<String>) {
+        let mut snaks = Map::new();
+        let mut order = vec![];
+        for part in parts {
+            let property = part["property"]["id"].as_str().unwrap_or_default();
+            if !snaks.contains_key(property) {
+                snaks.insert(property.to_string(), json!([]));
+                order.push(property.to_string());
+            }
+            if let Some(list) = snaks[property].as_array_mut() {
+                list.push(Self::snak(part));
+            }
+        }
+        (Value::Object(snaks), order)
+    }
+
+    /// Converts a REST property-value pair to a snak.
+    fn snak(j: &Value) -> Value {
+        let property = &j["property"]["id"];
+        let datatype = j["property"]["data_type"].as_str().unwrap_or_default();
+        match j["value"]["type"].as_str() {
+            Some("value") => json!({
+                "snaktype": "value",
+                "property": property,
+                "datatype": datatype,
+                "datavalue": Self::datavalue(datatype, &j["value"]["content"]),
+            }),
+            Some(snaktype) => {
+                json!({"snaktype": snaktype, "property": property, "datatype": datatype})
+            }
+            None => Value::Null,
+        }
+    }
+
+    fn datavalue(datatype: &str, content: &Value) -> Value {
+        match datatype {
+            "wikibase-item" | "wikibase-property" | "wikibase-lexeme" | "wikibase-form"
+            | "wikibase-sense" => {
+                json!({"type": "wikibase-entityid", "value": Self::entity_id(datatype, content)})
+            }
+            "time" => {
+                let mut value = content.clone();
+                for (key, default) in [("timezone", 0), ("before", 0), ("after", 0)] {
+                    if value.get(key).is_none() {
+                        value[key] = json!(default);
+                    }
+                }
+                json!({"type": "time", "value": value})
+            }
+            "globe-coordinate" => {
+                let mut value = content.clone();
+                if value.get("altitude").is_none() {
+                    value["altitude"] = Value::Null;
+                }
+                json!({"type": "globecoordinate", "value": value})
+            }
+            "quantity" | "monolingualtext" => json!({"type": datatype, "value": content}),
+            _ => json!({"type": "string", "value": content}),
+        }
+    }
+
+    /// `Q42` as `{"entity-type": "item", "numeric-id": 42, "id": "Q42"}`; forms and senses have no numeric ID.
+    fn entity_id(datatype: &str, content: &Value) -> Value {
+        let id = content.as_str().unwrap_or_default();
+        let entity_type = datatype.trim_start_matches("wikibase-");
+        match id.get(1..).and_then(|numeric| numeric.parse::<u64>().ok()) {
+            Some(numeric) => json!({"entity-type": entity_type, "numeric-id": numeric, "id": id}),
+            None => json!({"entity-type": entity_type, "id": id}),
+        }
+    }
+
+    fn object(j: &Value, key: &str) -> Map<String, Value> {
+        j[key].as_object().cloned().unwrap_or_default()
+    }
+
+    fn array(j: &Value) -> Vec<Value> {
+        j.as_array().cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entity_url() {
+        assert_eq!(
+            WikibaseRest::entity_url(Wiki::Wikidata, "Q42").unwrap(),
+            "https://www.wikidata.org/w/rest.php/wikibase/v1/entities/items/Q42"
+        );
+        assert!(WikibaseRest::entity_url(Wiki::TestWikidata, "P31")
+            .unwrap()
+            .ends_with("/properties/P31"));
+        assert!(WikibaseRest::entity_url(Wiki::Wikidata, "L7").is_none());
+        assert_eq!(WikibaseRest::parse_etag("\"12345\""), Some(12345));
+        assert_eq!(WikibaseRest::parse_etag("W/\"12345\""), Some(12345));
+    }
+
+    #[test]
+    fn test_to_entity_json() {
+        let rest = json!({
+            "id": "Q42",
+            "type": "item",
+            "labels": {"en": "Douglas Adams"},
+            "descriptions": {},
+            "aliases": {"en": ["DNA"]},
+            "sitelinks": {"enwiki": {"title": "Douglas Adams", "badges": [], "url": "https://en.wikipedia.org/wiki/Douglas_Adams"}},
+            "statements": {"P31": [{
+                "id": "Q42$F078E5B3-F9A8-480E-B7AC-D97778CBBEF9",
+                "rank": "normal",
+                "property": {"id": "P31", "data_type": "wikibase-item"},
+                "value": {"type": "value", "content": "Q5"},
+                "qualifiers": [{"property": {"id": "P1810", "data_type": "string"}, "value": {"type": "somevalue"}}],
+                "references": [{"hash": "abc", "parts": [{"property": {"id": "P143", "data_type": "wikibase-item"}, "value": {"type": "value", "content": "Q328"}}]}],
+            }]},
+        });
+        let mut action = json!({
+            "id": "Q42",
+            "labels": {"en": {"language": "en", "value": "Douglas Adams"}},
+            "descriptions": {},
+            "aliases": {"en": [{"language": "en", "value": "DNA"}]},
+            "sitelinks": {"enwiki": {"site": "enwiki", "title": "Douglas Adams", "badges": []}},
+            "claims": {"P31": [{
+                "id": "Q42$F078E5B3-F9A8-480E-B7AC-D97778CBBEF9",
+                "rank": "normal",
+                "type": "statement",
+                "mainsnak": {"snaktype": "value", "property": "P31", "hash": "h1", "datatype": "wikibase-item", "datavalue": {"type": "wikibase-entityid", "value": {"entity-type": "item", "numeric-id": 5, "id": "Q5"}}},
+                "qualifiers": {"P1810": [{"snaktype": "somevalue", "property": "P1810", "hash": "h2", "datatype": "string"}]},
+                "qualifiers-order": ["P1810"],
+                "references": [{"hash": "abc", "snaks": {"P143": [{"snaktype": "value", "property": "P143", "hash": "h3", "datatype": "wikibase-item", "datavalue": {"type": "wikibase-entityid", "value": {"entity-type": "item", "numeric-id": 328, "id": "Q328"}}}]}, "snaks-order": ["P143"]}],
+            }]},
+        });
+        WikibaseRest::strip_snak_hashes(&mut action);
+        assert_eq!(WikibaseRest::to_entity_json("Q42", &rest), action);
+    }
+}
@@ -0,0 +1,141 @@
+//! End-to-end tests against a local Wikibase: scripted edits, one pipeline run, and checks of the
+//! logged rows. Run via `tests/integration/run.sh`, which starts the containers and creates the
+//! wdrc tables.
+#![cfg(feature = "integration")]
+
+use serde_json::{json, Value};
+use std::{env, fs, time::Duration};
+use wdrc_rs::{wiki::Wiki, ItemId, WdRc};
+use wikimisc::mysql_async::{prelude::Queryable, Pool};
+
+fn config_file() -> String {
+    env::var("WDRC_INTEGRATION_CONFIG")
+        .unwrap_or_else(|_| "tests/integration/config.json".to_string())
+}
+
+/// Posts to the local Wikibase API as an anonymous user.
+async fn api_post(params: &[(&str, &str)]) -> Value {
+    let mut params = params.to_vec();
+    params.extend([("format", "json"), ("token", "+\\")]);
+    let client = WdRc::prepare_wd().reqwest_client().unwrap();
+    let j: Value = client
+        .post(Wiki::Local.api_url())
+        .form(&params)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(j["error"].is_null(), "API error: {j}");
+    j
+}
+
+/// Creates or edits an entity, returning its ID and the new revision ID.
+async fn edit_entity(target: (&str, &str), data: Value) -> (String, u64) {
+    let data = data.to_string();
+    let j = api_post(&[("action", "wbeditentity"), target, ("data", &data)]).await;
+    let id = j["entity"]["id"].as_str().unwrap().to_string();
+    (id, j["entity"]["lastrevid"].as_u64().unwrap())
+}
+
+/// Waits until the revision shows up in `recentchanges`, which MediaWiki fills after the response.
+async fn wait_for_recent_change(revision: u64) {
+    for _ in 0..30 {
+        let j = api_post(&[
+            ("action", "query"),
+            ("list", "recentchanges"),
+            ("rcprop", "ids"),
+            ("rclimit", "50"),
+        ])
+        .await;
+        let found = j["query"]["recentchanges"]
+            .as_array()
+            .is_some_and(|rcs| rcs.iter().any(|rc| rc["revid"].as_u64() == Some(revision)));
+        if found {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    panic!("Revision {revision} not in recentchanges");
+}
+
+fn wdrc_pool() -> Pool {
+    let config: Value = serde_json::from_str(&fs::read_to_string(config_file()).unwrap()).unwrap();
+    Pool::new(config["wdrc"]["url"].as_str().unwrap())
+}
+
+fn numeric(id: &str) -> ItemId {
+    WdRc::make_id_numeric(id).unwrap()
+}
+
+#[tokio::test]
+async fn test_pipeline() {
+    let label = |value: &str| json!({"en": {"language": "en", "value": value}});
+    let (property, _) = edit_entity(
+        ("new", "property"),
+        json!({"datatype": "string", "labels": label("wdrc test string")}),
+    )
+    .await;
+    let (item, _) = edit_entity(("new", "item"), json!({"labels": label("wdrc test item")})).await;
+    let claim = json!({
+        "type": "statement",
+        "rank": "normal",
+        "mainsnak": {
+            "snaktype": "value",
+            "property": property,
+            "datavalue": {"value": "some text", "type": "string"},
+        },
+    });
+    let (_, revision) = edit_entity(
+        ("id", &item),
+        json!({"labels": label("wdrc test item, renamed"), "claims": [claim]}),
+    )
+    .await;
+    wait_for_recent_change(revision).await;
+
+    let mut wdrc = WdRc::new(&config_file()).unwrap();
+    wdrc.run_once().await.unwrap();
+
+    let mut conn = wdrc_pool().get_conn().await.unwrap();
+    let statements: Vec<(ItemId, String)> = conn
+        .exec(
+            "SELECT `property`,`change_type` FROM `statements` WHERE `item`=? AND `revision`=?",
+            (numeric(&item), revision),
+        )
+        .await
+        .unwrap();
+    assert_eq!(statements, vec![(numeric(&property), "added".to_string())]);
+
+    let labels: Vec<(String, String)> = conn
+        .exec(
+            "SELECT `type`,`change_type` FROM `labels` WHERE `item`=? AND `revision`=?",
+            (numeric(&item), revision),
+        )
+        .await
+        .unwrap();
+    assert_eq!(labels, vec![("labels".to_string(), "changed".to_string())]);
+
+    let created: Option<String> = conn
+        .exec_first(
+            "SELECT `timestamp` FROM `creations` WHERE `q`=?",
+            (numeric(&item),),
+        )
+        .await
+        .unwrap();
+    assert!(created.is_some());
+    let property_created: Option<String> = conn
+        .exec_first(
+            "SELECT `timestamp` FROM `property_creations` WHERE `q`=?",
+            (numeric(&property),),
+        )
+        .await
+        .unwrap();
+    assert!(property_created.is_some());
+
+    let errors: Option<u64> = conn
+        .query_first("SELECT count(*) FROM `runs` WHERE `error` IS NOT NULL")
+        .await
+        .unwrap();
+    assert_eq!(errors, Some(0));
+}
@@ -0,0 +1,23 @@
+<?php
+// Entity namespaces as on Wikidata, which wdrc relies on: items in the main namespace,
+// properties in 120.
+$wgExtraNamespaces[120] = 'Property';
+$wgExtraNamespaces[121] = 'Property_talk';
+$wgWBRepoSettings['entitySources'] = [
+	'local' => [
+		'entityNamespaces' => [ 'item' => NS_MAIN, 'property' => 120 ],
+		'repoDatabase' => false,
+		'baseUri' => $wgServer . '/entity/',
+		'rdfNodeNamespacePrefix' => 'wd',
+		'rdfPredicateNamespacePrefix' => '',
+		'interwikiPrefix' => '',
+	],
+];
+$wgWBRepoSettings['localEntitySourceName'] = 'local';
+
+// Scripted anonymous edits
+$wgGroupPermissions['*']['edit'] = true;
+$wgGroupPermissions['*']['createpage'] = true;
+$wgGroupPermissions['*']['property-create'] = true;
+$wgRateLimits = [];
+$wgRCMaxAge = 365 * 24 * 3600;
@@ -0,0 +1,15 @@
+#!/bin/bash
+# Starts a local Wikibase, creates the wdrc tables, and runs the integration tests against them.
+# Set KEEP=1 to leave the containers running afterwards.
+set -e
+cd "$(dirname "$0")"
+docker compose up -d --wait
+docker compose exec -T mysql mariadb -uroot -psecret -e "CREATE DATABASE IF NOT EXISTS wdrc"
+cd ../..
+cargo run -p wdrc_rs -- init-db tests/integration/config.json
+status=0
+cargo test --features integration --test integration -- --test-threads=1 || status=$?
+if [ -z "$KEEP" ]; then
+	docker compose -f tests/integration/docker-compose.yml down -v
+fi
+exit $status